@@ -1,13 +1,48 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
-use marketplace::CurrencyType;
+use anchor_lang::solana_program::hash::hash;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke_signed;
+use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use marketplace::{CurrencyType, RoundingPolicy};
 
 // Import the marketplace program for cross-program invocation
 use marketplace::program::Marketplace;
 use marketplace::{self, Product, ProductStatus};
+use reputation::program::Reputation;
+use reputation::UserReputation;
 
 declare_id!("Escrow1111111111111111111111111111111111111111");
 
+/// Maximum number of scheduled releases on a single escrow
+pub const MAX_SCHEDULED_RELEASES: usize = 4;
+
+/// Number of `DisputeReason` variants
+pub const DISPUTE_REASON_COUNT: usize = 4;
+
+/// Maximum number of arbitrators assigned to a single dispute reason
+pub const MAX_ARBITRATORS_PER_REASON: usize = 3;
+
+/// Maximum number of line items in a cart escrow
+pub const MAX_CART_ITEMS: usize = 4;
+
+/// Maximum number of evidence submissions recorded on a dispute
+pub const MAX_EVIDENCE_ENTRIES: usize = 6;
+
+/// Maximum length of a shipment's carrier name
+pub const MAX_CARRIER_LEN: usize = 20;
+
+/// Maximum length of a shipment's tracking number
+pub const MAX_TRACKING_NUMBER_LEN: usize = 40;
+
+/// Maximum length of a dispute's description, in bytes
+pub const MAX_DISPUTE_DESCRIPTION_LEN: usize = 200;
+
+/// Version of the escrow state export encoding
+pub const ESCROW_STATE_VERSION: u8 = 1;
+
+/// Length of a version 1 escrow state export, in bytes
+pub const ESCROW_STATE_LEN: usize = 1 + 5 * 32 + 4 * 8 + 2 + 2 * 8;
+
 #[program]
 pub mod escrow {
     use super::*;
@@ -16,98 +51,309 @@ pub mod escrow {
     pub fn create_escrow(
         ctx: Context<CreateEscrow>,
         quantity: u64,
+        layaway_deadline: Option<i64>,
+        coupon: Option<Pubkey>,
     ) -> Result<()> {
         // Validate quantity is greater than 0
         require!(quantity > 0, EscrowError::InvalidQuantity);
+        require!(!ctx.accounts.marketplace.is_paused, EscrowError::MarketplacePaused);
+
+        // A layaway must leave the buyer time to pay
+        if let Some(deadline) = layaway_deadline {
+            require!(
+                deadline > Clock::get()?.unix_timestamp,
+                EscrowError::InvalidLayawayDeadline
+            );
+        }
 
-        // Calculate the total amount for the purchase
         let product = &ctx.accounts.product;
-        let total_amount = product.price.checked_mul(quantity).ok_or(EscrowError::CalculationError)?;
+        let now = Clock::get()?.unix_timestamp;
+        require_purchasable(product, &ctx.accounts.seller_status, quantity, now)?;
+        let escrow_mint = escrow_mint_for(
+            &ctx.accounts.marketplace,
+            &product.currency,
+            ctx.accounts.mint.as_ref(),
+        )?;
+
+        require_trusted_buyer(product, ctx.accounts.buyer_reputation.as_ref())?;
+
+        // Calculate the total amount for the purchase at the unit price of
+        // the volume tier the quantity reaches
+        let mut total_amount = product
+            .unit_price_for(quantity)
+            .checked_mul(quantity)
+            .ok_or(EscrowError::CalculationError)?;
+
+        // Apply the coupon discount, if one is redeemed
+        let mut discount_bps = 0;
+        if let Some(coupon_key) = coupon {
+            let coupon = ctx
+                .accounts
+                .coupon
+                .as_mut()
+                .ok_or(EscrowError::InvalidCoupon)?;
+            require_keys_eq!(coupon.key(), coupon_key, EscrowError::InvalidCoupon);
+            require_keys_eq!(
+                coupon.marketplace,
+                ctx.accounts.marketplace.key(),
+                EscrowError::InvalidCoupon
+            );
+            require!(now <= coupon.expires_at, EscrowError::CouponExpired);
+            require!(coupon.remaining_uses > 0, EscrowError::CouponExhausted);
+            
+            // The seller is owed what the discount leaves
+            (total_amount, _) = ctx
+                .accounts
+                .marketplace
+                .rounding_policy
+                .split(total_amount, 10000 - coupon.discount_bps as u64, 10000)
+                .ok_or(EscrowError::CalculationError)?;
+            discount_bps = coupon.discount_bps;
+            coupon.remaining_uses -= 1;
+        }
+
+        // Enforce the marketplace minimum and cap in the escrow currency's own units
+        require!(
+            total_amount >= ctx.accounts.marketplace.min_escrow_amount(&product.currency),
+            EscrowError::EscrowBelowMinimum
+        );
+        require!(
+            ctx.accounts
+                .marketplace
+                .within_max_escrow_value(&product.currency, total_amount),
+            EscrowError::OrderValueTooHigh
+        );
 
         // Set up the escrow account
         let escrow = &mut ctx.accounts.escrow;
-        escrow.marketplace = ctx.accounts.marketplace.key();
-        escrow.buyer = ctx.accounts.buyer.key();
-        escrow.seller = product.seller;
-        escrow.product = ctx.accounts.product.key();
-        escrow.quantity = quantity;
-        escrow.amount = total_amount;
-        escrow.currency = product.currency.clone();
+        set_escrow_terms(
+            escrow,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &ctx.accounts.seller_reputation,
+            ctx.accounts.buyer.key(),
+            quantity,
+            total_amount,
+        )?;
+        escrow.discount_bps = discount_bps;
+        escrow.mint = escrow_mint;
+        escrow.funded_amount = 0;
+        escrow.is_layaway = layaway_deadline.is_some();
+        escrow.layaway_deadline = layaway_deadline.unwrap_or(0);
+        escrow.layaway_forfeit_bps = ctx.accounts.marketplace.layaway_forfeit_bps;
         escrow.status = EscrowStatus::Created;
-        escrow.created_at = Clock::get()?.unix_timestamp;
-        escrow.updated_at = Clock::get()?.unix_timestamp;
+        escrow.nonce = ctx.accounts.buyer_escrow_counter.escrows_created;
         escrow.bump = *ctx.bumps.get("escrow").unwrap();
 
+        // Buyers may only hold so many open escrows, so inventory cannot be
+        // tied up by mass escrow creation
+        let buyer_escrow_counter = &mut ctx.accounts.buyer_escrow_counter;
+        let max_open_escrows = ctx.accounts.marketplace.max_open_escrows_per_buyer;
+        require!(
+            max_open_escrows == 0 || buyer_escrow_counter.open_escrows < max_open_escrows,
+            EscrowError::TooManyOpenEscrows
+        );
+        buyer_escrow_counter.marketplace = ctx.accounts.marketplace.key();
+        buyer_escrow_counter.buyer = ctx.accounts.buyer.key();
+        buyer_escrow_counter.open_escrows += 1;
+        buyer_escrow_counter.escrows_created += 1;
+        buyer_escrow_counter.bump = *ctx.bumps.get("buyer_escrow_counter").unwrap();
+
+        // Purchase the units through the marketplace, which reserves them
+        // until the escrow settles and counts the escrow as open
+        marketplace::cpi::purchase_product(
+            CpiContext::new_with_signer(
+                ctx.accounts.marketplace_program.to_account_info(),
+                marketplace::cpi::accounts::PurchaseProduct {
+                    escrow_authority: ctx.accounts.escrow_authority.to_account_info(),
+                    buyer: ctx.accounts.buyer.to_account_info(),
+                    marketplace: ctx.accounts.marketplace.to_account_info(),
+                    product: ctx.accounts.product.to_account_info(),
+                    seller_status: ctx.accounts.seller_status.to_account_info(),
+                },
+                &[&[marketplace::ESCROW_AUTHORITY_SEED, &[*ctx.bumps.get("escrow_authority").unwrap()]]],
+            ),
+            quantity,
+        )?;
+
         Ok(())
     }
 
     /// Fund the escrow with payment
-    pub fn fund_escrow(ctx: Context<FundEscrow>) -> Result<()> {
+    pub fn fund_escrow(
+        ctx: Context<FundEscrow>,
+        source_attestation_hash: Option<[u8; 32]>,
+    ) -> Result<()> {
+        // Regulated operators need the buyer to attest where the funds came from
+        require!(
+            !ctx.accounts.marketplace.require_source_attestation
+                || source_attestation_hash.is_some(),
+            EscrowError::SourceAttestationRequired
+        );
+        
         let escrow = &mut ctx.accounts.escrow;
         
+        // A re-submitted funding transaction must not move funds twice
+        require!(
+            escrow.funded_amount < escrow.amount,
+            EscrowError::AlreadyFunded
+        );
+        
         // Check that the escrow is in the correct state
         require!(
             escrow.status == EscrowStatus::Created,
             EscrowError::InvalidEscrowState
         );
+        
+        // A layaway past its deadline can only be settled as a default
+        require!(
+            !escrow.is_layaway || Clock::get()?.unix_timestamp <= escrow.layaway_deadline,
+            EscrowError::LayawayDeadlinePassed
+        );
 
-        // Handle different currency types
-        match escrow.currency {
+        // Layaway escrows may already hold installments, so only the
+        // outstanding balance is collected
+        let remaining = escrow
+            .amount
+            .checked_sub(escrow.funded_amount)
+            .ok_or(EscrowError::CalculationError)?;
+
+        // SOL payments must leave the buyer rent-exempt; token payments need
+        // the escrow's vault to exist already
+        let escrow_token_account = match escrow.currency {
             CurrencyType::SOL => {
-                // For SOL transfers, we'll use system program
-                // Verify the provided lamports match the escrow amount
-                let rent = Rent::get()?;
-                let required_lamports = escrow.amount + rent.minimum_balance(0);
-                
+                let required_lamports = remaining + Rent::get()?.minimum_balance(0);
                 require!(
                     ctx.accounts.buyer.lamports() >= required_lamports,
                     EscrowError::InsufficientFunds
                 );
-                
-                // Transfer SOL to the escrow account
-                let transfer_instruction = anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.buyer.to_account_info(),
-                    to: ctx.accounts.escrow_vault.to_account_info(),
-                };
-                
-                anchor_lang::system_program::transfer(
-                    CpiContext::new(
-                        ctx.accounts.system_program.to_account_info(),
-                        transfer_instruction,
-                    ),
-                    escrow.amount,
-                )?;
+                None
             },
-            CurrencyType::USDC | CurrencyType::USDT => {
-                // For token transfers, we use the token program
-                // Transfer tokens from the buyer to the escrow vault
-                let transfer_instruction = Transfer {
-                    from: ctx.accounts.buyer_token_account.to_account_info(),
-                    to: ctx.accounts.escrow_token_account.to_account_info(),
-                    authority: ctx.accounts.buyer.to_account_info(),
-                };
-                
-                token::transfer(
-                    CpiContext::new(
-                        ctx.accounts.token_program.to_account_info(),
-                        transfer_instruction,
-                    ),
-                    escrow.amount,
-                )?;
-            }
-        }
+            CurrencyType::USDC | CurrencyType::USDT => Some(escrow_token_vault(
+                escrow.key(),
+                escrow.mint,
+                ctx.accounts.escrow_token_account.as_ref(),
+            )?),
+        };
+        
+        deposit_to_escrow(
+            &escrow.currency,
+            escrow.mint,
+            &ctx.accounts.buyer,
+            &ctx.accounts.escrow_vault,
+            ctx.accounts.buyer_token_account.as_ref(),
+            escrow_token_account,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            remaining,
+        )?;
 
         // Update escrow status
+        escrow.funded_amount = escrow.amount;
+        escrow.source_attestation_hash = source_attestation_hash.unwrap_or([0u8; 32]);
         escrow.status = EscrowStatus::Funded;
         escrow.updated_at = Clock::get()?.unix_timestamp;
+        escrow.ship_by = escrow.ship_by_from(escrow.updated_at)?;
 
         Ok(())
     }
 
+    /// Fund several of the buyer's escrows in one transaction, so a cart
+    /// checkout either funds every escrow or none. `remaining_accounts` holds
+    /// an (escrow, vault) pair per escrow, where the vault is the escrow's
+    /// SOL vault or token account. All escrows must share a currency.
+    pub fn fund_escrows_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, FundEscrowsBatch<'info>>,
+        source_attestation_hash: Option<[u8; 32]>,
+    ) -> Result<()> {
+        require!(
+            !ctx.remaining_accounts.is_empty() && ctx.remaining_accounts.len() % 2 == 0,
+            EscrowError::InvalidBatchAccounts
+        );
+        require!(
+            !ctx.accounts.marketplace.require_source_attestation
+                || source_attestation_hash.is_some(),
+            EscrowError::SourceAttestationRequired
+        );
+        
+        let now = Clock::get()?.unix_timestamp;
+        let mut batch_currency: Option<CurrencyType> = None;
+        
+        for accounts in ctx.remaining_accounts.chunks(2) {
+            let (escrow_info, vault_info) = (&accounts[0], &accounts[1]);
+            let mut escrow = Account::<Escrow>::try_from(escrow_info)?;
+            
+            require!(escrow.buyer == ctx.accounts.buyer.key(), EscrowError::UnauthorizedBuyer);
+            require!(
+                escrow.marketplace == ctx.accounts.marketplace.key(),
+                EscrowError::InvalidEscrowAccount
+            );
+            require!(escrow.funded_amount < escrow.amount, EscrowError::AlreadyFunded);
+            require!(
+                escrow.status == EscrowStatus::Created,
+                EscrowError::InvalidEscrowState
+            );
+            require!(
+                !escrow.is_layaway || now <= escrow.layaway_deadline,
+                EscrowError::LayawayDeadlinePassed
+            );
+            
+            // One payment source, so one currency
+            let currency = batch_currency.get_or_insert(escrow.currency.clone());
+            require!(*currency == escrow.currency, EscrowError::BatchCurrencyMismatch);
+            
+            let remaining = escrow
+                .amount
+                .checked_sub(escrow.funded_amount)
+                .ok_or(EscrowError::CalculationError)?;
+            
+            let escrow_token_account = match escrow.currency {
+                CurrencyType::SOL => {
+                    let (vault, _) = Pubkey::find_program_address(
+                        &[b"escrow_vault", escrow.key().as_ref()],
+                        &crate::ID,
+                    );
+                    require_keys_eq!(vault_info.key(), vault, EscrowError::InvalidEscrowAccount);
+                    
+                    let required_lamports = remaining + Rent::get()?.minimum_balance(0);
+                    require!(
+                        ctx.accounts.buyer.lamports() >= required_lamports,
+                        EscrowError::InsufficientFunds
+                    );
+                    None
+                },
+                CurrencyType::USDC | CurrencyType::USDT => {
+                    Some(escrow_token_vault(escrow.key(), escrow.mint, Some(vault_info))?)
+                }
+            };
+            
+            deposit_to_escrow(
+                &escrow.currency,
+                escrow.mint,
+                &ctx.accounts.buyer,
+                vault_info,
+                ctx.accounts.buyer_token_account.as_ref(),
+                escrow_token_account,
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+                remaining,
+            )?;
+            
+            escrow.funded_amount = escrow.amount;
+            escrow.source_attestation_hash = source_attestation_hash.unwrap_or([0u8; 32]);
+            escrow.status = EscrowStatus::Funded;
+            escrow.updated_at = now;
+            escrow.ship_by = escrow.ship_by_from(now)?;
+            escrow.exit(&crate::ID)?;
+        }
+        
+        Ok(())
+    }
+
     /// Mark the order as shipped by the seller
     pub fn mark_as_shipped(
         ctx: Context<MarkAsShipped>,
-        tracking_id: Option<String>,
+        tracking: Option<TrackingId>,
     ) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
         
@@ -123,310 +369,3100 @@ pub mod escrow {
             EscrowError::UnauthorizedSeller
         );
         
+        // Layaway orders only ship once they are paid in full
+        require!(
+            escrow.funded_amount == escrow.amount,
+            EscrowError::LayawayNotPaid
+        );
+        
         // Store tracking ID if provided
-        if let Some(tracking_id) = tracking_id {
-            require!(tracking_id.len() <= 50, EscrowError::TrackingIdTooLong);
-            // In a real implementation, we would store the tracking ID in the account
-            // For simplicity, we're not storing it in this example
+        if let Some(tracking) = tracking {
+            tracking.validate()?;
+            escrow.tracking = tracking;
         }
         
-        // Update escrow status
+        // Update escrow status; refunded units are never shipped
+        escrow.shipped_quantity = escrow
+            .quantity
+            .checked_sub(escrow.refunded_quantity)
+            .ok_or(EscrowError::CalculationError)?;
         escrow.status = EscrowStatus::Shipped;
         escrow.updated_at = Clock::get()?.unix_timestamp;
+        escrow.shipped_at = escrow.updated_at;
+        escrow.dispute_deadline = escrow.dispute_deadline_from(escrow.updated_at)?;
         
         Ok(())
     }
 
-    /// Confirm delivery of the product and release funds to the seller
-    pub fn confirm_delivery(ctx: Context<ConfirmDelivery>) -> Result<()> {
+    /// Record a partial shipment of the order. The escrow stays `Funded` until
+    /// the whole quantity has shipped, at which point it becomes `Shipped`.
+    pub fn mark_partially_shipped(
+        ctx: Context<MarkAsShipped>,
+        shipped_quantity: u64,
+        tracking: Option<TrackingId>,
+    ) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
         
         // Check that the escrow is in the correct state
         require!(
-            escrow.status == EscrowStatus::Shipped,
+            escrow.status == EscrowStatus::Funded,
             EscrowError::InvalidEscrowState
         );
         
-        // Verify the signer is the buyer
+        // Layaway orders only ship once they are paid in full
         require!(
-            ctx.accounts.buyer.key() == escrow.buyer,
-            EscrowError::UnauthorizedBuyer
+            escrow.funded_amount == escrow.amount,
+            EscrowError::LayawayNotPaid
         );
         
-        // Transfer funds to the seller based on currency type
-        match escrow.currency {
-            CurrencyType::SOL => {
-                // For SOL transfers
-                let bump = escrow.bump;
-                let escrow_seeds = &[
-                    b"escrow",
-                    escrow.marketplace.as_ref(),
-                    escrow.buyer.as_ref(),
-                    escrow.product.as_ref(),
-                    &[bump],
-                ];
-                let signer = &[&escrow_seeds[..]];
-                
-                // Transfer SOL from escrow vault to seller
-                let transfer_instruction = anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow_vault.to_account_info(),
-                    to: ctx.accounts.seller.to_account_info(),
-                };
-                
-                anchor_lang::system_program::transfer(
-                    CpiContext::new_with_signer(
-                        ctx.accounts.system_program.to_account_info(),
-                        transfer_instruction,
-                        signer,
-                    ),
-                    escrow.amount,
-                )?;
-            },
-            CurrencyType::USDC | CurrencyType::USDT => {
-                // For token transfers
-                let bump = escrow.bump;
-                let escrow_seeds = &[
-                    b"escrow",
-                    escrow.marketplace.as_ref(),
-                    escrow.buyer.as_ref(),
-                    escrow.product.as_ref(),
-                    &[bump],
-                ];
-                let signer = &[&escrow_seeds[..]];
-                
-                // Transfer tokens from escrow to seller
-                let transfer_instruction = Transfer {
-                    from: ctx.accounts.escrow_token_account.to_account_info(),
-                    to: ctx.accounts.seller_token_account.to_account_info(),
-                    authority: ctx.accounts.escrow_vault.to_account_info(),
-                };
-                
-                token::transfer(
-                    CpiContext::new_with_signer(
-                        ctx.accounts.token_program.to_account_info(),
-                        transfer_instruction,
-                        signer,
-                    ),
-                    escrow.amount,
-                )?;
-            }
+        // Never ship more than was ordered and not refunded
+        let total_shipped = escrow
+            .shipped_quantity
+            .checked_add(shipped_quantity)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            shipped_quantity > 0 && total_shipped <= escrow.unrefunded_quantity(),
+            EscrowError::InvalidShipmentQuantity
+        );
+        
+        // Keep the latest shipment's tracking ID
+        if let Some(tracking) = tracking {
+            tracking.validate()?;
+            escrow.tracking = tracking;
         }
         
-        // Update escrow status
-        escrow.status = EscrowStatus::Completed;
+        // Update escrow status once everything has shipped
+        escrow.shipped_quantity = total_shipped;
         escrow.updated_at = Clock::get()?.unix_timestamp;
+        if total_shipped == escrow.unrefunded_quantity() {
+            escrow.status = EscrowStatus::Shipped;
+            escrow.shipped_at = escrow.updated_at;
+            escrow.dispute_deadline = escrow.dispute_deadline_from(escrow.updated_at)?;
+        }
         
         Ok(())
     }
 
-    /// Dispute a transaction if there's an issue with the order
-    pub fn dispute_transaction(
-        ctx: Context<DisputeTransaction>,
-        reason: String,
+    /// Agree a schedule of releases to the seller, paid as work progresses
+    /// without per-step buyer confirmation. Both parties sign, and the
+    /// schedule is fixed before the escrow is funded.
+    pub fn set_release_schedule(
+        ctx: Context<SetReleaseSchedule>,
+        schedule: Vec<ScheduledRelease>,
     ) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
         
-        // Check that the escrow is in a state that can be disputed
         require!(
-            escrow.status == EscrowStatus::Funded || escrow.status == EscrowStatus::Shipped,
+            escrow.status == EscrowStatus::Created && escrow.funded_amount == 0,
             EscrowError::InvalidEscrowState
         );
-        
-        // Verify the signer is either the buyer or seller
         require!(
-            ctx.accounts.user.key() == escrow.buyer || ctx.accounts.user.key() == escrow.seller,
-            EscrowError::Unauthorized
+            !schedule.is_empty() && schedule.len() <= MAX_SCHEDULED_RELEASES,
+            EscrowError::InvalidReleaseSchedule
         );
         
-        // Validate dispute reason
-        require!(reason.len() <= 200, EscrowError::DisputeReasonTooLong);
+        // Releases must be positive, in time order, and within the escrow amount
+        let mut total: u64 = 0;
+        let mut previous_release_at = i64::MIN;
+        for release in schedule.iter() {
+            require!(
+                release.amount > 0 && release.release_at > previous_release_at,
+                EscrowError::InvalidReleaseSchedule
+            );
+            total = total.checked_add(release.amount).ok_or(EscrowError::CalculationError)?;
+            previous_release_at = release.release_at;
+        }
+        require!(total <= escrow.amount, EscrowError::InvalidReleaseSchedule);
         
-        // Update escrow status
-        escrow.status = EscrowStatus::Disputed;
+        escrow.release_schedule = [ScheduledRelease::default(); MAX_SCHEDULED_RELEASES];
+        escrow.release_schedule[..schedule.len()].copy_from_slice(&schedule);
+        escrow.scheduled_release_count = schedule.len() as u8;
+        escrow.scheduled_releases_paid = 0;
         escrow.updated_at = Clock::get()?.unix_timestamp;
         
-        // In a real implementation, we would store the dispute reason
-        // For simplicity, we're not storing it in this example
-        
         Ok(())
     }
 
-    /// Cancel the escrow and refund the buyer
-    pub fn cancel_escrow(ctx: Context<CancelEscrow>) -> Result<()> {
+    /// Release the next scheduled portion to the seller once it is due.
+    /// Anyone may call this; a dispute freezes further releases.
+    pub fn release_scheduled_portion(ctx: Context<ReleaseScheduledPortion>) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
         let escrow = &mut ctx.accounts.escrow;
         
-        // Check that the escrow is in a state that can be cancelled
         require!(
-            escrow.status == EscrowStatus::Created || escrow.status == EscrowStatus::Funded,
+            escrow.status != EscrowStatus::Disputed,
+            EscrowError::ScheduledReleasesFrozen
+        );
+        require!(
+            escrow.status == EscrowStatus::Funded || escrow.status == EscrowStatus::Shipped,
             EscrowError::InvalidEscrowState
         );
+        require!(
+            escrow.scheduled_releases_paid < escrow.scheduled_release_count,
+            EscrowError::NoScheduledRelease
+        );
         
-        // If escrow is funded, refund the buyer
-        if escrow.status == EscrowStatus::Funded {
-            match escrow.currency {
-                CurrencyType::SOL => {
-                    // For SOL refunds
-                    let bump = escrow.bump;
-                    let escrow_seeds = &[
-                        b"escrow",
-                        escrow.marketplace.as_ref(),
-                        escrow.buyer.as_ref(),
-                        escrow.product.as_ref(),
-                        &[bump],
-                    ];
-                    let signer = &[&escrow_seeds[..]];
-                    
-                    // Transfer SOL from escrow vault back to buyer
-                    let transfer_instruction = anchor_lang::system_program::Transfer {
-                        from: ctx.accounts.escrow_vault.to_account_info(),
-                        to: ctx.accounts.buyer.to_account_info(),
-                    };
-                    
-                    anchor_lang::system_program::transfer(
-                        CpiContext::new_with_signer(
-                            ctx.accounts.system_program.to_account_info(),
-                            transfer_instruction,
-                            signer,
-                        ),
-                        escrow.amount,
-                    )?;
-                },
-                CurrencyType::USDC | CurrencyType::USDT => {
-                    // For token refunds
-                    let bump = escrow.bump;
-                    let escrow_seeds = &[
-                        b"escrow",
-                        escrow.marketplace.as_ref(),
-                        escrow.buyer.as_ref(),
-                        escrow.product.as_ref(),
-                        &[bump],
-                    ];
-                    let signer = &[&escrow_seeds[..]];
-                    
-                    // Transfer tokens from escrow back to buyer
-                    let transfer_instruction = Transfer {
-                        from: ctx.accounts.escrow_token_account.to_account_info(),
-                        to: ctx.accounts.buyer_token_account.to_account_info(),
-                        authority: ctx.accounts.escrow_vault.to_account_info(),
-                    };
-                    
-                    token::transfer(
-                        CpiContext::new_with_signer(
-                            ctx.accounts.token_program.to_account_info(),
-                            transfer_instruction,
-                            signer,
-                        ),
-                        escrow.amount,
-                    )?;
-                }
-            }
-        }
+        let release = escrow.release_schedule[escrow.scheduled_releases_paid as usize];
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= release.release_at, EscrowError::ScheduledReleaseNotDue);
         
-        // Update escrow status
-        escrow.status = EscrowStatus::Cancelled;
-        escrow.updated_at = Clock::get()?.unix_timestamp;
+        release_portion_to_seller(
+            escrow,
+            &ctx.accounts.escrow_vault,
+            &ctx.accounts.seller,
+            ctx.accounts.escrow_token_account.as_ref(),
+            ctx.accounts.seller_token_account.as_ref(),
+            &ctx.accounts.fee_destination,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            release.amount,
+        )?;
+        
+        escrow.scheduled_releases_paid += 1;
+        escrow.updated_at = now;
         
         Ok(())
     }
 
-    /// Resolve a dispute by an authorized marketplace authority
-    pub fn resolve_dispute(
-        ctx: Context<ResolveDispute>,
-        favor_seller: bool,
-    ) -> Result<()> {
+    /// Make the escrow releasable by `oracle_release` once the given condition
+    /// oracle reports true. Both parties must agree before funding.
+    pub fn set_release_condition(ctx: Context<SetReleaseSchedule>, oracle: Pubkey) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
         
-        // Check that the escrow is in disputed state
         require!(
-            escrow.status == EscrowStatus::Disputed,
+            escrow.status == EscrowStatus::Created && escrow.funded_amount == 0,
             EscrowError::InvalidEscrowState
         );
         
-        // Verify the signer is the marketplace authority
-        require!(
-            ctx.accounts.authority.key() == ctx.accounts.marketplace.authority,
-            EscrowError::UnauthorizedAuthority
-        );
-        
-        // Transfer funds based on resolution
-        match escrow.currency {
-            CurrencyType::SOL => {
-                // For SOL transfers
-                let bump = escrow.bump;
-                let escrow_seeds = &[
-                    b"escrow",
-                    escrow.marketplace.as_ref(),
-                    escrow.buyer.as_ref(),
-                    escrow.product.as_ref(),
-                    &[bump],
-                ];
-                let signer = &[&escrow_seeds[..]];
-                
-                let transfer_instruction = anchor_lang::system_program::Transfer {
-                    from: ctx.accounts.escrow_vault.to_account_info(),
-                    to: if favor_seller {
-                        ctx.accounts.seller.to_account_info()
-                    } else {
-                        ctx.accounts.buyer.to_account_info()
-                    },
-                };
-                
-                anchor_lang::system_program::transfer(
-                    CpiContext::new_with_signer(
-                        ctx.accounts.system_program.to_account_info(),
-                        transfer_instruction,
-                        signer,
-                    ),
-                    escrow.amount,
-                )?;
-            },
-            CurrencyType::USDC | CurrencyType::USDT => {
-                // For token transfers
-                let bump = escrow.bump;
-                let escrow_seeds = &[
-                    b"escrow",
-                    escrow.marketplace.as_ref(),
-                    escrow.buyer.as_ref(),
-                    escrow.product.as_ref(),
-                    &[bump],
-                ];
-                let signer = &[&escrow_seeds[..]];
-                
-                let transfer_instruction = Transfer {
-                    from: ctx.accounts.escrow_token_account.to_account_info(),
-                    to: if favor_seller {
-                        ctx.accounts.seller_token_account.to_account_info()
-                    } else {
-                        ctx.accounts.buyer_token_account.to_account_info()
-                    },
-                    authority: ctx.accounts.escrow_vault.to_account_info(),
-                };
-                
-                token::transfer(
-                    CpiContext::new_with_signer(
-                        ctx.accounts.token_program.to_account_info(),
-                        transfer_instruction,
-                        signer,
-                    ),
-                    escrow.amount,
-                )?;
-            }
-        }
-        
-        // Update escrow status
-        escrow.status = if favor_seller {
-            EscrowStatus::Completed
-        } else {
-            EscrowStatus::Refunded
-        };
+        escrow.release_condition_oracle = oracle;
         escrow.updated_at = Clock::get()?.unix_timestamp;
         
         Ok(())
     }
-}
 
-/// Account structure for escrow state
-#[account]
+    /// Create a condition oracle that `authority` reports on, e.g. for a
+    /// shipment's delivery scan
+    pub fn initialize_condition_oracle(
+        ctx: Context<InitializeConditionOracle>,
+        condition_id: [u8; 32],
+    ) -> Result<()> {
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.authority = ctx.accounts.authority.key();
+        oracle.condition_id = condition_id;
+        oracle.condition_met = false;
+        oracle.updated_at = Clock::get()?.unix_timestamp;
+        oracle.bump = *ctx.bumps.get("oracle").unwrap();
+        
+        Ok(())
+    }
+
+    /// Report whether the oracle's condition currently holds
+    pub fn report_condition(ctx: Context<ReportCondition>, condition_met: bool) -> Result<()> {
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.condition_met = condition_met;
+        oracle.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Release the escrow to the seller once its condition oracle reports
+    /// true. Anyone may call this.
+    pub fn oracle_release(ctx: Context<OracleRelease>) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(
+            escrow.status == EscrowStatus::Funded || escrow.status == EscrowStatus::Shipped,
+            EscrowError::InvalidEscrowState
+        );
+        require!(
+            escrow.release_condition_oracle != Pubkey::default(),
+            EscrowError::NoReleaseCondition
+        );
+        require!(
+            ctx.accounts.oracle.key() == escrow.release_condition_oracle,
+            EscrowError::InvalidConditionOracle
+        );
+        require!(ctx.accounts.oracle.condition_met, EscrowError::ReleaseConditionNotMet);
+        
+        // Report the settlement for accounting integrations
+        let fee = escrow.marketplace_fee();
+        emit!(SettlementBreakdown::new(escrow.key(), escrow, true, fee));
+        
+        let amount = escrow.unreleased_amount();
+        release_portion_to_seller(
+            escrow,
+            &ctx.accounts.escrow_vault,
+            &ctx.accounts.seller,
+            ctx.accounts.escrow_token_account.as_ref(),
+            ctx.accounts.seller_token_account.as_ref(),
+            &ctx.accounts.fee_destination,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            amount,
+        )?;
+        
+        // Units refunded before the release leave the escrow partially completed
+        escrow.confirmed_quantity = escrow.unrefunded_quantity();
+        escrow.status = if escrow.refunded_quantity == 0 {
+            EscrowStatus::Completed
+        } else {
+            EscrowStatus::PartiallyCompleted
+        };
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        escrow.completed_at = escrow.updated_at;
+        
+        // The escrow no longer counts as open against the product
+        record_escrow_closed(
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+            escrow.quantity,
+            escrow.unrefunded_quantity(),
+            escrow.amount - escrow.refunded_amount,
+            escrow.fees_collected,
+        )?;
+        
+        // Count the sale and purchase toward both parties' reputations
+        record_completed_transaction(
+            &ctx.accounts.reputation_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.seller,
+            &ctx.accounts.buyer,
+            ctx.accounts.seller_reputation.as_ref(),
+            ctx.accounts.buyer_reputation.as_ref(),
+            &ctx.accounts.system_program,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+        )?;
+        
+        Ok(())
+    }
+
+    /// Confirm delivery of the product and release funds to the seller
+    pub fn confirm_delivery<'info>(ctx: Context<'_, '_, '_, 'info, ConfirmDelivery<'info>>) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        
+        // Check that the escrow is in the correct state
+        require!(
+            escrow.status == EscrowStatus::Shipped,
+            EscrowError::InvalidEscrowState
+        );
+        
+        // Verify the signer is the buyer
+        require!(
+            ctx.accounts.buyer.key() == escrow.buyer,
+            EscrowError::UnauthorizedBuyer
+        );
+        
+        // Delivery can only be confirmed once every unit not refunded has shipped
+        require!(
+            escrow.shipped_quantity == escrow.unrefunded_quantity(),
+            EscrowError::NotFullyShipped
+        );
+        
+        let fee = pay_out_to_seller(
+            escrow,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.escrow_vault,
+            &ctx.accounts.seller,
+            &ctx.accounts.seller_status,
+            ctx.accounts.escrow_token_account.as_ref(),
+            ctx.accounts.seller_token_account.as_ref(),
+            &ctx.accounts.fee_destination,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            ctx.remaining_accounts,
+        )?;
+        escrow.fees_collected = escrow
+            .fees_collected
+            .checked_add(fee)
+            .ok_or(EscrowError::CalculationError)?;
+        
+        // Update escrow status
+        escrow.confirmed_quantity = escrow.shipped_quantity;
+        escrow.status = if escrow.refunded_quantity == 0 {
+            EscrowStatus::Completed
+        } else {
+            EscrowStatus::PartiallyCompleted
+        };
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        escrow.completed_at = escrow.updated_at;
+        
+        // The escrow no longer counts as open against the product
+        record_escrow_closed(
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+            escrow.quantity,
+            escrow.confirmed_quantity,
+            escrow.amount - escrow.refunded_amount,
+            escrow.fees_collected,
+        )?;
+        
+        // Count the sale and purchase toward both parties' reputations
+        if escrow.status == EscrowStatus::Completed {
+            record_completed_transaction(
+                &ctx.accounts.reputation_program,
+                &ctx.accounts.escrow_authority,
+                &ctx.accounts.marketplace,
+                &ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.seller,
+                &ctx.accounts.buyer.to_account_info(),
+                ctx.accounts.seller_reputation.as_ref(),
+                ctx.accounts.buyer_reputation.as_ref(),
+                &ctx.accounts.system_program,
+                *ctx.bumps.get("escrow_authority").unwrap(),
+            )?;
+        }
+        
+        Ok(())
+    }
+
+    /// Release a shipped escrow to the seller once the buyer has let the
+    /// marketplace's auto-release window pass without confirming delivery or
+    /// raising a dispute
+    pub fn claim_after_timeout<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimAfterTimeout<'info>>,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        require!(
+            escrow.status == EscrowStatus::Shipped,
+            EscrowError::InvalidEscrowState
+        );
+        require!(
+            escrow.shipped_quantity == escrow.unrefunded_quantity(),
+            EscrowError::NotFullyShipped
+        );
+        let claimable_at = escrow
+            .shipped_at
+            .checked_add(ctx.accounts.marketplace.auto_release_seconds)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            Clock::get()?.unix_timestamp >= claimable_at,
+            EscrowError::ConfirmationWindowOpen
+        );
+        
+        let fee = pay_out_to_seller(
+            escrow,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.escrow_vault,
+            &ctx.accounts.seller,
+            &ctx.accounts.seller_status,
+            ctx.accounts.escrow_token_account.as_ref(),
+            ctx.accounts.seller_token_account.as_ref(),
+            &ctx.accounts.fee_destination,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            ctx.remaining_accounts,
+        )?;
+        escrow.fees_collected = escrow
+            .fees_collected
+            .checked_add(fee)
+            .ok_or(EscrowError::CalculationError)?;
+        
+        // Settle the escrow as if the buyer had confirmed it
+        escrow.confirmed_quantity = escrow.shipped_quantity;
+        escrow.status = if escrow.refunded_quantity == 0 {
+            EscrowStatus::Completed
+        } else {
+            EscrowStatus::PartiallyCompleted
+        };
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        escrow.completed_at = escrow.updated_at;
+        
+        record_escrow_closed(
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+            escrow.quantity,
+            escrow.confirmed_quantity,
+            escrow.amount - escrow.refunded_amount,
+            escrow.fees_collected,
+        )?;
+        
+        // Count the sale and purchase toward both parties' reputations
+        if escrow.status == EscrowStatus::Completed {
+            record_completed_transaction(
+                &ctx.accounts.reputation_program,
+                &ctx.accounts.escrow_authority,
+                &ctx.accounts.marketplace,
+                &ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.seller.to_account_info(),
+                &ctx.accounts.buyer,
+                ctx.accounts.seller_reputation.as_ref(),
+                ctx.accounts.buyer_reputation.as_ref(),
+                &ctx.accounts.system_program,
+                *ctx.bumps.get("escrow_authority").unwrap(),
+            )?;
+        }
+        
+        Ok(())
+    }
+
+    /// Confirm delivery of some shipped units, releasing their share of the
+    /// escrow to the seller. The escrow settles once every unit is confirmed
+    /// or refunded.
+    pub fn confirm_partial_delivery(ctx: Context<ConfirmDelivery>, quantity: u64) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(
+            (escrow.status == EscrowStatus::Funded || escrow.status == EscrowStatus::Shipped)
+                && escrow.scheduled_release_count == 0,
+            EscrowError::InvalidEscrowState
+        );
+        
+        // Only shipped units can be confirmed, each at most once
+        let confirmed_quantity = escrow
+            .confirmed_quantity
+            .checked_add(quantity)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            quantity > 0 && confirmed_quantity <= escrow.shipped_quantity,
+            EscrowError::InvalidPartialQuantity
+        );
+        
+        let amount = escrow.portion_amount(quantity, true)?;
+        release_portion_to_seller(
+            escrow,
+            &ctx.accounts.escrow_vault,
+            &ctx.accounts.seller,
+            ctx.accounts.escrow_token_account.as_ref(),
+            ctx.accounts.seller_token_account.as_ref(),
+            &ctx.accounts.fee_destination,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            amount,
+        )?;
+        
+        escrow.confirmed_quantity = confirmed_quantity;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        
+        close_if_fully_accounted(
+            escrow,
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+        )?;
+        
+        // Confirming the last units counts the sale and purchase, as a full
+        // confirmation would
+        if escrow.status == EscrowStatus::Completed {
+            record_completed_transaction(
+                &ctx.accounts.reputation_program,
+                &ctx.accounts.escrow_authority,
+                &ctx.accounts.marketplace,
+                &ctx.accounts.buyer.to_account_info(),
+                &ctx.accounts.seller,
+                &ctx.accounts.buyer.to_account_info(),
+                ctx.accounts.seller_reputation.as_ref(),
+                ctx.accounts.buyer_reputation.as_ref(),
+                &ctx.accounts.system_program,
+                *ctx.bumps.get("escrow_authority").unwrap(),
+            )?;
+        }
+        
+        Ok(())
+    }
+
+    /// Refund the buyer for units the seller will not ship. The escrow
+    /// settles once every unit is confirmed or refunded.
+    pub fn refund_unshipped(ctx: Context<RefundUnshipped>, quantity: u64) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(
+            escrow.status == EscrowStatus::Funded && escrow.scheduled_release_count == 0,
+            EscrowError::InvalidEscrowState
+        );
+        
+        // Shipped units go through returns instead
+        let refunded_quantity = escrow
+            .refunded_quantity
+            .checked_add(quantity)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            quantity > 0 && quantity <= escrow.unrefunded_quantity() - escrow.shipped_quantity,
+            EscrowError::InvalidPartialQuantity
+        );
+        
+        let amount = escrow.portion_amount(quantity, false)?;
+        release_from_escrow(
+            escrow,
+            &ctx.accounts.escrow_vault,
+            &ctx.accounts.buyer,
+            ctx.accounts.escrow_token_account.as_ref(),
+            ctx.accounts.buyer_token_account.as_ref(),
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            amount,
+        )?;
+        
+        escrow.refunded_quantity = refunded_quantity;
+        escrow.refunded_amount = escrow
+            .refunded_amount
+            .checked_add(amount)
+            .ok_or(EscrowError::CalculationError)?;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        
+        // Everything left has shipped, so the order now awaits confirmation
+        if escrow.shipped_quantity > 0 && escrow.shipped_quantity == escrow.unrefunded_quantity() {
+            escrow.status = EscrowStatus::Shipped;
+        }
+        
+        close_if_fully_accounted(
+            escrow,
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+        )
+    }
+
+    /// Dispute a transaction if there's an issue with the order
+    pub fn dispute_transaction(
+        ctx: Context<DisputeTransaction>,
+        reason: String,
+        dispute_reason: DisputeReason,
+    ) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        // Check that the escrow is in a state that can be disputed
+        require!(
+            escrow.status == EscrowStatus::Funded || escrow.status == EscrowStatus::Shipped,
+            EscrowError::InvalidEscrowState
+        );
+        
+        // Verify the signer is either the buyer or seller
+        require!(
+            ctx.accounts.user.key() == escrow.buyer || ctx.accounts.user.key() == escrow.seller,
+            EscrowError::Unauthorized
+        );
+        
+        // Shipped orders can only be disputed for a while after shipment
+        require!(
+            escrow.dispute_deadline == 0 || Clock::get()?.unix_timestamp <= escrow.dispute_deadline,
+            EscrowError::DisputeWindowClosed
+        );
+        
+        // Validate dispute reason
+        require!(
+            reason.len() <= MAX_DISPUTE_DESCRIPTION_LEN,
+            EscrowError::DisputeReasonTooLong
+        );
+        
+        // Update escrow status and open the evidence window
+        let clock = Clock::get()?;
+        escrow.status = EscrowStatus::Disputed;
+        escrow.dispute_reason = dispute_reason;
+        escrow.updated_at = clock.unix_timestamp;
+        escrow.evidence_count = 0;
+        escrow.evidence_cutoff_slot = escrow.evidence_cutoff_from(clock.slot)?;
+        
+        // Keep the description and who raised it for whoever resolves it
+        escrow.dispute_description = Some(reason);
+        escrow.disputed_by = Some(ctx.accounts.user.key());
+        
+        Ok(())
+    }
+
+    /// Submit evidence for an open dispute. The hash commits to the off-chain
+    /// evidence, and the slot it lands in is recorded so arbitrators can order
+    /// submissions and none can be backdated past the cutoff.
+    pub fn submit_evidence(ctx: Context<SubmitEvidence>, evidence_hash: [u8; 32]) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(
+            escrow.status == EscrowStatus::Disputed,
+            EscrowError::InvalidEscrowState
+        );
+        
+        let slot = Clock::get()?.slot;
+        require!(
+            escrow.evidence_cutoff_slot == 0 || slot <= escrow.evidence_cutoff_slot,
+            EscrowError::EvidenceWindowClosed
+        );
+        
+        let index = escrow.evidence_count as usize;
+        require!(index < MAX_EVIDENCE_ENTRIES, EscrowError::EvidenceLimitReached);
+        escrow.evidence[index] = EvidenceEntry {
+            submitter: ctx.accounts.user.key(),
+            evidence_hash,
+            slot,
+        };
+        escrow.evidence_count += 1;
+        
+        Ok(())
+    }
+
+    /// Cancel the escrow and refund the buyer
+    pub fn cancel_escrow(ctx: Context<CancelEscrow>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        // Refunds move escrowed funds, so they honor the release pause
+        require!(
+            escrow.status != EscrowStatus::Funded || !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        // Check that the escrow is in a state that can be cancelled
+        require!(
+            escrow.status == EscrowStatus::Created || escrow.status == EscrowStatus::Funded,
+            EscrowError::InvalidEscrowState
+        );
+        
+        // A layaway with installments paid settles through the default path
+        require!(
+            escrow.status != EscrowStatus::Created || escrow.funded_amount == 0,
+            EscrowError::LayawayInProgress
+        );
+        
+        // Once units have shipped or funds have gone to the seller, only the
+        // unshipped remainder can be refunded, through `refund_unshipped`
+        require!(
+            escrow.shipped_quantity == 0
+                && escrow.confirmed_quantity == 0
+                && escrow.released_amount == 0,
+            EscrowError::OrderPartiallyFulfilled
+        );
+        
+        // While the seller is within their handling time, the order is theirs to ship
+        require!(
+            escrow.status != EscrowStatus::Funded
+                || escrow.ship_by == 0
+                || Clock::get()?.unix_timestamp > escrow.ship_by,
+            EscrowError::HandlingTimeNotElapsed
+        );
+        
+        // If escrow is funded, refund the buyer
+        if escrow.status == EscrowStatus::Funded {
+            require_accounts_match_currency(
+                &escrow.currency,
+                &[
+                    ctx.accounts.buyer_token_account.is_some(),
+                    ctx.accounts.escrow_token_account.is_some(),
+                ],
+            )?;
+            
+            // Serial cancellers forfeit part of the refund to the seller
+            let penalty = cancellation_penalty(
+                &ctx.accounts.marketplace,
+                ctx.accounts.buyer_reputation.as_ref(),
+                &ctx.accounts.reputation_program,
+                &ctx.accounts.escrow_authority,
+                *ctx.bumps.get("escrow_authority").unwrap(),
+                escrow.unreleased_amount(),
+            )?;
+            if penalty > 0 {
+                release_from_escrow(
+                    escrow,
+                    &ctx.accounts.escrow_vault,
+                    &ctx.accounts.seller,
+                    ctx.accounts.escrow_token_account.as_ref(),
+                    ctx.accounts.seller_token_account.as_ref(),
+                    &ctx.accounts.token_program,
+                    &ctx.accounts.system_program,
+                    penalty,
+                )?;
+            }
+            
+            match escrow.currency {
+                CurrencyType::SOL => {
+                    // For SOL refunds
+                    let bump = escrow.bump;
+                    let escrow_seeds = &[
+                        b"escrow",
+                        escrow.marketplace.as_ref(),
+                        escrow.buyer.as_ref(),
+                        escrow.product.as_ref(),
+                        &escrow.nonce.to_le_bytes(),
+                        &[bump],
+                    ];
+                    let signer = &[&escrow_seeds[..]];
+                    
+                    // Transfer SOL from escrow vault back to buyer
+                    let transfer_instruction = anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.escrow_vault.to_account_info(),
+                        to: ctx.accounts.buyer.to_account_info(),
+                    };
+                    
+                    anchor_lang::system_program::transfer(
+                        CpiContext::new_with_signer(
+                            ctx.accounts.system_program.to_account_info(),
+                            transfer_instruction,
+                            signer,
+                        ),
+                        escrow.unreleased_amount() - penalty,
+                    )?;
+                },
+                CurrencyType::USDC | CurrencyType::USDT => {
+                    // For token refunds
+                    let bump = escrow.bump;
+                    let escrow_seeds = &[
+                        b"escrow",
+                        escrow.marketplace.as_ref(),
+                        escrow.buyer.as_ref(),
+                        escrow.product.as_ref(),
+                        &escrow.nonce.to_le_bytes(),
+                        &[bump],
+                    ];
+                    let signer = &[&escrow_seeds[..]];
+                    
+                    // Transfer tokens from escrow back to buyer
+                    let transfer_instruction = Transfer {
+                        from: ctx.accounts.escrow_token_account.to_account_info(),
+                        to: ctx.accounts.buyer_token_account.to_account_info(),
+                        authority: ctx.accounts.escrow_vault.to_account_info(),
+                    };
+                    
+                    token::transfer(
+                        CpiContext::new_with_signer(
+                            ctx.accounts.token_program.to_account_info(),
+                            transfer_instruction,
+                            signer,
+                        ),
+                        escrow.unreleased_amount() - penalty,
+                    )?;
+                }
+            }
+        }
+        
+        // Update escrow status
+        escrow.status = EscrowStatus::Cancelled;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        
+        // The escrow no longer counts as open against the product
+        record_escrow_closed(
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+            escrow.quantity,
+            0,
+            0,
+            0,
+        )?;
+        
+        Ok(())
+    }
+
+    /// Resolve a dispute by an authorized marketplace authority. When the
+    /// marketplace has an appeal window, the resolution is only recorded and
+    /// funds move once it passes unappealed.
+    pub fn resolve_dispute(
+        ctx: Context<ResolveDispute>,
+        favor_seller: bool,
+        slash_bond_bps: u16,
+    ) -> Result<()> {
+        // Check that the escrow is in disputed state
+        require!(
+            ctx.accounts.escrow.status == EscrowStatus::Disputed,
+            EscrowError::InvalidEscrowState
+        );
+        
+        // Slashing only applies to fraud found against the seller
+        require!(slash_bond_bps <= 10000, EscrowError::InvalidBasisPoints);
+        require!(
+            slash_bond_bps == 0 || !favor_seller,
+            EscrowError::InvalidSlash
+        );
+        
+        require_dispute_resolver(
+            &ctx.accounts.marketplace,
+            &ctx.accounts.arbitrator_routing,
+            &ctx.accounts.escrow.dispute_reason,
+            &ctx.accounts.authority.key(),
+        )?;
+        
+        let appeal_window_seconds = ctx.accounts.marketplace.appeal_window_seconds;
+        if appeal_window_seconds == 0 {
+            let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+            return settle_dispute(ctx.accounts, escrow_authority_bump, favor_seller, slash_bond_bps);
+        }
+        
+        // Record the resolution and hold the funds until the appeal window passes
+        let escrow = &mut ctx.accounts.escrow;
+        let now = Clock::get()?.unix_timestamp;
+        escrow.pending_favor_seller = favor_seller;
+        escrow.pending_slash_bond_bps = slash_bond_bps;
+        escrow.resolution_executable_at = now
+            .checked_add(appeal_window_seconds)
+            .ok_or(EscrowError::CalculationError)?;
+        escrow.status = EscrowStatus::ResolutionPending;
+        escrow.updated_at = now;
+        
+        Ok(())
+    }
+
+    /// Resolve a dispute by splitting what the escrow holds: `seller_bps` of it
+    /// goes to the seller, less the marketplace fee on that share, and the
+    /// rest back to the buyer. A split has no losing party to appeal, so it
+    /// settles immediately and is only available without an appeal window.
+    pub fn resolve_dispute_split(mut ctx: Context<ResolveDispute>, seller_bps: u16) -> Result<()> {
+        require!(
+            ctx.accounts.escrow.status == EscrowStatus::Disputed,
+            EscrowError::InvalidEscrowState
+        );
+        require!(seller_bps <= 10000, EscrowError::InvalidBasisPoints);
+        require_dispute_resolver(
+            &ctx.accounts.marketplace,
+            &ctx.accounts.arbitrator_routing,
+            &ctx.accounts.escrow.dispute_reason,
+            &ctx.accounts.authority.key(),
+        )?;
+        require!(
+            ctx.accounts.marketplace.appeal_window_seconds == 0,
+            EscrowError::SplitNotAppealable
+        );
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        let accounts = &mut ctx.accounts;
+        let escrow = &mut accounts.escrow;
+        let (seller_share, buyer_share) = escrow
+            .rounding_policy
+            .split(escrow.unreleased_amount(), seller_bps as u64, 10000)
+            .ok_or(EscrowError::CalculationError)?;
+        let fee = escrow.fee_on(seller_share);
+        
+        release_from_escrow(
+            escrow,
+            &accounts.escrow_vault,
+            &accounts.seller,
+            accounts.escrow_token_account.as_ref(),
+            accounts.seller_token_account.as_ref(),
+            &accounts.token_program,
+            &accounts.system_program,
+            seller_share - fee,
+        )?;
+        collect_fee(
+            escrow,
+            &accounts.escrow_vault,
+            &accounts.fee_destination,
+            accounts.escrow_token_account.as_ref(),
+            &accounts.token_program,
+            &accounts.system_program,
+            fee,
+        )?;
+        release_from_escrow(
+            escrow,
+            &accounts.escrow_vault,
+            &accounts.buyer,
+            accounts.escrow_token_account.as_ref(),
+            accounts.buyer_token_account.as_ref(),
+            &accounts.token_program,
+            &accounts.system_program,
+            buyer_share,
+        )?;
+        
+        // Report the settlement for accounting integrations
+        emit!(SettlementBreakdown::split(escrow.key(), escrow, seller_share, fee));
+        escrow.fees_collected = escrow
+            .fees_collected
+            .checked_add(fee)
+            .ok_or(EscrowError::CalculationError)?;
+        
+        escrow.status = if seller_bps > 0 {
+            EscrowStatus::Completed
+        } else {
+            EscrowStatus::Refunded
+        };
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        if seller_bps > 0 {
+            escrow.completed_at = escrow.updated_at;
+        }
+        
+        // Neither party lost outright, so no loss is recorded against them
+        escrow.dispute_resolved_at = escrow.updated_at;
+        escrow.favored_seller = seller_bps == 10000;
+        escrow.dispute_description = None;
+        escrow.disputed_by = None;
+        
+        let completed_volume = escrow
+            .released_amount
+            .checked_add(seller_share)
+            .ok_or(EscrowError::CalculationError)?;
+        record_escrow_closed(
+            &accounts.marketplace_program,
+            &accounts.escrow_authority,
+            &accounts.marketplace,
+            &accounts.product,
+            &mut accounts.buyer_escrow_counter,
+            escrow_authority_bump,
+            escrow.quantity,
+            escrow.units_sold(),
+            completed_volume,
+            escrow.fees_collected,
+        )?;
+        
+        // A split paying the seller counts as a sale and purchase
+        if escrow.status == EscrowStatus::Completed {
+            record_completed_transaction(
+                &accounts.reputation_program,
+                &accounts.escrow_authority,
+                &accounts.marketplace,
+                &accounts.authority.to_account_info(),
+                &accounts.seller,
+                &accounts.buyer,
+                accounts.seller_reputation.as_ref(),
+                accounts.buyer_reputation.as_ref(),
+                &accounts.system_program,
+                escrow_authority_bump,
+            )?;
+        }
+        
+        Ok(())
+    }
+
+    /// Appeal a pending resolution. Only the losing party may appeal, and only
+    /// before the appeal window closes; the dispute then goes to the
+    /// marketplace's appeal arbitrator.
+    pub fn appeal_resolution(ctx: Context<AppealResolution>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(
+            escrow.status == EscrowStatus::ResolutionPending,
+            EscrowError::InvalidEscrowState
+        );
+        let loser = if escrow.pending_favor_seller { escrow.buyer } else { escrow.seller };
+        require!(
+            ctx.accounts.user.key() == loser,
+            EscrowError::NotLosingParty
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now < escrow.resolution_executable_at,
+            EscrowError::AppealWindowClosed
+        );
+        
+        // Update escrow status
+        escrow.status = EscrowStatus::Appealed;
+        escrow.updated_at = now;
+        
+        Ok(())
+    }
+
+    /// Carry out a pending resolution once its appeal window has passed.
+    /// Anyone may call this.
+    pub fn execute_resolution(ctx: Context<ResolveDispute>) -> Result<()> {
+        let escrow = &ctx.accounts.escrow;
+        
+        require!(
+            escrow.status == EscrowStatus::ResolutionPending,
+            EscrowError::InvalidEscrowState
+        );
+        require!(
+            Clock::get()?.unix_timestamp >= escrow.resolution_executable_at,
+            EscrowError::AppealWindowOpen
+        );
+        
+        let favor_seller = escrow.pending_favor_seller;
+        let slash_bond_bps = escrow.pending_slash_bond_bps;
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        settle_dispute(ctx.accounts, escrow_authority_bump, favor_seller, slash_bond_bps)
+    }
+
+    /// Decide an appealed dispute. Only the marketplace's appeal arbitrator may
+    /// call this, and its decision is final.
+    pub fn resolve_appeal(
+        ctx: Context<ResolveDispute>,
+        favor_seller: bool,
+        slash_bond_bps: u16,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.escrow.status == EscrowStatus::Appealed,
+            EscrowError::InvalidEscrowState
+        );
+        require!(
+            ctx.accounts.authority.key() == ctx.accounts.marketplace.appeal_arbitrator,
+            EscrowError::UnauthorizedArbitrator
+        );
+        
+        // Slashing only applies to fraud found against the seller
+        require!(slash_bond_bps <= 10000, EscrowError::InvalidBasisPoints);
+        require!(
+            slash_bond_bps == 0 || !favor_seller,
+            EscrowError::InvalidSlash
+        );
+        
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        settle_dispute(ctx.accounts, escrow_authority_bump, favor_seller, slash_bond_bps)
+    }
+
+    /// Request a return of a completed order within the product's return window
+    pub fn request_return(ctx: Context<RequestReturn>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        // Only completed orders can be returned, and a rejected return
+        // cannot be requested again
+        require!(
+            escrow.status == EscrowStatus::Completed,
+            EscrowError::InvalidEscrowState
+        );
+        require!(!escrow.return_rejected, EscrowError::ReturnAlreadyRejected);
+        
+        // Check the return window snapshotted at escrow creation
+        let now = Clock::get()?.unix_timestamp;
+        let return_deadline = escrow
+            .completed_at
+            .checked_add(escrow.return_window_seconds)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            escrow.return_window_seconds > 0 && now <= return_deadline,
+            EscrowError::ReturnWindowClosed
+        );
+        
+        // Update escrow status
+        escrow.status = EscrowStatus::ReturnRequested;
+        escrow.updated_at = now;
+        
+        Ok(())
+    }
+
+    /// Approve a requested return, refunding the buyer what the seller was
+    /// paid from the seller's funds. The marketplace keeps its fee, and the
+    /// sale and purchase are taken back off both parties' reputations.
+    pub fn approve_return(ctx: Context<ApproveReturn>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        // Check that a return has been requested
+        require!(
+            escrow.status == EscrowStatus::ReturnRequested,
+            EscrowError::InvalidEscrowState
+        );
+        
+        require_accounts_match_currency(
+            &escrow.currency,
+            &[
+                ctx.accounts.seller_token_account.is_some(),
+                ctx.accounts.buyer_token_account.is_some(),
+            ],
+        )?;
+        
+        // The escrow was already released at completion, so the refund is
+        // paid by the seller directly rather than from the escrow vault
+        let refund = escrow.seller_proceeds();
+        match escrow.currency {
+            CurrencyType::SOL => {
+                let transfer_instruction = anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.seller.to_account_info(),
+                    to: ctx.accounts.buyer.to_account_info(),
+                };
+                
+                anchor_lang::system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        transfer_instruction,
+                    ),
+                    refund,
+                )?;
+            },
+            CurrencyType::USDC | CurrencyType::USDT => {
+                let from = ctx
+                    .accounts
+                    .seller_token_account
+                    .as_ref()
+                    .ok_or(EscrowError::MissingTokenAccount)?;
+                let to = ctx
+                    .accounts
+                    .buyer_token_account
+                    .as_ref()
+                    .ok_or(EscrowError::MissingTokenAccount)?;
+                require_keys_eq!(from.mint, escrow.mint, EscrowError::InvalidMint);
+                require_keys_eq!(to.mint, escrow.mint, EscrowError::InvalidMint);
+                let transfer_instruction = Transfer {
+                    from: from.to_account_info(),
+                    to: to.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                };
+                
+                token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        transfer_instruction,
+                    ),
+                    refund,
+                )?;
+            }
+        }
+        
+        // Update escrow status
+        escrow.status = EscrowStatus::Refunded;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        
+        // The returned order no longer counts as a sale or purchase
+        reverse_completed_transaction(
+            &ctx.accounts.reputation_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.seller_reputation,
+            &ctx.accounts.buyer_reputation,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+        )
+    }
+
+    /// Reject a requested return, leaving the order completed. The buyer
+    /// cannot request a return of it again.
+    pub fn reject_return(ctx: Context<RejectReturn>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(
+            escrow.status == EscrowStatus::ReturnRequested,
+            EscrowError::InvalidEscrowState
+        );
+        
+        escrow.status = EscrowStatus::Completed;
+        escrow.return_rejected = true;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Deposit SOL into the seller's bond, which backs fraud penalties
+    pub fn deposit_seller_bond(ctx: Context<DepositSellerBond>, amount: u64) -> Result<()> {
+        require!(amount > 0, EscrowError::InvalidBondAmount);
+        
+        let transfer_instruction = anchor_lang::system_program::Transfer {
+            from: ctx.accounts.seller.to_account_info(),
+            to: ctx.accounts.seller_bond.to_account_info(),
+        };
+        
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                transfer_instruction,
+            ),
+            amount,
+        )?;
+        
+        let seller_bond = &mut ctx.accounts.seller_bond;
+        seller_bond.marketplace = ctx.accounts.marketplace.key();
+        seller_bond.seller = ctx.accounts.seller.key();
+        seller_bond.amount = seller_bond.amount.checked_add(amount).ok_or(EscrowError::CalculationError)?;
+        seller_bond.bump = *ctx.bumps.get("seller_bond").unwrap();
+        
+        Ok(())
+    }
+
+    /// Create a discount coupon redeemable at escrow creation. Only the hash
+    /// of the code is stored, and it seeds the coupon's address.
+    pub fn create_coupon(
+        ctx: Context<CreateCoupon>,
+        code_hash: [u8; 32],
+        discount_bps: u16,
+        max_uses: u32,
+        expires_at: i64,
+    ) -> Result<()> {
+        require!(
+            discount_bps > 0 && discount_bps <= 10000,
+            EscrowError::InvalidBasisPoints
+        );
+        require!(max_uses > 0, EscrowError::InvalidCoupon);
+        require!(
+            expires_at > Clock::get()?.unix_timestamp,
+            EscrowError::CouponExpired
+        );
+        
+        let coupon = &mut ctx.accounts.coupon;
+        coupon.marketplace = ctx.accounts.marketplace.key();
+        coupon.code_hash = code_hash;
+        coupon.discount_bps = discount_bps;
+        coupon.max_uses = max_uses;
+        coupon.remaining_uses = max_uses;
+        coupon.expires_at = expires_at;
+        coupon.bump = *ctx.bumps.get("coupon").unwrap();
+        
+        Ok(())
+    }
+
+    /// Assign the arbitrators who resolve disputes raised for `dispute_reason`.
+    /// An empty list returns those disputes to the marketplace authority.
+    pub fn set_arbitrator_pool(
+        ctx: Context<SetArbitratorPool>,
+        dispute_reason: DisputeReason,
+        arbitrators: Vec<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            arbitrators.len() <= MAX_ARBITRATORS_PER_REASON
+                && !arbitrators.contains(&Pubkey::default()),
+            EscrowError::InvalidArbitratorPool
+        );
+        
+        let routing = &mut ctx.accounts.arbitrator_routing;
+        routing.marketplace = ctx.accounts.marketplace.key();
+        let pool = &mut routing.pools[dispute_reason.index()];
+        pool.arbitrators = [Pubkey::default(); MAX_ARBITRATORS_PER_REASON];
+        pool.arbitrators[..arbitrators.len()].copy_from_slice(&arbitrators);
+        routing.bump = *ctx.bumps.get("arbitrator_routing").unwrap();
+        
+        Ok(())
+    }
+
+    /// Pay an installment towards a layaway escrow
+    pub fn pay_installment(ctx: Context<FundEscrow>, amount: u64) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        
+        // Installments are only accepted on unpaid layaways before the deadline
+        require!(escrow.is_layaway, EscrowError::NotLayaway);
+        require!(
+            escrow.status == EscrowStatus::Created,
+            EscrowError::InvalidEscrowState
+        );
+        require!(
+            Clock::get()?.unix_timestamp <= escrow.layaway_deadline,
+            EscrowError::LayawayDeadlinePassed
+        );
+        
+        // Never accept more than the outstanding balance
+        let funded_amount = escrow
+            .funded_amount
+            .checked_add(amount)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            amount > 0 && funded_amount <= escrow.amount,
+            EscrowError::InvalidInstallment
+        );
+        
+        // Token installments need the escrow's vault to exist already
+        let escrow_token_account = match escrow.currency {
+            CurrencyType::SOL => None,
+            CurrencyType::USDC | CurrencyType::USDT => Some(escrow_token_vault(
+                escrow.key(),
+                escrow.mint,
+                ctx.accounts.escrow_token_account.as_ref(),
+            )?),
+        };
+        
+        deposit_to_escrow(
+            &escrow.currency,
+            escrow.mint,
+            &ctx.accounts.buyer,
+            &ctx.accounts.escrow_vault,
+            ctx.accounts.buyer_token_account.as_ref(),
+            escrow_token_account,
+            &ctx.accounts.token_program,
+            &ctx.accounts.system_program,
+            amount,
+        )?;
+        
+        // The final installment funds the escrow
+        escrow.funded_amount = funded_amount;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        if funded_amount == escrow.amount {
+            escrow.status = EscrowStatus::Funded;
+            escrow.ship_by = escrow.ship_by_from(escrow.updated_at)?;
+        }
+        
+        Ok(())
+    }
+
+    /// Settle a layaway the buyer failed to pay off by its deadline. The
+    /// marketplace's forfeit share of the installments goes to the seller and
+    /// the rest is returned to the buyer.
+    pub fn claim_layaway_default(ctx: Context<ClaimLayawayDefault>) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        
+        require!(escrow.is_layaway, EscrowError::NotLayaway);
+        require!(
+            escrow.status == EscrowStatus::Created,
+            EscrowError::InvalidEscrowState
+        );
+        require!(
+            Clock::get()?.unix_timestamp > escrow.layaway_deadline,
+            EscrowError::LayawayDeadlineNotReached
+        );
+        
+        let (forfeited, refunded) = escrow
+            .rounding_policy
+            .split(escrow.funded_amount, escrow.layaway_forfeit_bps as u64, 10000)
+            .ok_or(EscrowError::CalculationError)?;
+        
+        if forfeited > 0 {
+            release_from_escrow(
+                escrow,
+                &ctx.accounts.escrow_vault,
+                &ctx.accounts.seller.to_account_info(),
+                ctx.accounts.escrow_token_account.as_ref(),
+                ctx.accounts.seller_token_account.as_ref(),
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+                forfeited,
+            )?;
+        }
+        if refunded > 0 {
+            release_from_escrow(
+                escrow,
+                &ctx.accounts.escrow_vault,
+                &ctx.accounts.buyer,
+                ctx.accounts.escrow_token_account.as_ref(),
+                ctx.accounts.buyer_token_account.as_ref(),
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+                refunded,
+            )?;
+        }
+        
+        // Update escrow status
+        escrow.status = EscrowStatus::Cancelled;
+        escrow.updated_at = Clock::get()?.unix_timestamp;
+        
+        // The escrow no longer counts as open against the product
+        record_escrow_closed(
+            &ctx.accounts.marketplace_program,
+            &ctx.accounts.escrow_authority,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &mut ctx.accounts.buyer_escrow_counter,
+            *ctx.bumps.get("escrow_authority").unwrap(),
+            escrow.quantity,
+            0,
+            forfeited,
+            0,
+        )?;
+        
+        Ok(())
+    }
+
+    /// Open one escrow over a cart of products, which may come from several
+    /// sellers. `remaining_accounts` holds a (product, seller status, seller
+    /// reputation) triple per line item, in the order of `quantities`. Carts
+    /// are paid in SOL, held in the cart account itself, and settle item by
+    /// item so each seller is paid or disputed on their own. A cart counts as
+    /// one open escrow against the buyer's limit.
+    pub fn create_cart_escrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, CreateCartEscrow<'info>>,
+        cart_id: u64,
+        quantities: Vec<u64>,
+    ) -> Result<()> {
+        require!(
+            !quantities.is_empty()
+                && quantities.len() <= MAX_CART_ITEMS
+                && ctx.remaining_accounts.len() == quantities.len() * 3,
+            EscrowError::InvalidCartItems
+        );
+        require!(!ctx.accounts.marketplace.is_paused, EscrowError::MarketplacePaused);
+        
+        let marketplace_key = ctx.accounts.marketplace.key();
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        let now = Clock::get()?.unix_timestamp;
+        let mut items: Vec<CartItem> = Vec::with_capacity(quantities.len());
+        let mut total_amount: u64 = 0;
+        
+        for (accounts, quantity) in ctx.remaining_accounts.chunks(3).zip(quantities) {
+            let (product_info, seller_status_info, seller_reputation_info) =
+                (&accounts[0], &accounts[1], &accounts[2]);
+            require!(quantity > 0, EscrowError::InvalidQuantity);
+            
+            let product = Account::<Product>::try_from(product_info)?;
+            require_keys_eq!(product.marketplace, marketplace_key, EscrowError::ProductMarketplaceMismatch);
+            require!(
+                items.iter().all(|item| item.product != product.key()),
+                EscrowError::InvalidCartItems
+            );
+            require!(product.currency == CurrencyType::SOL, EscrowError::UnsupportedCartCurrency);
+            
+            // Each line item is held to the same rules as a single escrow
+            let (seller_status, _) = Pubkey::find_program_address(
+                &[b"seller_status", marketplace_key.as_ref(), product.seller.as_ref()],
+                &marketplace::ID,
+            );
+            require_keys_eq!(seller_status_info.key(), seller_status, EscrowError::InvalidCartItems);
+            require_purchasable(&product, seller_status_info, quantity, now)?;
+            require_trusted_buyer(&product, ctx.accounts.buyer_reputation.as_ref())?;
+            
+            // Each seller pays the fee of their own volume tier
+            let (seller_reputation, _) = Pubkey::find_program_address(
+                &[b"user_reputation", product.seller.as_ref()],
+                &reputation::ID,
+            );
+            require_keys_eq!(seller_reputation_info.key(), seller_reputation, EscrowError::InvalidCartItems);
+            let fees_basis_points = ctx
+                .accounts
+                .marketplace
+                .fees_basis_points_for(UserReputation::total_sales_of(seller_reputation_info)?);
+            
+            let amount = product.unit_price_for(quantity).checked_mul(quantity).ok_or(EscrowError::CalculationError)?;
+            let fee = if product.fee_exempt {
+                0
+            } else {
+                ((amount as u128) * (fees_basis_points as u128) / 10000) as u64
+            };
+            total_amount = total_amount.checked_add(amount).ok_or(EscrowError::CalculationError)?;
+            items.push(CartItem {
+                product: product.key(),
+                seller: product.seller,
+                quantity,
+                amount,
+                fee,
+                status: CartItemStatus::Pending,
+                dispute_reason: None,
+            });
+            
+            // Every line item counts as an open escrow against its product
+            marketplace::cpi::record_escrow_opened(
+                CpiContext::new_with_signer(
+                    ctx.accounts.marketplace_program.to_account_info(),
+                    marketplace::cpi::accounts::TrackEscrow {
+                        escrow_authority: ctx.accounts.escrow_authority.to_account_info(),
+                        marketplace: ctx.accounts.marketplace.to_account_info(),
+                        product: product_info.clone(),
+                    },
+                    &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]],
+                ),
+                quantity,
+            )?;
+        }
+        
+        require!(
+            total_amount >= ctx.accounts.marketplace.min_escrow_amount(&CurrencyType::SOL),
+            EscrowError::EscrowBelowMinimum
+        );
+        require!(
+            ctx.accounts
+                .marketplace
+                .within_max_escrow_value(&CurrencyType::SOL, total_amount),
+            EscrowError::OrderValueTooHigh
+        );
+        
+        let buyer_escrow_counter = &mut ctx.accounts.buyer_escrow_counter;
+        let max_open_escrows = ctx.accounts.marketplace.max_open_escrows_per_buyer;
+        require!(
+            max_open_escrows == 0 || buyer_escrow_counter.open_escrows < max_open_escrows,
+            EscrowError::TooManyOpenEscrows
+        );
+        buyer_escrow_counter.marketplace = marketplace_key;
+        buyer_escrow_counter.buyer = ctx.accounts.buyer.key();
+        buyer_escrow_counter.open_escrows += 1;
+        buyer_escrow_counter.bump = *ctx.bumps.get("buyer_escrow_counter").unwrap();
+        
+        let cart = &mut ctx.accounts.cart;
+        cart.marketplace = marketplace_key;
+        cart.buyer = ctx.accounts.buyer.key();
+        cart.cart_id = cart_id;
+        cart.amount = total_amount;
+        cart.funded = false;
+        cart.items = items;
+        cart.fee_destination = ctx.accounts.marketplace.fee_destination_for(&CurrencyType::SOL);
+        cart.created_at = now;
+        cart.updated_at = now;
+        cart.bump = *ctx.bumps.get("cart").unwrap();
+        
+        Ok(())
+    }
+
+    /// Fund a cart escrow with the total of its line items
+    pub fn fund_cart_escrow(ctx: Context<FundCartEscrow>) -> Result<()> {
+        require!(!ctx.accounts.cart.funded, EscrowError::AlreadyFunded);
+        
+        let transfer_instruction = anchor_lang::system_program::Transfer {
+            from: ctx.accounts.buyer.to_account_info(),
+            to: ctx.accounts.cart.to_account_info(),
+        };
+        
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                transfer_instruction,
+            ),
+            ctx.accounts.cart.amount,
+        )?;
+        
+        let cart = &mut ctx.accounts.cart;
+        cart.funded = true;
+        cart.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Cancel a cart the buyer has not funded, releasing the units its line
+    /// items reserved and returning its rent. `remaining_accounts` holds the
+    /// product of each line item, in order.
+    pub fn cancel_cart_escrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, CancelCartEscrow<'info>>,
+    ) -> Result<()> {
+        let cart = &ctx.accounts.cart;
+        require!(!cart.funded, EscrowError::AlreadyFunded);
+        require!(
+            ctx.remaining_accounts.len() == cart.items.len(),
+            EscrowError::InvalidCartItems
+        );
+        
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        for (product_info, item) in ctx.remaining_accounts.iter().zip(&cart.items) {
+            require_keys_eq!(product_info.key(), item.product, EscrowError::InvalidCartItems);
+            marketplace::cpi::record_escrow_closed(
+                CpiContext::new_with_signer(
+                    ctx.accounts.marketplace_program.to_account_info(),
+                    marketplace::cpi::accounts::TrackEscrow {
+                        escrow_authority: ctx.accounts.escrow_authority.to_account_info(),
+                        marketplace: ctx.accounts.marketplace.to_account_info(),
+                        product: product_info.clone(),
+                    },
+                    &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]],
+                ),
+                item.quantity,
+                0,
+                0,
+                0,
+            )?;
+        }
+        
+        // The buyer may open another escrow in its place
+        let buyer_escrow_counter = &mut ctx.accounts.buyer_escrow_counter;
+        buyer_escrow_counter.open_escrows = buyer_escrow_counter.open_escrows.saturating_sub(1);
+        
+        Ok(())
+    }
+
+    /// Mark a seller's line item in a funded cart as shipped
+    pub fn mark_cart_item_shipped(ctx: Context<MarkCartItemShipped>, index: u8) -> Result<()> {
+        let cart = &mut ctx.accounts.cart;
+        require!(cart.funded, EscrowError::InvalidEscrowState);
+        
+        let item = cart.items.get_mut(index as usize).ok_or(EscrowError::InvalidCartItems)?;
+        require_keys_eq!(item.seller, ctx.accounts.seller.key(), EscrowError::UnauthorizedSeller);
+        require!(item.status == CartItemStatus::Pending, EscrowError::InvalidEscrowState);
+        
+        item.status = CartItemStatus::Shipped;
+        cart.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Confirm delivery of shipped line items, paying each seller their
+    /// item's amount less its marketplace fee. `remaining_accounts` holds a
+    /// (product, seller) pair per index in `indices`.
+    pub fn confirm_cart_items<'info>(
+        ctx: Context<'_, '_, '_, 'info, SettleCartItem<'info>>,
+        indices: Vec<u8>,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        require_keys_eq!(
+            ctx.accounts.authority.key(),
+            ctx.accounts.cart.buyer,
+            EscrowError::UnauthorizedBuyer
+        );
+        require!(
+            !indices.is_empty() && ctx.remaining_accounts.len() == indices.len() * 2,
+            EscrowError::InvalidCartItems
+        );
+        
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        for (accounts, index) in ctx.remaining_accounts.chunks(2).zip(indices) {
+            let (product_info, seller_info) = (&accounts[0], &accounts[1]);
+            let item = ctx
+                .accounts
+                .cart
+                .items
+                .get(index as usize)
+                .ok_or(EscrowError::InvalidCartItems)?;
+            require!(item.status == CartItemStatus::Shipped, EscrowError::InvalidEscrowState);
+            
+            settle_cart_item(ctx.accounts, product_info, seller_info, escrow_authority_bump, index, true)?;
+        }
+        
+        Ok(())
+    }
+
+    /// Dispute one line item of a funded cart. The rest of the cart keeps
+    /// settling normally.
+    pub fn dispute_cart_item(
+        ctx: Context<DisputeCartItem>,
+        index: u8,
+        dispute_reason: DisputeReason,
+    ) -> Result<()> {
+        let cart = &mut ctx.accounts.cart;
+        require!(cart.funded, EscrowError::InvalidEscrowState);
+        
+        let user = ctx.accounts.user.key();
+        let buyer = cart.buyer;
+        let item = cart.items.get_mut(index as usize).ok_or(EscrowError::InvalidCartItems)?;
+        require!(user == buyer || user == item.seller, EscrowError::Unauthorized);
+        require!(
+            item.status == CartItemStatus::Pending || item.status == CartItemStatus::Shipped,
+            EscrowError::InvalidEscrowState
+        );
+        
+        item.status = CartItemStatus::Disputed;
+        item.dispute_reason = Some(dispute_reason.clone());
+        cart.updated_at = Clock::get()?.unix_timestamp;
+        
+        emit!(CartItemDisputed {
+            cart: cart.key(),
+            index,
+            seller: cart.items[index as usize].seller,
+            dispute_reason,
+        });
+        
+        Ok(())
+    }
+
+    /// Resolve a disputed line item, paying its seller or refunding the
+    /// buyer its amount. `remaining_accounts` holds the item's product and
+    /// the account paid: the seller, or the buyer when refunded.
+    pub fn resolve_cart_item_dispute<'info>(
+        ctx: Context<'_, '_, '_, 'info, SettleCartItem<'info>>,
+        index: u8,
+        favor_seller: bool,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.marketplace.releases_paused,
+            EscrowError::ReleasesPaused
+        );
+        require!(ctx.remaining_accounts.len() == 2, EscrowError::InvalidCartItems);
+        
+        let item = ctx
+            .accounts
+            .cart
+            .items
+            .get(index as usize)
+            .ok_or(EscrowError::InvalidCartItems)?;
+        require!(item.status == CartItemStatus::Disputed, EscrowError::InvalidEscrowState);
+        require_dispute_resolver(
+            &ctx.accounts.marketplace,
+            &ctx.accounts.arbitrator_routing,
+            item.dispute_reason.as_ref().ok_or(EscrowError::InvalidEscrowState)?,
+            &ctx.accounts.authority.key(),
+        )?;
+        
+        let escrow_authority_bump = *ctx.bumps.get("escrow_authority").unwrap();
+        settle_cart_item(
+            ctx.accounts,
+            &ctx.remaining_accounts[0],
+            &ctx.remaining_accounts[1],
+            escrow_authority_bump,
+            index,
+            favor_seller,
+        )
+    }
+
+    /// Put `quantity` units of a SOL-priced product up for auction until
+    /// `end_time`. The units are reserved like an open escrow's, the opening
+    /// bid is the listed price for the quantity, and each later bid must beat
+    /// the highest by at least `min_increment` lamports.
+    pub fn create_auction(
+        ctx: Context<CreateAuction>,
+        quantity: u64,
+        min_increment: u64,
+        end_time: i64,
+    ) -> Result<()> {
+        require!(quantity > 0, EscrowError::InvalidQuantity);
+        require!(min_increment > 0, EscrowError::InvalidBidIncrement);
+        require!(!ctx.accounts.marketplace.is_paused, EscrowError::MarketplacePaused);
+        require!(
+            end_time > Clock::get()?.unix_timestamp,
+            EscrowError::InvalidAuctionEndTime
+        );
+        require!(
+            !marketplace::SellerStatus::is_on_hold(&ctx.accounts.seller_status)?,
+            EscrowError::SellerOnHold
+        );
+        
+        let product = &ctx.accounts.product;
+        require!(product.currency == CurrencyType::SOL, EscrowError::UnsupportedAuctionCurrency);
+        require!(
+            quantity >= product.min_order_quantity,
+            EscrowError::BelowMinimumOrder
+        );
+        require!(product.status != ProductStatus::Flagged, EscrowError::ProductFlagged);
+        require!(product.status != ProductStatus::SoldOut, EscrowError::ProductSoldOut);
+        require!(
+            product.available_quantity() >= quantity,
+            EscrowError::InsufficientInventory
+        );
+        let starting_price = product
+            .unit_price_for(quantity)
+            .checked_mul(quantity)
+            .ok_or(EscrowError::CalculationError)?;
+        
+        let auction = &mut ctx.accounts.auction;
+        auction.marketplace = ctx.accounts.marketplace.key();
+        auction.product = product.key();
+        auction.seller = product.seller;
+        auction.quantity = quantity;
+        auction.starting_price = starting_price;
+        auction.min_increment = min_increment;
+        auction.highest_bid = 0;
+        auction.highest_bidder = Pubkey::default();
+        auction.end_time = end_time;
+        auction.bump = *ctx.bumps.get("auction").unwrap();
+        
+        // The auctioned units are held back from sale until it settles
+        marketplace::cpi::record_escrow_opened(
+            CpiContext::new_with_signer(
+                ctx.accounts.marketplace_program.to_account_info(),
+                marketplace::cpi::accounts::TrackEscrow {
+                    escrow_authority: ctx.accounts.escrow_authority.to_account_info(),
+                    marketplace: ctx.accounts.marketplace.to_account_info(),
+                    product: ctx.accounts.product.to_account_info(),
+                },
+                &[&[marketplace::ESCROW_AUTHORITY_SEED, &[*ctx.bumps.get("escrow_authority").unwrap()]]],
+            ),
+            quantity,
+        )?;
+        
+        Ok(())
+    }
+
+    /// Bid `amount` lamports on an open auction. The bid is held in the
+    /// auction account and the bidder it outbids is refunded in full.
+    pub fn place_bid(ctx: Context<PlaceBid>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.marketplace.is_paused, EscrowError::MarketplacePaused);
+        
+        let auction = &ctx.accounts.auction;
+        require!(
+            Clock::get()?.unix_timestamp < auction.end_time,
+            EscrowError::AuctionEnded
+        );
+        require_keys_neq!(ctx.accounts.bidder.key(), auction.seller, EscrowError::SellerCannotBid);
+        require!(amount >= auction.minimum_bid()?, EscrowError::BidTooLow);
+        
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.bidder.to_account_info(),
+                    to: ctx.accounts.auction.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        
+        // Hand the outbid bidder their lamports back
+        let auction = &mut ctx.accounts.auction;
+        if auction.has_bid() {
+            let previous_bidder = ctx
+                .accounts
+                .previous_bidder
+                .as_ref()
+                .ok_or(EscrowError::InvalidPreviousBidder)?;
+            require_keys_eq!(
+                previous_bidder.key(),
+                auction.highest_bidder,
+                EscrowError::InvalidPreviousBidder
+            );
+            **auction.to_account_info().try_borrow_mut_lamports()? -= auction.highest_bid;
+            **previous_bidder.try_borrow_mut_lamports()? += auction.highest_bid;
+        }
+        
+        auction.highest_bid = amount;
+        auction.highest_bidder = ctx.accounts.bidder.key();
+        
+        emit!(BidPlaced {
+            auction: auction.key(),
+            bidder: auction.highest_bidder,
+            amount,
+        });
+        
+        Ok(())
+    }
+
+    /// Withdraw an auction nobody has bid on, releasing its units and
+    /// returning its rent to the seller
+    pub fn cancel_auction(ctx: Context<CancelAuction>) -> Result<()> {
+        require!(!ctx.accounts.auction.has_bid(), EscrowError::AuctionHasBids);
+        
+        marketplace::cpi::record_escrow_closed(
+            CpiContext::new_with_signer(
+                ctx.accounts.marketplace_program.to_account_info(),
+                marketplace::cpi::accounts::TrackEscrow {
+                    escrow_authority: ctx.accounts.escrow_authority.to_account_info(),
+                    marketplace: ctx.accounts.marketplace.to_account_info(),
+                    product: ctx.accounts.product.to_account_info(),
+                },
+                &[&[marketplace::ESCROW_AUTHORITY_SEED, &[*ctx.bumps.get("escrow_authority").unwrap()]]],
+            ),
+            ctx.accounts.auction.quantity,
+            0,
+            0,
+            0,
+        )
+    }
+
+    /// Settle an auction once it has ended, opening an escrow from the seller
+    /// to the highest bidder funded with the winning bid. The escrow is the
+    /// winner's next numbered escrow, so it never collides with one they
+    /// already hold for the product. It takes over the units the auction
+    /// reserved and then runs like any other, and
+    /// the auction's rent goes back to the seller. Anyone may settle, paying
+    /// the escrow's rent.
+    pub fn settle_auction(ctx: Context<SettleAuction>) -> Result<()> {
+        let auction = &ctx.accounts.auction;
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= auction.end_time, EscrowError::AuctionNotEnded);
+        require!(auction.has_bid(), EscrowError::NoBids);
+        let (quantity, winning_bid) = (auction.quantity, auction.highest_bid);
+        
+        let escrow = &mut ctx.accounts.escrow;
+        set_escrow_terms(
+            escrow,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &ctx.accounts.seller_reputation,
+            ctx.accounts.winner.key(),
+            quantity,
+            winning_bid,
+        )?;
+        escrow.mint = Pubkey::default();
+        escrow.funded_amount = winning_bid;
+        escrow.status = EscrowStatus::Funded;
+        escrow.ship_by = escrow.ship_by_from(now)?;
+        escrow.nonce = ctx.accounts.buyer_escrow_counter.escrows_created;
+        escrow.bump = *ctx.bumps.get("escrow").unwrap();
+        
+        // The winner holds one more open escrow, even past the usual limit,
+        // since the bid was already placed
+        let buyer_escrow_counter = &mut ctx.accounts.buyer_escrow_counter;
+        buyer_escrow_counter.marketplace = ctx.accounts.marketplace.key();
+        buyer_escrow_counter.buyer = ctx.accounts.winner.key();
+        buyer_escrow_counter.open_escrows += 1;
+        buyer_escrow_counter.escrows_created += 1;
+        buyer_escrow_counter.bump = *ctx.bumps.get("buyer_escrow_counter").unwrap();
+        
+        // Move the winning bid into the escrow's vault; the auction account
+        // is closed to the seller with its rent afterwards
+        **ctx.accounts.auction.to_account_info().try_borrow_mut_lamports()? -= winning_bid;
+        **ctx.accounts.escrow_vault.try_borrow_mut_lamports()? += winning_bid;
+        
+        Ok(())
+    }
+
+    /// Offer the seller `offered_price` per unit, below the list price, for
+    /// `quantity` units. The offer stays open for the product's offer window
+    /// and commits no funds until the seller accepts it.
+    pub fn make_offer(
+        ctx: Context<MakeOffer>,
+        offer_id: u64,
+        offered_price: u64,
+        quantity: u64,
+    ) -> Result<()> {
+        require!(quantity > 0, EscrowError::InvalidQuantity);
+        require!(!ctx.accounts.marketplace.is_paused, EscrowError::MarketplacePaused);
+        
+        let product = &ctx.accounts.product;
+        require!(product.offer_window_seconds > 0, EscrowError::OffersNotAccepted);
+        require_keys_neq!(ctx.accounts.buyer.key(), product.seller, EscrowError::Unauthorized);
+        require!(
+            offered_price > 0 && offered_price < product.unit_price_for(quantity),
+            EscrowError::InvalidOfferPrice
+        );
+        require!(
+            quantity >= product.min_order_quantity,
+            EscrowError::BelowMinimumOrder
+        );
+        require!(product.status != ProductStatus::Flagged, EscrowError::ProductFlagged);
+        require!(product.status != ProductStatus::SoldOut, EscrowError::ProductSoldOut);
+        require!(
+            product.available_quantity() >= quantity,
+            EscrowError::InsufficientInventory
+        );
+        require_trusted_buyer(product, ctx.accounts.buyer_reputation.as_ref())?;
+        
+        let now = Clock::get()?.unix_timestamp;
+        let offer = &mut ctx.accounts.offer;
+        offer.marketplace = ctx.accounts.marketplace.key();
+        offer.product = product.key();
+        offer.buyer = ctx.accounts.buyer.key();
+        offer.seller = product.seller;
+        offer.offer_id = offer_id;
+        offer.offered_price = offered_price;
+        offer.quantity = quantity;
+        offer.status = OfferStatus::Pending;
+        offer.created_at = now;
+        offer.expires_at = now
+            .checked_add(product.offer_window_seconds)
+            .ok_or(EscrowError::CalculationError)?;
+        offer.bump = *ctx.bumps.get("offer").unwrap();
+        
+        Ok(())
+    }
+
+    /// Accept an open offer, creating an escrow at the offered price that the
+    /// buyer then funds as usual. The seller pays the escrow's rent.
+    pub fn accept_offer(ctx: Context<AcceptOffer>) -> Result<()> {
+        require!(!ctx.accounts.marketplace.is_paused, EscrowError::MarketplacePaused);
+        
+        let offer = &ctx.accounts.offer;
+        let now = Clock::get()?.unix_timestamp;
+        require!(offer.status == OfferStatus::Pending, EscrowError::OfferNotPending);
+        require!(now <= offer.expires_at, EscrowError::OfferExpired);
+        
+        let product = &ctx.accounts.product;
+        require_purchasable(product, &ctx.accounts.seller_status, offer.quantity, now)?;
+        let escrow_mint = escrow_mint_for(
+            &ctx.accounts.marketplace,
+            &product.currency,
+            ctx.accounts.mint.as_ref(),
+        )?;
+        
+        let quantity = offer.quantity;
+        let total_amount = offer
+            .offered_price
+            .checked_mul(quantity)
+            .ok_or(EscrowError::CalculationError)?;
+        require!(
+            total_amount >= ctx.accounts.marketplace.min_escrow_amount(&product.currency),
+            EscrowError::EscrowBelowMinimum
+        );
+        require!(
+            ctx.accounts
+                .marketplace
+                .within_max_escrow_value(&product.currency, total_amount),
+            EscrowError::OrderValueTooHigh
+        );
+        
+        let escrow = &mut ctx.accounts.escrow;
+        set_escrow_terms(
+            escrow,
+            &ctx.accounts.marketplace,
+            &ctx.accounts.product,
+            &ctx.accounts.seller_reputation,
+            ctx.accounts.buyer.key(),
+            quantity,
+            total_amount,
+        )?;
+        escrow.mint = escrow_mint;
+        escrow.funded_amount = 0;
+        escrow.status = EscrowStatus::Created;
+        escrow.nonce = ctx.accounts.buyer_escrow_counter.escrows_created;
+        escrow.bump = *ctx.bumps.get("escrow").unwrap();
+        
+        let buyer_escrow_counter = &mut ctx.accounts.buyer_escrow_counter;
+        let max_open_escrows = ctx.accounts.marketplace.max_open_escrows_per_buyer;
+        require!(
+            max_open_escrows == 0 || buyer_escrow_counter.open_escrows < max_open_escrows,
+            EscrowError::TooManyOpenEscrows
+        );
+        buyer_escrow_counter.marketplace = ctx.accounts.marketplace.key();
+        buyer_escrow_counter.buyer = ctx.accounts.buyer.key();
+        buyer_escrow_counter.open_escrows += 1;
+        buyer_escrow_counter.escrows_created += 1;
+        buyer_escrow_counter.bump = *ctx.bumps.get("buyer_escrow_counter").unwrap();
+        
+        let offer = &mut ctx.accounts.offer;
+        offer.status = OfferStatus::Accepted;
+        
+        marketplace::cpi::record_escrow_opened(
+            CpiContext::new_with_signer(
+                ctx.accounts.marketplace_program.to_account_info(),
+                marketplace::cpi::accounts::TrackEscrow {
+                    escrow_authority: ctx.accounts.escrow_authority.to_account_info(),
+                    marketplace: ctx.accounts.marketplace.to_account_info(),
+                    product: ctx.accounts.product.to_account_info(),
+                },
+                &[&[marketplace::ESCROW_AUTHORITY_SEED, &[*ctx.bumps.get("escrow_authority").unwrap()]]],
+            ),
+            quantity,
+        )?;
+        
+        Ok(())
+    }
+
+    /// Turn down an open offer, which can then no longer be accepted
+    pub fn reject_offer(ctx: Context<RejectOffer>) -> Result<()> {
+        let offer = &mut ctx.accounts.offer;
+        require!(offer.status == OfferStatus::Pending, EscrowError::OfferNotPending);
+        offer.status = OfferStatus::Rejected;
+        
+        Ok(())
+    }
+
+    /// Return the escrow's essential fields in the versioned encoding of
+    /// `Escrow::export_state`, for relaying order state to other chains
+    pub fn serialize_escrow_state(ctx: Context<SerializeEscrowState>) -> Result<Vec<u8>> {
+        Ok(ctx.accounts.escrow.export_state(ctx.accounts.escrow.key()))
+    }
+
+    /// Close a settled escrow, returning its rent and anything left in its
+    /// SOL vault to the buyer
+    pub fn close_escrow(ctx: Context<CloseEscrow>) -> Result<()> {
+        require!(
+            ctx.accounts.escrow.status.is_settled(),
+            EscrowError::EscrowNotSettled
+        );
+        
+        // Returns and review moderation still need the escrow for a while
+        require!(
+            Clock::get()?.unix_timestamp > ctx.accounts.escrow.closable_from(&ctx.accounts.marketplace)?,
+            EscrowError::EscrowStillReviewable
+        );
+        
+        // The vault is a system account, emptied once its lamports are gone
+        let leftover = ctx.accounts.escrow_vault.lamports();
+        if leftover > 0 {
+            let escrow_key = ctx.accounts.escrow.key();
+            let vault_bump = [*ctx.bumps.get("escrow_vault").unwrap()];
+            let vault_seeds = &[b"escrow_vault".as_ref(), escrow_key.as_ref(), &vault_bump];
+            anchor_lang::system_program::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.escrow_vault.to_account_info(),
+                        to: ctx.accounts.buyer.to_account_info(),
+                    },
+                    &[&vault_seeds[..]],
+                ),
+                leftover,
+            )?;
+        }
+        
+        Ok(())
+    }
+}
+
+/// Check that `quantity` units of `product` can be sold at `now`
+fn require_purchasable(
+    product: &Product,
+    seller_status: &AccountInfo,
+    quantity: u64,
+    now: i64,
+) -> Result<()> {
+    // Seasonal listings can only be bought within their window
+    if let Some(available_from) = product.available_from {
+        require!(now >= available_from, EscrowError::ProductNotYetAvailable);
+    }
+    if let Some(available_until) = product.available_until {
+        require!(now <= available_until, EscrowError::ProductSeasonEnded);
+    }
+
+    // Sellers on hold keep their listings but take no new orders
+    require!(
+        !marketplace::SellerStatus::is_on_hold(seller_status)?,
+        EscrowError::SellerOnHold
+    );
+
+    // Wholesale listings sell no less than their minimum order
+    require!(
+        quantity >= product.min_order_quantity,
+        EscrowError::BelowMinimumOrder
+    );
+
+    // Flagged listings are held for moderation
+    require!(product.status != ProductStatus::Flagged, EscrowError::ProductFlagged);
+
+    // Units held by other open escrows cannot be sold again
+    require!(product.status != ProductStatus::SoldOut, EscrowError::ProductSoldOut);
+    require!(
+        product.available_quantity() >= quantity,
+        EscrowError::InsufficientInventory
+    );
+
+    // Listings taken down by the seller or the marketplace are off sale
+    require!(product.status == ProductStatus::Active, EscrowError::ProductNotActive);
+    
+    Ok(())
+}
+
+/// Check that the buyer with `buyer_reputation` has not lost more disputes
+/// than `product`'s seller accepts
+fn require_trusted_buyer(
+    product: &Product,
+    buyer_reputation: Option<&Account<UserReputation>>,
+) -> Result<()> {
+    if let Some(max_lost_disputes) = product.max_buyer_lost_disputes {
+        let buyer_reputation = buyer_reputation.ok_or(EscrowError::BuyerReputationRequired)?;
+        require!(
+            buyer_reputation.disputes_lost <= max_lost_disputes,
+            EscrowError::BuyerTooRisky
+        );
+    }
+    
+    Ok(())
+}
+
+/// Find the mint an escrow in `currency` is paid in, or the default pubkey
+/// for SOL. Token prices are in the currency's base units, so the mint paid
+/// with must use the same decimals or the escrow moves the wrong value.
+/// Marketplaces that name the currency's mint accept only that one.
+fn escrow_mint_for(
+    marketplace: &marketplace::MarketplaceState,
+    currency: &CurrencyType,
+    mint: Option<&Account<Mint>>,
+) -> Result<Pubkey> {
+    if *currency == CurrencyType::SOL {
+        return Ok(Pubkey::default());
+    }
+    let mint = mint.ok_or(EscrowError::MissingTokenAccount)?;
+    require!(
+        mint.decimals as u32 == currency.decimals(),
+        EscrowError::DecimalsMismatch
+    );
+    if let Some(expected_mint) = marketplace.mint_for(currency) {
+        require_keys_eq!(mint.key(), expected_mint, EscrowError::InvalidMint);
+    }
+    Ok(mint.key())
+}
+
+/// Set the terms an escrow for `quantity` units of `product` copies from the
+/// product and marketplace when it is opened for `buyer` at `amount`
+fn set_escrow_terms(
+    escrow: &mut Escrow,
+    marketplace: &Account<marketplace::MarketplaceState>,
+    product: &Account<Product>,
+    seller_reputation: &AccountInfo,
+    buyer: Pubkey,
+    quantity: u64,
+    amount: u64,
+) -> Result<()> {
+    escrow.marketplace = marketplace.key();
+    escrow.buyer = buyer;
+    escrow.seller = product.seller;
+    escrow.product = product.key();
+    escrow.quantity = quantity;
+    escrow.amount = amount;
+    escrow.currency = product.currency.clone();
+    escrow.return_window_seconds = product.return_window_seconds;
+    escrow.handling_time_seconds = product.handling_time_seconds;
+    escrow.seller_is_pda = product.seller_is_pda;
+    escrow.rounding_policy = marketplace.rounding_policy;
+    escrow.dispute_window_seconds = marketplace.dispute_window_seconds;
+    escrow.evidence_window_slots = marketplace.evidence_window_slots;
+    escrow.fee_exempt = product.fee_exempt;
+    escrow.fees_basis_points = marketplace
+        .fees_basis_points_for(UserReputation::total_sales_of(seller_reputation)?);
+    escrow.fee_destination = marketplace.fee_destination_for(&escrow.currency);
+    escrow.tracking = TrackingId::default();
+    escrow.shipped_at = 0;
+    escrow.evidence_cutoff_slot = 0;
+    escrow.evidence_count = 0;
+    escrow.dispute_description = None;
+    escrow.disputed_by = None;
+    escrow.created_at = Clock::get()?.unix_timestamp;
+    escrow.updated_at = escrow.created_at;
+    
+    Ok(())
+}
+
+/// Tell the marketplace that an escrow against `product` has settled,
+/// releasing the `reserved_quantity` it held and selling `quantity_sold` of
+/// it, with `completed_volume` being the amount sold and `fee` the
+/// marketplace fee taken from it
+#[allow(clippy::too_many_arguments)]
+fn record_escrow_closed<'info>(
+    marketplace_program: &Program<'info, Marketplace>,
+    escrow_authority: &AccountInfo<'info>,
+    marketplace: &Account<'info, marketplace::MarketplaceState>,
+    product: &Account<'info, Product>,
+    buyer_escrow_counter: &mut Account<'info, BuyerEscrowCounter>,
+    escrow_authority_bump: u8,
+    reserved_quantity: u64,
+    quantity_sold: u64,
+    completed_volume: u64,
+    fee: u64,
+) -> Result<()> {
+    // The buyer may open another escrow in its place
+    buyer_escrow_counter.open_escrows = buyer_escrow_counter.open_escrows.saturating_sub(1);
+    
+    marketplace::cpi::record_escrow_closed(
+        CpiContext::new_with_signer(
+            marketplace_program.to_account_info(),
+            marketplace::cpi::accounts::TrackEscrow {
+                escrow_authority: escrow_authority.clone(),
+                marketplace: marketplace.to_account_info(),
+                product: product.to_account_info(),
+            },
+            &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]],
+        ),
+        reserved_quantity,
+        quantity_sold,
+        completed_volume,
+        fee,
+    )
+}
+
+/// Settle an escrow once every unit has been confirmed or refunded, so it
+/// cannot linger open with nothing left to pay out
+fn close_if_fully_accounted<'info>(
+    escrow: &mut Account<'info, Escrow>,
+    marketplace_program: &Program<'info, Marketplace>,
+    escrow_authority: &AccountInfo<'info>,
+    marketplace: &Account<'info, marketplace::MarketplaceState>,
+    product: &Account<'info, Product>,
+    buyer_escrow_counter: &mut Account<'info, BuyerEscrowCounter>,
+    escrow_authority_bump: u8,
+) -> Result<()> {
+    if escrow.confirmed_quantity + escrow.refunded_quantity < escrow.quantity {
+        return Ok(());
+    }
+    
+    escrow.status = if escrow.refunded_quantity == 0 {
+        EscrowStatus::Completed
+    } else if escrow.confirmed_quantity == 0 {
+        EscrowStatus::Refunded
+    } else {
+        EscrowStatus::PartiallyCompleted
+    };
+    if escrow.status != EscrowStatus::Refunded {
+        escrow.completed_at = escrow.updated_at;
+    }
+    
+    record_escrow_closed(
+        marketplace_program,
+        escrow_authority,
+        marketplace,
+        product,
+        buyer_escrow_counter,
+        escrow_authority_bump,
+        escrow.quantity,
+        escrow.confirmed_quantity,
+        escrow.released_amount,
+        escrow.fees_collected,
+    )
+}
+
+/// Check `resolver` may resolve a dispute over `dispute_reason`. Disputes go
+/// to the arbitrators assigned to their reason, or to the marketplace
+/// authority or its arbiter when no pool is assigned.
+fn require_dispute_resolver(
+    marketplace: &marketplace::MarketplaceState,
+    arbitrator_routing: &AccountInfo,
+    dispute_reason: &DisputeReason,
+    resolver: &Pubkey,
+) -> Result<()> {
+    let pool = ArbitratorRouting::pool_for(arbitrator_routing, dispute_reason)?;
+    if pool.is_empty() {
+        require!(
+            marketplace.can_resolve_disputes(resolver),
+            EscrowError::UnauthorizedAuthority
+        );
+    } else {
+        require!(pool.contains(resolver), EscrowError::ArbitratorNotInPool);
+    }
+    
+    Ok(())
+}
+
+/// Pay out a dispute decision: release the escrow to the winner, slash the
+/// seller's bond if asked, and record the outcome
+fn settle_dispute<'info>(
+    accounts: &mut ResolveDispute<'info>,
+    escrow_authority_bump: u8,
+    favor_seller: bool,
+    slash_bond_bps: u16,
+) -> Result<()> {
+    require!(
+        !accounts.marketplace.releases_paused,
+        EscrowError::ReleasesPaused
+    );
+    
+    let escrow = &mut accounts.escrow;
+    
+    // Transfer funds based on resolution; the marketplace only takes its
+    // fee from a sale, not from a refund
+    let (recipient, recipient_token_account, fee) = if favor_seller {
+        (
+            &accounts.seller,
+            accounts.seller_token_account.as_ref(),
+            escrow.marketplace_fee(),
+        )
+    } else {
+        (&accounts.buyer, accounts.buyer_token_account.as_ref(), 0)
+    };
+    release_from_escrow(
+        escrow,
+        &accounts.escrow_vault,
+        recipient,
+        accounts.escrow_token_account.as_ref(),
+        recipient_token_account,
+        &accounts.token_program,
+        &accounts.system_program,
+        escrow.unreleased_amount() - fee,
+    )?;
+    collect_fee(
+        escrow,
+        &accounts.escrow_vault,
+        &accounts.fee_destination,
+        accounts.escrow_token_account.as_ref(),
+        &accounts.token_program,
+        &accounts.system_program,
+        fee,
+    )?;
+    
+    // Compensate the buyer from the seller's bond on confirmed fraud
+    if slash_bond_bps > 0 {
+        let seller_bond = accounts
+            .seller_bond
+            .as_mut()
+            .ok_or(EscrowError::SellerBondRequired)?;
+        // The seller keeps what is not slashed
+        let (_, slashed) = escrow
+            .rounding_policy
+            .split(seller_bond.amount, 10000 - slash_bond_bps as u64, 10000)
+            .ok_or(EscrowError::CalculationError)?;
+        
+        // The bond is owned by this program, so lamports move directly
+        seller_bond.amount = seller_bond
+            .amount
+            .checked_sub(slashed)
+            .ok_or(EscrowError::InsufficientBond)?;
+        **seller_bond.to_account_info().try_borrow_mut_lamports()? -= slashed;
+        **accounts.buyer.try_borrow_mut_lamports()? += slashed;
+        
+        emit!(SellerBondSlashed {
+            escrow: escrow.key(),
+            seller: escrow.seller,
+            amount: slashed,
+        });
+    }
+    
+    // Report the settlement for accounting integrations
+    emit!(SettlementBreakdown::new(escrow.key(), escrow, favor_seller, fee));
+    escrow.fees_collected = escrow
+        .fees_collected
+        .checked_add(fee)
+        .ok_or(EscrowError::CalculationError)?;
+    
+    // Update escrow status
+    escrow.status = if favor_seller {
+        EscrowStatus::Completed
+    } else {
+        EscrowStatus::Refunded
+    };
+    escrow.updated_at = Clock::get()?.unix_timestamp;
+    if favor_seller {
+        escrow.completed_at = escrow.updated_at;
+    }
+    
+    // Record the outcome so the reputation program can act on it
+    escrow.dispute_resolved_at = escrow.updated_at;
+    escrow.favored_seller = favor_seller;
+    escrow.dispute_description = None;
+    escrow.disputed_by = None;
+    let loser = if favor_seller { escrow.buyer } else { escrow.seller };
+    
+    // The escrow no longer counts as open; the seller keeps what it was paid
+    let completed_volume = if favor_seller { escrow.amount } else { escrow.released_amount };
+    record_escrow_closed(
+        &accounts.marketplace_program,
+        &accounts.escrow_authority,
+        &accounts.marketplace,
+        &accounts.product,
+        &mut accounts.buyer_escrow_counter,
+        escrow_authority_bump,
+        escrow.quantity,
+        escrow.units_sold(),
+        completed_volume,
+        escrow.fees_collected,
+    )?;
+    
+    // A seller-favored resolution counts as a sale and purchase
+    if favor_seller {
+        record_completed_transaction(
+            &accounts.reputation_program,
+            &accounts.escrow_authority,
+            &accounts.marketplace,
+            &accounts.authority.to_account_info(),
+            &accounts.seller,
+            &accounts.buyer,
+            accounts.seller_reputation.as_ref(),
+            accounts.buyer_reputation.as_ref(),
+            &accounts.system_program,
+            escrow_authority_bump,
+        )?;
+    }
+    
+    // Count the loss against the losing party's reputation
+    if let Some(loser_reputation) = &accounts.loser_reputation {
+        require!(
+            loser_reputation.user == loser,
+            EscrowError::InvalidReputationAccount
+        );
+        reputation::cpi::record_dispute_loss(CpiContext::new_with_signer(
+            accounts.reputation_program.to_account_info(),
+            reputation::cpi::accounts::RecordDisputeLoss {
+                escrow_authority: accounts.escrow_authority.to_account_info(),
+                user_reputation: loser_reputation.to_account_info(),
+            },
+            &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]],
+        ))?;
+    }
+    
+    Ok(())
+}
+
+/// Pay out one cart line item: to its seller less the item's fee, or back to
+/// the buyer in full. The cart owns its lamports, so they move directly.
+fn settle_cart_item<'info>(
+    accounts: &mut SettleCartItem<'info>,
+    product_info: &AccountInfo<'info>,
+    recipient: &AccountInfo<'info>,
+    escrow_authority_bump: u8,
+    index: u8,
+    to_seller: bool,
+) -> Result<()> {
+    let item = accounts.cart.items[index as usize].clone();
+    require_keys_eq!(product_info.key(), item.product, EscrowError::InvalidCartItems);
+    
+    let cart_info = accounts.cart.to_account_info();
+    if to_seller {
+        require_keys_eq!(recipient.key(), item.seller, EscrowError::InvalidCartItems);
+        require_keys_eq!(
+            accounts.fee_destination.key(),
+            accounts.cart.fee_destination,
+            EscrowError::InvalidFeeDestination
+        );
+        
+        **cart_info.try_borrow_mut_lamports()? -= item.amount;
+        **recipient.try_borrow_mut_lamports()? += item.amount - item.fee;
+        **accounts.fee_destination.try_borrow_mut_lamports()? += item.fee;
+    } else {
+        require_keys_eq!(recipient.key(), accounts.cart.buyer, EscrowError::InvalidCartItems);
+        
+        **cart_info.try_borrow_mut_lamports()? -= item.amount;
+        **recipient.try_borrow_mut_lamports()? += item.amount;
+    }
+    
+    let cart = &mut accounts.cart;
+    cart.items[index as usize].status = if to_seller {
+        CartItemStatus::Completed
+    } else {
+        CartItemStatus::Refunded
+    };
+    cart.updated_at = Clock::get()?.unix_timestamp;
+    
+    // The cart stays open against the buyer's limit until its last item settles
+    let cart_settled = cart.items.iter().all(|item| {
+        matches!(item.status, CartItemStatus::Completed | CartItemStatus::Refunded)
+    });
+    if cart_settled {
+        let buyer_escrow_counter = &mut accounts.buyer_escrow_counter;
+        buyer_escrow_counter.open_escrows = buyer_escrow_counter.open_escrows.saturating_sub(1);
+    }
+    
+    // The line item no longer counts as open; delivered units leave inventory
+    let (quantity_sold, completed_volume, fee) = if to_seller {
+        (item.quantity, item.amount, item.fee)
+    } else {
+        (0, 0, 0)
+    };
+    marketplace::cpi::record_escrow_closed(
+        CpiContext::new_with_signer(
+            accounts.marketplace_program.to_account_info(),
+            marketplace::cpi::accounts::TrackEscrow {
+                escrow_authority: accounts.escrow_authority.clone(),
+                marketplace: accounts.marketplace.to_account_info(),
+                product: product_info.clone(),
+            },
+            &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]],
+        ),
+        item.quantity,
+        quantity_sold,
+        completed_volume,
+        fee,
+    )
+}
+
+/// Pay a SOL release out in the seller's preferred currency through the
+/// marketplace's swap program. `remaining_accounts` holds the swap program,
+/// the SOL and payout currency price feeds and the seller's token account,
+/// followed by the accounts the swap instruction needs after its payer and
+/// destination. The conversion must be worth what the price feeds say, less
+/// the seller's slippage allowance. The feeds are set by the marketplace
+/// authority, so sellers opting into swapped payouts trust it to price them.
+fn swap_payout<'info>(
+    escrow: &Account<'info, Escrow>,
+    marketplace: &Account<'info, marketplace::MarketplaceState>,
+    escrow_vault: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    amount: u64,
+    payout_currency: CurrencyType,
+    max_slippage_bps: u16,
+) -> Result<()> {
+    require!(remaining_accounts.len() >= 4, EscrowError::InvalidSwapAccounts);
+    let swap_program = &remaining_accounts[0];
+    let seller_token_info = &remaining_accounts[3];
+    require!(
+        marketplace.swap_program != Pubkey::default()
+            && swap_program.key() == marketplace.swap_program,
+        EscrowError::InvalidSwapProgram
+    );
+    
+    let seller_token_account: TokenAccount = read_account(seller_token_info, &token::ID)?;
+    require_keys_eq!(seller_token_account.owner, escrow.seller, EscrowError::InvalidSwapAccounts);
+    let balance_before = seller_token_account.amount;
+    
+    // Value the payout in USD with the SOL feed, then in the payout currency
+    let sol_feed: marketplace::PriceFeed = read_account(&remaining_accounts[1], &marketplace::ID)?;
+    let payout_feed: marketplace::PriceFeed = read_account(&remaining_accounts[2], &marketplace::ID)?;
+    sol_feed.validate(marketplace.key(), &CurrencyType::SOL)?;
+    payout_feed.validate(marketplace.key(), &payout_currency)?;
+    let usd_value = sol_feed.reference_price(marketplace.key(), amount, &CurrencyType::SOL)?;
+    let expected_out = (usd_value as u128)
+        .checked_mul(10u128.pow(payout_currency.decimals()))
+        .ok_or(EscrowError::CalculationError)?
+        / payout_feed.usd_price as u128;
+    let min_amount_out = u64::try_from(expected_out * (10000 - max_slippage_bps as u128) / 10000)
+        .map_err(|_| error!(EscrowError::CalculationError))?;
+    
+    // Swap programs take the payer and destination first, then their own accounts
+    let mut accounts = vec![
+        AccountMeta::new(escrow_vault.key(), true),
+        AccountMeta::new(seller_token_info.key(), false),
+    ];
+    accounts.extend(remaining_accounts[4..].iter().map(|info| AccountMeta {
+        pubkey: info.key(),
+        is_signer: info.is_signer,
+        is_writable: info.is_writable,
+    }));
+    let mut data = hash(b"global:swap_sol_for_token").to_bytes()[..8].to_vec();
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&min_amount_out.to_le_bytes());
+    
+    let mut account_infos = vec![escrow_vault.clone(), seller_token_info.clone()];
+    account_infos.extend_from_slice(&remaining_accounts[4..]);
+    account_infos.push(swap_program.clone());
+    
+    let escrow_key = escrow.key();
+    let (_, vault_bump) = Pubkey::find_program_address(&[b"escrow_vault", escrow_key.as_ref()], &crate::ID);
+    invoke_signed(
+        &Instruction {
+            program_id: swap_program.key(),
+            accounts,
+            data,
+        },
+        &account_infos,
+        &[&[b"escrow_vault", escrow_key.as_ref(), &[vault_bump]]],
+    )?;
+    
+    // Never trust the swap program's own slippage check alone
+    let amount_out = read_account::<TokenAccount>(seller_token_info, &token::ID)?
+        .amount
+        .saturating_sub(balance_before);
+    require!(amount_out >= min_amount_out, EscrowError::PayoutSlippageExceeded);
+    
+    emit!(PayoutSwapped {
+        escrow: escrow_key,
+        seller: escrow.seller,
+        amount_in: amount,
+        amount_out,
+        currency: payout_currency,
+    });
+    
+    Ok(())
+}
+
+/// Deserialize an account passed without a typed slot, checking its owner
+/// the way `Account` would
+fn read_account<T: AccountDeserialize>(info: &AccountInfo, owner: &Pubkey) -> Result<T> {
+    if info.owner != owner {
+        return Err(Error::from(anchor_lang::error::ErrorCode::AccountOwnedByWrongProgram)
+            .with_pubkeys((*info.owner, *owner)));
+    }
+    T::try_deserialize(&mut &info.try_borrow_data()?[..])
+}
+
+/// Require token accounts exactly when the escrow is paid in tokens. The
+/// currency decides which accounts are used, so a mismatched set would
+/// otherwise be ignored rather than rejected.
+fn require_accounts_match_currency(currency: &CurrencyType, token_accounts: &[bool]) -> Result<()> {
+    let is_token = *currency != CurrencyType::SOL;
+    require!(
+        token_accounts.iter().all(|&present| present == is_token),
+        EscrowError::AccountsCurrencyMismatch
+    );
+    
+    Ok(())
+}
+
+/// Check an escrow's token vault, which must already be initialized, owned
+/// by the escrow PDA and hold the escrow's mint. Checked up front so a
+/// missing vault fails clearly instead of inside the token program.
+fn escrow_token_vault<'a, 'info>(
+    escrow: Pubkey,
+    mint: Pubkey,
+    escrow_token_account: Option<&'a AccountInfo<'info>>,
+) -> Result<&'a AccountInfo<'info>> {
+    let info = escrow_token_account.ok_or(EscrowError::VaultNotInitialized)?;
+    require!(
+        info.owner == &token::ID && !info.data_is_empty(),
+        EscrowError::VaultNotInitialized
+    );
+    
+    let vault: TokenAccount = read_account(info, &token::ID)?;
+    require!(vault.owner == escrow, EscrowError::VaultNotInitialized);
+    require_keys_eq!(vault.mint, mint, EscrowError::InvalidMint);
+    
+    Ok(info)
+}
+
+/// Move `amount` from the buyer into the escrow for the given currency. A
+/// token vault must have been checked with `escrow_token_vault`.
+#[allow(clippy::too_many_arguments)]
+fn deposit_to_escrow<'info>(
+    currency: &CurrencyType,
+    mint: Pubkey,
+    buyer: &Signer<'info>,
+    escrow_vault: &AccountInfo<'info>,
+    buyer_token_account: Option<&Account<'info, TokenAccount>>,
+    escrow_token_account: Option<&AccountInfo<'info>>,
+    token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+    amount: u64,
+) -> Result<()> {
+    require_accounts_match_currency(
+        currency,
+        &[buyer_token_account.is_some(), escrow_token_account.is_some()],
+    )?;
+    
+    match currency {
+        CurrencyType::SOL => {
+            let transfer_instruction = anchor_lang::system_program::Transfer {
+                from: buyer.to_account_info(),
+                to: escrow_vault.clone(),
+            };
+            
+            anchor_lang::system_program::transfer(
+                CpiContext::new(system_program.to_account_info(), transfer_instruction),
+                amount,
+            )
+        },
+        CurrencyType::USDC | CurrencyType::USDT => {
+            let from = buyer_token_account.ok_or(EscrowError::MissingTokenAccount)?;
+            let to = escrow_token_account.ok_or(EscrowError::MissingTokenAccount)?;
+            require_keys_eq!(from.mint, mint, EscrowError::InvalidMint);
+            let transfer_instruction = Transfer {
+                from: from.to_account_info(),
+                to: to.clone(),
+                authority: buyer.to_account_info(),
+            };
+            
+            token::transfer(
+                CpiContext::new(token_program.to_account_info(), transfer_instruction),
+                amount,
+            )
+        }
+    }
+}
+
+/// Count a funded cancellation against the buyer and return the share of
+/// `amount` it forfeits to the seller: the marketplace's cancellation fee for
+/// each cancellation in its window past the threshold, up to all of it. The
+/// buyer's reputation is required while a penalty is configured.
+fn cancellation_penalty<'info>(
+    marketplace: &marketplace::MarketplaceState,
+    buyer_reputation: Option<&Account<'info, UserReputation>>,
+    reputation_program: &Program<'info, Reputation>,
+    escrow_authority: &AccountInfo<'info>,
+    escrow_authority_bump: u8,
+    amount: u64,
+) -> Result<u64> {
+    let threshold = marketplace.cancellation_threshold;
+    let buyer_reputation = match buyer_reputation {
+        Some(buyer_reputation) => buyer_reputation,
+        None => {
+            require!(threshold == 0, EscrowError::BuyerReputationRequired);
+            return Ok(0);
+        }
+    };
+    
+    let recent_cancellations = reputation::cpi::record_funded_cancellation(
+        CpiContext::new_with_signer(
+            reputation_program.to_account_info(),
+            reputation::cpi::accounts::RecordFundedCancellation {
+                escrow_authority: escrow_authority.clone(),
+                user_reputation: buyer_reputation.to_account_info(),
+            },
+            &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]],
+        ),
+        marketplace.cancellation_window_seconds,
+    )?
+    .get();
+    if threshold == 0 || recent_cancellations <= threshold {
+        return Ok(0);
+    }
+    
+    let penalty_bps = (recent_cancellations - threshold)
+        .saturating_mul(marketplace.cancellation_fee_bps as u64)
+        .min(10000);
+    Ok(((amount as u128) * (penalty_bps as u128) / 10000) as u64)
+}
+
+/// Credit a completed escrow to the seller's sales and the buyer's purchases.
+/// Parties without a reputation account are skipped, so their escrows still
+/// complete. The escrow authority signs the reputation program's calls and
+/// `payer`, whoever settles the escrow, pays for them.
+#[allow(clippy::too_many_arguments)]
+fn record_completed_transaction<'info>(
+    reputation_program: &Program<'info, Reputation>,
+    escrow_authority: &AccountInfo<'info>,
+    marketplace: &Account<'info, marketplace::MarketplaceState>,
+    payer: &AccountInfo<'info>,
+    seller: &AccountInfo<'info>,
+    buyer: &AccountInfo<'info>,
+    seller_reputation: Option<&Account<'info, UserReputation>>,
+    buyer_reputation: Option<&Account<'info, UserReputation>>,
+    system_program: &Program<'info, System>,
+    escrow_authority_bump: u8,
+) -> Result<()> {
+    let signer_seeds: &[&[&[u8]]] = &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]];
+    let transaction_count = |user: &AccountInfo<'info>, user_reputation: &Account<'info, UserReputation>| {
+        CpiContext::new_with_signer(
+            reputation_program.to_account_info(),
+            reputation::cpi::accounts::RecordTransactionCount {
+                payer: payer.clone(),
+                authority: escrow_authority.clone(),
+                reputation_config: None,
+                marketplace: marketplace.to_account_info(),
+                user: user.clone(),
+                user_reputation: user_reputation.to_account_info(),
+                system_program: system_program.to_account_info(),
+            },
+            signer_seeds,
+        )
+    };
+    
+    if let Some(seller_reputation) = seller_reputation {
+        reputation::cpi::record_sale(transaction_count(seller, seller_reputation))?;
+    }
+    if let Some(buyer_reputation) = buyer_reputation {
+        reputation::cpi::record_purchase(transaction_count(buyer, buyer_reputation))?;
+    }
+    
+    Ok(())
+}
+
+/// Take an approved return's sale and purchase back off the parties'
+/// reputations. Parties without a reputation account are skipped; the
+/// accounts are passed by address, so neither party can opt out.
+fn reverse_completed_transaction<'info>(
+    reputation_program: &Program<'info, Reputation>,
+    escrow_authority: &AccountInfo<'info>,
+    seller_reputation: &AccountInfo<'info>,
+    buyer_reputation: &AccountInfo<'info>,
+    escrow_authority_bump: u8,
+) -> Result<()> {
+    let signer_seeds: &[&[&[u8]]] = &[&[marketplace::ESCROW_AUTHORITY_SEED, &[escrow_authority_bump]]];
+    let transaction_count = |user_reputation: &AccountInfo<'info>| {
+        CpiContext::new_with_signer(
+            reputation_program.to_account_info(),
+            reputation::cpi::accounts::ReverseTransactionCount {
+                escrow_authority: escrow_authority.clone(),
+                user_reputation: user_reputation.clone(),
+            },
+            signer_seeds,
+        )
+    };
+    let exists = |info: &AccountInfo| info.owner == &reputation::ID && !info.data_is_empty();
+    
+    if exists(seller_reputation) {
+        reputation::cpi::reverse_sale(transaction_count(seller_reputation))?;
+    }
+    if exists(buyer_reputation) {
+        reputation::cpi::reverse_purchase(transaction_count(buyer_reputation))?;
+    }
+    
+    Ok(())
+}
+
+/// Release what the escrow holds to the seller, less the marketplace fee,
+/// swapping it into the seller's preferred token for SOL escrows when they
+/// have set one. Returns the fee collected.
+#[allow(clippy::too_many_arguments)]
+fn pay_out_to_seller<'info>(
+    escrow: &Account<'info, Escrow>,
+    marketplace: &Account<'info, marketplace::MarketplaceState>,
+    escrow_vault: &AccountInfo<'info>,
+    seller: &AccountInfo<'info>,
+    seller_status: &AccountInfo<'info>,
+    escrow_token_account: Option<&Account<'info, TokenAccount>>,
+    seller_token_account: Option<&Account<'info, TokenAccount>>,
+    fee_destination: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<u64> {
+    require_accounts_match_currency(
+        &escrow.currency,
+        &[escrow_token_account.is_some(), seller_token_account.is_some()],
+    )?;
+    
+    // The marketplace keeps its fee out of the release
+    let fee = escrow.marketplace_fee();
+    let seller_net = escrow.unreleased_amount() - fee;
+    
+    // Sellers preferring a token are paid through the swap program
+    let payout_preference = match escrow.currency {
+        CurrencyType::SOL => marketplace::SellerStatus::payout_preference(seller_status)?,
+        CurrencyType::USDC | CurrencyType::USDT => None,
+    };
+    if let Some((payout_currency, max_slippage_bps)) = payout_preference {
+        swap_payout(
+            escrow,
+            marketplace,
+            escrow_vault,
+            remaining_accounts,
+            seller_net,
+            payout_currency,
+            max_slippage_bps,
+        )?;
+    } else {
+        release_from_escrow(
+            escrow,
+            escrow_vault,
+            seller,
+            escrow_token_account,
+            seller_token_account,
+            token_program,
+            system_program,
+            seller_net,
+        )?;
+    }
+    
+    collect_fee(
+        escrow,
+        escrow_vault,
+        fee_destination,
+        escrow_token_account,
+        token_program,
+        system_program,
+        fee,
+    )?;
+    
+    // Report the settlement for accounting integrations
+    emit!(SettlementBreakdown::new(escrow.key(), escrow, true, fee));
+    
+    Ok(fee)
+}
+
+/// Release `amount` of the escrow to the seller ahead of settlement, less the
+/// marketplace fee on it, and record both against the escrow. Returns the
+/// fee collected.
+#[allow(clippy::too_many_arguments)]
+fn release_portion_to_seller<'info>(
+    escrow: &mut Account<'info, Escrow>,
+    escrow_vault: &AccountInfo<'info>,
+    seller: &AccountInfo<'info>,
+    escrow_token_account: Option<&Account<'info, TokenAccount>>,
+    seller_token_account: Option<&Account<'info, TokenAccount>>,
+    fee_destination: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+    amount: u64,
+) -> Result<u64> {
+    let fee = escrow.fee_on(amount);
+    release_from_escrow(
+        escrow,
+        escrow_vault,
+        seller,
+        escrow_token_account,
+        seller_token_account,
+        token_program,
+        system_program,
+        amount - fee,
+    )?;
+    collect_fee(
+        escrow,
+        escrow_vault,
+        fee_destination,
+        escrow_token_account,
+        token_program,
+        system_program,
+        fee,
+    )?;
+    
+    escrow.released_amount = escrow
+        .released_amount
+        .checked_add(amount)
+        .ok_or(EscrowError::CalculationError)?;
+    escrow.fees_collected = escrow
+        .fees_collected
+        .checked_add(fee)
+        .ok_or(EscrowError::CalculationError)?;
+    
+    Ok(fee)
+}
+
+/// Send the marketplace fee on a release to the fee destination fixed when
+/// the escrow was created: the wallet for SOL, or a token account for
+/// tokens. Later changes to the marketplace's destinations only apply to
+/// newer escrows.
+fn collect_fee<'info>(
+    escrow: &Escrow,
+    escrow_vault: &AccountInfo<'info>,
+    fee_destination: &AccountInfo<'info>,
+    escrow_token_account: Option<&Account<'info, TokenAccount>>,
+    token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+    fee: u64,
+) -> Result<()> {
+    require_keys_eq!(
+        fee_destination.key(),
+        escrow.fee_destination,
+        EscrowError::InvalidFeeDestination
+    );
+    if fee == 0 {
+        return Ok(());
+    }
+    
+    match escrow.currency {
+        CurrencyType::SOL => release_from_escrow(
+            escrow,
+            escrow_vault,
+            fee_destination,
+            None,
+            None,
+            token_program,
+            system_program,
+            fee,
+        ),
+        CurrencyType::USDC | CurrencyType::USDT => {
+            // The fee destination arrives untyped, so its token account is
+            // checked here and the transfer made directly
+            let fee_token_account: TokenAccount = read_account(fee_destination, &token::ID)?;
+            require_keys_eq!(fee_token_account.mint, escrow.mint, EscrowError::InvalidMint);
+            let from = escrow_token_account.ok_or(EscrowError::MissingTokenAccount)?;
+            require_keys_eq!(from.mint, escrow.mint, EscrowError::InvalidMint);
+            
+            let bump = [escrow.bump];
+            let escrow_seeds = &[
+                b"escrow".as_ref(),
+                escrow.marketplace.as_ref(),
+                escrow.buyer.as_ref(),
+                escrow.product.as_ref(),
+                &escrow.nonce.to_le_bytes(),
+                &bump,
+            ];
+            let transfer_instruction = Transfer {
+                from: from.to_account_info(),
+                to: fee_destination.clone(),
+                authority: escrow_vault.clone(),
+            };
+            
+            token::transfer(
+                CpiContext::new_with_signer(
+                    token_program.to_account_info(),
+                    transfer_instruction,
+                    &[&escrow_seeds[..]],
+                ),
+                fee,
+            )
+        }
+    }
+}
+
+/// Move `amount` held by the escrow to `recipient`, signing with the escrow PDA
+#[allow(clippy::too_many_arguments)]
+fn release_from_escrow<'info>(
+    escrow: &Escrow,
+    escrow_vault: &AccountInfo<'info>,
+    recipient: &AccountInfo<'info>,
+    escrow_token_account: Option<&Account<'info, TokenAccount>>,
+    recipient_token_account: Option<&Account<'info, TokenAccount>>,
+    token_program: &Program<'info, Token>,
+    system_program: &Program<'info, System>,
+    amount: u64,
+) -> Result<()> {
+    require_accounts_match_currency(
+        &escrow.currency,
+        &[escrow_token_account.is_some(), recipient_token_account.is_some()],
+    )?;
+    
+    let bump = [escrow.bump];
+    let escrow_seeds = &[
+        b"escrow".as_ref(),
+        escrow.marketplace.as_ref(),
+        escrow.buyer.as_ref(),
+        escrow.product.as_ref(),
+        &escrow.nonce.to_le_bytes(),
+        &bump,
+    ];
+    let signer = &[&escrow_seeds[..]];
+    
+    match escrow.currency {
+        CurrencyType::SOL => {
+            let transfer_instruction = anchor_lang::system_program::Transfer {
+                from: escrow_vault.clone(),
+                to: recipient.clone(),
+            };
+            
+            anchor_lang::system_program::transfer(
+                CpiContext::new_with_signer(
+                    system_program.to_account_info(),
+                    transfer_instruction,
+                    signer,
+                ),
+                amount,
+            )
+        },
+        CurrencyType::USDC | CurrencyType::USDT => {
+            let from = escrow_token_account.ok_or(EscrowError::MissingTokenAccount)?;
+            let to = recipient_token_account.ok_or(EscrowError::MissingTokenAccount)?;
+            require_keys_eq!(from.mint, escrow.mint, EscrowError::InvalidMint);
+            require_keys_eq!(to.mint, escrow.mint, EscrowError::InvalidMint);
+            let transfer_instruction = Transfer {
+                from: from.to_account_info(),
+                to: to.to_account_info(),
+                authority: escrow_vault.clone(),
+            };
+            
+            token::transfer(
+                CpiContext::new_with_signer(
+                    token_program.to_account_info(),
+                    transfer_instruction,
+                    signer,
+                ),
+                amount,
+            )
+        }
+    }
+}
+
+/// Account structure for escrow state
+#[account]
 pub struct Escrow {
     /// Reference to marketplace
     pub marketplace: Pubkey,
@@ -448,63 +3484,2012 @@ pub struct Escrow {
     pub created_at: i64,
     /// Timestamp of last update
     pub updated_at: i64,
+    /// Return window copied from the product at creation
+    pub return_window_seconds: i64,
+    /// Timestamp when the escrow was completed (0 until then)
+    pub completed_at: i64,
+    /// Amount paid into the escrow so far
+    pub funded_amount: u64,
+    /// Whether the buyer pays in installments before shipment
+    pub is_layaway: bool,
+    /// Deadline for paying off a layaway (0 if not a layaway)
+    pub layaway_deadline: i64,
+    /// Share of installments forfeited to the seller on default, in basis points
+    pub layaway_forfeit_bps: u16,
+    /// Timestamp when a dispute on this escrow was resolved (0 if never)
+    pub dispute_resolved_at: i64,
+    /// Whether the dispute resolution favored the seller
+    pub favored_seller: bool,
+    /// Quantity shipped so far
+    pub shipped_quantity: u64,
+    /// Pre-agreed releases to the seller, of which the first
+    /// `scheduled_release_count` are in use
+    pub release_schedule: [ScheduledRelease; MAX_SCHEDULED_RELEASES],
+    /// Number of entries in the release schedule
+    pub scheduled_release_count: u8,
+    /// Number of scheduled releases already paid out
+    pub scheduled_releases_paid: u8,
+    /// Amount already released to the seller ahead of settlement
+    pub released_amount: u64,
+    /// Coupon discount applied to the amount, in basis points
+    pub discount_bps: u16,
+    /// Whether the pending dispute resolution favors the seller
+    pub pending_favor_seller: bool,
+    /// Bond share the pending resolution slashes, in basis points
+    pub pending_slash_bond_bps: u16,
+    /// Time after which an unappealed pending resolution can be executed
+    pub resolution_executable_at: i64,
+    /// Category of the open or last dispute, used to route it to arbitrators
+    pub dispute_reason: DisputeReason,
+    /// Hash of the buyer's off-chain source-of-funds documents (zero if none)
+    pub source_attestation_hash: [u8; 32],
+    /// Condition oracle that can release the escrow (default pubkey = none)
+    pub release_condition_oracle: Pubkey,
+    /// Quantity the buyer has confirmed as delivered
+    pub confirmed_quantity: u64,
+    /// Quantity refunded to the buyer before shipping
+    pub refunded_quantity: u64,
+    /// Amount refunded to the buyer for `refunded_quantity`
+    pub refunded_amount: u64,
+    /// Handling time copied from the product at creation
+    pub handling_time_seconds: i64,
+    /// Deadline to ship, set at funding from the handling time (0 = none)
+    pub ship_by: i64,
+    /// Whether the seller is a program-derived address. Seller instructions
+    /// take the seller as a `Signer`, which a PDA only satisfies when its
+    /// owning program signs for it through CPI.
+    pub seller_is_pda: bool,
+    /// Rounding policy of the marketplace when the escrow was created
+    pub rounding_policy: RoundingPolicy,
+    /// Dispute window copied from the marketplace at creation (0 = no limit)
+    pub dispute_window_seconds: i64,
+    /// Deadline to dispute, set at shipment from the dispute window (0 = none)
+    pub dispute_deadline: i64,
+    /// Whether the product was exempt from marketplace fees at creation
+    pub fee_exempt: bool,
+    /// Tracking ID of the latest shipment (empty until shipped with one)
+    pub tracking: TrackingId,
+    /// Evidence window copied from the marketplace at creation (0 = no cutoff)
+    pub evidence_window_slots: u64,
+    /// Last slot evidence is accepted for the open dispute (0 = no cutoff)
+    pub evidence_cutoff_slot: u64,
+    /// Evidence submitted for the open dispute, of which the first
+    /// `evidence_count` are in use
+    pub evidence: [EvidenceEntry; MAX_EVIDENCE_ENTRIES],
+    /// Number of evidence submissions recorded
+    pub evidence_count: u8,
+    /// Description of the open dispute (max 200 bytes), cleared once settled
+    pub dispute_description: Option<String>,
+    /// Party who raised the open dispute, cleared once settled
+    pub disputed_by: Option<Pubkey>,
+    /// Marketplace fee for the seller's volume tier at creation
+    pub fees_basis_points: u16,
+    /// When the whole order had shipped (0 until then)
+    pub shipped_at: i64,
+    /// Mint token escrows are paid in, fixed at creation (default for SOL)
+    pub mint: Pubkey,
+    /// Account the marketplace fee is paid to, fixed at creation
+    pub fee_destination: Pubkey,
+    /// Marketplace fees taken from releases to the seller so far
+    pub fees_collected: u64,
+    /// Whether the seller rejected a return of the order
+    pub return_rejected: bool,
+    /// Number of the escrow among its buyer's escrows, part of its address
+    /// so a closed escrow's address is never reused
+    pub nonce: u64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl Escrow {
+    /// Encode the escrow's essential fields independently of the account
+    /// layout, so the bytes stay the same across program upgrades. Version 1
+    /// is the version byte, then the escrow, marketplace, buyer, seller and
+    /// product keys, then quantity, amount, released and refunded amounts as
+    /// little-endian u64, the currency flag, the status code, and the
+    /// creation and last update times as little-endian i64.
+    pub fn export_state(&self, escrow: Pubkey) -> Vec<u8> {
+        let mut state = Vec::with_capacity(ESCROW_STATE_LEN);
+        state.push(ESCROW_STATE_VERSION);
+        for key in [escrow, self.marketplace, self.buyer, self.seller, self.product] {
+            state.extend_from_slice(key.as_ref());
+        }
+        for value in [self.quantity, self.amount, self.released_amount, self.refunded_amount] {
+            state.extend_from_slice(&value.to_le_bytes());
+        }
+        state.push(self.currency.flag());
+        state.push(self.status.code());
+        state.extend_from_slice(&self.created_at.to_le_bytes());
+        state.extend_from_slice(&self.updated_at.to_le_bytes());
+        
+        state
+    }
+
+    /// Amount the escrow still holds after any releases and partial refunds
+    pub fn unreleased_amount(&self) -> u64 {
+        self.amount
+            .saturating_sub(self.released_amount)
+            .saturating_sub(self.refunded_amount)
+    }
+
+    /// Marketplace fee on releasing what the escrow still holds, at the rate
+    /// set when it was created; none for products exempt at creation
+    pub fn marketplace_fee(&self) -> u64 {
+        self.fee_on(self.unreleased_amount())
+    }
+
+    /// Amount the seller was paid for the order, after partial refunds and
+    /// marketplace fees
+    pub fn seller_proceeds(&self) -> u64 {
+        self.amount
+            .saturating_sub(self.refunded_amount)
+            .saturating_sub(self.fees_collected)
+    }
+
+    /// Marketplace fee on releasing `amount` of the escrow to the seller
+    pub fn fee_on(&self, amount: u64) -> u64 {
+        if self.fee_exempt {
+            return 0;
+        }
+        
+        ((amount as u128) * (self.fees_basis_points as u128) / 10000) as u64
+    }
+
+    /// Ship-by deadline for an escrow funded at `funded_at`, 0 without a
+    /// handling time
+    pub fn ship_by_from(&self, funded_at: i64) -> Result<i64> {
+        if self.handling_time_seconds == 0 {
+            return Ok(0);
+        }
+        
+        Ok(funded_at
+            .checked_add(self.handling_time_seconds)
+            .ok_or(EscrowError::CalculationError)?)
+    }
+
+    /// Dispute deadline for an escrow shipped at `shipped_at`, 0 without a
+    /// dispute window
+    pub fn dispute_deadline_from(&self, shipped_at: i64) -> Result<i64> {
+        if self.dispute_window_seconds == 0 {
+            return Ok(0);
+        }
+        
+        Ok(shipped_at
+            .checked_add(self.dispute_window_seconds)
+            .ok_or(EscrowError::CalculationError)?)
+    }
+
+    /// Evidence cutoff for a dispute opened at `disputed_slot`, 0 without an
+    /// evidence window
+    pub fn evidence_cutoff_from(&self, disputed_slot: u64) -> Result<u64> {
+        if self.evidence_window_slots == 0 {
+            return Ok(0);
+        }
+        
+        Ok(disputed_slot
+            .checked_add(self.evidence_window_slots)
+            .ok_or(EscrowError::CalculationError)?)
+    }
+
+    /// Earliest time a settled escrow can be closed. The reputation program
+    /// reads the escrow to return completed orders, edit reviews after a
+    /// return and remove reviews after a seller-favored dispute, so the
+    /// account stays until each of those windows has passed.
+    pub fn closable_from(&self, marketplace: &marketplace::MarketplaceState) -> Result<i64> {
+        let mut closable_from = 0;
+        if self.completed_at > 0 {
+            closable_from = self
+                .completed_at
+                .checked_add(self.return_window_seconds)
+                .ok_or(EscrowError::CalculationError)?;
+        }
+        if self.status == EscrowStatus::Refunded && self.completed_at > 0 {
+            let return_edit_deadline = self
+                .updated_at
+                .checked_add(marketplace.return_review_grace_seconds)
+                .ok_or(EscrowError::CalculationError)?;
+            closable_from = closable_from.max(return_edit_deadline);
+        }
+        if self.dispute_resolved_at > 0 {
+            let removal_deadline = self
+                .dispute_resolved_at
+                .checked_add(marketplace.review_removal_grace_seconds)
+                .ok_or(EscrowError::CalculationError)?;
+            closable_from = closable_from.max(removal_deadline);
+        }
+        
+        Ok(closable_from)
+    }
+
+    /// Quantity still owed to the buyer after partial refunds
+    pub fn unrefunded_quantity(&self) -> u64 {
+        self.quantity.saturating_sub(self.refunded_quantity)
+    }
+
+    /// Units sold once the escrow has settled: all those not refunded when
+    /// it completed, otherwise only the units confirmed before it was refunded
+    pub fn units_sold(&self) -> u64 {
+        if self.status == EscrowStatus::Completed {
+            self.unrefunded_quantity()
+        } else {
+            self.confirmed_quantity
+        }
+    }
+
+    /// Share of the amount paying for `quantity` units, released to the
+    /// seller or refunded to the buyer and rounded by the escrow's rounding
+    /// policy. The final units accounted for take whatever remains, so
+    /// rounding never strands funds.
+    pub fn portion_amount(&self, quantity: u64, to_seller: bool) -> Result<u64> {
+        let accounted = self
+            .confirmed_quantity
+            .checked_add(self.refunded_quantity)
+            .and_then(|accounted| accounted.checked_add(quantity))
+            .ok_or(EscrowError::CalculationError)?;
+        if accounted == self.quantity {
+            return Ok(self.unreleased_amount());
+        }
+        
+        // Refunds are what the seller's share of the other units leaves
+        let amount = if to_seller {
+            self.rounding_policy.split(self.amount, quantity, self.quantity).map(|(seller, _)| seller)
+        } else {
+            self.rounding_policy
+                .split(self.amount, self.quantity - quantity, self.quantity)
+                .map(|(_, buyer)| buyer)
+        };
+        Ok(amount.ok_or(EscrowError::CalculationError)?)
+    }
+}
+
+/// Evidence submitted by a party to a dispute
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct EvidenceEntry {
+    /// Buyer or seller who submitted the evidence
+    pub submitter: Pubkey,
+    /// Hash of the off-chain evidence
+    pub evidence_hash: [u8; 32],
+    /// Slot the submission was recorded in
+    pub slot: u64,
+}
+
+/// Carrier and tracking number of a shipment, kept apart so frontends can
+/// build carrier tracking links
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct TrackingId {
+    /// Carrier handling the shipment
+    pub carrier: String,
+    /// Carrier's tracking number for the shipment
+    pub tracking_number: String,
+}
+
+impl TrackingId {
+    /// Check that both components are present and within their bounds
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            !self.carrier.is_empty() && !self.tracking_number.is_empty(),
+            EscrowError::InvalidTrackingId
+        );
+        require!(self.carrier.len() <= MAX_CARRIER_LEN, EscrowError::CarrierTooLong);
+        require!(
+            self.tracking_number.len() <= MAX_TRACKING_NUMBER_LEN,
+            EscrowError::TrackingIdTooLong
+        );
+        
+        Ok(())
+    }
+}
+
+/// A portion of the escrow released to the seller once `release_at` passes
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ScheduledRelease {
+    /// Earliest time the portion can be released
+    pub release_at: i64,
+    /// Amount released to the seller
+    pub amount: u64,
+}
+
+/// Account structure for an external condition, such as a delivery scan,
+/// reported by its authority
+#[account]
+pub struct ConditionOracle {
+    /// Only signer allowed to report the condition
+    pub authority: Pubkey,
+    /// Identifier of the condition, e.g. a hash of the shipment number
+    pub condition_id: [u8; 32],
+    /// Whether the condition currently holds
+    pub condition_met: bool,
+    /// Timestamp of the last report
+    pub updated_at: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Category of a dispute
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum DisputeReason {
+    /// The order never arrived
+    NotReceived,
+    /// The goods arrived damaged
+    Damaged,
+    /// The goods differ from the listing
+    NotAsDescribed,
+    /// Anything else
+    Other,
+}
+
+impl DisputeReason {
+    /// Position of the reason's pool in `ArbitratorRouting::pools`
+    pub fn index(&self) -> usize {
+        match self {
+            DisputeReason::NotReceived => 0,
+            DisputeReason::Damaged => 1,
+            DisputeReason::NotAsDescribed => 2,
+            DisputeReason::Other => 3,
+        }
+    }
+}
+
+/// Arbitrators assigned to one dispute reason; unused slots are the default pubkey
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ArbitratorPool {
+    pub arbitrators: [Pubkey; MAX_ARBITRATORS_PER_REASON],
+}
+
+/// Account structure routing a marketplace's disputes to arbitrator pools by reason
+#[account]
+pub struct ArbitratorRouting {
+    /// Marketplace whose disputes are routed
+    pub marketplace: Pubkey,
+    /// Arbitrator pool for each dispute reason, indexed by `DisputeReason::index`
+    pub pools: [ArbitratorPool; DISPUTE_REASON_COUNT],
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl ArbitratorRouting {
+    /// Arbitrators assigned to `reason`, read from a routing PDA that may not
+    /// be initialized yet. Marketplaces without routing have no pools.
+    pub fn pool_for(info: &AccountInfo, reason: &DisputeReason) -> Result<Vec<Pubkey>> {
+        if info.owner != &crate::ID || info.data_is_empty() {
+            return Ok(Vec::new());
+        }
+        let routing = ArbitratorRouting::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+        Ok(routing.pools[reason.index()]
+            .arbitrators
+            .iter()
+            .copied()
+            .filter(|arbitrator| *arbitrator != Pubkey::default())
+            .collect())
+    }
+}
+
+/// Account structure for a seller's bond. The bonded lamports are held in
+/// the account itself, on top of its rent-exempt minimum.
+#[account]
+pub struct SellerBond {
+    /// Marketplace the bond was posted on
+    pub marketplace: Pubkey,
+    /// Seller who posted the bond
+    pub seller: Pubkey,
+    /// Bonded lamports available for slashing
+    pub amount: u64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Account structure counting a buyer's open escrows on a marketplace
+#[account]
+pub struct BuyerEscrowCounter {
+    /// Marketplace the escrows are on
+    pub marketplace: Pubkey,
+    /// Buyer holding the escrows
+    pub buyer: Pubkey,
+    /// Escrows created by the buyer that have not yet settled
+    pub open_escrows: u64,
+    /// Escrows ever created by the buyer, numbering the next one
+    pub escrows_created: u64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Account structure for a discount coupon
+#[account]
+pub struct Coupon {
+    /// Marketplace the coupon is valid on
+    pub marketplace: Pubkey,
+    /// SHA-256 hash of the coupon code
+    pub code_hash: [u8; 32],
+    /// Discount in basis points
+    pub discount_bps: u16,
+    /// Number of redemptions allowed
+    pub max_uses: u32,
+    /// Redemptions left
+    pub remaining_uses: u32,
+    /// Timestamp after which the coupon can no longer be redeemed
+    pub expires_at: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// A line item of a cart escrow
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct CartItem {
+    /// Product bought
+    pub product: Pubkey,
+    /// Seller of the product
+    pub seller: Pubkey,
+    /// Quantity bought
+    pub quantity: u64,
+    /// Amount paid for the item, in lamports
+    pub amount: u64,
+    /// Marketplace fee taken from the amount when the seller is paid
+    pub fee: u64,
+    /// Settlement state of the item
+    pub status: CartItemStatus,
+    /// Reason the item was disputed, routing it to an arbitrator pool
+    pub dispute_reason: Option<DisputeReason>,
+}
+
+/// Settlement state of a cart line item
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum CartItemStatus {
+    /// Awaiting shipment
+    Pending,
+    /// Seller has shipped the item
+    Shipped,
+    /// Buyer or seller has disputed the item
+    Disputed,
+    /// Seller was paid for the item
+    Completed,
+    /// Buyer was refunded for the item
+    Refunded,
+}
+
+/// Account structure for an escrow over a multi-product cart. The escrowed
+/// lamports are held in the account itself, on top of its rent-exempt
+/// minimum.
+#[account]
+pub struct CartEscrow {
+    /// Marketplace the cart was bought on
+    pub marketplace: Pubkey,
+    /// Buyer of the cart
+    pub buyer: Pubkey,
+    /// Buyer-chosen identifier seeding the cart's address
+    pub cart_id: u64,
+    /// Total of the line items, in lamports
+    pub amount: u64,
+    /// Whether the buyer has paid the total
+    pub funded: bool,
+    /// Line items (at most `MAX_CART_ITEMS`)
+    pub items: Vec<CartItem>,
+    /// Account the marketplace fee is paid to, fixed at creation
+    pub fee_destination: Pubkey,
+    /// Timestamp when the cart was created
+    pub created_at: i64,
+    /// Timestamp of the last update
+    pub updated_at: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Account structure for a timed auction of a product's units. Bids are
+/// held in the account itself, on top of its rent-exempt minimum.
+#[account]
+pub struct Auction {
+    /// Marketplace the product is listed on
+    pub marketplace: Pubkey,
+    /// Product being auctioned
+    pub product: Pubkey,
+    /// Seller of the product
+    pub seller: Pubkey,
+    /// Quantity sold to the winner
+    pub quantity: u64,
+    /// Lowest opening bid, the product's price for the quantity
+    pub starting_price: u64,
+    /// Amount each bid must beat the highest by, in lamports
+    pub min_increment: u64,
+    /// Highest bid so far, in lamports (0 until the first bid)
+    pub highest_bid: u64,
+    /// Bidder holding the highest bid (default pubkey until the first bid)
+    pub highest_bidder: Pubkey,
+    /// Timestamp after which no bids are taken and the auction can settle
+    pub end_time: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl Auction {
+    /// Whether anyone has bid yet
+    pub fn has_bid(&self) -> bool {
+        self.highest_bid > 0
+    }
+
+    /// Lowest amount the next bid may be
+    pub fn minimum_bid(&self) -> Result<u64> {
+        if self.has_bid() {
+            Ok(self
+                .highest_bid
+                .checked_add(self.min_increment)
+                .ok_or(EscrowError::CalculationError)?)
+        } else {
+            Ok(self.starting_price)
+        }
+    }
+}
+
+/// Emitted when an auction takes a new highest bid
+#[event]
+pub struct BidPlaced {
+    pub auction: Pubkey,
+    pub bidder: Pubkey,
+    pub amount: u64,
+}
+
+/// Account structure for a buyer's offer below a product's list price
+#[account]
+pub struct Offer {
+    /// Marketplace the product is listed on
+    pub marketplace: Pubkey,
+    /// Product the offer is for
+    pub product: Pubkey,
+    /// Buyer making the offer
+    pub buyer: Pubkey,
+    /// Seller of the product, who may accept or reject the offer
+    pub seller: Pubkey,
+    /// Buyer-chosen identifier seeding the offer's address
+    pub offer_id: u64,
+    /// Price offered per unit
+    pub offered_price: u64,
+    /// Quantity the offer is for
+    pub quantity: u64,
+    /// Whether the offer is open, accepted or rejected
+    pub status: OfferStatus,
+    /// Timestamp when the offer was made
+    pub created_at: i64,
+    /// Timestamp after which the offer can no longer be accepted
+    pub expires_at: i64,
     /// PDA bump seed
     pub bump: u8,
 }
 
-/// Status of an escrow
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum EscrowStatus {
-    /// Escrow created but not funded
-    Created,
-    /// Buyer has funded the escrow
-    Funded,
-    /// Seller has marked the order as shipped
-    Shipped,
-    /// Buyer has confirmed delivery
-    Completed,
-    /// Either party has raised a dispute
-    Disputed,
-    /// Escrow was cancelled
-    Cancelled,
-    /// Funds were refunded to buyer
-    Refunded,
+/// Status of a buyer's offer
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OfferStatus {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// Emitted when a cart line item is disputed
+#[event]
+pub struct CartItemDisputed {
+    pub cart: Pubkey,
+    pub index: u8,
+    pub seller: Pubkey,
+    pub dispute_reason: DisputeReason,
+}
+
+/// Emitted when a seller's SOL payout is converted to their preferred currency
+#[event]
+pub struct PayoutSwapped {
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub currency: CurrencyType,
+}
+
+/// Emitted when a seller's bond is slashed during dispute resolution
+#[event]
+pub struct SellerBondSlashed {
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    pub amount: u64,
+}
+
+/// Itemized settlement of an escrow, emitted whenever its funds are released.
+/// The components always sum exactly to `gross`.
+#[event]
+pub struct SettlementBreakdown {
+    /// The settled escrow
+    pub escrow: Pubkey,
+    /// Currency the amounts are denominated in
+    pub currency: CurrencyType,
+    /// Amount still held by the escrow
+    pub gross: u64,
+    /// Fee retained by the marketplace
+    pub marketplace_fee: u64,
+    /// Tax withheld
+    pub tax: u64,
+    /// Fee paid to the dispute arbitrator
+    pub arbitrator_fee: u64,
+    /// Amount released to the seller
+    pub seller_net: u64,
+    /// Amount returned to the buyer
+    pub buyer_refund: u64,
+}
+
+impl SettlementBreakdown {
+    /// Break down a settlement paying the escrow to either the seller, less
+    /// `marketplace_fee`, or the buyer. No tax or arbitration charges are
+    /// deducted yet, so they are reported as zero.
+    pub fn new(escrow_key: Pubkey, escrow: &Escrow, to_seller: bool, marketplace_fee: u64) -> Self {
+        let gross = escrow.unreleased_amount();
+        let (seller_net, buyer_refund) = if to_seller {
+            (gross - marketplace_fee, 0)
+        } else {
+            (0, gross - marketplace_fee)
+        };
+        
+        Self {
+            escrow: escrow_key,
+            currency: escrow.currency.clone(),
+            gross,
+            marketplace_fee,
+            tax: 0,
+            arbitrator_fee: 0,
+            seller_net,
+            buyer_refund,
+        }
+    }
+
+    /// Break down a settlement splitting the escrow: `seller_share` to the
+    /// seller, less `marketplace_fee`, and the rest to the buyer
+    pub fn split(escrow_key: Pubkey, escrow: &Escrow, seller_share: u64, marketplace_fee: u64) -> Self {
+        let gross = escrow.unreleased_amount();
+        let seller_net = seller_share - marketplace_fee;
+        let buyer_refund = gross - seller_share;
+        
+        Self {
+            escrow: escrow_key,
+            currency: escrow.currency.clone(),
+            gross,
+            marketplace_fee,
+            tax: 0,
+            arbitrator_fee: 0,
+            seller_net,
+            buyer_refund,
+        }
+    }
+}
+
+/// Status of an escrow
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum EscrowStatus {
+    /// Escrow created but not funded
+    Created,
+    /// Buyer has funded the escrow
+    Funded,
+    /// Seller has marked the order as shipped
+    Shipped,
+    /// Buyer has confirmed delivery
+    Completed,
+    /// Either party has raised a dispute
+    Disputed,
+    /// Escrow was cancelled
+    Cancelled,
+    /// Funds were refunded to buyer
+    Refunded,
+    /// Buyer has requested a return after completion
+    ReturnRequested,
+    /// A dispute resolution is waiting out its appeal window
+    ResolutionPending,
+    /// The losing party has appealed the dispute resolution
+    Appealed,
+    /// Some units were delivered and paid for, the rest refunded
+    PartiallyCompleted,
+}
+
+impl EscrowStatus {
+    /// Stable code identifying the status in exported escrow state. Codes
+    /// are never reused, so new statuses must take new ones.
+    pub fn code(&self) -> u8 {
+        match self {
+            EscrowStatus::Created => 0,
+            EscrowStatus::Funded => 1,
+            EscrowStatus::Shipped => 2,
+            EscrowStatus::Completed => 3,
+            EscrowStatus::Disputed => 4,
+            EscrowStatus::Cancelled => 5,
+            EscrowStatus::Refunded => 6,
+            EscrowStatus::ReturnRequested => 7,
+            EscrowStatus::ResolutionPending => 8,
+            EscrowStatus::Appealed => 9,
+            EscrowStatus::PartiallyCompleted => 10,
+        }
+    }
+
+    /// Whether the escrow has paid out and will never move funds again
+    pub fn is_settled(&self) -> bool {
+        matches!(
+            self,
+            EscrowStatus::Completed
+                | EscrowStatus::PartiallyCompleted
+                | EscrowStatus::Cancelled
+                | EscrowStatus::Refunded
+        )
+    }
+}
+
+/// Accounts required for creating an escrow
+#[derive(Accounts)]
+pub struct CreateEscrow<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Marketplace account
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Product being purchased, which must be listed on the marketplace above
+    #[account(
+        mut,
+        constraint = product.marketplace == marketplace.key() @ EscrowError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace, numbering
+    /// their escrows
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + std::mem::size_of::<BuyerEscrowCounter>(),
+        seeds = [b"buyer_escrows", marketplace.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Escrow account
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + std::mem::size_of::<Escrow>() + MAX_CARRIER_LEN + MAX_TRACKING_NUMBER_LEN
+            + MAX_DISPUTE_DESCRIPTION_LEN,
+        seeds = [
+            b"escrow",
+            marketplace.key().as_ref(),
+            buyer.key().as_ref(),
+            product.key().as_ref(),
+            &buyer_escrow_counter.escrows_created.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Buyer's reputation (required when the product limits lost disputes)
+    #[account(
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Coupon being redeemed, if any
+    #[account(mut)]
+    pub coupon: Option<Account<'info, Coupon>>,
+    
+    /// Mint the escrow will be paid in (required for token escrows)
+    pub mint: Option<Account<'info, Mint>>,
+    
+    /// Status of the product's seller, which may not be initialized
+    #[account(
+        seeds = [b"seller_status", marketplace.key().as_ref(), product.seller.as_ref()],
+        bump,
+        seeds::program = marketplace::ID
+    )]
+    pub seller_status: AccountInfo<'info>,
+    
+    /// Seller's reputation, which may not be initialized, to find their fee tier
+    #[account(
+        seeds = [b"user_reputation", product.seller.as_ref()],
+        bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: AccountInfo<'info>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for funding an escrow
+#[derive(Accounts)]
+pub struct FundEscrow<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Escrow vault account that will hold the funds
+    #[account(mut)]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Buyer's token account (for token payments)
+    #[account(mut)]
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Escrow's token account (for token payments), which must already
+    /// exist and be owned by the escrow
+    #[account(mut)]
+    pub escrow_token_account: Option<AccountInfo<'info>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for funding a batch of escrows; the escrows and their
+/// vaults are passed in `remaining_accounts`
+#[derive(Accounts)]
+pub struct FundEscrowsBatch<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Marketplace every escrow in the batch belongs to
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Buyer's token account (for token payments)
+    #[account(mut)]
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for marking an order as shipped
+#[derive(Accounts)]
+pub struct MarkAsShipped<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::UnauthorizedSeller
+    )]
+    pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for confirming delivery
+#[derive(Accounts)]
+pub struct ConfirmDelivery<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    #[account(mut)]
+    pub seller: AccountInfo<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        mut,
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer,
+        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(mut)]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Escrow's token account (for token payments)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's token account (for token payments)
+    #[account(mut)]
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Status of the seller, holding their payout preference, which may not
+    /// be initialized
+    #[account(
+        seeds = [b"seller_status", marketplace.key().as_ref(), escrow.seller.as_ref()],
+        bump,
+        seeds::program = marketplace::ID
+    )]
+    pub seller_status: AccountInfo<'info>,
+    
+    /// Marketplace fee destination for the escrow currency
+    #[account(mut)]
+    pub fee_destination: AccountInfo<'info>,
+    
+    /// Seller's reputation, credited with the sale on completion
+    #[account(
+        mut,
+        seeds = [b"user_reputation", seller.key().as_ref()],
+        bump = seller_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Buyer's reputation, credited with the purchase on completion
+    #[account(
+        mut,
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
+    pub reputation_program: Program<'info, Reputation>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for a seller claiming an unconfirmed escrow
+#[derive(Accounts)]
+pub struct ClaimAfterTimeout<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        mut,
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::UnauthorizedSeller,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// The escrow's buyer, credited with the purchase
+    pub buyer: AccountInfo<'info>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(
+        mut,
+        seeds = [b"escrow_vault", escrow.key().as_ref()],
+        bump
+    )]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Escrow's token account (for token payments)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's token account (for token payments)
+    #[account(mut)]
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Status of the seller, holding their payout preference, which may not
+    /// be initialized
+    #[account(
+        seeds = [b"seller_status", marketplace.key().as_ref(), escrow.seller.as_ref()],
+        bump,
+        seeds::program = marketplace::ID
+    )]
+    pub seller_status: AccountInfo<'info>,
+    
+    /// Marketplace fee destination for the escrow currency
+    #[account(mut)]
+    pub fee_destination: AccountInfo<'info>,
+    
+    /// Seller's reputation, credited with the sale on completion
+    #[account(
+        mut,
+        seeds = [b"user_reputation", seller.key().as_ref()],
+        bump = seller_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Buyer's reputation, credited with the purchase on completion
+    #[account(
+        mut,
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
+    pub reputation_program: Program<'info, Reputation>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for refunding unshipped units
+#[derive(Accounts)]
+pub struct RefundUnshipped<'info> {
+    pub seller: Signer<'info>,
+    
+    #[account(mut)]
+    pub buyer: AccountInfo<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        mut,
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::UnauthorizedSeller,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(
+        mut,
+        seeds = [b"escrow_vault", escrow.key().as_ref()],
+        bump
+    )]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Escrow's token account (for token refunds)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Buyer's token account (for token refunds)
+    #[account(mut)]
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for disputing a transaction
+#[derive(Accounts)]
+pub struct DisputeTransaction<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = (escrow.buyer == user.key() || escrow.seller == user.key()) @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for submitting dispute evidence
+#[derive(Accounts)]
+pub struct SubmitEvidence<'info> {
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = (escrow.buyer == user.key() || escrow.seller == user.key()) @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for putting a product up for auction
+#[derive(Accounts)]
+pub struct CreateAuction<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Marketplace the product is listed on
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Product being auctioned, which must belong to the seller
+    #[account(
+        mut,
+        constraint = product.marketplace == marketplace.key() @ EscrowError::ProductMarketplaceMismatch,
+        constraint = product.seller == seller.key() @ EscrowError::UnauthorizedSeller
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + std::mem::size_of::<Auction>(),
+        seeds = [b"auction", product.key().as_ref()],
+        bump
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    /// Status of the seller, which may not be initialized
+    #[account(
+        seeds = [b"seller_status", marketplace.key().as_ref(), seller.key().as_ref()],
+        bump,
+        seeds::program = marketplace::ID
+    )]
+    pub seller_status: AccountInfo<'info>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for bidding on an auction
+#[derive(Accounts)]
+pub struct PlaceBid<'info> {
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+    
+    /// Marketplace the product is listed on
+    #[account(
+        constraint = marketplace.key() == auction.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        seeds = [b"auction", auction.product.as_ref()],
+        bump = auction.bump
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    /// Holder of the bid being beaten, refunded (required once the auction
+    /// has a bid)
+    #[account(mut)]
+    pub previous_bidder: Option<AccountInfo<'info>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for withdrawing an auction without bids
+#[derive(Accounts)]
+pub struct CancelAuction<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Marketplace the product is listed on
+    #[account(
+        mut,
+        constraint = marketplace.key() == auction.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Product being auctioned
+    #[account(
+        mut,
+        constraint = product.key() == auction.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        mut,
+        close = seller,
+        seeds = [b"auction", auction.product.as_ref()],
+        bump = auction.bump,
+        constraint = auction.seller == seller.key() @ EscrowError::UnauthorizedSeller
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+}
+
+/// Accounts required for settling an ended auction into an escrow
+#[derive(Accounts)]
+pub struct SettleAuction<'info> {
+    /// Anyone settling the auction, paying the escrow's rent
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// Marketplace the product is listed on
+    #[account(
+        constraint = marketplace.key() == auction.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Product being auctioned
+    #[account(
+        constraint = product.key() == auction.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        mut,
+        close = seller,
+        seeds = [b"auction", auction.product.as_ref()],
+        bump = auction.bump
+    )]
+    pub auction: Account<'info, Auction>,
+    
+    /// Seller of the product, refunded the auction's rent
+    #[account(
+        mut,
+        constraint = seller.key() == auction.seller @ EscrowError::InvalidEscrowAccount
+    )]
+    pub seller: AccountInfo<'info>,
+    
+    /// Highest bidder, who becomes the escrow's buyer
+    #[account(
+        constraint = winner.key() == auction.highest_bidder @ EscrowError::InvalidEscrowAccount
+    )]
+    pub winner: AccountInfo<'info>,
+    
+    /// Count of the winner's open escrows on the marketplace, numbering
+    /// their escrows
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + std::mem::size_of::<BuyerEscrowCounter>(),
+        seeds = [b"buyer_escrows", marketplace.key().as_ref(), winner.key().as_ref()],
+        bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Escrow opened for the winner
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + std::mem::size_of::<Escrow>() + MAX_CARRIER_LEN + MAX_TRACKING_NUMBER_LEN
+            + MAX_DISPUTE_DESCRIPTION_LEN,
+        seeds = [
+            b"escrow",
+            marketplace.key().as_ref(),
+            winner.key().as_ref(),
+            product.key().as_ref(),
+            &buyer_escrow_counter.escrows_created.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Escrow vault account receiving the winning bid
+    #[account(
+        mut,
+        seeds = [b"escrow_vault", escrow.key().as_ref()],
+        bump
+    )]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Seller's reputation, which may not be initialized, to find their fee tier
+    #[account(
+        seeds = [b"user_reputation", auction.seller.as_ref()],
+        bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for making an offer on a product
+#[derive(Accounts)]
+#[instruction(offer_id: u64)]
+pub struct MakeOffer<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Marketplace the product is listed on
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Product the offer is for
+    #[account(
+        constraint = product.marketplace == marketplace.key() @ EscrowError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + std::mem::size_of::<Offer>(),
+        seeds = [b"offer", product.key().as_ref(), buyer.key().as_ref(), &offer_id.to_le_bytes()],
+        bump
+    )]
+    pub offer: Account<'info, Offer>,
+    
+    /// Buyer's reputation (required when the product limits lost disputes)
+    #[account(
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for accepting an offer, creating its escrow
+#[derive(Accounts)]
+pub struct AcceptOffer<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Marketplace the product is listed on
+    #[account(
+        mut,
+        constraint = marketplace.key() == offer.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    /// Product the offer is for
+    #[account(
+        mut,
+        constraint = product.key() == offer.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        mut,
+        constraint = offer.seller == seller.key() @ EscrowError::UnauthorizedSeller
+    )]
+    pub offer: Account<'info, Offer>,
+    
+    /// Buyer who made the offer
+    #[account(
+        constraint = buyer.key() == offer.buyer @ EscrowError::InvalidEscrowAccount
+    )]
+    pub buyer: AccountInfo<'info>,
+    
+    /// Count of the buyer's open escrows on the marketplace, numbering
+    /// their escrows
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<BuyerEscrowCounter>(),
+        seeds = [b"buyer_escrows", marketplace.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Escrow opened for the buyer at the offered price
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + std::mem::size_of::<Escrow>() + MAX_CARRIER_LEN + MAX_TRACKING_NUMBER_LEN
+            + MAX_DISPUTE_DESCRIPTION_LEN,
+        seeds = [
+            b"escrow",
+            marketplace.key().as_ref(),
+            buyer.key().as_ref(),
+            product.key().as_ref(),
+            &buyer_escrow_counter.escrows_created.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Mint the escrow will be paid in (required for token escrows)
+    pub mint: Option<Account<'info, Mint>>,
+    
+    /// Status of the seller, which may not be initialized
+    #[account(
+        seeds = [b"seller_status", marketplace.key().as_ref(), seller.key().as_ref()],
+        bump,
+        seeds::program = marketplace::ID
+    )]
+    pub seller_status: AccountInfo<'info>,
+    
+    /// Seller's reputation, which may not be initialized, to find their fee tier
+    #[account(
+        seeds = [b"user_reputation", seller.key().as_ref()],
+        bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: AccountInfo<'info>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for rejecting an offer
+#[derive(Accounts)]
+pub struct RejectOffer<'info> {
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = offer.seller == seller.key() @ EscrowError::UnauthorizedSeller
+    )]
+    pub offer: Account<'info, Offer>,
+}
+
+/// Accounts required for exporting an escrow's state
+#[derive(Accounts)]
+pub struct SerializeEscrowState<'info> {
+    pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for closing a settled escrow
+#[derive(Accounts)]
+pub struct CloseEscrow<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    #[account(
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        close = buyer,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Escrow vault account, drained back to the buyer
+    #[account(
+        mut,
+        seeds = [b"escrow_vault", escrow.key().as_ref()],
+        bump
+    )]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for agreeing a release schedule
+#[derive(Accounts)]
+pub struct SetReleaseSchedule<'info> {
+    pub buyer: Signer<'info>,
+    
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer,
+        constraint = escrow.seller == seller.key() @ EscrowError::UnauthorizedSeller
+    )]
+    pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for releasing a scheduled portion
+#[derive(Accounts)]
+pub struct ReleaseScheduledPortion<'info> {
+    #[account(mut)]
+    pub seller: AccountInfo<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(
+        mut,
+        seeds = [b"escrow_vault", escrow.key().as_ref()],
+        bump
+    )]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Escrow's token account (for token payments)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's token account (for token payments)
+    #[account(mut)]
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Marketplace fee destination for the escrow currency
+    #[account(mut)]
+    pub fee_destination: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for creating a condition oracle
+#[derive(Accounts)]
+#[instruction(condition_id: [u8; 32])]
+pub struct InitializeConditionOracle<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + std::mem::size_of::<ConditionOracle>(),
+        seeds = [b"condition_oracle", authority.key().as_ref(), condition_id.as_ref()],
+        bump
+    )]
+    pub oracle: Account<'info, ConditionOracle>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for reporting a condition
+#[derive(Accounts)]
+pub struct ReportCondition<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"condition_oracle", authority.key().as_ref(), oracle.condition_id.as_ref()],
+        bump = oracle.bump,
+        constraint = oracle.authority == authority.key() @ EscrowError::Unauthorized
+    )]
+    pub oracle: Account<'info, ConditionOracle>,
+}
+
+/// Accounts required for releasing an escrow on its oracle's condition
+#[derive(Accounts)]
+pub struct OracleRelease<'info> {
+    /// Whoever triggers the release, paying for the reputation calls
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    #[account(mut)]
+    pub seller: AccountInfo<'info>,
+    
+    /// The escrow's buyer, credited with the purchase
+    pub buyer: AccountInfo<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        mut,
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Condition oracle the parties agreed on; its address pins the reporting authority
+    #[account(
+        seeds = [b"condition_oracle", oracle.authority.as_ref(), oracle.condition_id.as_ref()],
+        bump = oracle.bump
+    )]
+    pub oracle: Account<'info, ConditionOracle>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(
+        mut,
+        seeds = [b"escrow_vault", escrow.key().as_ref()],
+        bump
+    )]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Escrow's token account (for token payments)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's token account (for token payments)
+    #[account(mut)]
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Marketplace fee destination for the escrow currency
+    #[account(mut)]
+    pub fee_destination: AccountInfo<'info>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Seller's reputation, credited with the sale on completion
+    #[account(
+        mut,
+        seeds = [b"user_reputation", seller.key().as_ref()],
+        bump = seller_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Buyer's reputation, credited with the purchase on completion
+    #[account(
+        mut,
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
+    pub reputation_program: Program<'info, Reputation>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for appealing a dispute resolution
+#[derive(Accounts)]
+pub struct AppealResolution<'info> {
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = (escrow.buyer == user.key() || escrow.seller == user.key()) @ EscrowError::Unauthorized
+    )]
+    pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for cancelling an escrow
+#[derive(Accounts)]
+pub struct CancelEscrow<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        mut,
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Seller of the escrow, paid any cancellation penalty
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub seller: AccountInfo<'info>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(mut)]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Buyer's token account (for token refunds)
+    #[account(mut)]
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Escrow's token account (for token refunds)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's token account (for token cancellation penalties)
+    #[account(mut)]
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Buyer's reputation, counting funded cancellations (required while the
+    /// marketplace penalizes them)
+    #[account(
+        mut,
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
+    pub reputation_program: Program<'info, Reputation>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for resolving a dispute
+#[derive(Accounts)]
+pub struct ResolveDispute<'info> {
+    /// Marketplace authority or arbiter, pool arbitrator, appeal arbitrator,
+    /// or anyone executing a resolution whose appeal window has passed
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// Marketplace the escrow belongs to
+    #[account(
+        mut,
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(mut)]
+    pub buyer: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub seller: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount,
+        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Escrow vault account that holds the funds
+    #[account(mut)]
+    pub escrow_vault: AccountInfo<'info>,
+    
+    /// Buyer's token account (for token refunds)
+    #[account(mut)]
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's token account (for token payments)
+    #[account(mut)]
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Escrow's token account (for token transfers)
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's bond (required when slashing)
+    #[account(
+        mut,
+        seeds = [b"seller_bond", marketplace.key().as_ref(), seller.key().as_ref()],
+        bump = seller_bond.bump
+    )]
+    pub seller_bond: Option<Account<'info, SellerBond>>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// Marketplace fee destination for the escrow currency
+    #[account(mut)]
+    pub fee_destination: AccountInfo<'info>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    /// Arbitrator routing for the marketplace, which may not be initialized
+    #[account(seeds = [b"arbitrator_routing", marketplace.key().as_ref()], bump)]
+    pub arbitrator_routing: AccountInfo<'info>,
+    
+    /// Reputation of the losing party, to record the lost dispute
+    #[account(mut)]
+    pub loser_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Seller's reputation, credited with the sale when the seller is paid
+    #[account(
+        mut,
+        seeds = [b"user_reputation", seller.key().as_ref()],
+        bump = seller_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Buyer's reputation, credited with the purchase when the seller is paid
+    #[account(
+        mut,
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    pub reputation_program: Program<'info, Reputation>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for depositing a seller bond
+#[derive(Accounts)]
+pub struct DepositSellerBond<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// Marketplace the bond is posted on
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<SellerBond>(),
+        seeds = [b"seller_bond", marketplace.key().as_ref(), seller.key().as_ref()],
+        bump
+    )]
+    pub seller_bond: Account<'info, SellerBond>,
+    
+    pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for creating an escrow
+/// Accounts required for creating a coupon
 #[derive(Accounts)]
-pub struct CreateEscrow<'info> {
+#[instruction(code_hash: [u8; 32])]
+pub struct CreateCoupon<'info> {
     #[account(mut)]
-    pub buyer: Signer<'info>,
-    
-    /// Marketplace account
-    pub marketplace: AccountInfo<'info>,
+    pub authority: Signer<'info>,
     
-    /// Product being purchased
-    pub product: Account<'info, Product>,
+    /// Marketplace the coupon is issued on
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID,
+        constraint = marketplace.authority == authority.key() @ EscrowError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
     
-    /// Escrow account
     #[account(
         init,
-        payer = buyer,
-        space = 8 + std::mem::size_of::<Escrow>(),
-        seeds = [
-            b"escrow",
-            marketplace.key().as_ref(),
-            buyer.key().as_ref(),
-            product.key().as_ref(),
-        ],
+        payer = authority,
+        space = 8 + std::mem::size_of::<Coupon>(),
+        seeds = [b"coupon", marketplace.key().as_ref(), code_hash.as_ref()],
         bump
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub coupon: Account<'info, Coupon>,
     
     pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for funding an escrow
+/// Accounts required for assigning an arbitrator pool
 #[derive(Accounts)]
-pub struct FundEscrow<'info> {
+pub struct SetArbitratorPool<'info> {
     #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// Marketplace whose disputes are routed
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID,
+        constraint = marketplace.authority == authority.key() @ EscrowError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + std::mem::size_of::<ArbitratorRouting>(),
+        seeds = [b"arbitrator_routing", marketplace.key().as_ref()],
+        bump
+    )]
+    pub arbitrator_routing: Account<'info, ArbitratorRouting>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for requesting a return
+#[derive(Accounts)]
+pub struct RequestReturn<'info> {
     pub buyer: Signer<'info>,
     
     #[account(
@@ -512,27 +5497,65 @@ pub struct FundEscrow<'info> {
         constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
     )]
     pub escrow: Account<'info, Escrow>,
+}
+
+/// Accounts required for approving a return
+#[derive(Accounts)]
+pub struct ApproveReturn<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
     
-    /// Escrow vault account that will hold the funds
     #[account(mut)]
-    pub escrow_vault: AccountInfo<'info>,
+    pub buyer: AccountInfo<'info>,
     
-    /// Buyer's token account (for token payments)
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::UnauthorizedSeller,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount
+    )]
+    pub escrow: Account<'info, Escrow>,
+    
+    /// Seller's token account (for token refunds)
     #[account(mut)]
-    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    pub seller_token_account: Option<Account<'info, TokenAccount>>,
     
-    /// Escrow's token account (for token payments)
+    /// Buyer's token account (for token refunds)
     #[account(mut)]
-    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Seller's reputation, whose sale the return takes back, which may not
+    /// be initialized
+    #[account(
+        mut,
+        seeds = [b"user_reputation", seller.key().as_ref()],
+        bump,
+        seeds::program = reputation::ID
+    )]
+    pub seller_reputation: AccountInfo<'info>,
+    
+    /// Buyer's reputation, whose purchase the return takes back, which may
+    /// not be initialized
+    #[account(
+        mut,
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: AccountInfo<'info>,
+    
+    /// PDA authorizing this program's calls into the reputation program
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub reputation_program: Program<'info, Reputation>,
     
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for marking an order as shipped
+/// Accounts required for rejecting a return
 #[derive(Accounts)]
-pub struct MarkAsShipped<'info> {
-    #[account(mut)]
+pub struct RejectReturn<'info> {
     pub seller: Signer<'info>,
     
     #[account(
@@ -542,19 +5565,26 @@ pub struct MarkAsShipped<'info> {
     pub escrow: Account<'info, Escrow>,
 }
 
-/// Accounts required for confirming delivery
+/// Accounts required for settling a defaulted layaway
 #[derive(Accounts)]
-pub struct ConfirmDelivery<'info> {
+pub struct ClaimLayawayDefault<'info> {
     #[account(mut)]
-    pub buyer: Signer<'info>,
+    pub seller: Signer<'info>,
     
     #[account(mut)]
-    pub seller: AccountInfo<'info>,
+    pub buyer: AccountInfo<'info>,
     
+    /// Marketplace the escrow belongs to
     #[account(
         mut,
-        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer,
-        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount
+        constraint = marketplace.key() == escrow.marketplace @ EscrowError::InvalidEscrowAccount
+    )]
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = escrow.seller == seller.key() @ EscrowError::UnauthorizedSeller,
+        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount
     )]
     pub escrow: Account<'info, Escrow>,
     
@@ -570,94 +5600,192 @@ pub struct ConfirmDelivery<'info> {
     #[account(mut)]
     pub seller_token_account: Option<Account<'info, TokenAccount>>,
     
+    /// Buyer's token account (for token refunds)
+    #[account(mut)]
+    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    
+    /// Product the escrow is for
+    #[account(
+        mut,
+        constraint = product.key() == escrow.product @ EscrowError::InvalidEscrowAccount
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", escrow.marketplace.as_ref(), escrow.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for disputing a transaction
+/// Accounts required for opening a cart escrow
 #[derive(Accounts)]
-pub struct DisputeTransaction<'info> {
+#[instruction(cart_id: u64)]
+pub struct CreateCartEscrow<'info> {
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub buyer: Signer<'info>,
     
+    /// Marketplace the cart is bought on
     #[account(
         mut,
-        constraint = (escrow.buyer == user.key() || escrow.seller == user.key()) @ EscrowError::Unauthorized
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        seeds::program = marketplace::ID
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
+    
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + std::mem::size_of::<CartEscrow>() + MAX_CART_ITEMS * std::mem::size_of::<CartItem>(),
+        seeds = [b"cart", marketplace.key().as_ref(), buyer.key().as_ref(), &cart_id.to_le_bytes()],
+        bump
+    )]
+    pub cart: Account<'info, CartEscrow>,
+    
+    /// Buyer's reputation (required when a product limits lost disputes)
+    #[account(
+        seeds = [b"user_reputation", buyer.key().as_ref()],
+        bump = buyer_reputation.bump,
+        seeds::program = reputation::ID
+    )]
+    pub buyer_reputation: Option<Account<'info, UserReputation>>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + std::mem::size_of::<BuyerEscrowCounter>(),
+        seeds = [b"buyer_escrows", marketplace.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    
+    pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for cancelling an escrow
+/// Accounts required for funding a cart escrow
 #[derive(Accounts)]
-pub struct CancelEscrow<'info> {
+pub struct FundCartEscrow<'info> {
     #[account(mut)]
     pub buyer: Signer<'info>,
     
     #[account(
         mut,
-        constraint = escrow.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
+        constraint = cart.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
     )]
-    pub escrow: Account<'info, Escrow>,
-    
-    /// Escrow vault account that holds the funds
-    #[account(mut)]
-    pub escrow_vault: AccountInfo<'info>,
-    
-    /// Buyer's token account (for token refunds)
-    #[account(mut)]
-    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
-    
-    /// Escrow's token account (for token refunds)
-    #[account(mut)]
-    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    pub cart: Account<'info, CartEscrow>,
     
-    pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for resolving a dispute
+/// Accounts required for cancelling an unfunded cart escrow
 #[derive(Accounts)]
-pub struct ResolveDispute<'info> {
+pub struct CancelCartEscrow<'info> {
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub buyer: Signer<'info>,
     
-    /// Marketplace account
+    /// Marketplace the cart was bought on
     #[account(
-        constraint = marketplace.authority == authority.key() @ EscrowError::UnauthorizedAuthority
+        mut,
+        constraint = marketplace.key() == cart.marketplace @ EscrowError::InvalidEscrowAccount
     )]
     pub marketplace: Account<'info, marketplace::MarketplaceState>,
     
+    #[account(
+        mut,
+        close = buyer,
+        constraint = cart.buyer == buyer.key() @ EscrowError::UnauthorizedBuyer
+    )]
+    pub cart: Account<'info, CartEscrow>,
+    
+    /// Count of the buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", cart.marketplace.as_ref(), buyer.key().as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
+    
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+}
+
+/// Accounts required for shipping a cart line item
+#[derive(Accounts)]
+pub struct MarkCartItemShipped<'info> {
+    pub seller: Signer<'info>,
+    
     #[account(mut)]
-    pub buyer: AccountInfo<'info>,
+    pub cart: Account<'info, CartEscrow>,
+}
+
+/// Accounts required for disputing a cart line item
+#[derive(Accounts)]
+pub struct DisputeCartItem<'info> {
+    pub user: Signer<'info>,
     
     #[account(mut)]
-    pub seller: AccountInfo<'info>,
+    pub cart: Account<'info, CartEscrow>,
+}
+
+/// Accounts required for settling cart line items. The signer is the buyer
+/// when confirming and whoever may resolve the item's dispute otherwise.
+#[derive(Accounts)]
+pub struct SettleCartItem<'info> {
+    pub authority: Signer<'info>,
     
+    /// Marketplace the cart belongs to
     #[account(
         mut,
-        constraint = escrow.buyer == buyer.key() @ EscrowError::InvalidEscrowAccount,
-        constraint = escrow.seller == seller.key() @ EscrowError::InvalidEscrowAccount
+        constraint = marketplace.key() == cart.marketplace @ EscrowError::InvalidEscrowAccount
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub marketplace: Account<'info, marketplace::MarketplaceState>,
     
-    /// Escrow vault account that holds the funds
     #[account(mut)]
-    pub escrow_vault: AccountInfo<'info>,
+    pub cart: Account<'info, CartEscrow>,
     
-    /// Buyer's token account (for token refunds)
+    /// Account the cart's marketplace fees are paid to, fixed at creation
     #[account(mut)]
-    pub buyer_token_account: Option<Account<'info, TokenAccount>>,
+    pub fee_destination: AccountInfo<'info>,
     
-    /// Seller's token account (for token payments)
-    #[account(mut)]
-    pub seller_token_account: Option<Account<'info, TokenAccount>>,
+    /// Count of the cart buyer's open escrows on the marketplace
+    #[account(
+        mut,
+        seeds = [b"buyer_escrows", cart.marketplace.as_ref(), cart.buyer.as_ref()],
+        bump = buyer_escrow_counter.bump
+    )]
+    pub buyer_escrow_counter: Account<'info, BuyerEscrowCounter>,
     
-    /// Escrow's token account (for token transfers)
-    #[account(mut)]
-    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    /// Arbitrator routing for the marketplace, which may not be initialized
+    #[account(seeds = [b"arbitrator_routing", marketplace.key().as_ref()], bump)]
+    pub arbitrator_routing: AccountInfo<'info>,
     
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+    /// PDA authorizing this program's calls into the marketplace
+    #[account(seeds = [marketplace::ESCROW_AUTHORITY_SEED], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
 }
 
 /// Error codes for the escrow program
@@ -685,4 +5813,184 @@ pub enum EscrowError {
     DisputeReasonTooLong,
     #[msg("Invalid escrow account")]
     InvalidEscrowAccount,
-} 
\ No newline at end of file
+    #[msg("Return window has closed")]
+    ReturnWindowClosed,
+    #[msg("Token account required for this currency")]
+    MissingTokenAccount,
+    #[msg("Layaway deadline must be in the future")]
+    InvalidLayawayDeadline,
+    #[msg("Escrow is not a layaway")]
+    NotLayaway,
+    #[msg("Layaway has not been paid in full")]
+    LayawayNotPaid,
+    #[msg("Layaway has installments paid and cannot be cancelled")]
+    LayawayInProgress,
+    #[msg("Layaway payment deadline has passed")]
+    LayawayDeadlinePassed,
+    #[msg("Layaway payment deadline has not been reached")]
+    LayawayDeadlineNotReached,
+    #[msg("Installment exceeds the outstanding balance")]
+    InvalidInstallment,
+    #[msg("Product is not listed on this marketplace")]
+    ProductMarketplaceMismatch,
+    #[msg("Product is not yet available for purchase")]
+    ProductNotYetAvailable,
+    #[msg("Product season has ended")]
+    ProductSeasonEnded,
+    #[msg("Basis points must not exceed 10000")]
+    InvalidBasisPoints,
+    #[msg("Bond can only be slashed on a buyer-favored resolution")]
+    InvalidSlash,
+    #[msg("Seller bond account required for slashing")]
+    SellerBondRequired,
+    #[msg("Seller bond balance is insufficient")]
+    InsufficientBond,
+    #[msg("Bond amount must be greater than zero")]
+    InvalidBondAmount,
+    #[msg("Shipment quantity exceeds the unshipped quantity")]
+    InvalidShipmentQuantity,
+    #[msg("Not all of the order has shipped")]
+    NotFullyShipped,
+    #[msg("Fund releases are paused on this marketplace")]
+    ReleasesPaused,
+    #[msg("Buyer has lost too many disputes to purchase this product")]
+    BuyerTooRisky,
+    #[msg("Buyer reputation account is required for this product")]
+    BuyerReputationRequired,
+    #[msg("Reputation account does not belong to the expected user")]
+    InvalidReputationAccount,
+    #[msg("Release schedule is invalid")]
+    InvalidReleaseSchedule,
+    #[msg("Scheduled releases are frozen while the escrow is disputed")]
+    ScheduledReleasesFrozen,
+    #[msg("No scheduled release remains")]
+    NoScheduledRelease,
+    #[msg("Scheduled release is not yet due")]
+    ScheduledReleaseNotDue,
+    #[msg("Escrow has already been funded")]
+    AlreadyFunded,
+    #[msg("Escrow amount is below the marketplace minimum for its currency")]
+    EscrowBelowMinimum,
+    #[msg("Invalid coupon")]
+    InvalidCoupon,
+    #[msg("Coupon has expired")]
+    CouponExpired,
+    #[msg("Coupon has no uses left")]
+    CouponExhausted,
+    #[msg("Only the losing party may appeal")]
+    NotLosingParty,
+    #[msg("Appeal window has closed")]
+    AppealWindowClosed,
+    #[msg("Appeal window is still open")]
+    AppealWindowOpen,
+    #[msg("Unauthorized appeal arbitrator")]
+    UnauthorizedArbitrator,
+    #[msg("Seller is on hold and not taking orders")]
+    SellerOnHold,
+    #[msg("A source of funds attestation is required to fund escrows")]
+    SourceAttestationRequired,
+    #[msg("Arbitrator pool is too large or contains the default pubkey")]
+    InvalidArbitratorPool,
+    #[msg("Signer is not an arbitrator for this dispute reason")]
+    ArbitratorNotInPool,
+    #[msg("Escrow token vault is not initialized or not owned by the escrow")]
+    VaultNotInitialized,
+    #[msg("Escrow has no release condition")]
+    NoReleaseCondition,
+    #[msg("Oracle is not the escrow's release condition oracle")]
+    InvalidConditionOracle,
+    #[msg("Release condition has not been met")]
+    ReleaseConditionNotMet,
+    #[msg("Quantity exceeds the units available to confirm or refund")]
+    InvalidPartialQuantity,
+    #[msg("Batch accounts must be escrow and vault pairs")]
+    InvalidBatchAccounts,
+    #[msg("All escrows in a batch must use the same currency")]
+    BatchCurrencyMismatch,
+    #[msg("Seller is still within the handling time")]
+    HandlingTimeNotElapsed,
+    #[msg("Mint decimals do not match the product's currency")]
+    DecimalsMismatch,
+    #[msg("Cart items must be distinct products with matching accounts")]
+    InvalidCartItems,
+    #[msg("Carts can only be paid in SOL")]
+    UnsupportedCartCurrency,
+    #[msg("Fee destination does not match the marketplace's")]
+    InvalidFeeDestination,
+    #[msg("Buyer has too many open escrows on this marketplace")]
+    TooManyOpenEscrows,
+    #[msg("Token accounts must be passed for token escrows and only for them")]
+    AccountsCurrencyMismatch,
+    #[msg("The window to dispute this order has closed")]
+    DisputeWindowClosed,
+    #[msg("Tracking ID needs both a carrier and a tracking number")]
+    InvalidTrackingId,
+    #[msg("Carrier name too long")]
+    CarrierTooLong,
+    #[msg("Product is sold out")]
+    ProductSoldOut,
+    #[msg("Not enough unreserved inventory for this quantity")]
+    InsufficientInventory,
+    #[msg("Marketplace is paused")]
+    MarketplacePaused,
+    #[msg("Evidence is no longer accepted for this dispute")]
+    EvidenceWindowClosed,
+    #[msg("Dispute already has the maximum evidence submissions")]
+    EvidenceLimitReached,
+    #[msg("Swap program does not match the marketplace's")]
+    InvalidSwapProgram,
+    #[msg("Swap payout needs the swap program, price feeds and seller token account")]
+    InvalidSwapAccounts,
+    #[msg("Swapped payout is below the seller's slippage limit")]
+    PayoutSlippageExceeded,
+    #[msg("Escrow value exceeds the marketplace cap for its currency")]
+    OrderValueTooHigh,
+    #[msg("Split resolutions are unavailable while the marketplace has an appeal window")]
+    SplitNotAppealable,
+    #[msg("Buyer can still confirm delivery; the auto-release window has not passed")]
+    ConfirmationWindowOpen,
+    #[msg("Escrow has not settled yet")]
+    EscrowNotSettled,
+    #[msg("Token account mint does not match the escrow's mint")]
+    InvalidMint,
+    #[msg("Auctions are only available for SOL-priced products")]
+    UnsupportedAuctionCurrency,
+    #[msg("Minimum bid increment must be greater than zero")]
+    InvalidBidIncrement,
+    #[msg("Auction end time must be in the future")]
+    InvalidAuctionEndTime,
+    #[msg("Auction has ended")]
+    AuctionEnded,
+    #[msg("Auction has not ended yet")]
+    AuctionNotEnded,
+    #[msg("Bid is below the auction's minimum")]
+    BidTooLow,
+    #[msg("Sellers cannot bid on their own auctions")]
+    SellerCannotBid,
+    #[msg("Previous bidder does not hold the highest bid")]
+    InvalidPreviousBidder,
+    #[msg("Auction has no bids to settle")]
+    NoBids,
+    #[msg("Auction already has bids")]
+    AuctionHasBids,
+    #[msg("Product does not take offers")]
+    OffersNotAccepted,
+    #[msg("Offered price must be above zero and below the list price")]
+    InvalidOfferPrice,
+    #[msg("Offer is no longer open")]
+    OfferNotPending,
+    #[msg("Offer has expired")]
+    OfferExpired,
+    #[msg("Quantity is below the product's minimum order")]
+    BelowMinimumOrder,
+    #[msg("Product is flagged for moderation")]
+    ProductFlagged,
+    #[msg("The seller already rejected a return of this order")]
+    ReturnAlreadyRejected,
+    #[msg("Product has been taken off sale")]
+    ProductNotActive,
+    #[msg("Part of the order has shipped or been paid out; refund the unshipped units instead")]
+    OrderPartiallyFulfilled,
+    #[msg("Escrow can still be returned or have its reviews moderated")]
+    EscrowStillReviewable,
+}
@@ -1,10 +1,62 @@
 use anchor_lang::prelude::*;
 
-// Import SPL token program for handling token transfers
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+// Import SPL token accounts for checking token fee destinations
+use anchor_spl::token::TokenAccount;
+
+// Switchboard VRF used for fair featured-product rotation
+use switchboard_solana::VrfAccountData;
 
 declare_id!("Marketplace111111111111111111111111111111111111");
 
+/// Maximum string lengths, in bytes of UTF-8. Account space is reserved by
+/// byte length, so validating characters would let multibyte text overflow it.
+pub const MAX_TITLE_LEN: usize = 50;
+pub const MAX_DESCRIPTION_LEN: usize = 1000;
+pub const MAX_METADATA_URI_LEN: usize = 200;
+pub const MAX_TAG_LEN: usize = 16;
+pub const MAX_FLAG_REASON_LEN: usize = 140;
+
+/// Maximum number of tags on a product
+pub const MAX_TAGS: usize = 5;
+
+/// Maximum number of volume price tiers on a product
+pub const MAX_PRICE_TIERS: usize = 4;
+
+/// Decimals of reference prices, which are quoted in USD
+pub const REFERENCE_PRICE_DECIMALS: u32 = 6;
+
+/// Oldest price feed update accepted when computing reference prices
+pub const MAX_PRICE_FEED_AGE_SECONDS: i64 = 5 * 60;
+
+/// Escrow program allowed to report escrow activity on products
+pub const ESCROW_PROGRAM_ID: Pubkey =
+    anchor_lang::solana_program::pubkey!("Escrow1111111111111111111111111111111111111111");
+
+/// Seed of the escrow program PDA that signs its calls into this program
+pub const ESCROW_AUTHORITY_SEED: &[u8] = b"escrow_authority";
+
+/// Default time after a seller-favored dispute during which the authority may
+/// remove the buyer's linked review (30 days)
+pub const DEFAULT_REVIEW_REMOVAL_GRACE_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// Default time after an approved return during which the buyer may revise
+/// their review once more (14 days)
+pub const DEFAULT_RETURN_REVIEW_GRACE_SECONDS: i64 = 14 * 24 * 60 * 60;
+
+/// Default time after shipment after which the seller may claim an
+/// unconfirmed escrow (14 days)
+pub const DEFAULT_AUTO_RELEASE_SECONDS: i64 = 14 * 24 * 60 * 60;
+
+/// Number of marketplaces held by one registry page. Operators with more
+/// marketplaces open further pages.
+pub const MAX_REGISTRY_PAGE_ENTRIES: usize = 32;
+
+/// Number of products a marketplace's featured rotation can draw from
+pub const MAX_FEATURED_CANDIDATES: usize = 32;
+
+/// Maximum number of volume-based fee tiers on a marketplace
+pub const MAX_FEE_TIERS: usize = 4;
+
 #[program]
 pub mod marketplace {
     use super::*;
@@ -17,6 +69,9 @@ pub mod marketplace {
         // Check that fees are reasonable (max 10%)
         require!(fees_basis_points <= 1000, MarketplaceError::FeesTooHigh);
 
+        // The default destination receives SOL fees, so it must hold lamports
+        validate_fee_destination(&ctx.accounts.fee_destination, &CurrencyType::SOL)?;
+
         // Initialize marketplace state
         let marketplace = &mut ctx.accounts.marketplace;
         marketplace.authority = ctx.accounts.authority.key();
@@ -24,6 +79,51 @@ pub mod marketplace {
         marketplace.fees_basis_points = fees_basis_points;
         marketplace.fee_destination = ctx.accounts.fee_destination.key();
         marketplace.is_paused = false;
+        marketplace.releases_paused = false;
+        marketplace.accepted_currencies = CurrencyType::ALL_FLAGS;
+        marketplace.featured_vrf = Pubkey::default();
+        marketplace.featured_vrf_round = 0;
+        marketplace.layaway_forfeit_bps = 0;
+        marketplace.review_removal_grace_seconds = DEFAULT_REVIEW_REMOVAL_GRACE_SECONDS;
+        marketplace.min_escrow_sol = 0;
+        marketplace.min_escrow_usdc = 0;
+        marketplace.min_escrow_usdt = 0;
+        marketplace.require_metadata_hash = false;
+        marketplace.appeal_window_seconds = 0;
+        marketplace.appeal_arbitrator = Pubkey::default();
+        marketplace.sol_fee_destination = Pubkey::default();
+        marketplace.usdc_fee_destination = Pubkey::default();
+        marketplace.usdt_fee_destination = Pubkey::default();
+        marketplace.require_source_attestation = false;
+        marketplace.active_escrows = 0;
+        marketplace.sol_volume = 0;
+        marketplace.usdc_volume = 0;
+        marketplace.usdt_volume = 0;
+        marketplace.sol_fees_collected = 0;
+        marketplace.usdc_fees_collected = 0;
+        marketplace.usdt_fees_collected = 0;
+        marketplace.last_snapshot_slot = 0;
+        marketplace.return_review_grace_seconds = DEFAULT_RETURN_REVIEW_GRACE_SECONDS;
+        marketplace.listed_products = 0;
+        marketplace.rounding_policy = RoundingPolicy::DustToSeller;
+        marketplace.max_open_escrows_per_buyer = 0;
+        marketplace.default_currency = CurrencyType::SOL;
+        marketplace.dispute_window_seconds = 0;
+        marketplace.evidence_window_slots = 0;
+        marketplace.swap_program = Pubkey::default();
+        marketplace.max_escrow_sol = 0;
+        marketplace.max_escrow_usdc = 0;
+        marketplace.max_escrow_usdt = 0;
+        marketplace.require_purchase_reviews = false;
+        marketplace.cancellation_threshold = 0;
+        marketplace.cancellation_window_seconds = 0;
+        marketplace.cancellation_fee_bps = 0;
+        marketplace.arbiter = Pubkey::default();
+        marketplace.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        marketplace.fee_tier_count = 0;
+        marketplace.auto_release_seconds = DEFAULT_AUTO_RELEASE_SECONDS;
+        marketplace.usdc_mint = Pubkey::default();
+        marketplace.usdt_mint = Pubkey::default();
         marketplace.bump = *ctx.bumps.get("marketplace").unwrap();
 
         Ok(())
@@ -36,17 +136,62 @@ pub mod marketplace {
         description: String,
         price: u64,
         quantity: u64,
-        currency: CurrencyType,
+        currency: Option<CurrencyType>,
         metadata_uri: String,
-        category: String,
+        category: Category,
+        return_window_seconds: i64,
+        available_from: Option<i64>,
+        available_until: Option<i64>,
+        max_buyer_lost_disputes: Option<u64>,
+        metadata_hash: [u8; 32],
+        handling_time_seconds: i64,
+        tags: Vec<String>,
+        min_order_quantity: Option<u64>,
     ) -> Result<()> {
+        require!(!ctx.accounts.marketplace.is_paused, MarketplaceError::MarketplacePaused);
+        
         // Validate inputs
         require!(price > 0, MarketplaceError::InvalidPrice);
         require!(quantity > 0, MarketplaceError::InvalidQuantity);
-        require!(title.len() <= 50, MarketplaceError::TitleTooLong);
-        require!(description.len() <= 1000, MarketplaceError::DescriptionTooLong);
-        require!(metadata_uri.len() <= 200, MarketplaceError::MetadataUriTooLong);
-        require!(category.len() <= 20, MarketplaceError::CategoryTooLong);
+        require!(title.len() <= MAX_TITLE_LEN, MarketplaceError::TitleTooLong);
+        require!(description.len() <= MAX_DESCRIPTION_LEN, MarketplaceError::DescriptionTooLong);
+        require!(metadata_uri.len() <= MAX_METADATA_URI_LEN, MarketplaceError::MetadataUriTooLong);
+        require!(return_window_seconds >= 0, MarketplaceError::InvalidReturnWindow);
+        require!(handling_time_seconds >= 0, MarketplaceError::InvalidHandlingTime);
+        validate_availability_window(available_from, available_until)?;
+        validate_tags(&tags)?;
+        let min_order_quantity = min_order_quantity.unwrap_or(1);
+        require!(min_order_quantity > 0, MarketplaceError::InvalidMinOrderQuantity);
+        
+        // Listings priced without a currency use the marketplace default
+        let currency = currency.unwrap_or_else(|| ctx.accounts.marketplace.default_currency.clone());
+        require!(
+            ctx.accounts.marketplace.accepts(&currency),
+            MarketplaceError::CurrencyNotAccepted
+        );
+        require!(
+            !ctx.accounts.marketplace.require_metadata_hash || metadata_hash != [0u8; 32],
+            MarketplaceError::MetadataHashRequired
+        );
+
+        // A seller keeps one open listing per fingerprint; a relisting must
+        // show the listing holding it has closed
+        let holder = ctx.accounts.product_fingerprint.product;
+        if holder != Pubkey::default() {
+            let previous = match &ctx.accounts.fingerprinted_product {
+                Some(previous) => previous,
+                None => return err!(MarketplaceError::DuplicateListing),
+            };
+            require_keys_eq!(previous.key(), holder, MarketplaceError::DuplicateListing);
+            require!(previous.is_closed(), MarketplaceError::DuplicateListing);
+        }
+        let fingerprint = product_fingerprint(&title, category, &ctx.accounts.seller.key());
+        
+        let claim = &mut ctx.accounts.product_fingerprint;
+        claim.seller = ctx.accounts.seller.key();
+        claim.fingerprint = fingerprint;
+        claim.product = ctx.accounts.product.key();
+        claim.bump = *ctx.bumps.get("product_fingerprint").unwrap();
 
         // Create the product
         let product = &mut ctx.accounts.product;
@@ -60,38 +205,61 @@ pub mod marketplace {
         product.title = title;
         product.description = description;
         product.metadata_uri = metadata_uri;
+        product.metadata_hash = metadata_hash;
         product.status = ProductStatus::Active;
         product.created_at = Clock::get()?.unix_timestamp;
         product.updated_at = Clock::get()?.unix_timestamp;
         product.category = category;
+        product.return_window_seconds = return_window_seconds;
+        product.featured = false;
+        product.featured_until = 0;
+        product.available_from = available_from;
+        product.available_until = available_until;
+        product.active_escrows = 0;
+        product.max_buyer_lost_disputes = max_buyer_lost_disputes;
+        product.reference_price = match &ctx.accounts.price_feed {
+            Some(price_feed) => price_feed.reference_price(marketplace.key(), price, &product.currency)?,
+            None => 0,
+        };
+        product.handling_time_seconds = handling_time_seconds;
+        product.seller_is_pda = !product.seller.is_on_curve();
+        product.fee_exempt = false;
+        product.index = marketplace.product_count;
+        product.reserved_quantity = 0;
+        product.fingerprint = fingerprint;
+        product.tags = tags;
+        product.offer_window_seconds = 0;
+        product.min_order_quantity = min_order_quantity;
+        product.price_tiers = Vec::new();
+        product.moderated = false;
         product.bump = *ctx.bumps.get("product").unwrap();
 
         // Increment product count
         marketplace.product_count = marketplace.product_count.checked_add(1).unwrap();
+        marketplace.listed_products = marketplace.listed_products.checked_add(1).unwrap();
 
         Ok(())
     }
 
-    /// Update an existing product listing
+    /// Update an existing product listing. Allowed while the marketplace is
+    /// paused, so sellers can still deactivate or correct their listings.
     pub fn update_product(
         ctx: Context<UpdateProduct>,
-        title: Option<String>,
         description: Option<String>,
         price: Option<u64>,
         quantity: Option<u64>,
         metadata_uri: Option<String>,
         status: Option<ProductStatus>,
+        return_window_seconds: Option<i64>,
+        tags: Option<Vec<String>>,
+        min_order_quantity: Option<u64>,
     ) -> Result<()> {
         let product = &mut ctx.accounts.product;
+        let was_closed = product.is_closed();
 
         // Apply updates conditionally
-        if let Some(title) = title {
-            require!(title.len() <= 50, MarketplaceError::TitleTooLong);
-            product.title = title;
-        }
-
         if let Some(description) = description {
-            require!(description.len() <= 1000, MarketplaceError::DescriptionTooLong);
+            require!(description.len() <= MAX_DESCRIPTION_LEN, MarketplaceError::DescriptionTooLong);
             product.description = description;
         }
 
@@ -100,17 +268,68 @@ pub mod marketplace {
             product.price = price;
         }
 
+        // Open escrows settle against their reserved units, so the stock
+        // can't drop below them
         if let Some(quantity) = quantity {
+            require!(
+                quantity >= product.reserved_quantity,
+                MarketplaceError::QuantityBelowReserved
+            );
             product.quantity = quantity;
         }
 
         if let Some(metadata_uri) = metadata_uri {
-            require!(metadata_uri.len() <= 200, MarketplaceError::MetadataUriTooLong);
+            require!(metadata_uri.len() <= MAX_METADATA_URI_LEN, MarketplaceError::MetadataUriTooLong);
             product.metadata_uri = metadata_uri;
         }
 
-        if let Some(status) = status {
-            product.status = status;
+        // Restocking a sold out listing puts it back on sale, unless the
+        // seller sets a status in the same update. Flagging is left to the
+        // marketplace authority, and a listing it took down stays down.
+        match status {
+            Some(status) => {
+                require!(
+                    product.status != ProductStatus::Flagged && status != ProductStatus::Flagged,
+                    MarketplaceError::ProductFlagged
+                );
+                require!(
+                    !product.moderated || status == ProductStatus::Deactivated,
+                    MarketplaceError::ProductModerated
+                );
+                product.status = status;
+            }
+            None => {
+                if product.status == ProductStatus::SoldOut && quantity.map_or(false, |quantity| quantity > 0) {
+                    product.status = ProductStatus::Active;
+                }
+            }
+        }
+
+        // A closed listing back on sale must hold its fingerprint, so it
+        // can't reopen beside a relisting of the same title
+        if was_closed && product.status == ProductStatus::Active {
+            let claim = &mut ctx.accounts.product_fingerprint;
+            if claim.product == Pubkey::default() {
+                claim.product = product.key();
+            }
+            require_keys_eq!(claim.product, product.key(), MarketplaceError::DuplicateListing);
+        }
+
+        // A new return window only applies to escrows created afterwards,
+        // since each escrow snapshots the window at creation
+        if let Some(return_window_seconds) = return_window_seconds {
+            require!(return_window_seconds >= 0, MarketplaceError::InvalidReturnWindow);
+            product.return_window_seconds = return_window_seconds;
+        }
+
+        if let Some(tags) = tags {
+            validate_tags(&tags)?;
+            product.tags = tags;
+        }
+
+        if let Some(min_order_quantity) = min_order_quantity {
+            require!(min_order_quantity > 0, MarketplaceError::InvalidMinOrderQuantity);
+            product.min_order_quantity = min_order_quantity;
         }
 
         // Update the timestamp
@@ -119,12 +338,75 @@ pub mod marketplace {
         Ok(())
     }
 
-    /// Purchase a product from the marketplace
-    /// This function will be called by the escrow program
+    /// Retitle a product. A title that changes the listing's fingerprint
+    /// moves its claim there, which must be free as when listing.
+    pub fn rename_product(ctx: Context<RenameProduct>, title: String) -> Result<()> {
+        require!(title.len() <= MAX_TITLE_LEN, MarketplaceError::TitleTooLong);
+        
+        let product = &mut ctx.accounts.product;
+        let fingerprint = product_fingerprint(&title, product.category, &product.seller);
+        if fingerprint != product.fingerprint {
+            let claim = match ctx.accounts.new_product_fingerprint.as_mut() {
+                Some(claim) => claim,
+                None => return err!(MarketplaceError::FingerprintClaimRequired),
+            };
+            if claim.product != Pubkey::default() {
+                let previous = match &ctx.accounts.fingerprinted_product {
+                    Some(previous) => previous,
+                    None => return err!(MarketplaceError::DuplicateListing),
+                };
+                require_keys_eq!(previous.key(), claim.product, MarketplaceError::DuplicateListing);
+                require!(previous.is_closed(), MarketplaceError::DuplicateListing);
+            }
+            claim.seller = product.seller;
+            claim.fingerprint = fingerprint;
+            claim.product = product.key();
+            claim.bump = *ctx.bumps.get("new_product_fingerprint").unwrap();
+            
+            // Release the old fingerprint so the seller can list that title again
+            let previous_claim = &mut ctx.accounts.product_fingerprint;
+            if previous_claim.product == product.key() {
+                previous_claim.product = Pubkey::default();
+            }
+            product.fingerprint = fingerprint;
+        }
+        
+        product.title = title;
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Delete a deactivated or sold out listing with no open escrows,
+    /// returning its rent to the seller. Product indices are never reused,
+    /// so `product_count` keeps counting deleted listings.
+    pub fn delete_product(ctx: Context<DeleteProduct>) -> Result<()> {
+        let product = &ctx.accounts.product;
+        require!(product.is_closed(), MarketplaceError::ProductNotClosed);
+        require!(product.active_escrows == 0, MarketplaceError::ActiveEscrowsExist);
+
+        // Release the fingerprint if this listing still holds it, since the
+        // listing can no longer be shown to have closed
+        let claim = &mut ctx.accounts.product_fingerprint;
+        if claim.product == product.key() {
+            claim.product = Pubkey::default();
+        }
+
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.listed_products = marketplace.listed_products.saturating_sub(1);
+
+        Ok(())
+    }
+
+    /// Purchase `quantity` units of a product for an escrow, reserving them
+    /// until the escrow settles through `record_escrow_closed`. Only the
+    /// escrow program may call this, signing with its authority PDA.
     pub fn purchase_product(
         ctx: Context<PurchaseProduct>,
         quantity: u64,
     ) -> Result<()> {
+        require!(!ctx.accounts.marketplace.is_paused, MarketplaceError::MarketplacePaused);
+        
         let product = &mut ctx.accounts.product;
         
         // Verify the product is active
@@ -133,193 +415,2298 @@ pub mod marketplace {
             MarketplaceError::ProductNotActive
         );
         
-        // Verify there's enough inventory
+        // Seasonal listings can only be bought within their window
+        let now = Clock::get()?.unix_timestamp;
+        if let Some(available_from) = product.available_from {
+            require!(now >= available_from, MarketplaceError::ProductNotYetAvailable);
+        }
+        if let Some(available_until) = product.available_until {
+            require!(now <= available_until, MarketplaceError::ProductSeasonEnded);
+        }
+        
+        // Sellers on hold keep their listings but take no new orders
+        require!(
+            !SellerStatus::is_on_hold(&ctx.accounts.seller_status)?,
+            MarketplaceError::SellerOnHold
+        );
+        
+        // Wholesale listings sell no less than their minimum order
         require!(
-            product.quantity >= quantity,
+            quantity >= product.min_order_quantity,
+            MarketplaceError::BelowMinimumOrder
+        );
+        
+        // Units held by other open escrows cannot be sold again
+        require!(
+            product.available_quantity() >= quantity,
             MarketplaceError::InsufficientInventory
         );
         
-        // Update the product quantity
-        product.quantity = product.quantity.checked_sub(quantity).unwrap();
+        product.active_escrows = product.active_escrows.checked_add(1).unwrap();
+        product.reserved_quantity = product.reserved_quantity.checked_add(quantity).unwrap();
+        product.updated_at = now;
         
-        // If quantity becomes 0, mark as sold out
-        if product.quantity == 0 {
-            product.status = ProductStatus::SoldOut;
-        }
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.active_escrows = marketplace.active_escrows.checked_add(1).unwrap();
         
-        // Update the timestamp
+        Ok(())
+    }
+
+    /// Change the currency a product is priced in, along with its price in
+    /// the new currency's base units. Escrows snapshot the currency at
+    /// creation, so the change is only allowed once no escrow against the
+    /// product is still open. Volume tiers and the reference price were in
+    /// the old currency, so they are cleared.
+    pub fn change_currency(
+        ctx: Context<ChangeCurrency>,
+        new_currency: CurrencyType,
+        new_price: u64,
+    ) -> Result<()> {
+        let marketplace = &ctx.accounts.marketplace;
+        let product = &mut ctx.accounts.product;
+        
+        require!(product.active_escrows == 0, MarketplaceError::ActiveEscrowsExist);
+        require!(
+            marketplace.accepts(&new_currency),
+            MarketplaceError::CurrencyNotAccepted
+        );
+        require!(new_price > 0, MarketplaceError::InvalidPrice);
+        
+        // The smallest order the product allows must reach the new
+        // currency's escrow minimum and fit under its cap
+        let min_order_value = new_price.saturating_mul(product.min_order_quantity);
+        require!(
+            min_order_value >= marketplace.min_escrow_amount(&new_currency)
+                && marketplace.within_max_escrow_value(&new_currency, min_order_value),
+            MarketplaceError::PriceOutsideEscrowLimits
+        );
+        
+        product.currency = new_currency;
+        product.price = new_price;
+        product.price_tiers.clear();
+        product.reference_price = 0;
         product.updated_at = Clock::get()?.unix_timestamp;
         
         Ok(())
     }
-}
 
-/// Account structure for marketplace state
-#[account]
-pub struct MarketplaceState {
-    /// Authority that can manage marketplace settings
-    pub authority: Pubkey,
-    /// Counter for total products listed
-    pub product_count: u64,
-    /// Marketplace fee in basis points (1/100 of a percent)
-    pub fees_basis_points: u16,
-    /// Account where fees are collected
-    pub fee_destination: Pubkey,
-    /// Whether the marketplace is paused
-    pub is_paused: bool,
-    /// PDA bump seed
-    pub bump: u8,
-}
+    /// Reprice several of the seller's products at once. The products are
+    /// passed in `remaining_accounts`, matched by position with `prices`, and
+    /// the whole batch fails if any product cannot be repriced.
+    pub fn reprice_products<'info>(
+        ctx: Context<'_, '_, 'info, 'info, RepriceProducts<'info>>,
+        prices: Vec<u64>,
+    ) -> Result<()> {
+        require!(
+            !prices.is_empty() && prices.len() == ctx.remaining_accounts.len(),
+            MarketplaceError::InvalidRepriceBatch
+        );
+        
+        let now = Clock::get()?.unix_timestamp;
+        let seller = ctx.accounts.seller.key();
+        let marketplace_key = ctx.accounts.marketplace.key();
+        
+        for (account_info, price) in ctx.remaining_accounts.iter().zip(prices) {
+            require!(account_info.is_writable, MarketplaceError::InvalidRepriceBatch);
+            let mut product = Account::<Product>::try_from(account_info)?;
+            require!(product.seller == seller, MarketplaceError::NotProductOwner);
+            require!(
+                product.marketplace == marketplace_key,
+                MarketplaceError::ProductMarketplaceMismatch
+            );
+            require!(product.active_escrows == 0, MarketplaceError::ActiveEscrowsExist);
+            require!(price > 0, MarketplaceError::InvalidPrice);
+            
+            product.price = price;
+            product.updated_at = now;
+            product.exit(&crate::ID)?;
+        }
+        
+        Ok(())
+    }
 
-/// Account structure for a product listing
-#[account]
-pub struct Product {
-    /// Reference to marketplace
-    pub marketplace: Pubkey,
-    /// Seller of the product
-    pub seller: Pubkey,
-    /// Price in the specified currency
-    pub price: u64,
-    /// Available quantity
-    pub quantity: u64,
-    /// Currency type (SOL, USDC, etc.)
-    pub currency: CurrencyType,
-    /// Product title (max 50 chars)
-    pub title: String,
-    /// Product description (max 1000 chars)
-    pub description: String,
-    /// URI to additional metadata (IPFS/Arweave)
-    pub metadata_uri: String,
-    /// Current product status
-    pub status: ProductStatus,
-    /// Timestamp of creation
-    pub created_at: i64,
-    /// Timestamp of last update
-    pub updated_at: i64,
-    /// Product category
-    pub category: String,
-    /// PDA bump seed
-    pub bump: u8,
-}
+    /// Record that an escrow has been opened against a product, reserving
+    /// `quantity` units for it. Only callable by the escrow program.
+    pub fn record_escrow_opened(ctx: Context<TrackEscrow>, quantity: u64) -> Result<()> {
+        let product = &mut ctx.accounts.product;
+        product.active_escrows = product.active_escrows.checked_add(1).unwrap();
+        product.reserved_quantity = product.reserved_quantity.checked_add(quantity).unwrap();
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.active_escrows = marketplace.active_escrows.checked_add(1).unwrap();
+        
+        Ok(())
+    }
 
-/// Currency types supported by the marketplace
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum CurrencyType {
-    SOL,
-    USDC,
-    USDT,
-}
+    /// Record that an escrow against a product has reached a terminal state,
+    /// releasing the `reserved_quantity` units it held and taking the
+    /// `quantity_sold` units out of inventory. `completed_volume` is the
+    /// amount sold, or zero when the escrow did not complete, and `fee` the
+    /// marketplace fee taken from it. Cart line items settle the same way.
+    /// Only callable by the escrow program.
+    pub fn record_escrow_closed(
+        ctx: Context<TrackEscrow>,
+        reserved_quantity: u64,
+        quantity_sold: u64,
+        completed_volume: u64,
+        fee: u64,
+    ) -> Result<()> {
+        let product = &mut ctx.accounts.product;
+        require!(
+            product.quantity >= quantity_sold,
+            MarketplaceError::InsufficientInventory
+        );
+        product.quantity -= quantity_sold;
+        // A flagged listing stays flagged until the authority clears it
+        if product.quantity == 0 && quantity_sold > 0 && product.status != ProductStatus::Flagged {
+            product.status = ProductStatus::SoldOut;
+        }
+        product.active_escrows = product.active_escrows.saturating_sub(1);
+        product.reserved_quantity = product.reserved_quantity.saturating_sub(reserved_quantity);
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.active_escrows = marketplace.active_escrows.saturating_sub(1);
+        let volume = marketplace.volume_mut(&product.currency);
+        *volume = volume.checked_add(completed_volume).unwrap();
+        let fees_collected = marketplace.fees_collected_mut(&product.currency);
+        *fees_collected = fees_collected.checked_add(fee).unwrap();
+        
+        Ok(())
+    }
 
-/// Status of a product
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum ProductStatus {
-    Active,
-    SoldOut,
-    Deactivated,
-    Flagged,
-}
+    /// Emit the marketplace's running totals, so operators can chart them
+    /// without an indexer
+    pub fn snapshot_stats(ctx: Context<UpdateMarketplace>) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        let slot = Clock::get()?.slot;
+        
+        emit!(StatsSnapshot {
+            marketplace: marketplace.key(),
+            slot,
+            previous_snapshot_slot: marketplace.last_snapshot_slot,
+            product_count: marketplace.product_count,
+            active_escrows: marketplace.active_escrows,
+            sol_volume: marketplace.sol_volume,
+            usdc_volume: marketplace.usdc_volume,
+            usdt_volume: marketplace.usdt_volume,
+            sol_fees_collected: marketplace.sol_fees_collected,
+            usdc_fees_collected: marketplace.usdc_fees_collected,
+            usdt_fees_collected: marketplace.usdt_fees_collected,
+        });
+        marketplace.last_snapshot_slot = slot;
+        
+        Ok(())
+    }
 
-/// Accounts required for initializing a marketplace
-#[derive(Accounts)]
-pub struct InitializeMarketplace<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + std::mem::size_of::<MarketplaceState>(),
-        seeds = [b"marketplace", authority.key().as_ref()],
-        bump
-    )]
-    pub marketplace: Account<'info, MarketplaceState>,
-    
-    /// Fee destination account
-    pub fee_destination: AccountInfo<'info>,
-    
-    pub system_program: Program<'info, System>,
-}
+    /// Permanently close a wound-down marketplace, returning its rent to the
+    /// authority. Every escrow must have settled and every listing been removed.
+    pub fn close_marketplace(ctx: Context<CloseMarketplace>) -> Result<()> {
+        let marketplace = &ctx.accounts.marketplace;
+        require!(marketplace.active_escrows == 0, MarketplaceError::ActiveEscrowsExist);
+        require!(marketplace.listed_products == 0, MarketplaceError::ListedProductsExist);
+        
+        emit!(MarketplaceClosed {
+            marketplace: marketplace.key(),
+            authority: marketplace.authority,
+            product_count: marketplace.product_count,
+            closed_at: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
+    }
 
-/// Accounts required for creating a product
-#[derive(Accounts)]
-pub struct CreateProduct<'info> {
-    #[account(mut)]
-    pub seller: Signer<'info>,
-    
-    #[account(
-        mut,
-        seeds = [b"marketplace", marketplace.authority.as_ref()],
-        bump = marketplace.bump
-    )]
-    pub marketplace: Account<'info, MarketplaceState>,
-    
-    #[account(
-        init,
-        payer = seller,
-        space = 8 + std::mem::size_of::<Product>() + 50 + 1000 + 200 + 20, // Additional space for strings
-        seeds = [
-            b"product",
-            marketplace.key().as_ref(),
-            seller.key().as_ref(),
-            &marketplace.product_count.to_le_bytes()
-        ],
-        bump
-    )]
-    pub product: Account<'info, Product>,
-    
-    pub system_program: Program<'info, System>,
-}
+    /// Set which currencies new listings may use, as a bitmask of
+    /// `CurrencyType` flags
+    pub fn set_accepted_currencies(ctx: Context<UpdateMarketplace>, accepted_currencies: u8) -> Result<()> {
+        require!(
+            accepted_currencies != 0 && accepted_currencies & !CurrencyType::ALL_FLAGS == 0,
+            MarketplaceError::InvalidCurrencyMask
+        );
+        ctx.accounts.marketplace.accepted_currencies = accepted_currencies;
+        
+        Ok(())
+    }
 
-/// Accounts required for updating a product
-#[derive(Accounts)]
-pub struct UpdateProduct<'info> {
-    #[account(
-        constraint = seller.key() == product.seller @ MarketplaceError::NotProductOwner
-    )]
-    pub seller: Signer<'info>,
-    
-    #[account(
-        mut,
-        seeds = [
-            b"product",
-            product.marketplace.as_ref(),
-            product.seller.as_ref(),
-            &get_product_index(product.key()).to_le_bytes()
-        ],
-        bump = product.bump
-    )]
-    pub product: Account<'info, Product>,
-}
+    /// Set the currency listings are priced in when created without one
+    pub fn set_default_currency(ctx: Context<UpdateMarketplace>, currency: CurrencyType) -> Result<()> {
+        require!(
+            ctx.accounts.marketplace.accepts(&currency),
+            MarketplaceError::CurrencyNotAccepted
+        );
+        ctx.accounts.marketplace.default_currency = currency;
+        
+        Ok(())
+    }
 
-/// Accounts required for purchasing a product
-#[derive(Accounts)]
-pub struct PurchaseProduct<'info> {
-    /// The buyer of the product
+    /// Set the seasonal window in which a product can be purchased. Either
+    /// bound may be omitted to leave that side of the window open.
+    pub fn set_availability_window(
+        ctx: Context<UpdateProduct>,
+        available_from: Option<i64>,
+        available_until: Option<i64>,
+    ) -> Result<()> {
+        validate_availability_window(available_from, available_until)?;
+        
+        let product = &mut ctx.accounts.product;
+        product.available_from = available_from;
+        product.available_until = available_until;
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Set how long buyers' offers below the list price stay open for the
+    /// seller to accept. Zero stops the product taking new offers.
+    pub fn set_offer_window(ctx: Context<UpdateProduct>, offer_window_seconds: i64) -> Result<()> {
+        require!(offer_window_seconds >= 0, MarketplaceError::InvalidOfferWindow);
+        
+        let product = &mut ctx.accounts.product;
+        product.offer_window_seconds = offer_window_seconds;
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Replace a product's volume price tiers. Tiers must be in strictly
+    /// ascending order of quantity; an empty list removes volume pricing.
+    pub fn set_price_tiers(ctx: Context<UpdateProduct>, tiers: Vec<PriceTier>) -> Result<()> {
+        require!(tiers.len() <= MAX_PRICE_TIERS, MarketplaceError::InvalidPriceTiers);
+        require!(
+            tiers.windows(2).all(|pair| pair[0].min_quantity < pair[1].min_quantity),
+            MarketplaceError::InvalidPriceTiers
+        );
+        require!(
+            tiers.iter().all(|tier| tier.min_quantity > 0 && tier.unit_price > 0),
+            MarketplaceError::InvalidPriceTiers
+        );
+        
+        let product = &mut ctx.accounts.product;
+        product.price_tiers = tiers;
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Pause listing and buying on the marketplace. Pausing an already
+    /// paused marketplace succeeds without change.
+    pub fn pause_marketplace(ctx: Context<UpdateMarketplace>) -> Result<()> {
+        ctx.accounts.marketplace.is_paused = true;
+        
+        Ok(())
+    }
+
+    /// Resume listing and buying on the marketplace. Unpausing a running
+    /// marketplace succeeds without change.
+    pub fn unpause_marketplace(ctx: Context<UpdateMarketplace>) -> Result<()> {
+        ctx.accounts.marketplace.is_paused = false;
+        
+        Ok(())
+    }
+
+    /// Halt or resume releases of escrowed funds, independently of listing
+    /// pause, e.g. while a suspected exploit is investigated
+    pub fn set_releases_paused(ctx: Context<UpdateMarketplace>, paused: bool) -> Result<()> {
+        ctx.accounts.marketplace.releases_paused = paused;
+        
+        Ok(())
+    }
+
+    /// Set the Switchboard VRF account used to rotate featured products.
+    /// Only rounds the account produces after this point can drive a
+    /// rotation, so configuring an account again can't replay old results.
+    pub fn set_featured_vrf(ctx: Context<SetFeaturedVrf>) -> Result<()> {
+        let vrf_round = ctx.accounts.vrf.load()?.counter;
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.featured_vrf = ctx.accounts.vrf.key();
+        marketplace.featured_vrf_round = vrf_round;
+        
+        Ok(())
+    }
+
+    /// Set the swap program used to convert SOL payouts into a seller's
+    /// preferred currency. The default pubkey disables conversion.
+    pub fn set_swap_program(ctx: Context<UpdateMarketplace>, swap_program: Pubkey) -> Result<()> {
+        ctx.accounts.marketplace.swap_program = swap_program;
+        
+        Ok(())
+    }
+
+    /// Set the smallest escrow accepted in each currency, in that currency's
+    /// base units (lamports for SOL, 6-decimal units for USDC and USDT)
+    pub fn set_min_escrow_amounts(
+        ctx: Context<UpdateMarketplace>,
+        min_sol: u64,
+        min_usdc: u64,
+        min_usdt: u64,
+    ) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.min_escrow_sol = min_sol;
+        marketplace.min_escrow_usdc = min_usdc;
+        marketplace.min_escrow_usdt = min_usdt;
+        
+        Ok(())
+    }
+
+    /// Cap the value of any single escrow in each currency, in that
+    /// currency's base units. Zero leaves the currency uncapped.
+    pub fn set_max_escrow_values(
+        ctx: Context<UpdateMarketplace>,
+        max_sol: u64,
+        max_usdc: u64,
+        max_usdt: u64,
+    ) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.max_escrow_sol = max_sol;
+        marketplace.max_escrow_usdc = max_usdc;
+        marketplace.max_escrow_usdt = max_usdt;
+        
+        Ok(())
+    }
+
+    /// Require new listings to commit to a hash of their off-chain metadata
+    pub fn set_require_metadata_hash(ctx: Context<UpdateMarketplace>, required: bool) -> Result<()> {
+        ctx.accounts.marketplace.require_metadata_hash = required;
+        
+        Ok(())
+    }
+
+    /// Penalize buyers who keep cancelling funded escrows. Once a buyer has
+    /// cancelled more than `threshold` within `window_seconds`, each further
+    /// cancellation forfeits `fee_bps` more of the refund to the seller, up
+    /// to all of it. A zero threshold disables the penalty.
+    pub fn set_cancellation_penalty(
+        ctx: Context<UpdateMarketplace>,
+        threshold: u64,
+        window_seconds: i64,
+        fee_bps: u16,
+    ) -> Result<()> {
+        require!(window_seconds >= 0, MarketplaceError::InvalidCancellationPenalty);
+        require!(fee_bps <= 10000, MarketplaceError::InvalidCancellationPenalty);
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.cancellation_threshold = threshold;
+        marketplace.cancellation_window_seconds = window_seconds;
+        marketplace.cancellation_fee_bps = fee_bps;
+        
+        Ok(())
+    }
+
+    /// Set the reduced fees for high-volume sellers. Each tier applies to
+    /// sellers with at least `min_sales` completed sales, in ascending order
+    /// of `min_sales`; sellers below the first tier pay `fees_basis_points`.
+    pub fn set_fee_tiers(ctx: Context<UpdateMarketplace>, tiers: Vec<FeeTier>) -> Result<()> {
+        require!(tiers.len() <= MAX_FEE_TIERS, MarketplaceError::InvalidFeeTiers);
+        require!(
+            tiers.windows(2).all(|pair| pair[0].min_sales < pair[1].min_sales),
+            MarketplaceError::InvalidFeeTiers
+        );
+        require!(
+            tiers.iter().all(|tier| tier.fees_basis_points <= 1000),
+            MarketplaceError::FeesTooHigh
+        );
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        marketplace.fee_tiers[..tiers.len()].copy_from_slice(&tiers);
+        marketplace.fee_tier_count = tiers.len() as u8;
+        
+        Ok(())
+    }
+
+    /// Appoint the arbiter who resolves disputes alongside the authority.
+    /// `Pubkey::default()` leaves disputes to the authority alone.
+    pub fn set_arbiter(ctx: Context<UpdateMarketplace>, arbiter: Pubkey) -> Result<()> {
+        ctx.accounts.marketplace.arbiter = arbiter;
+        
+        Ok(())
+    }
+
+    /// Only count a buyer's purchases toward their reputation once they have
+    /// reviewed them
+    pub fn set_require_purchase_reviews(ctx: Context<UpdateMarketplace>, required: bool) -> Result<()> {
+        ctx.accounts.marketplace.require_purchase_reviews = required;
+        
+        Ok(())
+    }
+
+    /// Require buyers to attest their source of funds when funding escrows
+    pub fn set_require_source_attestation(ctx: Context<UpdateMarketplace>, required: bool) -> Result<()> {
+        ctx.accounts.marketplace.require_source_attestation = required;
+        
+        Ok(())
+    }
+
+    /// Set how long after shipment an order can be disputed. Escrows keep
+    /// the window in force when they were created; zero lifts the limit.
+    pub fn set_dispute_window(ctx: Context<UpdateMarketplace>, dispute_window_seconds: i64) -> Result<()> {
+        require!(dispute_window_seconds >= 0, MarketplaceError::InvalidDisputeWindow);
+        ctx.accounts.marketplace.dispute_window_seconds = dispute_window_seconds;
+        
+        Ok(())
+    }
+
+    /// Set how long after shipment a seller may claim an escrow the buyer has
+    /// neither confirmed nor disputed
+    pub fn set_auto_release_window(ctx: Context<UpdateMarketplace>, auto_release_seconds: i64) -> Result<()> {
+        require!(auto_release_seconds > 0, MarketplaceError::InvalidAutoReleaseWindow);
+        ctx.accounts.marketplace.auto_release_seconds = auto_release_seconds;
+        
+        Ok(())
+    }
+
+    /// Set how many slots after a dispute opens evidence is accepted. Escrows
+    /// keep the window in force when they were created; zero lifts the cutoff.
+    pub fn set_evidence_window(ctx: Context<UpdateMarketplace>, evidence_window_slots: u64) -> Result<()> {
+        ctx.accounts.marketplace.evidence_window_slots = evidence_window_slots;
+        
+        Ok(())
+    }
+
+    /// Set how long dispute resolutions wait before paying out, and who decides
+    /// appeals lodged in that time. A zero window pays out immediately.
+    pub fn set_appeal_policy(
+        ctx: Context<UpdateMarketplace>,
+        appeal_window_seconds: i64,
+        appeal_arbitrator: Pubkey,
+    ) -> Result<()> {
+        require!(
+            appeal_window_seconds >= 0
+                && (appeal_window_seconds == 0 || appeal_arbitrator != Pubkey::default()),
+            MarketplaceError::InvalidAppealPolicy
+        );
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.appeal_window_seconds = appeal_window_seconds;
+        marketplace.appeal_arbitrator = appeal_arbitrator;
+        
+        Ok(())
+    }
+
+    /// Change the marketplace fee and default fee destination. Escrows keep
+    /// the fee in force when they were created, so only later escrows pay
+    /// the new fee; fees of open escrows go to the new destination when they
+    /// settle.
+    pub fn update_fees(ctx: Context<UpdateFees>, fees_basis_points: u16) -> Result<()> {
+        require!(fees_basis_points <= 1000, MarketplaceError::FeesTooHigh);
+        validate_fee_destination(&ctx.accounts.fee_destination, &CurrencyType::SOL)?;
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        marketplace.fees_basis_points = fees_basis_points;
+        marketplace.fee_destination = ctx.accounts.fee_destination.key();
+        
+        Ok(())
+    }
+
+    /// Route fees taken in `currency` to the `fee_destination` account instead
+    /// of the default fee destination. Passing the default pubkey restores the
+    /// default.
+    pub fn set_currency_fee_destination(
+        ctx: Context<SetCurrencyFeeDestination>,
+        currency: CurrencyType,
+    ) -> Result<()> {
+        let destination = ctx.accounts.fee_destination.key();
+        if destination != Pubkey::default() {
+            validate_fee_destination(&ctx.accounts.fee_destination, &currency)?;
+        }
+        
+        let marketplace = &mut ctx.accounts.marketplace;
+        match currency {
+            CurrencyType::SOL => marketplace.sol_fee_destination = destination,
+            CurrencyType::USDC => marketplace.usdc_fee_destination = destination,
+            CurrencyType::USDT => marketplace.usdt_fee_destination = destination,
+        }
+        
+        Ok(())
+    }
+
+    /// Set the mint that escrows in a token `currency` must be paid in.
+    /// Escrows keep the mint in force when they were created; passing the
+    /// default pubkey accepts any mint with the currency's decimals.
+    pub fn set_currency_mint(
+        ctx: Context<UpdateMarketplace>,
+        currency: CurrencyType,
+        mint: Pubkey,
+    ) -> Result<()> {
+        let marketplace = &mut ctx.accounts.marketplace;
+        match currency {
+            CurrencyType::SOL => return err!(MarketplaceError::InvalidCurrencyMint),
+            CurrencyType::USDC => marketplace.usdc_mint = mint,
+            CurrencyType::USDT => marketplace.usdt_mint = mint,
+        }
+        
+        Ok(())
+    }
+
+    /// Choose who receives the rounding remainder when escrows are split
+    /// between buyer and seller. Escrows keep the policy in force when they
+    /// were created.
+    pub fn set_rounding_policy(ctx: Context<UpdateMarketplace>, policy: RoundingPolicy) -> Result<()> {
+        ctx.accounts.marketplace.rounding_policy = policy;
+        
+        Ok(())
+    }
+
+    /// Cap the number of escrows a buyer may hold open at once, or lift the
+    /// cap with 0
+    pub fn set_max_open_escrows_per_buyer(ctx: Context<UpdateMarketplace>, max_open_escrows: u64) -> Result<()> {
+        ctx.accounts.marketplace.max_open_escrows_per_buyer = max_open_escrows;
+        
+        Ok(())
+    }
+
+    /// Return how the marketplace's rounding policy splits `total` when the
+    /// seller is owed `seller_bps` of it
+    pub fn preview_split(ctx: Context<PreviewSplit>, total: u64, seller_bps: u16) -> Result<SplitShares> {
+        require!(seller_bps <= 10000, MarketplaceError::InvalidBasisPoints);
+        
+        let (seller, buyer) = ctx
+            .accounts
+            .marketplace
+            .rounding_policy
+            .split(total, seller_bps as u64, 10000)
+            .ok_or(MarketplaceError::InvalidBasisPoints)?;
+        Ok(SplitShares { seller, buyer })
+    }
+
+    /// Set the share of a defaulted layaway's payments forfeited to the seller
+    pub fn set_layaway_forfeit(ctx: Context<UpdateMarketplace>, forfeit_bps: u16) -> Result<()> {
+        require!(forfeit_bps <= 10000, MarketplaceError::InvalidBasisPoints);
+        ctx.accounts.marketplace.layaway_forfeit_bps = forfeit_bps;
+        
+        Ok(())
+    }
+
+    /// Set how long after a seller-favored dispute a linked review may be removed
+    pub fn set_review_removal_grace(
+        ctx: Context<UpdateMarketplace>,
+        grace_seconds: i64,
+    ) -> Result<()> {
+        require!(grace_seconds >= 0, MarketplaceError::InvalidGracePeriod);
+        ctx.accounts.marketplace.review_removal_grace_seconds = grace_seconds;
+        
+        Ok(())
+    }
+
+    /// Set how long after an approved return the buyer may revise their review
+    pub fn set_return_review_grace(
+        ctx: Context<UpdateMarketplace>,
+        grace_seconds: i64,
+    ) -> Result<()> {
+        require!(grace_seconds >= 0, MarketplaceError::InvalidGracePeriod);
+        ctx.accounts.marketplace.return_review_grace_seconds = grace_seconds;
+        
+        Ok(())
+    }
+
+    /// Enter one of the seller's products into the marketplace's featured
+    /// rotation, the registry `rotate_featured_products` draws from
+    pub fn enter_featured_rotation(ctx: Context<EnterFeaturedRotation>) -> Result<()> {
+        require!(
+            ctx.accounts.product.status == ProductStatus::Active,
+            MarketplaceError::ProductNotActive
+        );
+        let product = ctx.accounts.product.key();
+        let registry = &mut ctx.accounts.featured_registry;
+        registry.marketplace = ctx.accounts.marketplace.key();
+        registry.bump = *ctx.bumps.get("featured_registry").unwrap();
+        
+        require!(
+            !registry.entries().contains(&product),
+            MarketplaceError::ProductAlreadyInRotation
+        );
+        let index = registry.count as usize;
+        require!(
+            index < registry.products.len(),
+            MarketplaceError::FeaturedRotationFull
+        );
+        
+        registry.products[index] = product;
+        registry.count += 1;
+        
+        Ok(())
+    }
+
+    /// Take one of the seller's products out of the featured rotation,
+    /// giving up any featured slot it holds
+    pub fn leave_featured_rotation(ctx: Context<LeaveFeaturedRotation>) -> Result<()> {
+        let product_key = ctx.accounts.product.key();
+        let registry = &mut ctx.accounts.featured_registry;
+        
+        let index = registry
+            .entries()
+            .iter()
+            .position(|entry| *entry == product_key)
+            .ok_or(MarketplaceError::ProductNotInRotation)?;
+        
+        // Keep entries contiguous by moving the last one into the gap
+        let last = registry.count as usize - 1;
+        let moved = registry.products[last];
+        registry.products[index] = moved;
+        registry.products[last] = Pubkey::default();
+        registry.count -= 1;
+        
+        let product = &mut ctx.accounts.product;
+        product.featured = false;
+        product.featured_until = 0;
+        
+        Ok(())
+    }
+
+    /// Rotate featured placement across the products in the marketplace's
+    /// featured rotation, selecting `feature_count` of them with the
+    /// marketplace's VRF result so the selection can be verified by anyone.
+    /// Every registered product is passed in `remaining_accounts` in
+    /// registry order, so the authority has no say in the candidates. Each
+    /// VRF round drives one rotation, which is logged with its round,
+    /// candidates and picks.
+    pub fn rotate_featured_products<'info>(
+        ctx: Context<'_, '_, 'info, 'info, RotateFeaturedProducts<'info>>,
+        feature_count: u8,
+        featured_seconds: i64,
+    ) -> Result<()> {
+        require!(featured_seconds > 0, MarketplaceError::InvalidFeaturedDuration);
+        let candidates = ctx.accounts.featured_registry.entries().to_vec();
+        require!(
+            feature_count as usize <= candidates.len(),
+            MarketplaceError::TooManyFeaturedProducts
+        );
+        require!(
+            ctx.remaining_accounts.len() == candidates.len(),
+            MarketplaceError::InvalidFeaturedCandidate
+        );
+
+        // Read the randomness produced by the VRF. A round already used
+        // can't be replayed to rotate again on the same result.
+        let vrf = ctx.accounts.vrf.load()?;
+        let randomness = vrf.get_result()?;
+        require!(randomness != [0u8; 32], MarketplaceError::VrfResultNotReady);
+        let vrf_round = vrf.counter;
+        drop(vrf);
+        require!(
+            vrf_round > ctx.accounts.marketplace.featured_vrf_round,
+            MarketplaceError::VrfResultAlreadyUsed
+        );
+
+        let selected = select_featured_indices(&randomness, candidates.len(), feature_count as usize);
+
+        let now = Clock::get()?.unix_timestamp;
+        let featured_until = now
+            .checked_add(featured_seconds)
+            .ok_or(MarketplaceError::InvalidFeaturedDuration)?;
+        let mut featured = Vec::with_capacity(selected.len());
+        let mut remaining = Vec::with_capacity(candidates.len());
+
+        // Every candidate is rewritten so products that lose their slot are unfeatured
+        for (index, account_info) in ctx.remaining_accounts.iter().enumerate() {
+            require_keys_eq!(
+                account_info.key(),
+                candidates[index],
+                MarketplaceError::InvalidFeaturedCandidate
+            );
+            // A deleted product leaves the rotation, and a slot drawn for it stays empty
+            if account_info.data_is_empty() {
+                continue;
+            }
+            require!(account_info.is_writable, MarketplaceError::InvalidFeaturedCandidate);
+            remaining.push(candidates[index]);
+            let mut product = Account::<Product>::try_from(account_info)?;
+
+            if selected.contains(&index) {
+                product.featured = true;
+                product.featured_until = featured_until;
+                featured.push(candidates[index]);
+            } else {
+                product.featured = false;
+                product.featured_until = 0;
+            }
+            product.exit(&crate::ID)?;
+        }
+
+        let registry = &mut ctx.accounts.featured_registry;
+        if remaining.len() < candidates.len() {
+            registry.products = [Pubkey::default(); MAX_FEATURED_CANDIDATES];
+            registry.products[..remaining.len()].copy_from_slice(&remaining);
+            registry.count = remaining.len() as u8;
+        }
+
+        ctx.accounts.marketplace.featured_vrf_round = vrf_round;
+        emit!(FeaturedProductsRotated {
+            marketplace: ctx.accounts.marketplace.key(),
+            vrf_round,
+            candidates,
+            featured,
+            featured_until,
+        });
+
+        Ok(())
+    }
+
+    /// Publish the USD price of a currency for reference pricing. The price is
+    /// whatever the marketplace authority submits; no oracle backs it.
+    pub fn set_price_feed(
+        ctx: Context<SetPriceFeed>,
+        currency: CurrencyType,
+        usd_price: u64,
+    ) -> Result<()> {
+        require!(usd_price > 0, MarketplaceError::InvalidPrice);
+        
+        let price_feed = &mut ctx.accounts.price_feed;
+        price_feed.marketplace = ctx.accounts.marketplace.key();
+        price_feed.currency = currency;
+        price_feed.usd_price = usd_price;
+        price_feed.updated_at = Clock::get()?.unix_timestamp;
+        price_feed.bump = *ctx.bumps.get("price_feed").unwrap();
+        
+        Ok(())
+    }
+
+    /// Recompute a product's reference price from the latest price feed
+    pub fn refresh_reference_price(ctx: Context<RefreshReferencePrice>) -> Result<()> {
+        let product = &mut ctx.accounts.product;
+        product.reference_price = ctx.accounts.price_feed.reference_price(
+            product.marketplace,
+            product.price,
+            &product.currency,
+        )?;
+        
+        Ok(())
+    }
+
+    /// Waive the marketplace fee on a product, e.g. a subsidized staple, or
+    /// reinstate it. Escrows keep the exemption in force when they were
+    /// created.
+    pub fn set_fee_exempt(ctx: Context<SetFeeExempt>, fee_exempt: bool) -> Result<()> {
+        ctx.accounts.product.fee_exempt = fee_exempt;
+        
+        Ok(())
+    }
+
+    /// Flag a product for review by the marketplace authority, giving the
+    /// reason. Each wallet can flag a product once.
+    pub fn flag_product(ctx: Context<FlagProduct>, reason: String) -> Result<()> {
+        require!(reason.len() <= MAX_FLAG_REASON_LEN, MarketplaceError::FlagReasonTooLong);
+        
+        let product_flag = &mut ctx.accounts.product_flag;
+        product_flag.product = ctx.accounts.product.key();
+        product_flag.flagger = ctx.accounts.flagger.key();
+        product_flag.reason = reason;
+        product_flag.status = FlagStatus::Open;
+        product_flag.created_at = Clock::get()?.unix_timestamp;
+        product_flag.bump = *ctx.bumps.get("product_flag").unwrap();
+        
+        Ok(())
+    }
+
+    /// Resolve an open flag, either flagging or deactivating the product or
+    /// dismissing the flag
+    pub fn resolve_flag(ctx: Context<ResolveFlag>, resolution: FlagResolution) -> Result<()> {
+        let product_flag = &mut ctx.accounts.product_flag;
+        require!(product_flag.status == FlagStatus::Open, MarketplaceError::FlagAlreadyResolved);
+        
+        let product = &mut ctx.accounts.product;
+        match resolution {
+            FlagResolution::Flag => {
+                product.status = ProductStatus::Flagged;
+                product_flag.status = FlagStatus::Upheld;
+            }
+            FlagResolution::Deactivate => {
+                product.status = ProductStatus::Deactivated;
+                product.moderated = true;
+                product_flag.status = FlagStatus::Upheld;
+            }
+            FlagResolution::Dismiss => product_flag.status = FlagStatus::Dismissed,
+        }
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Clear a product's `Flagged` status, either reinstating the listing or
+    /// taking it down. A listing taken down can be deleted by its seller but
+    /// never put back on sale.
+    pub fn clear_product_flag(ctx: Context<ClearProductFlag>, take_down: bool) -> Result<()> {
+        let product = &mut ctx.accounts.product;
+        require!(product.status == ProductStatus::Flagged, MarketplaceError::ProductNotFlagged);
+        
+        if take_down {
+            product.status = ProductStatus::Deactivated;
+            product.moderated = true;
+        } else if product.quantity == 0 {
+            product.status = ProductStatus::SoldOut;
+        } else {
+            product.status = ProductStatus::Active;
+        }
+        product.updated_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Put the seller's listings on hold, or lift the hold
+    pub fn set_seller_hold(ctx: Context<SetSellerHold>, on_hold: bool) -> Result<()> {
+        let seller_status = &mut ctx.accounts.seller_status;
+        seller_status.marketplace = ctx.accounts.marketplace.key();
+        seller_status.seller = ctx.accounts.seller.key();
+        seller_status.on_hold = on_hold;
+        seller_status.bump = *ctx.bumps.get("seller_status").unwrap();
+        
+        Ok(())
+    }
+
+    /// Choose the currency SOL payouts are converted to before reaching the
+    /// seller, and the most the conversion may fall short of the price feeds,
+    /// in basis points. `None` keeps payouts in SOL.
+    pub fn set_payout_preference(
+        ctx: Context<SetPayoutPreference>,
+        currency: Option<CurrencyType>,
+        max_slippage_bps: u16,
+    ) -> Result<()> {
+        if let Some(currency) = &currency {
+            require!(*currency != CurrencyType::SOL, MarketplaceError::InvalidPayoutCurrency);
+            require!(
+                ctx.accounts.marketplace.accepts(currency),
+                MarketplaceError::CurrencyNotAccepted
+            );
+        }
+        require!(max_slippage_bps <= 10000, MarketplaceError::InvalidBasisPoints);
+        
+        let seller_status = &mut ctx.accounts.seller_status;
+        seller_status.marketplace = ctx.accounts.marketplace.key();
+        seller_status.seller = ctx.accounts.seller.key();
+        seller_status.preferred_payout_currency = currency;
+        seller_status.max_payout_slippage_bps = max_slippage_bps;
+        seller_status.bump = *ctx.bumps.get("seller_status").unwrap();
+        
+        Ok(())
+    }
+
+    /// Open a page of the super-authority's marketplace registry
+    pub fn initialize_registry_page(ctx: Context<InitializeRegistryPage>, page: u32) -> Result<()> {
+        let registry = &mut ctx.accounts.registry;
+        registry.super_authority = ctx.accounts.super_authority.key();
+        registry.page = page;
+        registry.count = 0;
+        registry.bump = *ctx.bumps.get("registry").unwrap();
+        
+        Ok(())
+    }
+
+    /// Add a child marketplace to a registry page
+    pub fn register_marketplace(ctx: Context<UpdateRegistry>) -> Result<()> {
+        let marketplace = ctx.accounts.marketplace.key();
+        let registry = &mut ctx.accounts.registry;
+        
+        require!(
+            !registry.entries().contains(&marketplace),
+            MarketplaceError::MarketplaceAlreadyRegistered
+        );
+        let index = registry.count as usize;
+        require!(
+            index < registry.marketplaces.len(),
+            MarketplaceError::RegistryPageFull
+        );
+        
+        registry.marketplaces[index] = marketplace;
+        registry.count += 1;
+        
+        Ok(())
+    }
+
+    /// Remove a child marketplace from a registry page
+    pub fn deregister_marketplace(ctx: Context<UpdateRegistry>) -> Result<()> {
+        let marketplace = ctx.accounts.marketplace.key();
+        let registry = &mut ctx.accounts.registry;
+        
+        let index = registry
+            .entries()
+            .iter()
+            .position(|entry| *entry == marketplace)
+            .ok_or(MarketplaceError::MarketplaceNotRegistered)?;
+        
+        // Keep entries contiguous by moving the last one into the gap
+        let last = registry.count as usize - 1;
+        let moved = registry.marketplaces[last];
+        registry.marketplaces[index] = moved;
+        registry.marketplaces[last] = Pubkey::default();
+        registry.count -= 1;
+        
+        Ok(())
+    }
+}
+
+/// Ensure a product's tags are few and short enough to fit its account
+fn validate_tags(tags: &[String]) -> Result<()> {
+    require!(tags.len() <= MAX_TAGS, MarketplaceError::TooManyTags);
+    for tag in tags {
+        require!(tag.len() <= MAX_TAG_LEN, MarketplaceError::TagTooLong);
+    }
+    
+    Ok(())
+}
+
+/// Ensure an availability window, when fully specified, is not inverted
+fn validate_availability_window(available_from: Option<i64>, available_until: Option<i64>) -> Result<()> {
+    if let (Some(available_from), Some(available_until)) = (available_from, available_until) {
+        require!(
+            available_from < available_until,
+            MarketplaceError::InvalidAvailabilityWindow
+        );
+    }
+    
+    Ok(())
+}
+
+/// Ensure a fee destination can receive fees in `currency`: a system account
+/// for SOL, an initialized token account for the stablecoins
+fn validate_fee_destination(destination: &AccountInfo, currency: &CurrencyType) -> Result<()> {
+    match currency {
+        CurrencyType::SOL => require!(
+            destination.owner == &anchor_lang::system_program::ID && !destination.executable,
+            MarketplaceError::InvalidFeeDestination
+        ),
+        CurrencyType::USDC | CurrencyType::USDT => {
+            require!(
+                destination.owner == &anchor_spl::token::ID,
+                MarketplaceError::InvalidFeeDestination
+            );
+            TokenAccount::try_deserialize(&mut &destination.try_borrow_data()?[..])
+                .map_err(|_| error!(MarketplaceError::InvalidFeeDestination))?;
+        }
+    }
+    
+    Ok(())
+}
+
+/// Fingerprint of a seller's listing, used to detect duplicates. Titles are
+/// compared case-insensitively with runs of whitespace collapsed, so
+/// cosmetic edits don't make a listing distinct.
+pub fn product_fingerprint(title: &str, category: Category, seller: &Pubkey) -> [u8; 32] {
+    let title = normalize_listing_text(title);
+    anchor_lang::solana_program::hash::hashv(&[
+        title.as_bytes(),
+        &[0],
+        &[category as u8],
+        &[0],
+        seller.as_ref(),
+    ])
+    .to_bytes()
+}
+
+fn normalize_listing_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Pick `count` distinct indices out of `candidates` using a partial
+/// Fisher-Yates shuffle seeded by the VRF result. Each draw hashes the
+/// randomness with the draw number so the result is reproducible off-chain.
+pub fn select_featured_indices(randomness: &[u8; 32], candidates: usize, count: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..candidates).collect();
+    let count = count.min(candidates);
+
+    for draw in 0..count {
+        let digest = anchor_lang::solana_program::hash::hashv(&[randomness, &[draw as u8]]);
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&digest.to_bytes()[..8]);
+        let offset = (u64::from_le_bytes(word) % (candidates - draw) as u64) as usize;
+        indices.swap(draw, draw + offset);
+    }
+
+    indices.truncate(count);
+    indices
+}
+
+/// Account structure for marketplace state
+#[account]
+pub struct MarketplaceState {
+    /// Authority that can manage marketplace settings
+    pub authority: Pubkey,
+    /// Counter for total products listed
+    pub product_count: u64,
+    /// Marketplace fee in basis points (1/100 of a percent)
+    pub fees_basis_points: u16,
+    /// Account where fees are collected
+    pub fee_destination: Pubkey,
+    /// Whether the marketplace is paused
+    pub is_paused: bool,
+    /// Whether releases of escrowed funds are halted
+    pub releases_paused: bool,
+    /// Bitmask of `CurrencyType` flags listings may be priced in
+    pub accepted_currencies: u8,
+    /// Switchboard VRF account used for featured-product rotation
+    pub featured_vrf: Pubkey,
+    /// Last round of `featured_vrf` used for a rotation
+    pub featured_vrf_round: u128,
+    /// Share of layaway payments forfeited to the seller on default, in basis points
+    pub layaway_forfeit_bps: u16,
+    /// Seconds after a seller-favored dispute during which the buyer's linked review can be removed
+    pub review_removal_grace_seconds: i64,
+    /// Smallest escrow accepted in SOL, in lamports
+    pub min_escrow_sol: u64,
+    /// Smallest escrow accepted in USDC, in base units
+    pub min_escrow_usdc: u64,
+    /// Smallest escrow accepted in USDT, in base units
+    pub min_escrow_usdt: u64,
+    /// Whether new listings must provide a non-zero metadata hash
+    pub require_metadata_hash: bool,
+    /// Seconds a dispute resolution can be appealed before it pays out
+    pub appeal_window_seconds: i64,
+    /// Higher-tier arbitrator who decides appealed disputes
+    pub appeal_arbitrator: Pubkey,
+    /// Fee destination for SOL settlements (default pubkey = `fee_destination`)
+    pub sol_fee_destination: Pubkey,
+    /// Fee destination for USDC settlements (default pubkey = `fee_destination`)
+    pub usdc_fee_destination: Pubkey,
+    /// Fee destination for USDT settlements (default pubkey = `fee_destination`)
+    pub usdt_fee_destination: Pubkey,
+    /// Whether buyers must attest their source of funds when funding escrows
+    pub require_source_attestation: bool,
+    /// Number of escrows on the marketplace that have not yet settled
+    pub active_escrows: u64,
+    /// Completed sales volume in SOL, in lamports
+    pub sol_volume: u64,
+    /// Completed sales volume in USDC, in base units
+    pub usdc_volume: u64,
+    /// Completed sales volume in USDT, in base units
+    pub usdt_volume: u64,
+    /// Fees collected in SOL, in lamports
+    pub sol_fees_collected: u64,
+    /// Fees collected in USDC, in base units
+    pub usdc_fees_collected: u64,
+    /// Fees collected in USDT, in base units
+    pub usdt_fees_collected: u64,
+    /// Slot of the last stats snapshot (0 if none)
+    pub last_snapshot_slot: u64,
+    /// Seconds after an approved return during which the buyer's review of the escrow can be revised
+    pub return_review_grace_seconds: i64,
+    /// Number of product listings that have not been removed
+    pub listed_products: u64,
+    /// Who receives the rounding remainder of buyer/seller splits
+    pub rounding_policy: RoundingPolicy,
+    /// Most escrows a buyer may hold open at once (0 = unlimited)
+    pub max_open_escrows_per_buyer: u64,
+    /// Currency of listings created without one
+    pub default_currency: CurrencyType,
+    /// Seconds after shipment during which the order can be disputed (0 = no limit)
+    pub dispute_window_seconds: i64,
+    /// Slots after a dispute opens during which evidence is accepted (0 = no cutoff)
+    pub evidence_window_slots: u64,
+    /// Swap program that converts SOL payouts for sellers preferring a token
+    /// (default pubkey = none)
+    pub swap_program: Pubkey,
+    /// Largest escrow accepted in SOL, in lamports (0 = no cap)
+    pub max_escrow_sol: u64,
+    /// Largest escrow accepted in USDC, in base units (0 = no cap)
+    pub max_escrow_usdc: u64,
+    /// Largest escrow accepted in USDT, in base units (0 = no cap)
+    pub max_escrow_usdt: u64,
+    /// Whether purchases only count toward a buyer's reputation once reviewed
+    pub require_purchase_reviews: bool,
+    /// Funded cancellations a buyer may make within the window before
+    /// forfeiting part of their refunds (0 = no penalty)
+    pub cancellation_threshold: u64,
+    /// Seconds over which a buyer's funded cancellations are counted
+    /// (0 = since their first cancellation)
+    pub cancellation_window_seconds: i64,
+    /// Share of the refund forfeited to the seller for each cancellation past
+    /// the threshold, in basis points
+    pub cancellation_fee_bps: u16,
+    /// Mediator who may resolve disputes on the authority's behalf, or
+    /// `Pubkey::default()` for none
+    pub arbiter: Pubkey,
+    /// Reduced fees by seller sales volume, of which the first
+    /// `fee_tier_count` are in use
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+    /// Number of fee tiers configured
+    pub fee_tier_count: u8,
+    /// Time after shipment after which the seller may claim an unconfirmed
+    /// escrow
+    pub auto_release_seconds: i64,
+    /// Mint USDC escrows are paid in (default = any)
+    pub usdc_mint: Pubkey,
+    /// Mint USDT escrows are paid in (default = any)
+    pub usdt_mint: Pubkey,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl MarketplaceState {
+    /// Fee charged to a seller with `total_sales` completed sales: the
+    /// highest tier they reach, or the base fee below the first tier
+    pub fn fees_basis_points_for(&self, total_sales: u64) -> u16 {
+        self.fee_tiers[..self.fee_tier_count as usize]
+            .iter()
+            .rev()
+            .find(|tier| total_sales >= tier.min_sales)
+            .map_or(self.fees_basis_points, |tier| tier.fees_basis_points)
+    }
+
+    /// Whether `signer` may resolve disputes not routed to an arbitrator pool
+    pub fn can_resolve_disputes(&self, signer: &Pubkey) -> bool {
+        *signer == self.authority || (self.arbiter != Pubkey::default() && *signer == self.arbiter)
+    }
+
+    /// Whether listings may be priced in `currency`
+    pub fn accepts(&self, currency: &CurrencyType) -> bool {
+        self.accepted_currencies & currency.flag() != 0
+    }
+
+    /// Completed sales volume counter for `currency`
+    pub fn volume_mut(&mut self, currency: &CurrencyType) -> &mut u64 {
+        match currency {
+            CurrencyType::SOL => &mut self.sol_volume,
+            CurrencyType::USDC => &mut self.usdc_volume,
+            CurrencyType::USDT => &mut self.usdt_volume,
+        }
+    }
+
+    /// Fees collected counter for `currency`
+    pub fn fees_collected_mut(&mut self, currency: &CurrencyType) -> &mut u64 {
+        match currency {
+            CurrencyType::SOL => &mut self.sol_fees_collected,
+            CurrencyType::USDC => &mut self.usdc_fees_collected,
+            CurrencyType::USDT => &mut self.usdt_fees_collected,
+        }
+    }
+
+    /// Mint escrows in `currency` must be paid in, or `None` when any mint
+    /// is accepted. SOL has no mint.
+    pub fn mint_for(&self, currency: &CurrencyType) -> Option<Pubkey> {
+        let mint = match currency {
+            CurrencyType::SOL => return None,
+            CurrencyType::USDC => self.usdc_mint,
+            CurrencyType::USDT => self.usdt_mint,
+        };
+        (mint != Pubkey::default()).then_some(mint)
+    }
+
+    /// Account that receives fees taken in `currency`, falling back to the
+    /// marketplace-wide `fee_destination` when none is configured
+    pub fn fee_destination_for(&self, currency: &CurrencyType) -> Pubkey {
+        let destination = match currency {
+            CurrencyType::SOL => self.sol_fee_destination,
+            CurrencyType::USDC => self.usdc_fee_destination,
+            CurrencyType::USDT => self.usdt_fee_destination,
+        };
+        if destination == Pubkey::default() {
+            self.fee_destination
+        } else {
+            destination
+        }
+    }
+
+    /// Smallest escrow accepted in `currency`, in its base units
+    pub fn min_escrow_amount(&self, currency: &CurrencyType) -> u64 {
+        match currency {
+            CurrencyType::SOL => self.min_escrow_sol,
+            CurrencyType::USDC => self.min_escrow_usdc,
+            CurrencyType::USDT => self.min_escrow_usdt,
+        }
+    }
+
+    /// Whether a single escrow of `amount` in `currency` is within the cap
+    pub fn within_max_escrow_value(&self, currency: &CurrencyType, amount: u64) -> bool {
+        let max = match currency {
+            CurrencyType::SOL => self.max_escrow_sol,
+            CurrencyType::USDC => self.max_escrow_usdc,
+            CurrencyType::USDT => self.max_escrow_usdt,
+        };
+        max == 0 || amount <= max
+    }
+}
+
+/// Running totals of a marketplace, emitted by `snapshot_stats`
+#[event]
+pub struct StatsSnapshot {
+    pub marketplace: Pubkey,
+    /// Slot the snapshot was taken in
+    pub slot: u64,
+    /// Slot of the previous snapshot (0 if none), to compute rates between them
+    pub previous_snapshot_slot: u64,
+    pub product_count: u64,
+    pub active_escrows: u64,
+    pub sol_volume: u64,
+    pub usdc_volume: u64,
+    pub usdt_volume: u64,
+    pub sol_fees_collected: u64,
+    pub usdc_fees_collected: u64,
+    pub usdt_fees_collected: u64,
+}
+
+/// A marketplace was permanently closed by `close_marketplace`
+#[event]
+pub struct MarketplaceClosed {
+    pub marketplace: Pubkey,
+    pub authority: Pubkey,
+    /// Number of products ever listed on the marketplace
+    pub product_count: u64,
+    pub closed_at: i64,
+}
+
+#[event]
+pub struct FeaturedProductsRotated {
+    pub marketplace: Pubkey,
+    /// VRF round whose result made the selection
+    pub vrf_round: u128,
+    /// Products the selection was drawn from, in draw order
+    pub candidates: Vec<Pubkey>,
+    pub featured: Vec<Pubkey>,
+    pub featured_until: i64,
+}
+
+/// One page of an operator's registry of child marketplaces
+#[account]
+pub struct MarketplaceRegistry {
+    /// Operator allowed to change the registry
+    pub super_authority: Pubkey,
+    /// Index of this page
+    pub page: u32,
+    /// Number of registered marketplaces on this page
+    pub count: u8,
+    /// Registered marketplaces; only the first `count` are in use
+    pub marketplaces: [Pubkey; MAX_REGISTRY_PAGE_ENTRIES],
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl MarketplaceRegistry {
+    /// Marketplaces currently registered on this page
+    pub fn entries(&self) -> &[Pubkey] {
+        &self.marketplaces[..self.count as usize]
+    }
+}
+
+/// Products of a marketplace entered into its featured rotation
+#[account]
+pub struct FeaturedRegistry {
+    /// Marketplace whose rotation this is
+    pub marketplace: Pubkey,
+    /// Number of products in the rotation
+    pub count: u8,
+    /// Products in the rotation; only the first `count` are in use
+    pub products: [Pubkey; MAX_FEATURED_CANDIDATES],
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl FeaturedRegistry {
+    /// Products currently in the rotation
+    pub fn entries(&self) -> &[Pubkey] {
+        &self.products[..self.count as usize]
+    }
+}
+
+/// USD price of a currency, published for reference pricing. The feed is
+/// written by the marketplace authority and not read from an oracle, so its
+/// prices are only as trustworthy as that authority.
+#[account]
+pub struct PriceFeed {
+    /// Marketplace the feed serves
+    pub marketplace: Pubkey,
+    /// Currency being priced
+    pub currency: CurrencyType,
+    /// USD value of one whole unit, with `REFERENCE_PRICE_DECIMALS`
+    pub usd_price: u64,
+    /// Timestamp of the last price update
+    pub updated_at: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl PriceFeed {
+    /// Check that the feed prices `currency` for `marketplace` and is fresh
+    pub fn validate(&self, marketplace: Pubkey, currency: &CurrencyType) -> Result<()> {
+        require!(
+            self.marketplace == marketplace && self.currency == *currency,
+            MarketplaceError::PriceFeedMismatch
+        );
+        require!(
+            Clock::get()?.unix_timestamp - self.updated_at <= MAX_PRICE_FEED_AGE_SECONDS,
+            MarketplaceError::StalePriceFeed
+        );
+        
+        Ok(())
+    }
+
+    /// Convert `price`, in base units of `currency`, to a USD reference price
+    pub fn reference_price(&self, marketplace: Pubkey, price: u64, currency: &CurrencyType) -> Result<u64> {
+        self.validate(marketplace, currency)?;
+        
+        let reference_price = (price as u128)
+            .checked_mul(self.usd_price as u128)
+            .ok_or(MarketplaceError::ReferencePriceOverflow)?
+            / 10u128.pow(currency.decimals());
+        u64::try_from(reference_price).map_err(|_| error!(MarketplaceError::ReferencePriceOverflow))
+    }
+}
+
+/// Per-seller settings on a marketplace
+#[account]
+pub struct SellerStatus {
+    /// Marketplace the seller lists on
+    pub marketplace: Pubkey,
+    /// The seller
+    pub seller: Pubkey,
+    /// Whether the seller's listings are temporarily closed to purchases
+    pub on_hold: bool,
+    /// Currency SOL payouts are converted to (`None` = paid in SOL)
+    pub preferred_payout_currency: Option<CurrencyType>,
+    /// Most a payout conversion may fall short of the price feeds, in basis points
+    pub max_payout_slippage_bps: u16,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl SellerStatus {
+    /// Whether a seller status PDA marks its seller as on hold. Sellers that
+    /// never set a hold have no account yet and are treated as available.
+    pub fn is_on_hold(info: &AccountInfo) -> Result<bool> {
+        if info.owner != &crate::ID || info.data_is_empty() {
+            return Ok(false);
+        }
+        Ok(SellerStatus::try_deserialize(&mut &info.try_borrow_data()?[..])?.on_hold)
+    }
+
+    /// The seller's preferred payout currency and slippage limit, read the
+    /// same way. Sellers without a status account are paid in SOL.
+    pub fn payout_preference(info: &AccountInfo) -> Result<Option<(CurrencyType, u16)>> {
+        if info.owner != &crate::ID || info.data_is_empty() {
+            return Ok(None);
+        }
+        let seller_status = SellerStatus::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+        Ok(seller_status
+            .preferred_payout_currency
+            .clone()
+            .map(|currency| (currency, seller_status.max_payout_slippage_bps)))
+    }
+}
+
+/// Account structure for a product listing
+#[account]
+pub struct Product {
+    /// Reference to marketplace
+    pub marketplace: Pubkey,
+    /// Seller of the product
+    pub seller: Pubkey,
+    /// Price in the specified currency
+    pub price: u64,
+    /// Available quantity
+    pub quantity: u64,
+    /// Currency type (SOL, USDC, etc.)
+    pub currency: CurrencyType,
+    /// Product title (max 50 bytes)
+    pub title: String,
+    /// Product description (max 1000 bytes)
+    pub description: String,
+    /// URI to additional metadata (IPFS/Arweave, max 200 bytes)
+    pub metadata_uri: String,
+    /// SHA-256 hash of the off-chain metadata, for clients to verify what they fetch
+    pub metadata_hash: [u8; 32],
+    /// Current product status
+    pub status: ProductStatus,
+    /// Timestamp of creation
+    pub created_at: i64,
+    /// Timestamp of last update
+    pub updated_at: i64,
+    /// Product category
+    pub category: Category,
+    /// Seconds after completion during which the buyer may request a return (0 = no returns)
+    pub return_window_seconds: i64,
+    /// Whether the product currently holds a featured slot
+    pub featured: bool,
+    /// Timestamp until which the featured slot is valid
+    pub featured_until: i64,
+    /// Start of the seasonal purchase window, if any
+    pub available_from: Option<i64>,
+    /// End of the seasonal purchase window, if any
+    pub available_until: Option<i64>,
+    /// Number of escrows against this product that have not yet settled
+    pub active_escrows: u64,
+    /// Most disputes a buyer may have lost and still purchase, if limited
+    pub max_buyer_lost_disputes: Option<u64>,
+    /// Price converted to USD with `REFERENCE_PRICE_DECIMALS` (0 if never priced)
+    pub reference_price: u64,
+    /// Seconds after funding within which the seller promises to ship (0 = no promise)
+    pub handling_time_seconds: i64,
+    /// Whether the seller is a program-derived address, which can only sign
+    /// through its owning program's CPI
+    pub seller_is_pda: bool,
+    /// Whether the marketplace waives its fee on sales of the product
+    pub fee_exempt: bool,
+    /// Index of the product on its marketplace, used in its PDA seeds
+    pub index: u64,
+    /// Units held by open escrows and not yet taken out of `quantity`
+    pub reserved_quantity: u64,
+    /// Hash of the normalized title, category and seller at creation
+    pub fingerprint: [u8; 32],
+    /// Free-form discovery tags, e.g. "organic" or "bulk"
+    pub tags: Vec<String>,
+    /// Seconds a buyer's offer stays open for the seller (0 = no offers)
+    pub offer_window_seconds: i64,
+    /// Fewest units a single order may buy
+    pub min_order_quantity: u64,
+    /// Volume discounts, in ascending order of quantity (at most
+    /// `MAX_PRICE_TIERS`). Orders below the first tier pay `price`.
+    pub price_tiers: Vec<PriceTier>,
+    /// Whether the marketplace authority took the listing down after a
+    /// flag, so the seller cannot put it back on sale
+    pub moderated: bool,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl Product {
+    /// Units that new escrows can still take
+    pub fn available_quantity(&self) -> u64 {
+        self.quantity.saturating_sub(self.reserved_quantity)
+    }
+    
+    /// Whether the listing has been taken down or sold out, releasing its
+    /// fingerprint for a new listing
+    pub fn is_closed(&self) -> bool {
+        matches!(self.status, ProductStatus::Deactivated | ProductStatus::SoldOut)
+    }
+    
+    /// Unit price of an order for `quantity` units: the price of the highest
+    /// tier the quantity reaches, or the base price below every tier
+    pub fn unit_price_for(&self, quantity: u64) -> u64 {
+        self.price_tiers
+            .iter()
+            .rev()
+            .find(|tier| tier.min_quantity <= quantity)
+            .map_or(self.price, |tier| tier.unit_price)
+    }
+}
+
+/// A seller's claim on a listing fingerprint, held by their latest listing
+/// with it
+#[account]
+pub struct ProductFingerprint {
+    /// Seller the fingerprint belongs to
+    pub seller: Pubkey,
+    /// Fingerprint from `product_fingerprint`
+    pub fingerprint: [u8; 32],
+    /// Latest listing created with the fingerprint
+    pub product: Pubkey,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// A user's report of a product for moderation
+#[account]
+pub struct ProductFlag {
+    /// Product flagged
+    pub product: Pubkey,
+    /// Wallet that flagged the product
+    pub flagger: Pubkey,
+    /// Reason given for the flag (max 140 bytes)
+    pub reason: String,
+    /// Whether the flag is open, upheld or dismissed
+    pub status: FlagStatus,
+    /// Timestamp of the flag
+    pub created_at: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Marketplace fee for sellers past a sales volume threshold
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeTier {
+    /// Completed sales a seller needs to reach the tier
+    pub min_sales: u64,
+    /// Fee charged in the tier, in basis points
+    pub fees_basis_points: u16,
+}
+
+/// Unit price for orders of at least a given quantity
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PriceTier {
+    /// Units an order needs to reach the tier
+    pub min_quantity: u64,
+    /// Price per unit in the tier
+    pub unit_price: u64,
+}
+
+/// Who receives the indivisible remainder when an amount is split between
+/// buyer and seller. Splits compute one share and give the other party the
+/// rest, so the shares always sum to the amount split.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingPolicy {
+    /// The seller's share rounds up
+    DustToSeller,
+    /// The seller's share rounds down
+    DustToBuyer,
+}
+
+impl RoundingPolicy {
+    /// Split `total` into `(seller, buyer)` shares, the seller being owed
+    /// `numerator / denominator` of it. `None` if the fraction exceeds one.
+    pub fn split(&self, total: u64, numerator: u64, denominator: u64) -> Option<(u64, u64)> {
+        if denominator == 0 || numerator > denominator {
+            return None;
+        }
+        
+        let scaled = (total as u128) * (numerator as u128);
+        let mut seller = scaled / denominator as u128;
+        if *self == RoundingPolicy::DustToSeller && scaled % denominator as u128 != 0 {
+            seller += 1;
+        }
+        
+        // The seller's share never exceeds the total, so it fits in a u64
+        let seller = seller as u64;
+        Some((seller, total - seller))
+    }
+}
+
+/// Shares returned by `preview_split`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SplitShares {
+    pub seller: u64,
+    pub buyer: u64,
+}
+
+/// Currency types supported by the marketplace
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum CurrencyType {
+    SOL,
+    USDC,
+    USDT,
+}
+
+impl CurrencyType {
+    /// Bitmask with every currency's flag set
+    pub const ALL_FLAGS: u8 = 0b111;
+
+    /// Flag identifying this currency in a bitmask
+    pub fn flag(&self) -> u8 {
+        match self {
+            CurrencyType::SOL => 1 << 0,
+            CurrencyType::USDC => 1 << 1,
+            CurrencyType::USDT => 1 << 2,
+        }
+    }
+
+    /// Decimals of the currency's base unit
+    pub fn decimals(&self) -> u32 {
+        match self {
+            CurrencyType::SOL => 9,
+            CurrencyType::USDC | CurrencyType::USDT => 6,
+        }
+    }
+}
+
+/// Category a product is listed under. Listings created before categories
+/// were an enum stored free-form strings and must be recreated.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Grains,
+    Produce,
+    Livestock,
+    Seeds,
+    Equipment,
+    Other,
+}
+
+/// Status of a product
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum ProductStatus {
+    Active,
+    SoldOut,
+    Deactivated,
+    Flagged,
+}
+
+/// Status of a product flag
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum FlagStatus {
+    Open,
+    Upheld,
+    Dismissed,
+}
+
+/// Outcome the marketplace authority chooses for a product flag
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum FlagResolution {
+    Flag,
+    Deactivate,
+    Dismiss,
+}
+
+/// Accounts required for initializing a marketplace
+#[derive(Accounts)]
+pub struct InitializeMarketplace<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + std::mem::size_of::<MarketplaceState>(),
+        seeds = [b"marketplace", authority.key().as_ref()],
+        bump
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Fee destination account, which must be able to receive SOL
+    pub fee_destination: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for creating a product
+#[derive(Accounts)]
+#[instruction(
+    title: String,
+    description: String,
+    price: u64,
+    quantity: u64,
+    currency: Option<CurrencyType>,
+    metadata_uri: String,
+    category: Category
+)]
+pub struct CreateProduct<'info> {
+    /// Seller wallet, or a program-derived address signing through its
+    /// owning program
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + std::mem::size_of::<Product>()
+            + MAX_TITLE_LEN + MAX_DESCRIPTION_LEN + MAX_METADATA_URI_LEN // Additional space for strings
+            + MAX_TAGS * (4 + MAX_TAG_LEN) // and each tag
+            + MAX_PRICE_TIERS * std::mem::size_of::<PriceTier>(),
+        seeds = [
+            b"product",
+            marketplace.key().as_ref(),
+            seller.key().as_ref(),
+            &marketplace.product_count.to_le_bytes()
+        ],
+        bump
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Price feed for the product's currency, to compute its reference price
+    pub price_feed: Option<Account<'info, PriceFeed>>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<ProductFingerprint>(),
+        seeds = [
+            b"product_fingerprint",
+            marketplace.key().as_ref(),
+            &crate::product_fingerprint(&title, category, &seller.key())
+        ],
+        bump
+    )]
+    pub product_fingerprint: Account<'info, ProductFingerprint>,
+    
+    /// Listing holding the fingerprint, required when relisting it
+    pub fingerprinted_product: Option<Account<'info, Product>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for updating a product
+#[derive(Accounts)]
+pub struct UpdateProduct<'info> {
+    #[account(
+        constraint = seller.key() == product.seller @ MarketplaceError::NotProductOwner
+    )]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [
+            b"product",
+            product.marketplace.as_ref(),
+            product.seller.as_ref(),
+            &product.index.to_le_bytes()
+        ],
+        bump = product.bump
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// The seller's claim on the product's fingerprint
+    #[account(
+        mut,
+        seeds = [b"product_fingerprint", product.marketplace.as_ref(), &product.fingerprint],
+        bump = product_fingerprint.bump
+    )]
+    pub product_fingerprint: Account<'info, ProductFingerprint>,
+}
+
+/// Accounts required for retitling a product
+#[derive(Accounts)]
+#[instruction(title: String)]
+pub struct RenameProduct<'info> {
+    #[account(
+        mut,
+        constraint = seller.key() == product.seller @ MarketplaceError::NotProductOwner
+    )]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [
+            b"product",
+            product.marketplace.as_ref(),
+            product.seller.as_ref(),
+            &product.index.to_le_bytes()
+        ],
+        bump = product.bump
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// The seller's claim on the product's current fingerprint
+    #[account(
+        mut,
+        seeds = [b"product_fingerprint", product.marketplace.as_ref(), &product.fingerprint],
+        bump = product_fingerprint.bump
+    )]
+    pub product_fingerprint: Account<'info, ProductFingerprint>,
+    
+    /// Claim on the new title's fingerprint, required when it differs
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<ProductFingerprint>(),
+        seeds = [
+            b"product_fingerprint",
+            product.marketplace.as_ref(),
+            &crate::product_fingerprint(&title, product.category, &product.seller)
+        ],
+        bump
+    )]
+    pub new_product_fingerprint: Option<Account<'info, ProductFingerprint>>,
+    
+    /// Listing holding the new fingerprint, required when it is still claimed
+    pub fingerprinted_product: Option<Account<'info, Product>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for deleting a product
+#[derive(Accounts)]
+pub struct DeleteProduct<'info> {
+    #[account(
+        mut,
+        constraint = seller.key() == product.seller @ MarketplaceError::NotProductOwner
+    )]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        close = seller,
+        seeds = [
+            b"product",
+            marketplace.key().as_ref(),
+            product.seller.as_ref(),
+            &product.index.to_le_bytes()
+        ],
+        bump = product.bump
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// The seller's claim on the product's fingerprint
+    #[account(
+        mut,
+        seeds = [b"product_fingerprint", marketplace.key().as_ref(), &product.fingerprint],
+        bump = product_fingerprint.bump
+    )]
+    pub product_fingerprint: Account<'info, ProductFingerprint>,
+}
+
+/// Accounts required for purchasing a product
+#[derive(Accounts)]
+pub struct PurchaseProduct<'info> {
+    /// PDA of the escrow program, proving the purchase comes through an
+    /// escrow rather than a direct call
+    #[account(
+        seeds = [ESCROW_AUTHORITY_SEED],
+        bump,
+        seeds::program = ESCROW_PROGRAM_ID
+    )]
+    pub escrow_authority: Signer<'info>,
+    
+    /// The buyer of the product
     pub buyer: Signer<'info>,
     
-    /// The product being purchased
+    /// Marketplace the product is listed on
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = product.marketplace == marketplace.key() @ MarketplaceError::ProductMarketplaceMismatch
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// The product being purchased
+    #[account(
+        mut,
+        seeds = [
+            b"product",
+            product.marketplace.as_ref(),
+            product.seller.as_ref(),
+            &product.index.to_le_bytes()
+        ],
+        bump = product.bump
+    )]
+    pub product: Account<'info, Product>,
+    
+    /// Status of the product's seller, which may not be initialized
+    #[account(
+        seeds = [b"seller_status", product.marketplace.as_ref(), product.seller.as_ref()],
+        bump
+    )]
+    pub seller_status: AccountInfo<'info>,
+}
+
+/// Accounts required for changing a product's currency
+#[derive(Accounts)]
+pub struct ChangeCurrency<'info> {
+    pub seller: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = product.seller == seller.key() @ MarketplaceError::NotProductOwner,
+        constraint = product.marketplace == marketplace.key() @ MarketplaceError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+}
+
+/// Accounts required for the escrow program to track escrows on a product
+#[derive(Accounts)]
+pub struct TrackEscrow<'info> {
+    /// PDA of the escrow program, proving the call comes from it
+    #[account(
+        seeds = [ESCROW_AUTHORITY_SEED],
+        bump,
+        seeds::program = ESCROW_PROGRAM_ID
+    )]
+    pub escrow_authority: Signer<'info>,
+    
+    #[account(mut)]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = product.marketplace == marketplace.key() @ MarketplaceError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+}
+
+/// Accounts required for authority-only marketplace settings
+#[derive(Accounts)]
+pub struct UpdateMarketplace<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+}
+
+/// Accounts required for setting a per-currency fee destination
+#[derive(Accounts)]
+pub struct SetCurrencyFeeDestination<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Account the currency's fees are paid to
+    pub fee_destination: AccountInfo<'info>,
+}
+
+/// Accounts required for updating the marketplace fee
+#[derive(Accounts)]
+pub struct UpdateFees<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Default account fees are paid to, which must be able to receive SOL
+    pub fee_destination: AccountInfo<'info>,
+}
+
+/// Accounts required for closing a marketplace
+#[derive(Accounts)]
+pub struct CloseMarketplace<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+}
+
+/// Accounts required for previewing a split
+#[derive(Accounts)]
+pub struct PreviewSplit<'info> {
+    pub marketplace: Account<'info, MarketplaceState>,
+}
+
+/// Accounts required for repricing a seller's products
+#[derive(Accounts)]
+pub struct RepriceProducts<'info> {
+    pub seller: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+}
+
+/// Accounts required for rotating featured products
+#[derive(Accounts)]
+pub struct RotateFeaturedProducts<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Products the rotation draws from
+    #[account(
+        mut,
+        seeds = [b"featured_registry", marketplace.key().as_ref()],
+        bump = featured_registry.bump
+    )]
+    pub featured_registry: Account<'info, FeaturedRegistry>,
+    
+    /// VRF account configured on the marketplace
+    #[account(
+        constraint = vrf.key() == marketplace.featured_vrf @ MarketplaceError::InvalidVrfAccount
+    )]
+    pub vrf: AccountLoader<'info, VrfAccountData>,
+}
+
+/// Accounts required for configuring the featured-rotation VRF
+#[derive(Accounts)]
+pub struct SetFeaturedVrf<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Switchboard VRF account to draw featured products with
+    pub vrf: AccountLoader<'info, VrfAccountData>,
+}
+
+/// Accounts required for entering a product into the featured rotation
+#[derive(Accounts)]
+pub struct EnterFeaturedRotation<'info> {
+    #[account(
+        mut,
+        constraint = seller.key() == product.seller @ MarketplaceError::NotProductOwner
+    )]
+    pub seller: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        seeds = [
+            b"product",
+            marketplace.key().as_ref(),
+            product.seller.as_ref(),
+            &product.index.to_le_bytes()
+        ],
+        bump = product.bump
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<FeaturedRegistry>(),
+        seeds = [b"featured_registry", marketplace.key().as_ref()],
+        bump
+    )]
+    pub featured_registry: Account<'info, FeaturedRegistry>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for taking a product out of the featured rotation
+#[derive(Accounts)]
+pub struct LeaveFeaturedRotation<'info> {
+    #[account(
+        constraint = seller.key() == product.seller @ MarketplaceError::NotProductOwner
+    )]
+    pub seller: Signer<'info>,
+    
     #[account(
         mut,
         seeds = [
             b"product",
             product.marketplace.as_ref(),
             product.seller.as_ref(),
-            &get_product_index(product.key()).to_le_bytes()
+            &product.index.to_le_bytes()
         ],
         bump = product.bump
     )]
     pub product: Account<'info, Product>,
     
-    /// Token program for transfers
-    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        seeds = [b"featured_registry", product.marketplace.as_ref()],
+        bump = featured_registry.bump
+    )]
+    pub featured_registry: Account<'info, FeaturedRegistry>,
+}
+
+/// Accounts required for publishing a currency's price
+#[derive(Accounts)]
+#[instruction(currency: CurrencyType)]
+pub struct SetPriceFeed<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + std::mem::size_of::<PriceFeed>(),
+        seeds = [b"price_feed", marketplace.key().as_ref(), &[currency.flag()]],
+        bump
+    )]
+    pub price_feed: Account<'info, PriceFeed>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for refreshing a product's reference price
+#[derive(Accounts)]
+pub struct RefreshReferencePrice<'info> {
+    #[account(mut)]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        seeds = [b"price_feed", product.marketplace.as_ref(), &[product.currency.flag()]],
+        bump = price_feed.bump
+    )]
+    pub price_feed: Account<'info, PriceFeed>,
+}
+
+/// Accounts required for exempting a product from fees
+#[derive(Accounts)]
+pub struct SetFeeExempt<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = product.marketplace == marketplace.key() @ MarketplaceError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+}
+
+/// Accounts required for flagging a product
+#[derive(Accounts)]
+pub struct FlagProduct<'info> {
+    #[account(mut)]
+    pub flagger: Signer<'info>,
+    
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        init,
+        payer = flagger,
+        space = 8 + std::mem::size_of::<ProductFlag>() + MAX_FLAG_REASON_LEN,
+        seeds = [b"product_flag", product.key().as_ref(), flagger.key().as_ref()],
+        bump
+    )]
+    pub product_flag: Account<'info, ProductFlag>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for resolving a product flag
+#[derive(Accounts)]
+pub struct ResolveFlag<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = product.marketplace == marketplace.key() @ MarketplaceError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        mut,
+        seeds = [b"product_flag", product.key().as_ref(), product_flag.flagger.as_ref()],
+        bump = product_flag.bump
+    )]
+    pub product_flag: Account<'info, ProductFlag>,
+}
+
+/// Accounts required for clearing a product's flag
+#[derive(Accounts)]
+pub struct ClearProductFlag<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        seeds = [b"marketplace", marketplace.authority.as_ref()],
+        bump = marketplace.bump,
+        constraint = marketplace.authority == authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        constraint = product.marketplace == marketplace.key() @ MarketplaceError::ProductMarketplaceMismatch
+    )]
+    pub product: Account<'info, Product>,
+}
+
+/// Accounts required for setting a seller's hold
+#[derive(Accounts)]
+pub struct SetSellerHold<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<SellerStatus>(),
+        seeds = [b"seller_status", marketplace.key().as_ref(), seller.key().as_ref()],
+        bump
+    )]
+    pub seller_status: Account<'info, SellerStatus>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for setting a seller's payout preference
+#[derive(Accounts)]
+pub struct SetPayoutPreference<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<SellerStatus>(),
+        seeds = [b"seller_status", marketplace.key().as_ref(), seller.key().as_ref()],
+        bump
+    )]
+    pub seller_status: Account<'info, SellerStatus>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for opening a registry page
+#[derive(Accounts)]
+#[instruction(page: u32)]
+pub struct InitializeRegistryPage<'info> {
+    #[account(mut)]
+    pub super_authority: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = super_authority,
+        space = 8 + std::mem::size_of::<MarketplaceRegistry>(),
+        seeds = [b"marketplace_registry", super_authority.key().as_ref(), &page.to_le_bytes()],
+        bump
+    )]
+    pub registry: Account<'info, MarketplaceRegistry>,
+    
+    pub system_program: Program<'info, System>,
 }
 
-/// Helper function to extract product index from PDA
-pub fn get_product_index(product_key: Pubkey) -> u64 {
-    // In a real implementation, we would need to find a way to extract
-    // the index from the PDA or store it in the account
-    // For simplicity, we return 0
-    0
+/// Accounts required for registering or deregistering a marketplace
+#[derive(Accounts)]
+pub struct UpdateRegistry<'info> {
+    pub super_authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marketplace_registry", super_authority.key().as_ref(), &registry.page.to_le_bytes()],
+        bump = registry.bump,
+        constraint = registry.super_authority == super_authority.key() @ MarketplaceError::UnauthorizedAuthority
+    )]
+    pub registry: Account<'info, MarketplaceRegistry>,
+    
+    /// The child marketplace
+    pub marketplace: Account<'info, MarketplaceState>,
 }
 
 /// Error codes for the marketplace program
@@ -331,18 +2718,150 @@ pub enum MarketplaceError {
     InvalidPrice,
     #[msg("Invalid quantity")]
     InvalidQuantity,
-    #[msg("Title exceeds maximum length")]
+    #[msg("Title exceeds maximum length of 50 bytes")]
     TitleTooLong,
-    #[msg("Description exceeds maximum length")]
+    #[msg("Description exceeds maximum length of 1000 bytes")]
     DescriptionTooLong,
-    #[msg("Metadata URI exceeds maximum length")]
+    #[msg("Metadata URI exceeds maximum length of 200 bytes")]
     MetadataUriTooLong,
-    #[msg("Category exceeds maximum length")]
-    CategoryTooLong,
     #[msg("Not the product owner")]
     NotProductOwner,
     #[msg("Product is not active")]
     ProductNotActive,
     #[msg("Insufficient inventory")]
     InsufficientInventory,
-} 
\ No newline at end of file
+    #[msg("Return window must not be negative")]
+    InvalidReturnWindow,
+    #[msg("Unauthorized marketplace authority")]
+    UnauthorizedAuthority,
+    #[msg("VRF account does not match the marketplace configuration")]
+    InvalidVrfAccount,
+    #[msg("VRF result is not available yet")]
+    VrfResultNotReady,
+    #[msg("Cannot feature more products than candidates provided")]
+    TooManyFeaturedProducts,
+    #[msg("Featured duration must be positive")]
+    InvalidFeaturedDuration,
+    #[msg("Featured candidate is not a writable product of this marketplace")]
+    InvalidFeaturedCandidate,
+    #[msg("Basis points must not exceed 10000")]
+    InvalidBasisPoints,
+    #[msg("Grace period must not be negative")]
+    InvalidGracePeriod,
+    #[msg("Availability window must start before it ends")]
+    InvalidAvailabilityWindow,
+    #[msg("Product is not yet available for purchase")]
+    ProductNotYetAvailable,
+    #[msg("Product season has ended")]
+    ProductSeasonEnded,
+    #[msg("Currency is not accepted by this marketplace")]
+    CurrencyNotAccepted,
+    #[msg("Invalid accepted currency mask")]
+    InvalidCurrencyMask,
+    #[msg("Product has escrows that have not settled")]
+    ActiveEscrowsExist,
+    #[msg("Product is not listed on this marketplace")]
+    ProductMarketplaceMismatch,
+    #[msg("Registry page is full")]
+    RegistryPageFull,
+    #[msg("Marketplace is already registered")]
+    MarketplaceAlreadyRegistered,
+    #[msg("Marketplace is not registered on this page")]
+    MarketplaceNotRegistered,
+    #[msg("A metadata hash is required on this marketplace")]
+    MetadataHashRequired,
+    #[msg("Appeal window must be non-negative and have an arbitrator")]
+    InvalidAppealPolicy,
+    #[msg("Seller is on hold and not taking orders")]
+    SellerOnHold,
+    #[msg("Price feed does not match the product's marketplace and currency")]
+    PriceFeedMismatch,
+    #[msg("Price feed has not been updated recently")]
+    StalePriceFeed,
+    #[msg("Reference price overflowed")]
+    ReferencePriceOverflow,
+    #[msg("Fee destination cannot receive fees in this currency")]
+    InvalidFeeDestination,
+    #[msg("Handling time must be non-negative")]
+    InvalidHandlingTime,
+    #[msg("Marketplace still has product listings")]
+    ListedProductsExist,
+    #[msg("Dispute window cannot be negative")]
+    InvalidDisputeWindow,
+    #[msg("Reprice batch needs one writable product per price")]
+    InvalidRepriceBatch,
+    #[msg("Marketplace is paused")]
+    MarketplacePaused,
+    #[msg("Payouts can only be converted to a token currency")]
+    InvalidPayoutCurrency,
+    #[msg("Cancellation penalty needs a non-negative window and at most 10000 basis points")]
+    InvalidCancellationPenalty,
+    #[msg("Seller already has an open listing for this product")]
+    DuplicateListing,
+    #[msg("Fee tiers must be in ascending order of sales and at most the tier limit")]
+    InvalidFeeTiers,
+    #[msg("Auto-release window must be positive")]
+    InvalidAutoReleaseWindow,
+    #[msg("Only token currencies have a mint")]
+    InvalidCurrencyMint,
+    #[msg("Only deactivated or sold out products can be deleted")]
+    ProductNotClosed,
+    #[msg("Products can have at most 5 tags")]
+    TooManyTags,
+    #[msg("Tag exceeds maximum length of 16 bytes")]
+    TagTooLong,
+    #[msg("Offer window cannot be negative")]
+    InvalidOfferWindow,
+    #[msg("Minimum order quantity must be at least 1")]
+    InvalidMinOrderQuantity,
+    #[msg("Quantity is below the product's minimum order")]
+    BelowMinimumOrder,
+    #[msg("Price tiers must be at most 4, priced, and in ascending order of quantity")]
+    InvalidPriceTiers,
+    #[msg("Flag reason exceeds maximum length of 140 bytes")]
+    FlagReasonTooLong,
+    #[msg("Flag has already been resolved")]
+    FlagAlreadyResolved,
+    #[msg("Only the marketplace authority can flag a product or clear its flag")]
+    ProductFlagged,
+    #[msg("Product was taken down by the marketplace and cannot be relisted")]
+    ProductModerated,
+    #[msg("The claim on the new title's fingerprint is required to rename the product")]
+    FingerprintClaimRequired,
+    #[msg("VRF round has already been used for a rotation")]
+    VrfResultAlreadyUsed,
+    #[msg("Price is outside the marketplace's escrow limits for the currency")]
+    PriceOutsideEscrowLimits,
+    #[msg("Quantity cannot drop below the units reserved by open escrows")]
+    QuantityBelowReserved,
+    #[msg("Product is already in the featured rotation")]
+    ProductAlreadyInRotation,
+    #[msg("Featured rotation is full")]
+    FeaturedRotationFull,
+    #[msg("Product is not in the featured rotation")]
+    ProductNotInRotation,
+    #[msg("Product is not flagged")]
+    ProductNotFlagged,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn featured_selection_is_reproducible_from_the_vrf_result() {
+        assert_eq!(select_featured_indices(&[7u8; 32], 10, 3), vec![1, 5, 2]);
+
+        let mut randomness = [0u8; 32];
+        for (index, byte) in randomness.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        assert_eq!(select_featured_indices(&randomness, 5, 5), vec![1, 4, 3, 2, 0]);
+    }
+
+    #[test]
+    fn featured_selection_is_capped_at_the_candidates() {
+        assert_eq!(select_featured_indices(&[7u8; 32], 2, 4), vec![1, 0]);
+        assert!(select_featured_indices(&[7u8; 32], 0, 1).is_empty());
+    }
+}
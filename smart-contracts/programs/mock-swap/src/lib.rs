@@ -0,0 +1,158 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+declare_id!("MockSwap11111111111111111111111111111111111");
+
+/// Seed of a pool's PDA, which owns the pool's token reserve
+pub const POOL_SEED: &[u8] = b"pool";
+
+/// Lamports in one SOL, the unit pool rates are quoted per
+pub const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
+
+/// Fixed-rate swap standing in for a DEX. It implements the
+/// `swap_sol_for_token(amount_in, min_amount_out)` instruction the escrow
+/// program calls to convert seller payouts, taking the payer and the
+/// destination token account first.
+#[program]
+pub mod mock_swap {
+    use super::*;
+
+    /// Open a pool selling tokens from `reserve` at `rate` base units per SOL.
+    /// The reserve must be owned by the pool PDA.
+    pub fn initialize_pool(ctx: Context<InitializePool>, rate: u64) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        pool.authority = ctx.accounts.authority.key();
+        pool.reserve = ctx.accounts.reserve.key();
+        pool.rate = rate;
+        pool.bump = *ctx.bumps.get("pool").unwrap();
+        
+        Ok(())
+    }
+
+    /// Change the pool's rate, e.g. to simulate the market moving
+    pub fn set_rate(ctx: Context<SetRate>, rate: u64) -> Result<()> {
+        ctx.accounts.pool.rate = rate;
+        
+        Ok(())
+    }
+
+    /// Swap `amount_in` lamports from the payer for tokens at the pool's rate
+    pub fn swap_sol_for_token(
+        ctx: Context<SwapSolForToken>,
+        amount_in: u64,
+        min_amount_out: u64,
+    ) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        let amount_out = u64::try_from(
+            (amount_in as u128) * (pool.rate as u128) / (LAMPORTS_PER_SOL as u128),
+        )
+        .map_err(|_| error!(MockSwapError::CalculationError))?;
+        require!(amount_out >= min_amount_out, MockSwapError::SlippageExceeded);
+        
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: ctx.accounts.pool.to_account_info(),
+                },
+            ),
+            amount_in,
+        )?;
+        
+        let authority = pool.authority;
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.reserve.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[&[POOL_SEED, authority.as_ref(), &[pool.bump]]],
+            ),
+            amount_out,
+        )
+    }
+}
+
+/// Account structure for a fixed-rate pool
+#[account]
+pub struct Pool {
+    /// Authority that can change the rate
+    pub authority: Pubkey,
+    /// Token account the pool pays out of
+    pub reserve: Pubkey,
+    /// Token base units paid per SOL
+    pub rate: u64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Accounts required for opening a pool
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + std::mem::size_of::<Pool>(),
+        seeds = [POOL_SEED, authority.key().as_ref()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(constraint = reserve.owner == pool.key() @ MockSwapError::InvalidReserve)]
+    pub reserve: Account<'info, TokenAccount>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for changing a pool's rate
+#[derive(Accounts)]
+pub struct SetRate<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [POOL_SEED, authority.key().as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+/// Accounts required for a swap
+#[derive(Accounts)]
+pub struct SwapSolForToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [POOL_SEED, pool.authority.as_ref()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(mut, address = pool.reserve @ MockSwapError::InvalidReserve)]
+    pub reserve: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Error codes for the mock swap program
+#[error_code]
+pub enum MockSwapError {
+    #[msg("Reserve is not the pool's token account")]
+    InvalidReserve,
+    #[msg("Swap output is below the minimum")]
+    SlippageExceeded,
+    #[msg("Calculation error")]
+    CalculationError,
+}
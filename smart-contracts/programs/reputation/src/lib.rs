@@ -1,7 +1,53 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hash;
+use marketplace::{CurrencyType, MarketplaceState, Product};
 
 declare_id!("Reputation111111111111111111111111111111111111");
 
+/// Maximum review comment length, in bytes of UTF-8. Account space is
+/// reserved by byte length, so multibyte characters count for more than one.
+pub const MAX_COMMENT_LEN: usize = 500;
+
+/// Maximum number of attachments on a review
+pub const MAX_ATTACHMENTS: usize = 3;
+
+/// Maximum attachment URI length, in bytes
+pub const MAX_ATTACHMENT_URI_LEN: usize = 200;
+
+/// Schemes attachment URIs may use
+pub const ATTACHMENT_URI_SCHEMES: [&str; 3] = ["ipfs://", "ar://", "https://"];
+
+/// Time after creation during which a review can be edited (24 hours)
+pub const REVIEW_EDIT_WINDOW_SECONDS: i64 = 24 * 60 * 60;
+
+/// Maximum seller response length, in bytes of UTF-8
+pub const MAX_RESPONSE_LEN: usize = 300;
+
+/// Time a seller must wait between responses to reviews (1 hour)
+pub const RESPONSE_COOLDOWN_SECONDS: i64 = 60 * 60;
+
+/// Number of times a response can be edited after it is posted
+pub const MAX_RESPONSE_EDITS: u8 = 2;
+
+/// Age at which a rating counts half as much toward the weighted score (90 days)
+pub const RATING_HALF_LIFE_SECONDS: i64 = 90 * 24 * 60 * 60;
+
+/// Fixed-point scale of decayed rating weights; a fresh rating weighs this much
+pub const RATING_WEIGHT_ONE: u64 = 1_000_000;
+
+/// Completed sales a seller needs to reach the Bronze tier
+pub const BRONZE_TIER_MIN_SALES: u64 = 10;
+
+/// Completed sales a seller needs to reach the Silver tier
+pub const SILVER_TIER_MIN_SALES: u64 = 50;
+
+/// Completed sales a seller needs to reach the Gold tier
+pub const GOLD_TIER_MIN_SALES: u64 = 200;
+
+/// Escrow program whose accounts reviews reference
+pub const ESCROW_PROGRAM_ID: Pubkey =
+    anchor_lang::solana_program::pubkey!("Escrow1111111111111111111111111111111111111111");
+
 #[program]
 pub mod reputation {
     use super::*;
@@ -17,24 +63,90 @@ pub mod reputation {
         reputation.total_sales = 0;
         reputation.total_purchases = 0;
         reputation.is_verified = false;
+        reputation.disputes_lost = 0;
+        reputation.average_rating_x100 = 0;
+        reputation.pending_review_purchases = 0;
+        reputation.funded_cancellations = 0;
+        reputation.recent_cancellations = 0;
+        reputation.recent_cancellations_since = 0;
+        reputation.unverified_at = None;
+        reputation.weighted_rating_sum = 0;
+        reputation.weighted_review_weight = 0;
+        reputation.weighted_as_of = 0;
+        reputation.weighted_score_x100 = 0;
+        reputation.seller_total_rating = 0;
+        reputation.seller_review_count = 0;
+        reputation.buyer_total_rating = 0;
+        reputation.buyer_review_count = 0;
+        reputation.tier = SellerTier::Unranked;
         reputation.created_at = Clock::get()?.unix_timestamp;
         reputation.bump = *ctx.bumps.get("user_reputation").unwrap();
         
         Ok(())
     }
 
-    /// Create a review for a user
+    /// Create a review for a user. Reviews must reference a completed escrow
+    /// between the author and recipient, in either direction.
     pub fn create_review(
         ctx: Context<CreateReview>,
         rating: u8,
         comment: String,
-        transaction_reference: Option<Pubkey>,
+        transaction_reference: Pubkey,
+        attachment_uris: Vec<String>,
+        role: ReviewerRole,
     ) -> Result<()> {
         // Validate the rating is between 1 and 5
         require!(rating >= 1 && rating <= 5, ReputationError::InvalidRating);
         
+        // Users cannot rate themselves
+        require!(
+            ctx.accounts.author.key() != ctx.accounts.recipient.key(),
+            ReputationError::SelfReviewNotAllowed
+        );
+        
         // Validate comment length
-        require!(comment.len() <= 500, ReputationError::CommentTooLong);
+        require!(comment.len() <= MAX_COMMENT_LEN, ReputationError::CommentTooLong);
+        
+        // Validate attachments, e.g. photos of the product as received
+        validate_attachment_uris(&attachment_uris)?;
+        
+        // A user's first review creates their reputation
+        ctx.accounts.user_reputation.populate_if_new(
+            ctx.accounts.recipient.key(),
+            *ctx.bumps.get("user_reputation").unwrap(),
+        )?;
+        
+        // Only the parties to a completed escrow may review each other
+        require_keys_eq!(
+            ctx.accounts.escrow.key(),
+            transaction_reference,
+            ReputationError::InvalidEscrowAccount
+        );
+        let escrow = EscrowRecord::load(&ctx.accounts.escrow)?;
+        let author = ctx.accounts.author.key();
+        let recipient = ctx.accounts.recipient.key();
+        require!(
+            (escrow.buyer == author && escrow.seller == recipient)
+                || (escrow.seller == author && escrow.buyer == recipient),
+            ReputationError::InvalidEscrowAccount
+        );
+        require!(escrow.is_completed(), ReputationError::EscrowNotCompleted);
+        require_keys_eq!(
+            ctx.accounts.product.key(),
+            escrow.product,
+            ReputationError::InvalidProductReputation
+        );
+        
+        // The recipient is rated in the role they had in the escrow
+        let recipient_role = if escrow.seller == recipient {
+            ReviewerRole::Seller
+        } else {
+            ReviewerRole::Buyer
+        };
+        require!(role == recipient_role, ReputationError::InvalidReviewerRole);
+        
+        // A buyer's review of the seller carries a verified purchase badge
+        let verified_purchase = escrow.buyer == author;
         
         // Create the review
         let review = &mut ctx.accounts.review;
@@ -42,21 +154,373 @@ pub mod reputation {
         review.recipient = ctx.accounts.recipient.key();
         review.rating = rating;
         review.comment = comment;
-        review.transaction_reference = transaction_reference;
+        review.transaction_reference = Some(transaction_reference);
         review.created_at = Clock::get()?.unix_timestamp;
+        review.imported = false;
+        review.attachment_uris = attachment_uris;
+        review.edited_at = 0;
+        review.return_edit_used = false;
+        review.verified_purchase = verified_purchase;
+        review.counted_toward_product = verified_purchase;
+        review.helpful_votes = 0;
+        review.unhelpful_votes = 0;
+        review.seller_response = None;
+        review.response_edited_at = 0;
+        review.response_edit_count = 0;
         review.bump = *ctx.bumps.get("review").unwrap();
         
+        // Reviewing a purchase lets one held back for review count
+        if verified_purchase {
+            if let Some(author_reputation) = ctx.accounts.author_reputation.as_mut() {
+                if author_reputation.pending_review_purchases > 0 {
+                    author_reputation.pending_review_purchases -= 1;
+                    author_reputation.total_purchases = author_reputation.total_purchases.checked_add(1).unwrap();
+                }
+            }
+        }
+        
         // Update the recipient's reputation
         let user_reputation = &mut ctx.accounts.user_reputation;
         
         // Add the new rating to the total and increment review count
         user_reputation.total_rating = user_reputation.total_rating.checked_add(rating as u64).unwrap();
         user_reputation.review_count = user_reputation.review_count.checked_add(1).unwrap();
+        user_reputation.add_weighted_rating(rating, review.created_at);
+        user_reputation.add_role_rating(role, rating);
+        user_reputation.refresh_average();
+        
+        // Buyers' reviews of an escrow always rate the product it was for,
+        // so every verified rating lands in the product's aggregate, created
+        // with the product's first review
+        match ctx.accounts.product_reputation.as_mut() {
+            Some(product_reputation) => {
+                require!(verified_purchase, ReputationError::InvalidProductReputation);
+                product_reputation.populate_if_new(
+                    escrow.product,
+                    *ctx.bumps.get("product_reputation").unwrap(),
+                );
+                require!(
+                    escrow.product == product_reputation.product,
+                    ReputationError::InvalidProductReputation
+                );
+                
+                product_reputation.total_rating = product_reputation.total_rating.checked_add(rating as u64).unwrap();
+                product_reputation.review_count = product_reputation.review_count.checked_add(1).unwrap();
+            }
+            None => require!(!verified_purchase, ReputationError::InvalidProductReputation),
+        }
+        
+        Ok(())
+    }
+
+    /// Seed historical reviews migrated from another platform. Each review
+    /// takes two `remaining_accounts`: the review PDA to create, seeded by
+    /// `[b"imported_review", author, recipient, created_at]`, and the
+    /// recipient's reputation account. Imported reviews are flagged so they
+    /// can be told apart from native ones. Imports only add to the rating
+    /// aggregates, weighted by their original age; stored averages are
+    /// brought up to date with `verify_reputation_consistency` once a
+    /// migration completes.
+    pub fn import_reviews_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ImportReviews<'info>>,
+        reviews: Vec<ImportedReview>,
+    ) -> Result<()> {
+        require!(
+            ctx.remaining_accounts.len() == reviews.len() * 2,
+            ReputationError::InvalidImportAccounts
+        );
+        
+        let now = Clock::get()?.unix_timestamp;
+        let space = 8 + std::mem::size_of::<Review>() + MAX_COMMENT_LEN + MAX_RESPONSE_LEN;
+        let lamports = Rent::get()?.minimum_balance(space);
+        
+        for (imported, accounts) in reviews.into_iter().zip(ctx.remaining_accounts.chunks(2)) {
+            let (review_info, reputation_info) = (&accounts[0], &accounts[1]);
+            
+            // Validate the historical review like a native one
+            require!(imported.rating >= 1 && imported.rating <= 5, ReputationError::InvalidRating);
+            require!(imported.comment.len() <= MAX_COMMENT_LEN, ReputationError::CommentTooLong);
+            require!(imported.created_at <= now, ReputationError::InvalidImportedReview);
+            
+            // Imported reviews have no transaction to key them like native
+            // ones, so they live in their own namespace keyed by the time the
+            // review was written
+            let created_at_bytes = imported.created_at.to_le_bytes();
+            let (review_key, bump) = Pubkey::find_program_address(
+                &[
+                    b"imported_review",
+                    imported.author.as_ref(),
+                    imported.recipient.as_ref(),
+                    &created_at_bytes,
+                ],
+                &crate::ID,
+            );
+            require_keys_eq!(review_info.key(), review_key, ReputationError::InvalidImportAccounts);
+            
+            anchor_lang::system_program::create_account(
+                CpiContext::new_with_signer(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::CreateAccount {
+                        from: ctx.accounts.authority.to_account_info(),
+                        to: review_info.clone(),
+                    },
+                    &[&[
+                        b"imported_review",
+                        imported.author.as_ref(),
+                        imported.recipient.as_ref(),
+                        &created_at_bytes,
+                        &[bump],
+                    ]],
+                ),
+                lamports,
+                space as u64,
+                &crate::ID,
+            )?;
+            
+            let review = Review {
+                author: imported.author,
+                recipient: imported.recipient,
+                rating: imported.rating,
+                comment: imported.comment,
+                transaction_reference: None,
+                created_at: imported.created_at,
+                imported: true,
+                attachment_uris: Vec::new(),
+                edited_at: 0,
+                return_edit_used: false,
+                verified_purchase: false,
+                counted_toward_product: false,
+                helpful_votes: 0,
+                unhelpful_votes: 0,
+                seller_response: None,
+                response_edited_at: 0,
+                response_edit_count: 0,
+                bump,
+            };
+            review.try_serialize(&mut &mut review_info.try_borrow_mut_data()?[..])?;
+            
+            // Add the rating to the recipient's reputation
+            let mut user_reputation = Account::<UserReputation>::try_from(reputation_info)?;
+            require_keys_eq!(
+                user_reputation.user,
+                imported.recipient,
+                ReputationError::InvalidImportAccounts
+            );
+            user_reputation.total_rating = user_reputation.total_rating.checked_add(imported.rating as u64).unwrap();
+            user_reputation.review_count = user_reputation.review_count.checked_add(1).unwrap();
+            user_reputation.add_weighted_rating(imported.rating, imported.created_at);
+            user_reputation.exit(&crate::ID)?;
+        }
+        
+        Ok(())
+    }
+
+    /// Revise a review's rating and comment. Reviews can be edited for a day
+    /// after they are written. Once a return is approved on the referenced
+    /// escrow, the buyer may instead revise it one more time within the
+    /// marketplace's return review grace, however old the review is.
+    pub fn edit_review(ctx: Context<EditReview>, rating: u8, comment: String) -> Result<()> {
+        require!(rating >= 1 && rating <= 5, ReputationError::InvalidRating);
+        require!(comment.len() <= MAX_COMMENT_LEN, ReputationError::CommentTooLong);
+        
+        let review = &mut ctx.accounts.review;
+        let now = Clock::get()?.unix_timestamp;
+        
+        let escrow = match ctx.accounts.escrow.as_ref() {
+            Some(escrow_info) => {
+                require!(
+                    review.transaction_reference == Some(escrow_info.key()),
+                    ReputationError::InvalidEscrowAccount
+                );
+                Some(EscrowRecord::load(escrow_info)?)
+            }
+            None => None,
+        };
+        
+        let returned = escrow
+            .as_ref()
+            .and_then(|escrow| Some((escrow.marketplace, escrow.returned_at()?)));
+        match returned {
+            // A returned order re-opens the review once, whatever its age
+            Some((escrow_marketplace, returned_at)) => {
+                let marketplace = ctx
+                    .accounts
+                    .marketplace
+                    .as_ref()
+                    .ok_or(ReputationError::InvalidEscrowAccount)?;
+                require!(
+                    escrow_marketplace == marketplace.key(),
+                    ReputationError::InvalidEscrowAccount
+                );
+                let return_edit_deadline = returned_at
+                    .checked_add(marketplace.return_review_grace_seconds)
+                    .ok_or(ReputationError::ReviewEditWindowClosed)?;
+                require!(
+                    !review.return_edit_used && now <= return_edit_deadline,
+                    ReputationError::ReviewEditWindowClosed
+                );
+                review.return_edit_used = true;
+            }
+            None => {
+                let edit_deadline = review
+                    .created_at
+                    .checked_add(REVIEW_EDIT_WINDOW_SECONDS)
+                    .ok_or(ReputationError::ReviewEditWindowClosed)?;
+                require!(now <= edit_deadline, ReputationError::ReviewEditWindowClosed);
+            }
+        }
+        
+        // Replace the old rating in the recipient's reputation
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.total_rating = user_reputation
+            .total_rating
+            .checked_sub(review.rating as u64)
+            .unwrap()
+            .checked_add(rating as u64)
+            .unwrap();
+        user_reputation.remove_weighted_rating(review.rating, review.created_at);
+        user_reputation.add_weighted_rating(rating, review.created_at);
+        if let Some(role) = review.recipient_role() {
+            user_reputation.remove_role_rating(role, review.rating);
+            user_reputation.add_role_rating(role, rating);
+        }
+        user_reputation.refresh_average();
+        
+        // And in the product's, exactly when the review counted toward it
+        match ctx.accounts.product_reputation.as_mut() {
+            Some(product_reputation) => {
+                require!(review.counted_toward_product, ReputationError::InvalidProductReputation);
+                let escrow = escrow.as_ref().ok_or(ReputationError::InvalidEscrowAccount)?;
+                require!(
+                    escrow.product == product_reputation.product,
+                    ReputationError::InvalidProductReputation
+                );
+                product_reputation.total_rating = product_reputation
+                    .total_rating
+                    .checked_sub(review.rating as u64)
+                    .and_then(|total| total.checked_add(rating as u64))
+                    .ok_or(ReputationError::InvalidProductReputation)?;
+            }
+            None => require!(!review.counted_toward_product, ReputationError::InvalidProductReputation),
+        }
+        
+        review.rating = rating;
+        review.comment = comment;
+        review.edited_at = now;
+        
+        Ok(())
+    }
+
+    /// Post the reviewed seller's public response to a review. A review takes
+    /// one response, which is revised through `edit_review_response` rather
+    /// than overwritten. Sellers must wait out a cooldown between responses,
+    /// so they cannot flood reviews.
+    pub fn respond_to_review(ctx: Context<RespondToReview>, response: String) -> Result<()> {
+        validate_response(&response)?;
+        require!(
+            ctx.accounts.review.seller_response.is_none(),
+            ReputationError::ResponseAlreadyPosted
+        );
+        
+        let now = Clock::get()?.unix_timestamp;
+        let activity = &mut ctx.accounts.seller_activity;
+        require!(
+            activity.last_response_at == 0
+                || now >= activity.last_response_at + RESPONSE_COOLDOWN_SECONDS,
+            ReputationError::ResponseCooldown
+        );
+        activity.seller = ctx.accounts.seller.key();
+        activity.last_response_at = now;
+        activity.response_count = activity.response_count.checked_add(1).unwrap();
+        activity.bump = *ctx.bumps.get("seller_activity").unwrap();
+        
+        ctx.accounts.review.seller_response = Some(response);
+        
+        Ok(())
+    }
+
+    /// Revise a response to a review, at most `MAX_RESPONSE_EDITS` times
+    pub fn edit_review_response(ctx: Context<EditReviewResponse>, response: String) -> Result<()> {
+        validate_response(&response)?;
+        
+        let review = &mut ctx.accounts.review;
+        require!(review.seller_response.is_some(), ReputationError::NoResponseToEdit);
+        require!(
+            review.response_edit_count < MAX_RESPONSE_EDITS,
+            ReputationError::ResponseEditLimitReached
+        );
+        review.seller_response = Some(response);
+        review.response_edit_count += 1;
+        review.response_edited_at = Clock::get()?.unix_timestamp;
+        
+        Ok(())
+    }
+
+    /// Vote on whether a review was helpful. Each wallet votes once per
+    /// review, and authors cannot vote on their own reviews.
+    pub fn vote_on_review(ctx: Context<VoteOnReview>, helpful: bool) -> Result<()> {
+        let review = &mut ctx.accounts.review;
+        if helpful {
+            review.helpful_votes = review.helpful_votes.checked_add(1).unwrap();
+        } else {
+            review.unhelpful_votes = review.unhelpful_votes.checked_add(1).unwrap();
+        }
+        
+        let review_vote = &mut ctx.accounts.review_vote;
+        review_vote.review = review.key();
+        review_vote.voter = ctx.accounts.voter.key();
+        review_vote.helpful = helpful;
+        review_vote.created_at = Clock::get()?.unix_timestamp;
+        review_vote.bump = *ctx.bumps.get("review_vote").unwrap();
+        
+        Ok(())
+    }
+
+    /// Initialize the rating aggregate for a product
+    pub fn initialize_product_reputation(ctx: Context<InitializeProductReputation>) -> Result<()> {
+        let product_reputation = &mut ctx.accounts.product_reputation;
+        product_reputation.product = ctx.accounts.product.key();
+        product_reputation.total_rating = 0;
+        product_reputation.review_count = 0;
+        product_reputation.bump = *ctx.bumps.get("product_reputation").unwrap();
+        
+        Ok(())
+    }
+
+    /// Return a product's average rating in hundredths of a star
+    pub fn product_rating(ctx: Context<ProductRating>) -> Result<u64> {
+        Ok(ctx.accounts.product_reputation.average_rating_x100())
+    }
+
+    /// Create the reputation program's config, making the signer its admin
+    /// and trusting `marketplace` with reputations. The config can only be
+    /// created once, so deployments initialize it right after deploying.
+    pub fn initialize_reputation_config(ctx: Context<InitializeReputationConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.reputation_config;
+        config.admin = ctx.accounts.admin.key();
+        config.marketplace = ctx.accounts.marketplace.key();
+        config.bump = *ctx.bumps.get("reputation_config").unwrap();
+        
+        Ok(())
+    }
+
+    /// Set the marketplace whose authority verifies users, sets verification
+    /// thresholds and records transactions outside escrows. Reputations are
+    /// shared by every marketplace, so only the config's admin may choose it.
+    pub fn set_reputation_marketplace(ctx: Context<SetReputationMarketplace>) -> Result<()> {
+        ctx.accounts.reputation_config.marketplace = ctx.accounts.marketplace.key();
+        
+        Ok(())
+    }
+
+    /// Hand the reputation config's admin role to another wallet
+    pub fn set_reputation_admin(ctx: Context<SetReputationAdmin>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.reputation_config.admin = new_admin;
         
         Ok(())
     }
 
-    /// Verify a user (can only be called by a marketplace authority)
+    /// Verify a user. Only the trusted marketplace's authority may verify users.
     pub fn verify_user(ctx: Context<VerifyUser>) -> Result<()> {
         // Update the user's verification status
         let user_reputation = &mut ctx.accounts.user_reputation;
@@ -65,25 +529,297 @@ pub mod reputation {
         Ok(())
     }
 
+    /// Revoke a user's verification, recording when it was revoked. Only the
+    /// trusted marketplace's authority may unverify users.
+    pub fn unverify_user(ctx: Context<VerifyUser>) -> Result<()> {
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.is_verified = false;
+        user_reputation.unverified_at = Some(Clock::get()?.unix_timestamp);
+        
+        Ok(())
+    }
+
+    /// Set the reputation a user needs to verify themselves with `auto_verify`
+    pub fn set_verification_thresholds(
+        ctx: Context<SetVerificationThresholds>,
+        min_sales: u64,
+        min_average_rating_x100: u64,
+        max_disputes_lost: u64,
+    ) -> Result<()> {
+        require!(min_average_rating_x100 <= 500, ReputationError::InvalidVerificationThresholds);
+        
+        let config = &mut ctx.accounts.verification_config;
+        config.marketplace = ctx.accounts.marketplace.key();
+        config.min_sales = min_sales;
+        config.min_average_rating_x100 = min_average_rating_x100;
+        config.max_disputes_lost = max_disputes_lost;
+        config.bump = *ctx.bumps.get("verification_config").unwrap();
+        
+        Ok(())
+    }
+
+    /// Verify a user whose reputation meets the trusted marketplace's
+    /// verification thresholds. Anyone may call this; it only succeeds for
+    /// users who qualify. Users whose verification was revoked need the
+    /// marketplace authority to verify them again.
+    pub fn auto_verify(ctx: Context<AutoVerify>) -> Result<()> {
+        let config = &ctx.accounts.verification_config;
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        
+        require!(user_reputation.unverified_at.is_none(), ReputationError::VerificationRevoked);
+        require!(
+            user_reputation.total_sales >= config.min_sales
+                && user_reputation.computed_average_rating_x100() >= config.min_average_rating_x100
+                && user_reputation.disputes_lost <= config.max_disputes_lost,
+            ReputationError::VerificationThresholdsNotMet
+        );
+        user_reputation.is_verified = true;
+        
+        Ok(())
+    }
+
+    /// Remove a review left by a buyer who lost a dispute on the linked escrow.
+    /// Only the marketplace authority may remove it, and only within the
+    /// marketplace's grace period after the seller-favored resolution.
+    pub fn remove_review(ctx: Context<RemoveReview>) -> Result<()> {
+        let review = &ctx.accounts.review;
+        let escrow = EscrowRecord::load(&ctx.accounts.escrow)?;
+        
+        // The escrow must belong to this marketplace and be between the review's parties
+        require!(
+            escrow.marketplace == ctx.accounts.marketplace.key(),
+            ReputationError::InvalidEscrowAccount
+        );
+        require!(
+            escrow.buyer == review.author && escrow.seller == review.recipient,
+            ReputationError::InvalidEscrowAccount
+        );
+        
+        // Only a dispute resolved for the seller makes the buyer's review removable
+        require!(
+            escrow.dispute_resolved_at > 0 && escrow.favored_seller,
+            ReputationError::ReviewNotRemovable
+        );
+        let removal_deadline = escrow
+            .dispute_resolved_at
+            .checked_add(ctx.accounts.marketplace.review_removal_grace_seconds)
+            .ok_or(ReputationError::ReviewNotRemovable)?;
+        require!(
+            Clock::get()?.unix_timestamp <= removal_deadline,
+            ReputationError::ReviewRemovalWindowClosed
+        );
+        
+        // Back the rating out of the recipient's reputation
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.total_rating = user_reputation.total_rating.checked_sub(review.rating as u64).unwrap();
+        user_reputation.review_count = user_reputation.review_count.checked_sub(1).unwrap();
+        user_reputation.remove_weighted_rating(review.rating, review.created_at);
+        user_reputation.remove_role_rating(ReviewerRole::Seller, review.rating);
+        user_reputation.refresh_average();
+        
+        // The removed review no longer rates the escrow's product either
+        match ctx.accounts.product_reputation.as_mut() {
+            Some(product_reputation) => {
+                require!(review.counted_toward_product, ReputationError::InvalidProductReputation);
+                require!(
+                    escrow.product == product_reputation.product,
+                    ReputationError::InvalidProductReputation
+                );
+                product_reputation.total_rating = product_reputation
+                    .total_rating
+                    .checked_sub(review.rating as u64)
+                    .ok_or(ReputationError::InvalidProductReputation)?;
+                product_reputation.review_count = product_reputation
+                    .review_count
+                    .checked_sub(1)
+                    .ok_or(ReputationError::InvalidProductReputation)?;
+            }
+            None => require!(!review.counted_toward_product, ReputationError::InvalidProductReputation),
+        }
+        
+        Ok(())
+    }
+
+    /// Record a dispute the user lost. Only the escrow program may call this,
+    /// signing with its escrow authority PDA when it resolves a dispute.
+    pub fn record_dispute_loss(ctx: Context<RecordDisputeLoss>) -> Result<()> {
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.disputes_lost = user_reputation.disputes_lost.checked_add(1).unwrap();
+        
+        Ok(())
+    }
+
+    /// Record a buyer's cancellation of a funded escrow, counting it within
+    /// the marketplace's `window_seconds` (0 = no window). Only callable by
+    /// the escrow program. Returns the cancellations in the current window.
+    pub fn record_funded_cancellation(
+        ctx: Context<RecordFundedCancellation>,
+        window_seconds: i64,
+    ) -> Result<u64> {
+        let now = Clock::get()?.unix_timestamp;
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.funded_cancellations = user_reputation.funded_cancellations.checked_add(1).unwrap();
+        
+        // A cancellation after the window has passed starts a new one
+        if user_reputation.recent_cancellations == 0
+            || (window_seconds > 0 && now - user_reputation.recent_cancellations_since >= window_seconds)
+        {
+            user_reputation.recent_cancellations = 0;
+            user_reputation.recent_cancellations_since = now;
+        }
+        user_reputation.recent_cancellations = user_reputation.recent_cancellations.checked_add(1).unwrap();
+        
+        Ok(user_reputation.recent_cancellations)
+    }
+
+    /// Recompute a user's stored average rating from their rating aggregates,
+    /// correcting and reporting any drift. Permissionless, so anyone can
+    /// check a reputation's integrity. Returns whether drift was found. The
+    /// weighted score is brought up to date as well.
+    pub fn verify_reputation_consistency(ctx: Context<VerifyReputationConsistency>) -> Result<bool> {
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.weighted_score_x100 = user_reputation.compute_weighted_score();
+        let stored = user_reputation.average_rating_x100;
+        let recomputed = user_reputation.computed_average_rating_x100() as u16;
+        if stored == recomputed {
+            return Ok(false);
+        }
+        
+        user_reputation.average_rating_x100 = recomputed;
+        emit!(ReputationDriftCorrected {
+            user: user_reputation.user,
+            stored_average_rating_x100: stored,
+            recomputed_average_rating_x100: recomputed,
+        });
+        
+        Ok(true)
+    }
+
+    /// Return a snapshot of a user's reputation for other programs. The
+    /// values are written as this program's return data, so a caller that
+    /// CPIs in can trust them as program-authored.
+    pub fn reputation_proof(ctx: Context<ReputationProof>) -> Result<ReputationSnapshot> {
+        let user_reputation = &ctx.accounts.user_reputation;
+        
+        Ok(ReputationSnapshot {
+            user: user_reputation.user,
+            average_rating_x100: user_reputation.computed_average_rating_x100(),
+            review_count: user_reputation.review_count,
+            total_sales: user_reputation.total_sales,
+            is_verified: user_reputation.is_verified,
+            slot: Clock::get()?.slot,
+        })
+    }
+
     /// Record a completed sale for a user
     pub fn record_sale(ctx: Context<RecordTransactionCount>) -> Result<()> {
         // Increment the user's total sales count
         let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.populate_if_new(
+            ctx.accounts.user.key(),
+            *ctx.bumps.get("user_reputation").unwrap(),
+        )?;
         user_reputation.total_sales = user_reputation.total_sales.checked_add(1).unwrap();
+        user_reputation.tier = SellerTier::for_sales(user_reputation.total_sales);
         
         Ok(())
     }
 
-    /// Record a completed purchase for a user
+    /// Record a completed purchase for a user. On marketplaces requiring
+    /// purchase reviews it is held back until the user reviews a purchase.
     pub fn record_purchase(ctx: Context<RecordTransactionCount>) -> Result<()> {
+        let needs_review = ctx.accounts.marketplace.require_purchase_reviews;
+        
         // Increment the user's total purchases count
         let user_reputation = &mut ctx.accounts.user_reputation;
-        user_reputation.total_purchases = user_reputation.total_purchases.checked_add(1).unwrap();
+        user_reputation.populate_if_new(
+            ctx.accounts.user.key(),
+            *ctx.bumps.get("user_reputation").unwrap(),
+        )?;
+        if needs_review {
+            user_reputation.pending_review_purchases =
+                user_reputation.pending_review_purchases.checked_add(1).unwrap();
+        } else {
+            user_reputation.total_purchases = user_reputation.total_purchases.checked_add(1).unwrap();
+        }
+        
+        Ok(())
+    }
+
+    /// Take back a sale recorded for an escrow whose return was approved.
+    /// Only the escrow program may call this.
+    pub fn reverse_sale(ctx: Context<ReverseTransactionCount>) -> Result<()> {
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        user_reputation.total_sales = user_reputation.total_sales.saturating_sub(1);
+        user_reputation.tier = SellerTier::for_sales(user_reputation.total_sales);
+        
+        Ok(())
+    }
+
+    /// Take back a purchase recorded for an escrow whose return was approved,
+    /// starting with purchases still held back for a review. Only the escrow
+    /// program may call this.
+    pub fn reverse_purchase(ctx: Context<ReverseTransactionCount>) -> Result<()> {
+        let user_reputation = &mut ctx.accounts.user_reputation;
+        if user_reputation.pending_review_purchases > 0 {
+            user_reputation.pending_review_purchases -= 1;
+        } else {
+            user_reputation.total_purchases = user_reputation.total_purchases.saturating_sub(1);
+        }
         
         Ok(())
     }
 }
 
+/// Whether `authority` may record transactions on `marketplace`: the escrow
+/// program's authority PDA settling an escrow, or the authority of the
+/// marketplace the reputation config trusts
+fn can_record_transactions(
+    marketplace: &Account<MarketplaceState>,
+    reputation_config: Option<&ReputationConfig>,
+    authority: &Pubkey,
+) -> bool {
+    let (escrow_authority, _) =
+        Pubkey::find_program_address(&[marketplace::ESCROW_AUTHORITY_SEED], &ESCROW_PROGRAM_ID);
+    let trusted = reputation_config.map_or(false, |config| config.marketplace == marketplace.key());
+    escrow_authority == *authority || (trusted && marketplace.authority == *authority)
+}
+
+/// Ensure review attachments are few enough and use an accepted scheme
+fn validate_attachment_uris(attachment_uris: &[String]) -> Result<()> {
+    require!(
+        attachment_uris.len() <= MAX_ATTACHMENTS,
+        ReputationError::TooManyAttachments
+    );
+    for uri in attachment_uris {
+        require!(
+            uri.len() <= MAX_ATTACHMENT_URI_LEN
+                && ATTACHMENT_URI_SCHEMES
+                    .iter()
+                    .any(|scheme| uri.len() > scheme.len() && uri.starts_with(scheme)),
+            ReputationError::InvalidAttachmentUri
+        );
+    }
+    
+    Ok(())
+}
+
+/// Ensure a seller response fits its account and only links to URIs with an
+/// accepted scheme
+fn validate_response(response: &str) -> Result<()> {
+    require!(response.len() <= MAX_RESPONSE_LEN, ReputationError::ResponseTooLong);
+    for link in response.split_whitespace().filter(|word| word.contains("://")) {
+        require!(
+            ATTACHMENT_URI_SCHEMES
+                .iter()
+                .any(|scheme| link.len() > scheme.len() && link.starts_with(scheme)),
+            ReputationError::InvalidResponseLink
+        );
+    }
+    
+    Ok(())
+}
+
 /// Account structure for user reputation data
 #[account]
 pub struct UserReputation {
@@ -99,12 +835,294 @@ pub struct UserReputation {
     pub total_purchases: u64,
     /// Whether the user is verified by the marketplace
     pub is_verified: bool,
+    /// Number of disputes resolved against the user
+    pub disputes_lost: u64,
     /// Timestamp when the reputation was created
     pub created_at: i64,
+    /// Average rating in hundredths of a star as of the last rating change,
+    /// e.g. 425 = 4.25 stars, for clients reading the account directly
+    pub average_rating_x100: u16,
+    /// Purchases that will count toward `total_purchases` once reviewed
+    pub pending_review_purchases: u64,
+    /// Total number of funded escrows the user cancelled as a buyer
+    pub funded_cancellations: u64,
+    /// Funded cancellations within the current window
+    pub recent_cancellations: u64,
+    /// Start of the current cancellation window
+    pub recent_cancellations_since: i64,
+    /// When the marketplace last revoked the user's verification
+    pub unverified_at: Option<i64>,
+    /// Sum of ratings each weighted by its decay as of `weighted_as_of`,
+    /// in units of `RATING_WEIGHT_ONE` stars
+    pub weighted_rating_sum: u64,
+    /// Sum of the decayed weights of the ratings, in `RATING_WEIGHT_ONE` units
+    pub weighted_review_weight: u64,
+    /// Time the weighted aggregates are decayed to (the newest rating's)
+    pub weighted_as_of: i64,
+    /// Recency-weighted average rating in hundredths of a star as of the
+    /// last rating change
+    pub weighted_score_x100: u64,
+    /// Sum of ratings received as a seller
+    pub seller_total_rating: u64,
+    /// Number of reviews received as a seller
+    pub seller_review_count: u64,
+    /// Sum of ratings received as a buyer
+    pub buyer_total_rating: u64,
+    /// Number of reviews received as a buyer
+    pub buyer_review_count: u64,
+    /// Seller badge earned by completed sales
+    pub tier: SellerTier,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl UserReputation {
+    /// Fill in a reputation account that was just created on the user's first
+    /// sale, purchase or review. Accounts that already belong to the user are
+    /// left untouched, so counters are never reset.
+    pub fn populate_if_new(&mut self, user: Pubkey, bump: u8) -> Result<()> {
+        if self.user == Pubkey::default() {
+            self.user = user;
+            self.created_at = Clock::get()?.unix_timestamp;
+            self.bump = bump;
+        }
+        require!(self.user == user, ReputationError::InvalidReputationAccount);
+        
+        Ok(())
+    }
+
+    /// Completed sales recorded on a reputation PDA. Users without a
+    /// reputation account yet have made no sales.
+    pub fn total_sales_of(info: &AccountInfo) -> Result<u64> {
+        if info.owner != &crate::ID || info.data_is_empty() {
+            return Ok(0);
+        }
+        Ok(UserReputation::try_deserialize(&mut &info.try_borrow_data()?[..])?.total_sales)
+    }
+
+    /// Bring the stored averages up to date with the rating aggregates
+    pub fn refresh_average(&mut self) {
+        // Ratings are at most 5 stars, so the average always fits
+        self.average_rating_x100 = self.computed_average_rating_x100() as u16;
+        self.weighted_score_x100 = self.compute_weighted_score();
+    }
+
+    /// Average rating in hundredths of a star with each rating weighted by
+    /// how recently it was given, halving every `RATING_HALF_LIFE_SECONDS`.
+    /// Falls back to the plain average once every rating has decayed away.
+    pub fn compute_weighted_score(&self) -> u64 {
+        if self.weighted_review_weight == 0 {
+            return self.computed_average_rating_x100();
+        }
+        ((self.weighted_rating_sum as u128) * 100 / (self.weighted_review_weight as u128)) as u64
+    }
+
+    /// Add a rating given at `created_at` to the weighted aggregates. The
+    /// aggregates are kept decayed to the newest rating's time, so a newer
+    /// rating first decays them to its own time and an older one, such as an
+    /// imported review, is decayed to theirs.
+    pub fn add_weighted_rating(&mut self, rating: u8, created_at: i64) {
+        let weight = if created_at >= self.weighted_as_of {
+            let decay = decay_weight(created_at - self.weighted_as_of);
+            self.weighted_rating_sum = decayed(self.weighted_rating_sum, decay);
+            self.weighted_review_weight = decayed(self.weighted_review_weight, decay);
+            self.weighted_as_of = created_at;
+            RATING_WEIGHT_ONE
+        } else {
+            decay_weight(self.weighted_as_of - created_at)
+        };
+        self.weighted_rating_sum = self.weighted_rating_sum.saturating_add(weight * rating as u64);
+        self.weighted_review_weight = self.weighted_review_weight.saturating_add(weight);
+    }
+
+    /// Take a rating given at `created_at` back out of the weighted aggregates
+    pub fn remove_weighted_rating(&mut self, rating: u8, created_at: i64) {
+        let weight = decay_weight(self.weighted_as_of.saturating_sub(created_at));
+        self.weighted_rating_sum = self.weighted_rating_sum.saturating_sub(weight * rating as u64);
+        self.weighted_review_weight = self.weighted_review_weight.saturating_sub(weight);
+    }
+
+    /// Average rating in hundredths of a star recomputed from the rating
+    /// aggregates, 0 with no reviews
+    pub fn computed_average_rating_x100(&self) -> u64 {
+        average_x100(self.total_rating, self.review_count)
+    }
+
+    /// Average rating received as a seller, in hundredths of a star
+    pub fn seller_average_rating_x100(&self) -> u64 {
+        average_x100(self.seller_total_rating, self.seller_review_count)
+    }
+
+    /// Average rating received as a buyer, in hundredths of a star
+    pub fn buyer_average_rating_x100(&self) -> u64 {
+        average_x100(self.buyer_total_rating, self.buyer_review_count)
+    }
+
+    /// Add a rating to the pool of the role it was received in
+    pub fn add_role_rating(&mut self, role: ReviewerRole, rating: u8) {
+        let (total_rating, review_count) = self.role_pool_mut(role);
+        *total_rating = total_rating.checked_add(rating as u64).unwrap();
+        *review_count = review_count.checked_add(1).unwrap();
+    }
+
+    /// Take a rating back out of the pool of the role it was received in
+    pub fn remove_role_rating(&mut self, role: ReviewerRole, rating: u8) {
+        let (total_rating, review_count) = self.role_pool_mut(role);
+        *total_rating = total_rating.saturating_sub(rating as u64);
+        *review_count = review_count.saturating_sub(1);
+    }
+
+    fn role_pool_mut(&mut self, role: ReviewerRole) -> (&mut u64, &mut u64) {
+        match role {
+            ReviewerRole::Seller => (&mut self.seller_total_rating, &mut self.seller_review_count),
+            ReviewerRole::Buyer => (&mut self.buyer_total_rating, &mut self.buyer_review_count),
+        }
+    }
+}
+
+/// Average of `review_count` ratings summing to `total_rating`, in
+/// hundredths of a star, 0 with no reviews
+fn average_x100(total_rating: u64, review_count: u64) -> u64 {
+    total_rating
+        .checked_mul(100)
+        .unwrap()
+        .checked_div(review_count)
+        .unwrap_or(0)
+}
+
+/// Seller badge earned by sales volume
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SellerTier {
+    Unranked,
+    Bronze,
+    Silver,
+    Gold,
+}
+
+impl SellerTier {
+    /// Tier reached with `total_sales` completed sales
+    pub fn for_sales(total_sales: u64) -> Self {
+        if total_sales >= GOLD_TIER_MIN_SALES {
+            SellerTier::Gold
+        } else if total_sales >= SILVER_TIER_MIN_SALES {
+            SellerTier::Silver
+        } else if total_sales >= BRONZE_TIER_MIN_SALES {
+            SellerTier::Bronze
+        } else {
+            SellerTier::Unranked
+        }
+    }
+}
+
+/// Role a review's recipient is rated in
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewerRole {
+    Seller,
+    Buyer,
+}
+
+/// Weight, in `RATING_WEIGHT_ONE` units, of a rating `age` seconds old: halved
+/// for every whole half-life and interpolated linearly within the last one
+fn decay_weight(age: i64) -> u64 {
+    if age <= 0 {
+        return RATING_WEIGHT_ONE;
+    }
+    let half_lives = age / RATING_HALF_LIFE_SECONDS;
+    if half_lives >= 64 {
+        return 0;
+    }
+    let weight = RATING_WEIGHT_ONE >> half_lives;
+    let into_half_life = (age % RATING_HALF_LIFE_SECONDS) as u64;
+    weight - weight * into_half_life / (2 * RATING_HALF_LIFE_SECONDS as u64)
+}
+
+/// Scale a weighted aggregate by `decay`, in `RATING_WEIGHT_ONE` units
+fn decayed(value: u64, decay: u64) -> u64 {
+    ((value as u128) * (decay as u128) / (RATING_WEIGHT_ONE as u128)) as u64
+}
+
+/// Reputation program settings, managed by its admin
+#[account]
+pub struct ReputationConfig {
+    /// Wallet allowed to change the settings
+    pub admin: Pubkey,
+    /// The marketplace whose authority verifies users and records
+    /// transactions outside escrows
+    pub marketplace: Pubkey,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Reputation a user needs to verify themselves on a marketplace
+#[account]
+pub struct VerificationConfig {
+    /// The marketplace whose authority set the thresholds
+    pub marketplace: Pubkey,
+    /// Fewest completed sales
+    pub min_sales: u64,
+    /// Lowest average rating, in hundredths of a star
+    pub min_average_rating_x100: u64,
+    /// Most disputes the user may have lost
+    pub max_disputes_lost: u64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Account structure for a product's rating aggregate, built from buyers'
+/// reviews of escrows for the product
+#[account]
+pub struct ProductReputation {
+    /// The product being rated
+    pub product: Pubkey,
+    /// Sum of all ratings received (sum of stars)
+    pub total_rating: u64,
+    /// Number of reviews received
+    pub review_count: u64,
     /// PDA bump seed
     pub bump: u8,
 }
 
+impl ProductReputation {
+    /// Fill in a rating aggregate that was just created on the product's
+    /// first review. Existing aggregates are left untouched.
+    pub fn populate_if_new(&mut self, product: Pubkey, bump: u8) {
+        if self.product == Pubkey::default() {
+            self.product = product;
+            self.bump = bump;
+        }
+    }
+
+    /// Average rating in hundredths of a star, 0 with no reviews
+    pub fn average_rating_x100(&self) -> u64 {
+        average_x100(self.total_rating, self.review_count)
+    }
+}
+
+/// Point-in-time view of a user's reputation returned by `reputation_proof`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReputationSnapshot {
+    /// The user the snapshot describes
+    pub user: Pubkey,
+    /// Average rating in hundredths of a star
+    pub average_rating_x100: u64,
+    /// Number of reviews received
+    pub review_count: u64,
+    /// Total number of successful sales
+    pub total_sales: u64,
+    /// Whether the user is verified by the marketplace
+    pub is_verified: bool,
+    /// Slot at which the snapshot was taken
+    pub slot: u64,
+}
+
+/// Emitted when a stored average rating had drifted from the aggregates
+#[event]
+pub struct ReputationDriftCorrected {
+    pub user: Pubkey,
+    pub stored_average_rating_x100: u16,
+    pub recomputed_average_rating_x100: u16,
+}
+
 /// Account structure for a review
 #[account]
 pub struct Review {
@@ -114,79 +1132,521 @@ pub struct Review {
     pub recipient: Pubkey,
     /// Rating (1-5 stars)
     pub rating: u8,
-    /// Review comment
+    /// Review comment (max 500 bytes)
     pub comment: String,
     /// Optional reference to the transaction this review is for
     pub transaction_reference: Option<Pubkey>,
     /// Timestamp when the review was created
     pub created_at: i64,
+    /// Whether the review was imported from another platform
+    pub imported: bool,
+    /// URIs of attached images (at most 3, each at most 200 bytes)
+    pub attachment_uris: Vec<String>,
+    /// Timestamp of the last edit (0 if never edited)
+    pub edited_at: i64,
+    /// Whether the edit re-opened by a return has been used
+    pub return_edit_used: bool,
+    /// Whether the review is linked to the author's completed purchase from
+    /// the recipient
+    pub verified_purchase: bool,
+    /// Whether the rating was added to the product's rating aggregate
+    pub counted_toward_product: bool,
+    /// Number of voters who found the review helpful
+    pub helpful_votes: u64,
+    /// Number of voters who found the review unhelpful
+    pub unhelpful_votes: u64,
+    /// The recipient's public response (max 300 bytes)
+    pub seller_response: Option<String>,
+    /// Timestamp of the last edit to the response (0 if never edited)
+    pub response_edited_at: i64,
+    /// Number of times the response has been edited
+    pub response_edit_count: u8,
     /// PDA bump seed
     pub bump: u8,
 }
 
-/// Accounts required for initializing a user's reputation
-#[derive(Accounts)]
-pub struct InitializeUserReputation<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    
-    #[account(
-        init,
-        payer = user,
-        space = 8 + std::mem::size_of::<UserReputation>(),
-        seeds = [b"user_reputation", user.key().as_ref()],
-        bump
-    )]
-    pub user_reputation: Account<'info, UserReputation>,
-    
-    pub system_program: Program<'info, System>,
+impl Review {
+    /// Role the recipient was rated in. Native reviews of the seller carry
+    /// the verified purchase badge; imported reviews count toward neither.
+    pub fn recipient_role(&self) -> Option<ReviewerRole> {
+        if self.imported {
+            None
+        } else if self.verified_purchase {
+            Some(ReviewerRole::Seller)
+        } else {
+            Some(ReviewerRole::Buyer)
+        }
+    }
 }
 
-/// Accounts required for creating a review
-#[derive(Accounts)]
-pub struct CreateReview<'info> {
-    #[account(mut)]
-    pub author: Signer<'info>,
-    
-    /// The user being reviewed
-    pub recipient: AccountInfo<'info>,
-    
-    /// The reputation account of the recipient
-    #[account(
-        mut,
-        seeds = [b"user_reputation", recipient.key().as_ref()],
-        bump = user_reputation.bump
-    )]
-    pub user_reputation: Account<'info, UserReputation>,
+/// Account structure recording a wallet's vote on a review, so each
+/// wallet votes once
+#[account]
+pub struct ReviewVote {
+    /// Review voted on
+    pub review: Pubkey,
+    /// Wallet that voted
+    pub voter: Pubkey,
+    /// Whether the vote found the review helpful
+    pub helpful: bool,
+    /// Timestamp of the vote
+    pub created_at: i64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// Account structure tracking a seller's review responses, used to rate
+/// limit them
+#[account]
+pub struct SellerActivity {
+    /// Seller the activity belongs to
+    pub seller: Pubkey,
+    /// Timestamp of the seller's last response (0 if none)
+    pub last_response_at: i64,
+    /// Number of responses the seller has posted
+    pub response_count: u64,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+/// A historical review supplied to `import_reviews_batch`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ImportedReview {
+    /// User who wrote the review
+    pub author: Pubkey,
+    /// User who received the review
+    pub recipient: Pubkey,
+    /// Rating (1-5 stars)
+    pub rating: u8,
+    /// Review comment (max 500 bytes)
+    pub comment: String,
+    /// Timestamp of the review on the original platform
+    pub created_at: i64,
+}
+
+/// Leading fields of the escrow program's `Escrow` account. The escrow
+/// program links against this crate, so the layout is mirrored here rather
+/// than imported; escrow fields are only ever appended after these.
+#[derive(AnchorDeserialize)]
+pub struct EscrowRecord {
+    pub marketplace: Pubkey,
+    pub buyer: Pubkey,
+    pub seller: Pubkey,
+    pub product: Pubkey,
+    pub quantity: u64,
+    pub amount: u64,
+    pub currency: CurrencyType,
+    /// Index of the escrow's `EscrowStatus` variant
+    pub status: u8,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub return_window_seconds: i64,
+    pub completed_at: i64,
+    pub funded_amount: u64,
+    pub is_layaway: bool,
+    pub layaway_deadline: i64,
+    pub layaway_forfeit_bps: u16,
+    pub dispute_resolved_at: i64,
+    pub favored_seller: bool,
+}
+
+impl EscrowRecord {
+    /// Index of `EscrowStatus::Completed`
+    pub const STATUS_COMPLETED: u8 = 3;
+    /// Index of `EscrowStatus::Refunded`
+    pub const STATUS_REFUNDED: u8 = 6;
+    /// Index of `EscrowStatus::PartiallyCompleted`
+    pub const STATUS_PARTIALLY_COMPLETED: u8 = 10;
+
+    /// Whether the buyer confirmed delivery of at least part of the order
+    pub fn is_completed(&self) -> bool {
+        self.status == Self::STATUS_COMPLETED || self.status == Self::STATUS_PARTIALLY_COMPLETED
+    }
+
+    /// When a completed order was refunded through an approved return. The
+    /// escrow is not touched again after approval, so its last update marks
+    /// the approval.
+    pub fn returned_at(&self) -> Option<i64> {
+        (self.status == Self::STATUS_REFUNDED && self.completed_at > 0).then_some(self.updated_at)
+    }
+
+    /// Deserialize an escrow account after checking its owner and discriminator
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        require_keys_eq!(*info.owner, ESCROW_PROGRAM_ID, ReputationError::InvalidEscrowAccount);
+        
+        let data = info.try_borrow_data()?;
+        let discriminator = hash(b"account:Escrow").to_bytes();
+        require!(
+            data.len() >= 8 && data[..8] == discriminator[..8],
+            ReputationError::InvalidEscrowAccount
+        );
+        
+        EscrowRecord::deserialize(&mut &data[8..])
+            .map_err(|_| error!(ReputationError::InvalidEscrowAccount))
+    }
+}
+
+/// Accounts required for initializing a user's reputation
+#[derive(Accounts)]
+pub struct InitializeUserReputation<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = user,
+        space = 8 + std::mem::size_of::<UserReputation>(),
+        seeds = [b"user_reputation", user.key().as_ref()],
+        bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for creating a review
+#[derive(Accounts)]
+#[instruction(rating: u8, comment: String, transaction_reference: Pubkey)]
+pub struct CreateReview<'info> {
+    #[account(mut)]
+    pub author: Signer<'info>,
+    
+    /// The user being reviewed
+    pub recipient: AccountInfo<'info>,
     
-    /// The review account
+    /// The reputation account of the recipient, created on their first review
+    #[account(
+        init_if_needed,
+        payer = author,
+        space = 8 + std::mem::size_of::<UserReputation>(),
+        seeds = [b"user_reputation", recipient.key().as_ref()],
+        bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+    
+    /// The review account, one per author and transaction
     #[account(
         init,
         payer = author,
-        space = 8 + std::mem::size_of::<Review>() + 500, // Extra space for the comment string
+        space = 8 + std::mem::size_of::<Review>() + MAX_COMMENT_LEN // Extra space for the comment string
+            + MAX_ATTACHMENTS * (4 + MAX_ATTACHMENT_URI_LEN) // and each attachment URI
+            + MAX_RESPONSE_LEN, // and the seller's response
         seeds = [
             b"review",
             author.key().as_ref(),
             recipient.key().as_ref(),
-            &Clock::get()?.unix_timestamp.to_le_bytes(),
+            transaction_reference.as_ref(),
         ],
         bump
     )]
     pub review: Account<'info, Review>,
     
+    /// The completed escrow the review references
+    pub escrow: AccountInfo<'info>,
+    
+    /// The product the escrow was for
+    pub product: AccountInfo<'info>,
+    
+    /// The rating aggregate of the escrow's product, created on its first
+    /// review. Required for the buyer's review and rejected for the seller's.
+    #[account(
+        init_if_needed,
+        payer = author,
+        space = 8 + std::mem::size_of::<ProductReputation>(),
+        seeds = [b"product_reputation", product.key().as_ref()],
+        bump
+    )]
+    pub product_reputation: Option<Account<'info, ProductReputation>>,
+    
+    /// The author's reputation, whose purchases held back for review the
+    /// review lets count
+    #[account(
+        mut,
+        seeds = [b"user_reputation", author.key().as_ref()],
+        bump = author_reputation.bump
+    )]
+    pub author_reputation: Option<Account<'info, UserReputation>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for editing a review
+#[derive(Accounts)]
+pub struct EditReview<'info> {
+    pub author: Signer<'info>,
+    
+    /// The review being edited
+    #[account(
+        mut,
+        constraint = review.author == author.key() @ ReputationError::InvalidReviewAuthor
+    )]
+    pub review: Account<'info, Review>,
+    
+    /// The reputation account of the review recipient
+    #[account(
+        mut,
+        seeds = [b"user_reputation", review.recipient.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+    
+    /// The escrow the review references, needed to edit after a return or
+    /// to update the product's rating
+    pub escrow: Option<AccountInfo<'info>>,
+    
+    /// The marketplace the escrow belongs to, needed to edit after a return
+    pub marketplace: Option<Account<'info, MarketplaceState>>,
+    
+    /// The rating aggregate of the escrow's product, required exactly when the
+    /// review counted toward it
+    #[account(
+        mut,
+        seeds = [b"product_reputation", product_reputation.product.as_ref()],
+        bump = product_reputation.bump
+    )]
+    pub product_reputation: Option<Account<'info, ProductReputation>>,
+}
+
+/// Accounts required for responding to a review
+#[derive(Accounts)]
+pub struct RespondToReview<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+    
+    /// The review being responded to, which must be about the seller
+    #[account(
+        mut,
+        constraint = review.recipient == seller.key() @ ReputationError::InvalidResponder
+    )]
+    pub review: Account<'info, Review>,
+    
+    /// The seller's response activity, created on their first response
+    #[account(
+        init_if_needed,
+        payer = seller,
+        space = 8 + std::mem::size_of::<SellerActivity>(),
+        seeds = [b"seller_activity", seller.key().as_ref()],
+        bump
+    )]
+    pub seller_activity: Account<'info, SellerActivity>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for editing a review response
+#[derive(Accounts)]
+pub struct EditReviewResponse<'info> {
+    pub seller: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = review.recipient == seller.key() @ ReputationError::InvalidResponder
+    )]
+    pub review: Account<'info, Review>,
+}
+
+/// Accounts required for voting on a review
+#[derive(Accounts)]
+pub struct VoteOnReview<'info> {
+    #[account(mut)]
+    pub voter: Signer<'info>,
+    
+    /// The review being voted on, which must not be the voter's own
+    #[account(
+        mut,
+        constraint = review.author != voter.key() @ ReputationError::AuthorCannotVote
+    )]
+    pub review: Account<'info, Review>,
+    
+    /// The voter's vote, one per review
+    #[account(
+        init,
+        payer = voter,
+        space = 8 + std::mem::size_of::<ReviewVote>(),
+        seeds = [b"review_vote", review.key().as_ref(), voter.key().as_ref()],
+        bump
+    )]
+    pub review_vote: Account<'info, ReviewVote>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for importing historical reviews
+#[derive(Accounts)]
+pub struct ImportReviews<'info> {
+    /// Marketplace authority, who pays for the imported reviews
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        constraint = marketplace.authority == authority.key() @ ReputationError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
     pub system_program: Program<'info, System>,
 }
 
-/// Accounts required for verifying a user
+/// Accounts required for initializing a product's rating aggregate
+#[derive(Accounts)]
+pub struct InitializeProductReputation<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// The product listing
+    pub product: Account<'info, Product>,
+    
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + std::mem::size_of::<ProductReputation>(),
+        seeds = [b"product_reputation", product.key().as_ref()],
+        bump
+    )]
+    pub product_reputation: Account<'info, ProductReputation>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for reading a product's rating
+#[derive(Accounts)]
+pub struct ProductRating<'info> {
+    #[account(
+        seeds = [b"product_reputation", product_reputation.product.as_ref()],
+        bump = product_reputation.bump
+    )]
+    pub product_reputation: Account<'info, ProductReputation>,
+}
+
+/// Accounts required for creating the reputation config
+#[derive(Accounts)]
+pub struct InitializeReputationConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    /// The marketplace to trust
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + std::mem::size_of::<ReputationConfig>(),
+        seeds = [b"reputation_config"],
+        bump
+    )]
+    pub reputation_config: Account<'info, ReputationConfig>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for choosing the marketplace trusted with reputations
+#[derive(Accounts)]
+pub struct SetReputationMarketplace<'info> {
+    pub admin: Signer<'info>,
+    
+    /// The marketplace to trust
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    #[account(
+        mut,
+        seeds = [b"reputation_config"],
+        bump = reputation_config.bump,
+        constraint = reputation_config.admin == admin.key() @ ReputationError::UnauthorizedAuthority
+    )]
+    pub reputation_config: Account<'info, ReputationConfig>,
+}
+
+/// Accounts required for changing the reputation config's admin
+#[derive(Accounts)]
+pub struct SetReputationAdmin<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"reputation_config"],
+        bump = reputation_config.bump,
+        constraint = reputation_config.admin == admin.key() @ ReputationError::UnauthorizedAuthority
+    )]
+    pub reputation_config: Account<'info, ReputationConfig>,
+}
+
+/// Accounts required for verifying or unverifying a user
 #[derive(Accounts)]
 pub struct VerifyUser<'info> {
     #[account(mut)]
     pub authority: Signer<'info>,
     
-    /// The marketplace account (to check authority)
-    /// In a real implementation, we would have a proper checks here
-    /// For simplicity, we're just assuming the signer is authorized
-    pub marketplace: AccountInfo<'info>,
+    /// The marketplace whose authority verifies users
+    #[account(
+        constraint = marketplace.authority == authority.key() @ ReputationError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Names the marketplace trusted to verify users
+    #[account(
+        seeds = [b"reputation_config"],
+        bump = reputation_config.bump,
+        constraint = reputation_config.marketplace == marketplace.key() @ ReputationError::UntrustedMarketplace
+    )]
+    pub reputation_config: Account<'info, ReputationConfig>,
+    
+    /// The reputation account to verify or unverify
+    #[account(
+        mut,
+        seeds = [b"user_reputation", user_reputation.user.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+}
+
+/// Accounts required for setting verification thresholds
+#[derive(Accounts)]
+pub struct SetVerificationThresholds<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        constraint = marketplace.authority == authority.key() @ ReputationError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Names the marketplace trusted to set verification thresholds
+    #[account(
+        seeds = [b"reputation_config"],
+        bump = reputation_config.bump,
+        constraint = reputation_config.marketplace == marketplace.key() @ ReputationError::UntrustedMarketplace
+    )]
+    pub reputation_config: Account<'info, ReputationConfig>,
+    
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + std::mem::size_of::<VerificationConfig>(),
+        seeds = [b"verification_config", marketplace.key().as_ref()],
+        bump
+    )]
+    pub verification_config: Account<'info, VerificationConfig>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for automatically verifying a user
+#[derive(Accounts)]
+pub struct AutoVerify<'info> {
+    /// Thresholds of the marketplace the user qualifies on
+    #[account(
+        seeds = [b"verification_config", verification_config.marketplace.as_ref()],
+        bump = verification_config.bump
+    )]
+    pub verification_config: Account<'info, VerificationConfig>,
+    
+    /// Names the marketplace whose thresholds verify users
+    #[account(
+        seeds = [b"reputation_config"],
+        bump = reputation_config.bump,
+        constraint = reputation_config.marketplace == verification_config.marketplace
+            @ ReputationError::UntrustedMarketplace
+    )]
+    pub reputation_config: Account<'info, ReputationConfig>,
     
     /// The reputation account to verify
     #[account(
@@ -200,15 +1660,98 @@ pub struct VerifyUser<'info> {
 /// Accounts required for recording a transaction count
 #[derive(Accounts)]
 pub struct RecordTransactionCount<'info> {
+    /// Pays for the reputation account when it is created
     #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// The trusted marketplace's authority, or the escrow program's
+    /// authority PDA when an escrow settles
     pub authority: Signer<'info>,
     
-    /// The marketplace account (to check authority)
-    /// In a real implementation, we would have a proper checks here
-    /// For simplicity, we're just assuming the signer is authorized
-    pub marketplace: AccountInfo<'info>,
+    /// Names the trusted marketplace, required unless an escrow settles
+    #[account(
+        seeds = [b"reputation_config"],
+        bump = reputation_config.bump
+    )]
+    pub reputation_config: Option<Account<'info, ReputationConfig>>,
+    
+    /// The marketplace the transaction happened on
+    #[account(
+        constraint = can_record_transactions(&marketplace, reputation_config.as_deref(), &authority.key())
+            @ ReputationError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// The user whose transaction is recorded
+    pub user: AccountInfo<'info>,
     
-    /// The user's reputation account
+    /// The user's reputation account, created on their first transaction
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + std::mem::size_of::<UserReputation>(),
+        seeds = [b"user_reputation", user.key().as_ref()],
+        bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for taking back a recorded sale or purchase
+#[derive(Accounts)]
+pub struct ReverseTransactionCount<'info> {
+    /// PDA of the escrow program that approved the return
+    #[account(
+        seeds = [marketplace::ESCROW_AUTHORITY_SEED],
+        bump,
+        seeds::program = ESCROW_PROGRAM_ID
+    )]
+    pub escrow_authority: Signer<'info>,
+    
+    /// The reputation account of the seller or buyer
+    #[account(
+        mut,
+        seeds = [b"user_reputation", user_reputation.user.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+}
+
+/// Accounts required for checking a reputation's stored average
+#[derive(Accounts)]
+pub struct VerifyReputationConsistency<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_reputation", user_reputation.user.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+}
+
+/// Accounts required for reading a reputation proof
+#[derive(Accounts)]
+pub struct ReputationProof<'info> {
+    /// The reputation account to snapshot
+    #[account(
+        seeds = [b"user_reputation", user_reputation.user.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+}
+
+/// Accounts required for recording a lost dispute
+#[derive(Accounts)]
+pub struct RecordDisputeLoss<'info> {
+    /// PDA of the escrow program that resolved the dispute
+    #[account(
+        seeds = [marketplace::ESCROW_AUTHORITY_SEED],
+        bump,
+        seeds::program = ESCROW_PROGRAM_ID
+    )]
+    pub escrow_authority: Signer<'info>,
+    
+    /// The reputation account of the losing party
     #[account(
         mut,
         seeds = [b"user_reputation", user_reputation.user.as_ref()],
@@ -217,11 +1760,132 @@ pub struct RecordTransactionCount<'info> {
     pub user_reputation: Account<'info, UserReputation>,
 }
 
+/// Accounts required for recording a funded cancellation
+#[derive(Accounts)]
+pub struct RecordFundedCancellation<'info> {
+    /// PDA of the escrow program that cancelled the escrow
+    #[account(
+        seeds = [marketplace::ESCROW_AUTHORITY_SEED],
+        bump,
+        seeds::program = ESCROW_PROGRAM_ID
+    )]
+    pub escrow_authority: Signer<'info>,
+    
+    /// The reputation account of the cancelling buyer
+    #[account(
+        mut,
+        seeds = [b"user_reputation", user_reputation.user.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+}
+
+/// Accounts required for removing a review after a seller-favored dispute
+#[derive(Accounts)]
+pub struct RemoveReview<'info> {
+    pub authority: Signer<'info>,
+    
+    /// The marketplace whose authority moderates reviews
+    #[account(
+        constraint = marketplace.authority == authority.key() @ ReputationError::UnauthorizedAuthority
+    )]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// The review author, who receives the reclaimed rent
+    #[account(mut)]
+    pub author: AccountInfo<'info>,
+    
+    /// The review being removed
+    #[account(
+        mut,
+        close = author,
+        constraint = review.author == author.key() @ ReputationError::InvalidReviewAuthor
+    )]
+    pub review: Account<'info, Review>,
+    
+    /// The escrow the review references
+    #[account(
+        constraint = review.transaction_reference == Some(escrow.key()) @ ReputationError::InvalidEscrowAccount
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    /// The reputation account of the review recipient
+    #[account(
+        mut,
+        seeds = [b"user_reputation", review.recipient.as_ref()],
+        bump = user_reputation.bump
+    )]
+    pub user_reputation: Account<'info, UserReputation>,
+    
+    /// The rating aggregate of the escrow's product, required exactly when the
+    /// review counted toward it
+    #[account(
+        mut,
+        seeds = [b"product_reputation", product_reputation.product.as_ref()],
+        bump = product_reputation.bump
+    )]
+    pub product_reputation: Option<Account<'info, ProductReputation>>,
+}
+
 /// Error codes for the reputation program
 #[error_code]
 pub enum ReputationError {
     #[msg("Rating must be between 1 and 5")]
     InvalidRating,
-    #[msg("Comment exceeds maximum length of 500 characters")]
+    #[msg("Comment exceeds maximum length of 500 bytes")]
     CommentTooLong,
+    #[msg("Unauthorized marketplace authority")]
+    UnauthorizedAuthority,
+    #[msg("Invalid escrow account")]
+    InvalidEscrowAccount,
+    #[msg("Review author does not match")]
+    InvalidReviewAuthor,
+    #[msg("Review is not eligible for removal")]
+    ReviewNotRemovable,
+    #[msg("Review removal window has closed")]
+    ReviewRemovalWindowClosed,
+    #[msg("Product reputation does not match the escrow's product")]
+    InvalidProductReputation,
+    #[msg("Import accounts do not match the reviews")]
+    InvalidImportAccounts,
+    #[msg("Imported review is invalid")]
+    InvalidImportedReview,
+    #[msg("Reputation account does not belong to the user")]
+    InvalidReputationAccount,
+    #[msg("Reviews may have at most 3 attachments")]
+    TooManyAttachments,
+    #[msg("Attachment URI must be an ipfs://, ar:// or https:// URI of at most 200 bytes")]
+    InvalidAttachmentUri,
+    #[msg("Review can no longer be edited")]
+    ReviewEditWindowClosed,
+    #[msg("Minimum average rating cannot exceed 5 stars")]
+    InvalidVerificationThresholds,
+    #[msg("Reputation does not meet the verification thresholds")]
+    VerificationThresholdsNotMet,
+    #[msg("Only the reviewed user can respond to a review")]
+    InvalidResponder,
+    #[msg("Response exceeds maximum length of 300 bytes")]
+    ResponseTooLong,
+    #[msg("Response links must be ipfs://, ar:// or https:// URIs")]
+    InvalidResponseLink,
+    #[msg("Seller must wait before responding to another review")]
+    ResponseCooldown,
+    #[msg("Response has been edited the maximum number of times")]
+    ResponseEditLimitReached,
+    #[msg("Users cannot review themselves")]
+    SelfReviewNotAllowed,
+    #[msg("Reviews require a completed escrow between the author and recipient")]
+    EscrowNotCompleted,
+    #[msg("The user's verification was revoked by the marketplace")]
+    VerificationRevoked,
+    #[msg("Reviewer role does not match the recipient's side of the escrow")]
+    InvalidReviewerRole,
+    #[msg("Authors cannot vote on their own reviews")]
+    AuthorCannotVote,
+    #[msg("Marketplace is not trusted to manage reputations")]
+    UntrustedMarketplace,
+    #[msg("Review already has a response")]
+    ResponseAlreadyPosted,
+    #[msg("Review has no response to edit")]
+    NoResponseToEdit,
 } 
\ No newline at end of file
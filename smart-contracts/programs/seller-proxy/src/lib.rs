@@ -0,0 +1,130 @@
+use anchor_lang::prelude::*;
+use escrow::program::Escrow as EscrowProgram;
+use escrow::Escrow;
+use marketplace::program::Marketplace;
+use marketplace::{Category, CurrencyType, MarketplaceState};
+
+declare_id!("SellerProxy11111111111111111111111111111111");
+
+/// Seed of the PDA that sells on behalf of an operator
+pub const PROXY_SELLER_SEED: &[u8] = b"proxy_seller";
+
+/// Example aggregator that lists and ships products under a program-owned
+/// seller. The proxy seller is a PDA, so it can only sign through this
+/// program, which only does so for the operator it was derived from.
+#[program]
+pub mod seller_proxy {
+    use super::*;
+
+    /// List a SOL-priced product with the operator's proxy seller as its seller.
+    /// The proxy seller pays the listing's rent, so it must hold lamports.
+    pub fn list_product(
+        ctx: Context<ListProduct>,
+        title: String,
+        price: u64,
+        quantity: u64,
+    ) -> Result<()> {
+        let operator = ctx.accounts.operator.key();
+        let bump = *ctx.bumps.get("proxy_seller").unwrap();
+        
+        marketplace::cpi::create_product(
+            CpiContext::new_with_signer(
+                ctx.accounts.marketplace_program.to_account_info(),
+                marketplace::cpi::accounts::CreateProduct {
+                    seller: ctx.accounts.proxy_seller.to_account_info(),
+                    marketplace: ctx.accounts.marketplace.to_account_info(),
+                    product: ctx.accounts.product.to_account_info(),
+                    price_feed: None,
+                    product_fingerprint: ctx.accounts.product_fingerprint.to_account_info(),
+                    fingerprinted_product: ctx.accounts.fingerprinted_product.clone(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                },
+                &[&[PROXY_SELLER_SEED, operator.as_ref(), &[bump]]],
+            ),
+            title,
+            String::new(),
+            price,
+            quantity,
+            Some(CurrencyType::SOL),
+            String::new(),
+            Category::Other,
+            0,
+            None,
+            None,
+            None,
+            [0u8; 32],
+            0,
+            Vec::new(),
+            None,
+        )
+    }
+
+    /// Mark an escrow for one of the proxy seller's products as shipped
+    pub fn ship(ctx: Context<Ship>) -> Result<()> {
+        let operator = ctx.accounts.operator.key();
+        let bump = *ctx.bumps.get("proxy_seller").unwrap();
+        
+        escrow::cpi::mark_as_shipped(
+            CpiContext::new_with_signer(
+                ctx.accounts.escrow_program.to_account_info(),
+                escrow::cpi::accounts::MarkAsShipped {
+                    seller: ctx.accounts.proxy_seller.to_account_info(),
+                    escrow: ctx.accounts.escrow.to_account_info(),
+                },
+                &[&[PROXY_SELLER_SEED, operator.as_ref(), &[bump]]],
+            ),
+            None,
+        )
+    }
+}
+
+/// Accounts required for listing a product through the proxy
+#[derive(Accounts)]
+pub struct ListProduct<'info> {
+    pub operator: Signer<'info>,
+    
+    /// PDA selling on the operator's behalf
+    #[account(
+        mut,
+        seeds = [PROXY_SELLER_SEED, operator.key().as_ref()],
+        bump
+    )]
+    pub proxy_seller: SystemAccount<'info>,
+    
+    #[account(mut)]
+    pub marketplace: Account<'info, MarketplaceState>,
+    
+    /// Product PDA, created by the marketplace program
+    #[account(mut)]
+    pub product: AccountInfo<'info>,
+    
+    /// The proxy seller's claim on the listing's fingerprint, checked by the
+    /// marketplace program
+    #[account(mut)]
+    pub product_fingerprint: AccountInfo<'info>,
+    
+    /// Listing holding the fingerprint, required when relisting it
+    pub fingerprinted_product: Option<AccountInfo<'info>>,
+    
+    pub marketplace_program: Program<'info, Marketplace>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts required for shipping through the proxy
+#[derive(Accounts)]
+pub struct Ship<'info> {
+    pub operator: Signer<'info>,
+    
+    /// PDA selling on the operator's behalf
+    #[account(
+        mut,
+        seeds = [PROXY_SELLER_SEED, operator.key().as_ref()],
+        bump
+    )]
+    pub proxy_seller: SystemAccount<'info>,
+    
+    #[account(mut)]
+    pub escrow: Account<'info, Escrow>,
+    
+    pub escrow_program: Program<'info, EscrowProgram>,
+}
@@ -161,6 +161,50 @@ async function initializeMarketplace(marketplaceProgramId: PublicKey) {
   }
 }
 
+// Create the reputation config, making the deployer its admin and trusting
+// the marketplace with reputations. Run right after deploying, since the
+// config can only be created once.
+async function initializeReputationConfig(reputationProgramId: PublicKey, marketplacePda: PublicKey) {
+  console.log("Initializing Reputation config...");
+  
+  try {
+    // Load the IDL
+    const idl = JSON.parse(
+      fs.readFileSync(
+        path.resolve(__dirname, '../target/idl/reputation.json'),
+        'utf-8'
+      )
+    );
+    
+    // Create the program interface
+    const program = new anchor.Program(idl, reputationProgramId, provider);
+    
+    // Find the reputation config PDA
+    const [reputationConfigPda] = await PublicKey.findProgramAddress(
+      [Buffer.from("reputation_config")],
+      reputationProgramId
+    );
+    
+    const tx = await program.methods
+      .initializeReputationConfig()
+      .accounts({
+        admin: wallet.publicKey,
+        marketplace: marketplacePda,
+        reputationConfig: reputationConfigPda,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+    
+    console.log(`Reputation config initialized successfully! Transaction signature: ${tx}`);
+    console.log(`Reputation config PDA: ${reputationConfigPda.toString()}`);
+    
+    return reputationConfigPda;
+  } catch (error) {
+    console.error("Error initializing Reputation config:", error);
+    throw error;
+  }
+}
+
 // Main deployment function
 async function main() {
   console.log("Starting AgroMark smart contract deployment to devnet...");
@@ -184,12 +228,16 @@ async function main() {
     // Initialize the marketplace
     const marketplacePda = await initializeMarketplace(marketplaceProgramId);
     
+    // Trust the marketplace with reputations
+    const reputationConfigPda = await initializeReputationConfig(reputationProgramId, marketplacePda);
+    
     console.log("\nDeployment completed successfully!");
     console.log("Program IDs:");
     console.log(`- Marketplace: ${marketplaceProgramId.toString()}`);
     console.log(`- Escrow: ${escrowProgramId.toString()}`);
     console.log(`- Reputation: ${reputationProgramId.toString()}`);
     console.log(`\nMarketplace PDA: ${marketplacePda.toString()}`);
+    console.log(`Reputation config PDA: ${reputationConfigPda.toString()}`);
     
     // Save the deployment information to a file
     const deploymentInfo = {
@@ -202,6 +250,7 @@ async function main() {
       },
       pdas: {
         marketplace: marketplacePda.toString(),
+        reputationConfig: reputationConfigPda.toString(),
       },
       timestamp: new Date().toISOString(),
     };
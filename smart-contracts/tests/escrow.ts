@@ -2,9 +2,39 @@ import * as anchor from '@project-serum/anchor';
 import { Program } from '@project-serum/anchor';
 import { Escrow } from '../target/types/escrow';
 import { Marketplace } from '../target/types/marketplace';
+import { SellerProxy } from '../target/types/seller_proxy';
+import { MockSwap } from '../target/types/mock_swap';
+import { Reputation } from '../target/types/reputation';
 import { expect } from 'chai';
 import { PublicKey, Keypair } from '@solana/web3.js';
 import { TOKEN_PROGRAM_ID, Token } from '@solana/spl-token';
+import { createHash } from 'crypto';
+import { startAnchor, Clock } from 'solana-bankrun';
+import { BankrunProvider } from 'anchor-bankrun';
+import {
+  airdrop,
+  createProduct,
+  createEscrow,
+  createFundedEscrow,
+  createMint,
+  fundEscrow,
+  findEscrowPdas,
+  findMarketplacePda,
+  escrowTrackingAccounts,
+  findBuyerEscrowCounterPda,
+  findSolFeeDestination,
+  initReputation,
+  findSellerStatusPda,
+  setSellerHold,
+  findUserReputationPda,
+  findProductFingerprintPda,
+  findArbitratorRoutingPda,
+  shipAndConfirm,
+  productFingerprintOf,
+  fundedKeypair,
+  expectError,
+  sleep,
+} from './helpers';
 
 describe('escrow', () => {
   // Configure the client to use the local cluster
@@ -32,7 +62,7 @@ describe('escrow', () => {
   const productDescription = "Fresh organic apples from local orchard";
   const productPrice = new anchor.BN(300000); // 0.3 SOL in lamports
   const productQuantity = new anchor.BN(50);
-  const productCategory = "Fruits";
+  const productCategory = { produce: {} };
   const metadataUri = "https://arweave.net/xyz789";
   
   // Purchase data
@@ -100,12 +130,23 @@ describe('escrow', () => {
           productQuantity,
           { sol: {} }, // CurrencyType enum
           metadataUri,
-          productCategory
+          productCategory,
+          new anchor.BN(0), // No returns accepted
+          null, // Available immediately
+          null, // No end of season
+          null, // Any buyer may purchase
+          Array(32).fill(0), // No metadata hash
+          new anchor.BN(0), // No handling time promised
+          [], // No tags
+          null // Minimum order of one unit
         )
         .accounts({
           seller: seller.publicKey,
           marketplace: marketplacePda,
           product: productPda,
+          priceFeed: null,
+          productFingerprint: findProductFingerprintPda(marketplacePda, seller.publicKey, productTitle, productCategory),
+          fingerprintedProduct: null,
           systemProgram: anchor.web3.SystemProgram.programId,
         })
         .signers([seller])
@@ -122,6 +163,7 @@ describe('escrow', () => {
         marketplacePda.toBuffer(),
         buyer.publicKey.toBuffer(),
         productPda.toBuffer(),
+        new anchor.BN(0).toArrayLike(Buffer, "le", 8),
       ],
       escrowProgram.programId
     );
@@ -141,12 +183,19 @@ describe('escrow', () => {
   it('Creates an escrow', async () => {
     // Create the escrow
     const tx = await escrowProgram.methods
-      .createEscrow(purchaseQuantity)
+      .createEscrow(purchaseQuantity, null, null)
       .accounts({
         buyer: buyer.publicKey,
         marketplace: marketplacePda,
         product: productPda,
         escrow: escrowPda,
+        buyerReputation: null,
+        coupon: null,
+        mint: null,
+        sellerStatus: findSellerStatusPda(marketplacePda, seller.publicKey),
+        sellerReputation: findUserReputationPda(seller.publicKey),
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, buyer.publicKey),
+        ...escrowTrackingAccounts(escrowProgram),
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([buyer])
@@ -173,12 +222,19 @@ describe('escrow', () => {
   it('Funds an escrow with SOL', async () => {
     // Create the escrow first
     await escrowProgram.methods
-      .createEscrow(purchaseQuantity)
+      .createEscrow(purchaseQuantity, null, null)
       .accounts({
         buyer: buyer.publicKey,
         marketplace: marketplacePda,
         product: productPda,
         escrow: escrowPda,
+        buyerReputation: null,
+        coupon: null,
+        mint: null,
+        sellerStatus: findSellerStatusPda(marketplacePda, seller.publicKey),
+        sellerReputation: findUserReputationPda(seller.publicKey),
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, buyer.publicKey),
+        ...escrowTrackingAccounts(escrowProgram),
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([buyer])
@@ -186,10 +242,11 @@ describe('escrow', () => {
     
     // Fund the escrow
     await escrowProgram.methods
-      .fundEscrow()
+      .fundEscrow(null)
       .accounts({
         buyer: buyer.publicKey,
         escrow: escrowPda,
+        marketplace: marketplacePda,
         escrowVault: escrowVaultPda,
         buyerTokenAccount: null, // Not needed for SOL
         escrowTokenAccount: null, // Not needed for SOL
@@ -209,4 +266,3592 @@ describe('escrow', () => {
     const vaultBalance = await provider.connection.getBalance(escrowVaultPda);
     expect(vaultBalance).to.be.at.least(escrow.amount.toNumber());
   });
-}); 
\ No newline at end of file
+
+  it('Rejects funding an escrow twice', async () => {
+    const doubleBuyer = await fundedKeypair(provider);
+    const doubleSeller = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, doubleSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, doubleBuyer);
+    const vaultBalance = await provider.connection.getBalance(pdas.escrowVault);
+
+    // A double-click re-submits the same funding instruction
+    await expectError(fundEscrow(escrowProgram, pdas, doubleBuyer), "AlreadyFunded");
+
+    expect(await provider.connection.getBalance(pdas.escrowVault)).to.equal(vaultBalance);
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.fundedAmount.toNumber()).to.equal(escrow.amount.toNumber());
+  });
+
+  const approveReturn = (returnSeller: Keypair, returnBuyer: Keypair, escrow: PublicKey) =>
+    escrowProgram.methods
+      .approveReturn()
+      .accounts({
+        seller: returnSeller.publicKey,
+        buyer: returnBuyer.publicKey,
+        escrow,
+        sellerTokenAccount: null,
+        buyerTokenAccount: null,
+        sellerReputation: findUserReputationPda(returnSeller.publicKey),
+        buyerReputation: findUserReputationPda(returnBuyer.publicKey),
+        escrowAuthority: escrowTrackingAccounts(escrowProgram).escrowAuthority,
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([returnSeller])
+      .rpc();
+
+  it('Accepts a return requested within the return window', async () => {
+    const returnSeller = await fundedKeypair(provider);
+    const returnBuyer = await fundedKeypair(provider);
+    const reputationProgram = anchor.workspace.Reputation as Program<Reputation>;
+    const sellerReputation = await initReputation(reputationProgram, returnSeller);
+    const product = await createProduct(marketplaceProgram, marketplacePda, returnSeller, {
+      returnWindowSeconds: new anchor.BN(3600),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, returnBuyer);
+    await shipAndConfirm(escrowProgram, pdas, returnBuyer, returnSeller, sellerReputation);
+    expect((await reputationProgram.account.userReputation.fetch(sellerReputation)).totalSales.toNumber())
+      .to.equal(1);
+
+    await escrowProgram.methods
+      .requestReturn()
+      .accounts({ buyer: returnBuyer.publicKey, escrow: pdas.escrow })
+      .signers([returnBuyer])
+      .rpc();
+    expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).status)
+      .to.deep.equal({ returnRequested: {} });
+
+    const buyerBalanceBefore = await provider.connection.getBalance(returnBuyer.publicKey);
+    await approveReturn(returnSeller, returnBuyer, pdas.escrow);
+
+    // The buyer gets back what the seller was paid; the marketplace keeps its fee
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ refunded: {} });
+    expect(escrow.feesCollected.toNumber()).to.be.greaterThan(0);
+    const buyerBalanceAfter = await provider.connection.getBalance(returnBuyer.publicKey);
+    expect(buyerBalanceAfter - buyerBalanceBefore)
+      .to.equal(escrow.amount.toNumber() - escrow.feesCollected.toNumber());
+
+    // The returned order no longer counts as a sale
+    const reputation = await reputationProgram.account.userReputation.fetch(sellerReputation);
+    expect(reputation.totalSales.toNumber()).to.equal(0);
+  });
+
+  it('Lets the seller reject a return, once and for all', async () => {
+    const returnSeller = await fundedKeypair(provider);
+    const returnBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, returnSeller, {
+      returnWindowSeconds: new anchor.BN(3600),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, returnBuyer);
+    await shipAndConfirm(escrowProgram, pdas, returnBuyer, returnSeller);
+
+    const requestReturn = () =>
+      escrowProgram.methods
+        .requestReturn()
+        .accounts({ buyer: returnBuyer.publicKey, escrow: pdas.escrow })
+        .signers([returnBuyer])
+        .rpc();
+    await requestReturn();
+
+    // Only the seller may reject
+    await expectError(
+      escrowProgram.methods
+        .rejectReturn()
+        .accounts({ seller: returnBuyer.publicKey, escrow: pdas.escrow })
+        .signers([returnBuyer])
+        .rpc(),
+      "UnauthorizedSeller"
+    );
+
+    await escrowProgram.methods
+      .rejectReturn()
+      .accounts({ seller: returnSeller.publicKey, escrow: pdas.escrow })
+      .signers([returnSeller])
+      .rpc();
+
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ completed: {} });
+    expect(escrow.returnRejected).to.equal(true);
+
+    await expectError(requestReturn(), "ReturnAlreadyRejected");
+    await expectError(approveReturn(returnSeller, returnBuyer, pdas.escrow), "InvalidEscrowState");
+  });
+
+  it('Rejects a return requested after the return window', async () => {
+    const returnSeller = await fundedKeypair(provider);
+    const returnBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, returnSeller, {
+      returnWindowSeconds: new anchor.BN(1),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, returnBuyer);
+    await shipAndConfirm(escrowProgram, pdas, returnBuyer, returnSeller);
+
+    // Let the one second window lapse
+    await sleep(3000);
+
+    await expectError(
+      escrowProgram.methods
+        .requestReturn()
+        .accounts({ buyer: returnBuyer.publicKey, escrow: pdas.escrow })
+        .signers([returnBuyer])
+        .rpc(),
+      "ReturnWindowClosed"
+    );
+  });
+
+  describe('layaway', () => {
+    const createLayaway = async (deadlineSeconds: number) => {
+      const layawaySeller = await fundedKeypair(provider);
+      const layawayBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, layawaySeller, {
+        price: new anchor.BN(1000000),
+      });
+      const pdas = findEscrowPdas(escrowProgram, marketplacePda, layawayBuyer.publicKey, product);
+      const deadline = new anchor.BN(Math.floor(Date.now() / 1000) + deadlineSeconds);
+
+      await escrowProgram.methods
+        .createEscrow(new anchor.BN(1), deadline, null)
+        .accounts({
+          buyer: layawayBuyer.publicKey,
+          marketplace: marketplacePda,
+          product,
+          escrow: pdas.escrow,
+          buyerReputation: null,
+          coupon: null,
+          mint: null,
+          sellerStatus: findSellerStatusPda(marketplacePda, layawaySeller.publicKey),
+          sellerReputation: findUserReputationPda(layawaySeller.publicKey),
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, layawayBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([layawayBuyer])
+        .rpc();
+
+      return { layawaySeller, layawayBuyer, product, pdas };
+    };
+
+    const payInstallment = (buyerKeypair: Keypair, pdas, amount: number) =>
+      escrowProgram.methods
+        .payInstallment(new anchor.BN(amount))
+        .accounts({
+          buyer: buyerKeypair.publicKey,
+          escrow: pdas.escrow,
+          marketplace: marketplacePda,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          escrowTokenAccount: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([buyerKeypair])
+        .rpc();
+
+    it('Ships a layaway only once it is paid in full', async () => {
+      const { layawaySeller, layawayBuyer, pdas } = await createLayaway(3600);
+
+      await payInstallment(layawayBuyer, pdas, 400000);
+      await expectError(
+        escrowProgram.methods
+          .markAsShipped(null)
+          .accounts({ seller: layawaySeller.publicKey, escrow: pdas.escrow })
+          .signers([layawaySeller])
+          .rpc(),
+        "InvalidEscrowState"
+      );
+
+      await payInstallment(layawayBuyer, pdas, 600000);
+      let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ funded: {} });
+      expect(escrow.fundedAmount.toNumber()).to.equal(1000000);
+
+      await shipAndConfirm(escrowProgram, pdas, layawayBuyer, layawaySeller);
+      escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ completed: {} });
+    });
+
+    it('Rejects paying off a layaway in full after its deadline', async () => {
+      const { layawayBuyer, pdas } = await createLayaway(2);
+      await payInstallment(layawayBuyer, pdas, 400000);
+
+      await sleep(3000);
+      await expectError(fundEscrow(escrowProgram, pdas, layawayBuyer), "LayawayDeadlinePassed");
+
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ created: {} });
+      expect(escrow.fundedAmount.toNumber()).to.equal(400000);
+    });
+
+    it('Forfeits part of a defaulted layaway to the seller', async () => {
+      await marketplaceProgram.methods
+        .setLayawayForfeit(2000) // 20%
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+      const { layawaySeller, layawayBuyer, product, pdas } = await createLayaway(2);
+      await payInstallment(layawayBuyer, pdas, 500000);
+
+      // Let the payment deadline lapse
+      await sleep(4000);
+
+      const sellerBalanceBefore = await provider.connection.getBalance(layawaySeller.publicKey);
+      const buyerBalanceBefore = await provider.connection.getBalance(layawayBuyer.publicKey);
+      await escrowProgram.methods
+        .claimLayawayDefault()
+        .accounts({
+          seller: layawaySeller.publicKey,
+          buyer: layawayBuyer.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          buyerTokenAccount: null,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, layawayBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([layawaySeller])
+        .rpc();
+
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ cancelled: {} });
+      const buyerBalanceAfter = await provider.connection.getBalance(layawayBuyer.publicKey);
+      expect(buyerBalanceAfter - buyerBalanceBefore).to.equal(400000);
+      const sellerBalanceAfter = await provider.connection.getBalance(layawaySeller.publicKey);
+      expect(sellerBalanceAfter - sellerBalanceBefore).to.be.approximately(100000, 10000);
+    });
+  });
+
+  it('Sells products past the seller\'s first listing', async () => {
+    const multiSeller = await fundedKeypair(provider);
+    const multiBuyer = await fundedKeypair(provider);
+    const products = [];
+    for (let i = 0; i < 3; i++) {
+      products.push(await createProduct(marketplaceProgram, marketplacePda, multiSeller));
+    }
+
+    for (const product of products.slice(1)) {
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, multiBuyer);
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.product.toBase58()).to.equal(product.toBase58());
+      expect(escrow.status).to.deep.equal({ funded: {} });
+    }
+  });
+
+  it('Purchases the units through the marketplace when an escrow is created', async () => {
+    const purchaseSeller = await fundedKeypair(provider);
+    const purchaseBuyer = await fundedKeypair(provider);
+    // The third of the seller's listings, so its seeds use a non-zero index
+    await createProduct(marketplaceProgram, marketplacePda, purchaseSeller);
+    await createProduct(marketplaceProgram, marketplacePda, purchaseSeller);
+    const product = await createProduct(marketplaceProgram, marketplacePda, purchaseSeller, {
+      quantity: new anchor.BN(4),
+    });
+    const marketplaceBefore = await marketplaceProgram.account.marketplaceState.fetch(marketplacePda);
+
+    await createEscrow(escrowProgram, marketplacePda, product, purchaseBuyer, new anchor.BN(3));
+
+    // The purchase reserves the units once and counts one open escrow
+    const productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.quantity.toNumber()).to.equal(4);
+    expect(productAccount.reservedQuantity.toNumber()).to.equal(3);
+    expect(productAccount.activeEscrows.toNumber()).to.equal(1);
+    const marketplaceAfter = await marketplaceProgram.account.marketplaceState.fetch(marketplacePda);
+    expect(marketplaceAfter.activeEscrows.sub(marketplaceBefore.activeEscrows).toNumber()).to.equal(1);
+
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, await fundedKeypair(provider), new anchor.BN(2)),
+      "InsufficientInventory"
+    );
+  });
+
+  it('Rejects escrows for inventory reserved by open escrows', async () => {
+    const stockSeller = await fundedKeypair(provider);
+    const [first, second, third] = await Promise.all([1, 2, 3].map(() => fundedKeypair(provider)));
+    const product = await createProduct(marketplaceProgram, marketplacePda, stockSeller, {
+      quantity: new anchor.BN(3),
+    });
+
+    const held = await createEscrow(escrowProgram, marketplacePda, product, first, new anchor.BN(2));
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, second, new anchor.BN(2)),
+      "InsufficientInventory"
+    );
+    await createEscrow(escrowProgram, marketplacePda, product, second, new anchor.BN(1));
+
+    // Fully reserved, yet still listed rather than sold out
+    const productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.reservedQuantity.toNumber()).to.equal(3);
+    expect(productAccount.status).to.deep.equal({ active: {} });
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, third, new anchor.BN(1)),
+      "InsufficientInventory"
+    );
+
+    // Cancelling an escrow releases its reservation
+    await escrowProgram.methods
+      .cancelEscrow()
+      .accounts({
+        buyer: first.publicKey,
+        marketplace: marketplacePda,
+        escrow: held.escrow,
+        seller: stockSeller.publicKey,
+        escrowVault: held.escrowVault,
+        buyerTokenAccount: null,
+        escrowTokenAccount: null,
+        sellerTokenAccount: null,
+        buyerReputation: null,
+        product,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, first.publicKey),
+        ...escrowTrackingAccounts(escrowProgram),
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([first])
+      .rpc();
+    await createEscrow(escrowProgram, marketplacePda, product, third, new anchor.BN(2));
+  });
+
+  it('Takes completed escrows out of inventory and sells out the listing', async () => {
+    const stockSeller = await fundedKeypair(provider);
+    const stockBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, stockSeller, {
+      quantity: new anchor.BN(3),
+    });
+
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, stockBuyer, new anchor.BN(3));
+    await shipAndConfirm(escrowProgram, pdas, stockBuyer, stockSeller);
+
+    const productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.quantity.toNumber()).to.equal(0);
+    expect(productAccount.reservedQuantity.toNumber()).to.equal(0);
+    expect(productAccount.status).to.deep.equal({ soldOut: {} });
+  });
+
+  it('Keeps sellers from destocking below the units reserved by open escrows', async () => {
+    const stockSeller = await fundedKeypair(provider);
+    const stockBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, stockSeller, {
+      quantity: new anchor.BN(5),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, stockBuyer, new anchor.BN(3));
+    const setQuantity = async (quantity: number) =>
+      marketplaceProgram.methods
+        .updateProduct(null, null, new anchor.BN(quantity), null, null, null, null, null)
+        .accounts({
+          seller: stockSeller.publicKey,
+          product,
+          productFingerprint: await productFingerprintOf(marketplaceProgram, product),
+        })
+        .signers([stockSeller])
+        .rpc();
+
+    await expectError(setQuantity(2), "QuantityBelowReserved");
+    await setQuantity(3);
+
+    // The escrow still settles against the units it reserved
+    await shipAndConfirm(escrowProgram, pdas, stockBuyer, stockSeller);
+    const productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.quantity.toNumber()).to.equal(0);
+    expect(productAccount.status).to.deep.equal({ soldOut: {} });
+  });
+
+  it('Rejects escrows below the product\'s minimum order quantity', async () => {
+    const wholesaler = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, wholesaler, {
+      price: new anchor.BN(10000),
+      quantity: new anchor.BN(200),
+      minOrderQuantity: new anchor.BN(50),
+    });
+    const productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.minOrderQuantity.toNumber()).to.equal(50);
+
+    const smallBuyer = await fundedKeypair(provider);
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, smallBuyer, new anchor.BN(49)),
+      "BelowMinimumOrder"
+    );
+
+    for (const quantity of [50, 75]) {
+      const wholesaleBuyer = await fundedKeypair(provider);
+      const pdas = await createEscrow(escrowProgram, marketplacePda, product, wholesaleBuyer, new anchor.BN(quantity));
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.quantity.toNumber()).to.equal(quantity);
+    }
+  });
+
+  it('Prices orders at the unit price of the volume tier they reach', async () => {
+    const wholesaler = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, wholesaler, {
+      price: new anchor.BN(100000),
+      quantity: new anchor.BN(500),
+    });
+    const tier = (minQuantity: number, unitPrice: number) => ({
+      minQuantity: new anchor.BN(minQuantity),
+      unitPrice: new anchor.BN(unitPrice),
+    });
+    const setPriceTiers = async (tiers: ReturnType<typeof tier>[]) =>
+      marketplaceProgram.methods
+        .setPriceTiers(tiers)
+        .accounts({ seller: wholesaler.publicKey, product, productFingerprint: await productFingerprintOf(marketplaceProgram, product) })
+        .signers([wholesaler])
+        .rpc();
+
+    await expectError(setPriceTiers([tier(50, 80000), tier(10, 90000)]), "InvalidPriceTiers");
+    await setPriceTiers([tier(10, 90000), tier(50, 80000)]);
+
+    // Below every tier the base price applies
+    const orders: [number, number][] = [[5, 100000], [10, 90000], [49, 90000], [50, 80000], [120, 80000]];
+    for (const [quantity, unitPrice] of orders) {
+      const tierBuyer = await fundedKeypair(provider);
+      const pdas = await createEscrow(escrowProgram, marketplacePda, product, tierBuyer, new anchor.BN(quantity));
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.amount.toNumber()).to.equal(quantity * unitPrice);
+    }
+  });
+
+  it('Rejects an escrow for a product from another marketplace', async () => {
+    // Stand up a second marketplace under a different authority
+    const otherAuthority = await fundedKeypair(provider);
+    const [otherMarketplacePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("marketplace"), otherAuthority.publicKey.toBuffer()],
+      marketplaceProgram.programId
+    );
+    await marketplaceProgram.methods
+      .initializeMarketplace(250)
+      .accounts({
+        authority: otherAuthority.publicKey,
+        marketplace: otherMarketplacePda,
+        feeDestination: otherAuthority.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([otherAuthority])
+      .rpc();
+
+    const otherBuyer = await fundedKeypair(provider);
+    const pdas = findEscrowPdas(escrowProgram, otherMarketplacePda, otherBuyer.publicKey, productPda);
+
+    await expectError(
+      escrowProgram.methods
+        .createEscrow(purchaseQuantity, null, null)
+        .accounts({
+          buyer: otherBuyer.publicKey,
+          marketplace: otherMarketplacePda,
+          product: productPda,
+          escrow: pdas.escrow,
+          buyerReputation: null,
+          coupon: null,
+          mint: null,
+          sellerStatus: findSellerStatusPda(otherMarketplacePda, seller.publicKey),
+          sellerReputation: findUserReputationPda(seller.publicKey),
+          buyerEscrowCounter: findBuyerEscrowCounterPda(otherMarketplacePda, otherBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([otherBuyer])
+        .rpc(),
+      "ProductMarketplaceMismatch"
+    );
+  });
+
+  it('Emits a settlement breakdown that sums to the escrow amount', async () => {
+    const settlementSeller = await fundedKeypair(provider);
+    const settlementBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, settlementSeller);
+    const pdas = await createFundedEscrow(
+      escrowProgram,
+      marketplacePda,
+      product,
+      settlementBuyer,
+      new anchor.BN(3)
+    );
+
+    let breakdown = null;
+    const listener = escrowProgram.addEventListener('SettlementBreakdown', (event) => {
+      if (event.escrow.equals(pdas.escrow)) {
+        breakdown = event;
+      }
+    });
+    await shipAndConfirm(escrowProgram, pdas, settlementBuyer, settlementSeller);
+    await sleep(1000);
+    await escrowProgram.removeEventListener(listener);
+
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(breakdown).to.not.equal(null);
+    expect(breakdown.gross.toString()).to.equal(escrow.amount.toString());
+    expect(breakdown.marketplaceFee.toString()).to.equal(escrow.amount.muln(250).divn(10000).toString());
+    const total = breakdown.marketplaceFee
+      .add(breakdown.tax)
+      .add(breakdown.arbitratorFee)
+      .add(breakdown.sellerNet)
+      .add(breakdown.buyerRefund);
+    expect(total.toString()).to.equal(escrow.amount.toString());
+  });
+
+  describe('seasonal availability', () => {
+    const tryEscrow = async (availableFrom: number | null, availableUntil: number | null) => {
+      const seasonalSeller = await fundedKeypair(provider);
+      const seasonalBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, seasonalSeller, {
+        availableFrom: availableFrom === null ? null : new anchor.BN(availableFrom),
+        availableUntil: availableUntil === null ? null : new anchor.BN(availableUntil),
+      });
+      const pdas = findEscrowPdas(escrowProgram, marketplacePda, seasonalBuyer.publicKey, product);
+      return () =>
+        escrowProgram.methods
+          .createEscrow(new anchor.BN(1), null, null)
+          .accounts({
+            buyer: seasonalBuyer.publicKey,
+            marketplace: marketplacePda,
+            product,
+            escrow: pdas.escrow,
+            buyerReputation: null,
+            coupon: null,
+            mint: null,
+            sellerStatus: findSellerStatusPda(marketplacePda, seasonalSeller.publicKey),
+            sellerReputation: findUserReputationPda(seasonalSeller.publicKey),
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, seasonalBuyer.publicKey),
+            ...escrowTrackingAccounts(escrowProgram),
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([seasonalBuyer])
+          .rpc();
+    };
+    const now = () => Math.floor(Date.now() / 1000);
+
+    it('Rejects purchases before the season opens', async () => {
+      const purchase = await tryEscrow(now() + 3600, now() + 7200);
+      await expectError(purchase(), "ProductNotYetAvailable");
+    });
+
+    it('Accepts purchases within the season', async () => {
+      const purchase = await tryEscrow(now() - 3600, now() + 3600);
+      await purchase();
+    });
+
+    it('Rejects purchases after the season ends', async () => {
+      const purchase = await tryEscrow(now() - 3600, now() + 2);
+      await sleep(4000);
+      await expectError(purchase(), "ProductSeasonEnded");
+    });
+  });
+
+  it('Slashes the seller bond on a fraud resolution', async () => {
+    const bondedSeller = await fundedKeypair(provider, 5);
+    const bondBuyer = await fundedKeypair(provider);
+    const [sellerBond] = PublicKey.findProgramAddressSync(
+      [Buffer.from("seller_bond"), marketplacePda.toBuffer(), bondedSeller.publicKey.toBuffer()],
+      escrowProgram.programId
+    );
+    await escrowProgram.methods
+      .depositSellerBond(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accounts({
+        seller: bondedSeller.publicKey,
+        marketplace: marketplacePda,
+        sellerBond,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bondedSeller])
+      .rpc();
+
+    const product = await createProduct(marketplaceProgram, marketplacePda, bondedSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, bondBuyer);
+    await escrowProgram.methods
+      .disputeTransaction("Counterfeit goods", { notAsDescribed: {} })
+      .accounts({ user: bondBuyer.publicKey, escrow: pdas.escrow })
+      .signers([bondBuyer])
+      .rpc();
+
+    const buyerBalanceBefore = await provider.connection.getBalance(bondBuyer.publicKey);
+    await escrowProgram.methods
+      .resolveDispute(false, 5000) // Refund the buyer and slash half the bond
+      .accounts({
+        authority: marketplaceAuthority.publicKey,
+        marketplace: marketplacePda,
+        buyer: bondBuyer.publicKey,
+        seller: bondedSeller.publicKey,
+        escrow: pdas.escrow,
+        escrowVault: pdas.escrowVault,
+        buyerTokenAccount: null,
+        sellerTokenAccount: null,
+        escrowTokenAccount: null,
+        sellerBond,
+        product,
+        arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+        loserReputation: null,
+        sellerReputation: null,
+        buyerReputation: null,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, bondBuyer.publicKey),
+        feeDestination: feesDestination,
+        ...escrowTrackingAccounts(escrowProgram),
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const bond = await escrowProgram.account.sellerBond.fetch(sellerBond);
+    expect(bond.amount.toNumber()).to.equal(anchor.web3.LAMPORTS_PER_SOL / 2);
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    const buyerBalanceAfter = await provider.connection.getBalance(bondBuyer.publicKey);
+    expect(buyerBalanceAfter - buyerBalanceBefore).to.equal(
+      escrow.amount.toNumber() + anchor.web3.LAMPORTS_PER_SOL / 2
+    );
+  });
+
+  describe('with a warped clock', () => {
+    // The validator's clock follows wall time, so timeout tests run against
+    // a bankrun bank whose clock they can move forward
+    it('Lets the seller claim an escrow the buyer never confirms', async () => {
+      const patientSeller = Keypair.generate();
+      const silentBuyer = Keypair.generate();
+      const context = await startAnchor(
+        '.',
+        [],
+        [patientSeller, silentBuyer].map((keypair) => ({
+          address: keypair.publicKey,
+          info: {
+            lamports: 2 * anchor.web3.LAMPORTS_PER_SOL,
+            data: Buffer.alloc(0),
+            owner: anchor.web3.SystemProgram.programId,
+            executable: false,
+          },
+        }))
+      );
+      const bankProvider = new BankrunProvider(context) as unknown as anchor.Provider;
+      const bankMarketplace = new Program<Marketplace>(
+        marketplaceProgram.idl,
+        marketplaceProgram.programId,
+        bankProvider
+      );
+      const bankEscrow = new Program<Escrow>(escrowProgram.idl, escrowProgram.programId, bankProvider);
+      const reputationProgram = anchor.workspace.Reputation as Program<Reputation>;
+      const bankReputation = new Program<Reputation>(
+        reputationProgram.idl,
+        reputationProgram.programId,
+        bankProvider
+      );
+
+      // A fresh marketplace with the default 14 day auto-release window
+      const authority = context.payer.publicKey;
+      const marketplace = findMarketplacePda(bankMarketplace, authority);
+      await bankMarketplace.methods
+        .initializeMarketplace(250)
+        .accounts({
+          authority,
+          marketplace,
+          feeDestination: authority,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+      const sellerReputation = await initReputation(bankReputation, patientSeller);
+      const product = await createProduct(bankMarketplace, marketplace, patientSeller);
+
+      const pdas = findEscrowPdas(bankEscrow, marketplace, silentBuyer.publicKey, product);
+      await bankEscrow.methods
+        .createEscrow(new anchor.BN(1), null, null)
+        .accounts({
+          buyer: silentBuyer.publicKey,
+          marketplace,
+          product,
+          escrow: pdas.escrow,
+          buyerReputation: null,
+          coupon: null,
+          mint: null,
+          sellerStatus: findSellerStatusPda(marketplace, patientSeller.publicKey),
+          sellerReputation,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplace, silentBuyer.publicKey),
+          ...escrowTrackingAccounts(bankEscrow),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([silentBuyer])
+        .rpc();
+      await fundEscrow(bankEscrow, pdas, silentBuyer);
+      await bankEscrow.methods
+        .markAsShipped(null)
+        .accounts({ seller: patientSeller.publicKey, escrow: pdas.escrow })
+        .signers([patientSeller])
+        .rpc();
+
+      const claim = (reputation: PublicKey | null) =>
+        bankEscrow.methods
+          .claimAfterTimeout()
+          .accounts({
+            seller: patientSeller.publicKey,
+            marketplace,
+            escrow: pdas.escrow,
+            buyer: silentBuyer.publicKey,
+            escrowVault: pdas.escrowVault,
+            escrowTokenAccount: null,
+            sellerTokenAccount: null,
+            product,
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplace, silentBuyer.publicKey),
+            sellerStatus: findSellerStatusPda(marketplace, patientSeller.publicKey),
+            feeDestination: authority,
+            sellerReputation: reputation,
+            buyerReputation: null,
+            ...escrowTrackingAccounts(bankEscrow),
+            reputationProgram: bankReputation.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([patientSeller])
+          .rpc();
+
+      const warp = async (seconds: number) => {
+        const clock = await context.banksClient.getClock();
+        context.setClock(
+          new Clock(
+            clock.slot,
+            clock.epochStartTimestamp,
+            clock.epoch,
+            clock.leaderScheduleEpoch,
+            clock.unixTimestamp + BigInt(seconds)
+          )
+        );
+      };
+      // A day short of the window the buyer may still confirm
+      await warp(13 * 24 * 60 * 60);
+      await expectError(claim(null), "ConfirmationWindowOpen");
+
+      await warp(24 * 60 * 60);
+      const sellerBalanceBefore = await context.banksClient.getBalance(patientSeller.publicKey);
+      await claim(sellerReputation);
+
+      const escrow = await bankEscrow.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ completed: {} });
+      const sellerBalanceAfter = await context.banksClient.getBalance(patientSeller.publicKey);
+      expect(Number(sellerBalanceAfter - sellerBalanceBefore)).to.equal(
+        escrow.amount.toNumber() - escrow.feesCollected.toNumber()
+      );
+
+      // The claimed sale counts toward the seller's reputation
+      const reputation = await bankReputation.account.userReputation.fetch(sellerReputation);
+      expect(reputation.totalSales.toNumber()).to.equal(1);
+    });
+  });
+
+  it('Splits disputed funds between buyer and seller', async () => {
+    const splitSeller = await fundedKeypair(provider);
+    const splitBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, splitSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, splitBuyer);
+    await escrowProgram.methods
+      .disputeTransaction("Half the crates arrived bruised", { damaged: {} })
+      .accounts({ user: splitBuyer.publicKey, escrow: pdas.escrow })
+      .signers([splitBuyer])
+      .rpc();
+    const resolveSplit = (sellerBps: number) =>
+      escrowProgram.methods
+        .resolveDisputeSplit(sellerBps)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          buyer: splitBuyer.publicKey,
+          seller: splitSeller.publicKey,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          sellerTokenAccount: null,
+          escrowTokenAccount: null,
+          sellerBond: null,
+          product,
+          arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+          loserReputation: null,
+          sellerReputation: null,
+          buyerReputation: null,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, splitBuyer.publicKey),
+          feeDestination: feesDestination,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    await expectError(resolveSplit(10001), "InvalidBasisPoints");
+
+    const buyerBalanceBefore = await provider.connection.getBalance(splitBuyer.publicKey);
+    const sellerBalanceBefore = await provider.connection.getBalance(splitSeller.publicKey);
+    await resolveSplit(5000);
+
+    // The seller's half pays the 2.5% marketplace fee; the buyer's half does not
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    const half = escrow.amount.toNumber() / 2;
+    expect((await provider.connection.getBalance(splitSeller.publicKey)) - sellerBalanceBefore).to.equal(
+      half - (half * 250) / 10000
+    );
+    expect((await provider.connection.getBalance(splitBuyer.publicKey)) - buyerBalanceBefore).to.equal(half);
+    expect(escrow.status).to.deep.equal({ completed: {} });
+  });
+
+  it('Completes an order shipped in two parts', async () => {
+    const splitSeller = await fundedKeypair(provider);
+    const splitBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, splitSeller);
+    const pdas = await createFundedEscrow(
+      escrowProgram,
+      marketplacePda,
+      product,
+      splitBuyer,
+      new anchor.BN(4)
+    );
+    const shipPart = (quantity: number, trackingNumber: string) =>
+      escrowProgram.methods
+        .markPartiallyShipped(new anchor.BN(quantity), { carrier: "DHL", trackingNumber })
+        .accounts({ seller: splitSeller.publicKey, escrow: pdas.escrow })
+        .signers([splitSeller])
+        .rpc();
+
+    await shipPart(3, "PART-1");
+    let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.shippedQuantity.toNumber()).to.equal(3);
+    expect(escrow.status).to.deep.equal({ funded: {} });
+
+    await shipPart(1, "PART-2");
+    escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.shippedQuantity.toNumber()).to.equal(4);
+    expect(escrow.status).to.deep.equal({ shipped: {} });
+    expect(escrow.tracking).to.deep.equal({ carrier: "DHL", trackingNumber: "PART-2" });
+
+    await escrowProgram.methods
+      .confirmDelivery()
+      .accounts({
+        buyer: splitBuyer.publicKey,
+        seller: splitSeller.publicKey,
+        marketplace: marketplacePda,
+        escrow: pdas.escrow,
+        escrowVault: pdas.escrowVault,
+        escrowTokenAccount: null,
+        sellerTokenAccount: null,
+        product,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, splitBuyer.publicKey),
+        sellerStatus: findSellerStatusPda(marketplacePda, splitSeller.publicKey),
+        feeDestination: feesDestination,
+        sellerReputation: null,
+        buyerReputation: null,
+        ...escrowTrackingAccounts(escrowProgram),
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([splitBuyer])
+      .rpc();
+    escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ completed: {} });
+
+    // The last shipment stays readable once delivery is confirmed
+    expect(escrow.tracking).to.deep.equal({ carrier: "DHL", trackingNumber: "PART-2" });
+  });
+
+  it('Stores the carrier and tracking number of a shipment', async () => {
+    const trackedBuyer = await fundedKeypair(provider);
+    const trackedSeller = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, trackedSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, trackedBuyer);
+    const ship = (carrier: string, trackingNumber: string) =>
+      escrowProgram.methods
+        .markAsShipped({ carrier, trackingNumber })
+        .accounts({ seller: trackedSeller.publicKey, escrow: pdas.escrow })
+        .signers([trackedSeller])
+        .rpc();
+
+    await expectError(ship("C".repeat(21), "1Z999AA10123456784"), "CarrierTooLong");
+    await expectError(ship("UPS", "9".repeat(41)), "TrackingIdTooLong");
+    await expectError(ship("", "1Z999AA10123456784"), "InvalidTrackingId");
+
+    await ship("C".repeat(20), "9".repeat(40));
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ shipped: {} });
+    expect(escrow.tracking).to.deep.equal({ carrier: "C".repeat(20), trackingNumber: "9".repeat(40) });
+  });
+
+  it('Keeps the dispute description and who raised it until the dispute is settled', async () => {
+    const describedBuyer = await fundedKeypair(provider);
+    const describedSeller = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, describedSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, describedBuyer);
+    await escrowProgram.methods
+      .disputeTransaction("Half the crate was rotten", { notAsDescribed: {} })
+      .accounts({ user: describedBuyer.publicKey, escrow: pdas.escrow })
+      .signers([describedBuyer])
+      .rpc();
+
+    let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.disputeDescription).to.equal("Half the crate was rotten");
+    expect(escrow.disputedBy.toString()).to.equal(describedBuyer.publicKey.toString());
+
+    await escrowProgram.methods
+      .resolveDispute(false, 0)
+      .accounts({
+        authority: marketplaceAuthority.publicKey,
+        marketplace: marketplacePda,
+        buyer: describedBuyer.publicKey,
+        seller: describedSeller.publicKey,
+        escrow: pdas.escrow,
+        escrowVault: pdas.escrowVault,
+        buyerTokenAccount: null,
+        sellerTokenAccount: null,
+        escrowTokenAccount: null,
+        sellerBond: null,
+        product,
+        arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+        loserReputation: null,
+        sellerReputation: null,
+        buyerReputation: null,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, describedBuyer.publicKey),
+        feeDestination: feesDestination,
+        ...escrowTrackingAccounts(escrowProgram),
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ refunded: {} });
+    expect(escrow.disputeDescription).to.equal(null);
+    expect(escrow.disputedBy).to.equal(null);
+  });
+
+  describe('closing escrows', () => {
+    // A short removal grace, so tests can wait it out
+    before(async () => {
+      await marketplaceProgram.methods
+        .setReviewRemovalGrace(new anchor.BN(2))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    });
+
+    after(async () => {
+      await marketplaceProgram.methods
+        .setReviewRemovalGrace(new anchor.BN(30 * 24 * 60 * 60))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    });
+
+    const close = (buyer: Keypair, pdas: { escrow: PublicKey; escrowVault: PublicKey }) =>
+      escrowProgram.methods
+        .closeEscrow()
+        .accounts({
+          buyer: buyer.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([buyer])
+        .rpc();
+
+    it('Closes a settled escrow and returns its rent to the buyer', async () => {
+      const closingSeller = await fundedKeypair(provider);
+      const closingBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, closingSeller, {
+        returnWindowSeconds: new anchor.BN(2),
+      });
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, closingBuyer);
+
+      await expectError(close(closingBuyer, pdas), "EscrowNotSettled");
+      await shipAndConfirm(escrowProgram, pdas, closingBuyer, closingSeller);
+
+      // The order can still be returned until its return window passes
+      await expectError(close(closingBuyer, pdas), "EscrowStillReviewable");
+      await sleep(3000);
+
+      const reclaimable =
+        (await provider.connection.getBalance(pdas.escrow)) +
+        (await provider.connection.getBalance(pdas.escrowVault));
+      const buyerBalanceBefore = await provider.connection.getBalance(closingBuyer.publicKey);
+      await close(closingBuyer, pdas);
+
+      expect(await provider.connection.getAccountInfo(pdas.escrow)).to.equal(null);
+      expect(await provider.connection.getBalance(pdas.escrowVault)).to.equal(0);
+      const buyerBalanceAfter = await provider.connection.getBalance(closingBuyer.publicKey);
+      expect(buyerBalanceAfter - buyerBalanceBefore).to.equal(reclaimable);
+
+      // Buying the product again opens an escrow at a new address, so nothing
+      // keyed by the closed escrow, like its reviews, is handed to the new one
+      const repeat = await createEscrow(escrowProgram, marketplacePda, product, closingBuyer);
+      expect(repeat.escrow.toString()).to.not.equal(pdas.escrow.toString());
+      expect((await escrowProgram.account.escrow.fetch(repeat.escrow)).nonce.toNumber()).to.equal(1);
+    });
+
+    it('Keeps an escrow the buyer lost a dispute on until its review can no longer be removed', async () => {
+      const disputedSeller = await fundedKeypair(provider);
+      const losingBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, disputedSeller);
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, losingBuyer);
+      await escrowProgram.methods
+        .disputeTransaction("Never arrived", { notReceived: {} })
+        .accounts({ user: losingBuyer.publicKey, escrow: pdas.escrow })
+        .signers([losingBuyer])
+        .rpc();
+      await escrowProgram.methods
+        .resolveDispute(true, 0)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          buyer: losingBuyer.publicKey,
+          seller: disputedSeller.publicKey,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          sellerTokenAccount: null,
+          escrowTokenAccount: null,
+          sellerBond: null,
+          product,
+          arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+          loserReputation: null,
+          sellerReputation: null,
+          buyerReputation: null,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, losingBuyer.publicKey),
+          feeDestination: feesDestination,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+      expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).status).to.deep.equal({ completed: {} });
+
+      await expectError(close(losingBuyer, pdas), "EscrowStillReviewable");
+      await sleep(3000);
+      await close(losingBuyer, pdas);
+      expect(await provider.connection.getAccountInfo(pdas.escrow)).to.equal(null);
+    });
+  });
+
+  it('Exports escrow state in a stable versioned encoding', async () => {
+    const exportBuyer = await fundedKeypair(provider);
+    const exportSeller = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, exportSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, exportBuyer, new anchor.BN(2));
+
+    const state: Buffer = Buffer.from(
+      await escrowProgram.methods.serializeEscrowState().accounts({ escrow: pdas.escrow }).view()
+    );
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+
+    // The encoding has a fixed size however many fields the account gains
+    expect(state.length).to.equal(211);
+    expect(state[0]).to.equal(1);
+    const key = (index: number) => new PublicKey(state.subarray(1 + 32 * index, 33 + 32 * index));
+    expect(key(0).toString()).to.equal(pdas.escrow.toString());
+    expect(key(1).toString()).to.equal(escrow.marketplace.toString());
+    expect(key(2).toString()).to.equal(escrow.buyer.toString());
+    expect(key(3).toString()).to.equal(escrow.seller.toString());
+    expect(key(4).toString()).to.equal(escrow.product.toString());
+    const u64At = (offset: number) => new anchor.BN(state.subarray(offset, offset + 8), 'le');
+    expect(u64At(161).toNumber()).to.equal(2);
+    expect(u64At(169).toString()).to.equal(escrow.amount.toString());
+    expect(u64At(177).toNumber()).to.equal(0);
+    expect(u64At(185).toNumber()).to.equal(0);
+    expect(state[193]).to.equal(1); // SOL
+    expect(state[194]).to.equal(1); // Funded
+    expect(u64At(195).toString()).to.equal(escrow.createdAt.toString());
+    expect(u64At(203).toString()).to.equal(escrow.updatedAt.toString());
+  });
+
+  describe('partial confirmation and refunds', () => {
+    let partialSeller: Keypair;
+    let partialBuyer: Keypair;
+    let product: PublicKey;
+    let pdas: { escrow: PublicKey; escrowVault: PublicKey };
+
+    beforeEach(async () => {
+      partialSeller = await fundedKeypair(provider);
+      partialBuyer = await fundedKeypair(provider);
+      product = await createProduct(marketplaceProgram, marketplacePda, partialSeller);
+      pdas = await createFundedEscrow(
+        escrowProgram,
+        marketplacePda,
+        product,
+        partialBuyer,
+        new anchor.BN(3)
+      );
+    });
+
+    const confirmPart = (
+      quantity: number,
+      reputations: { sellerReputation: PublicKey | null; buyerReputation: PublicKey | null } = {
+        sellerReputation: null,
+        buyerReputation: null,
+      }
+    ) =>
+      escrowProgram.methods
+        .confirmPartialDelivery(new anchor.BN(quantity))
+        .accounts({
+          buyer: partialBuyer.publicKey,
+          seller: partialSeller.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, partialBuyer.publicKey),
+          sellerStatus: findSellerStatusPda(marketplacePda, partialSeller.publicKey),
+          feeDestination: feesDestination,
+          ...reputations,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partialBuyer])
+        .rpc();
+
+    const refundPart = (quantity: number) =>
+      escrowProgram.methods
+        .refundUnshipped(new anchor.BN(quantity))
+        .accounts({
+          seller: partialSeller.publicKey,
+          buyer: partialBuyer.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          buyerTokenAccount: null,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, partialBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partialSeller])
+        .rpc();
+
+    it('Settles as partially completed once every unit is confirmed or refunded', async () => {
+      await escrowProgram.methods
+        .markPartiallyShipped(new anchor.BN(2), { carrier: "DHL", trackingNumber: "PART-1" })
+        .accounts({ seller: partialSeller.publicKey, escrow: pdas.escrow })
+        .signers([partialSeller])
+        .rpc();
+      await expectError(refundPart(2), "InvalidPartialQuantity");
+      await refundPart(1);
+
+      let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ shipped: {} });
+
+      await confirmPart(1);
+      await expectError(confirmPart(2), "InvalidPartialQuantity");
+      await confirmPart(1);
+
+      escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ partiallyCompleted: {} });
+      expect(escrow.releasedAmount.add(escrow.refundedAmount).toString()).to.equal(
+        escrow.amount.toString()
+      );
+
+      // Nothing is left to settle
+      await expectError(confirmPart(1), "InvalidEscrowState");
+    });
+
+    it('Takes the marketplace fee from each confirmed portion', async () => {
+      await escrowProgram.methods
+        .markPartiallyShipped(new anchor.BN(3), { carrier: "DHL", trackingNumber: "PART-2" })
+        .accounts({ seller: partialSeller.publicKey, escrow: pdas.escrow })
+        .signers([partialSeller])
+        .rpc();
+
+      const sellerBalanceBefore = await provider.connection.getBalance(partialSeller.publicKey);
+      await confirmPart(1);
+      let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      const portion = escrow.releasedAmount.toNumber();
+      const portionFee = Math.floor((portion * escrow.feesBasisPoints) / 10000);
+      expect(portionFee).to.be.greaterThan(0);
+      expect(escrow.feesCollected.toNumber()).to.equal(portionFee);
+      let sellerBalance = await provider.connection.getBalance(partialSeller.publicKey);
+      expect(sellerBalance - sellerBalanceBefore).to.equal(portion - portionFee);
+
+      // The rest settles the escrow, which never pays more than the seller's net
+      await confirmPart(2);
+      escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ completed: {} });
+      sellerBalance = await provider.connection.getBalance(partialSeller.publicKey);
+      expect(sellerBalance - sellerBalanceBefore).to.equal(
+        escrow.amount.toNumber() - escrow.feesCollected.toNumber()
+      );
+      expect(escrow.feesCollected.toNumber()).to.be.greaterThan(portionFee);
+    });
+
+    it('Credits the sale and purchase once the last units are confirmed', async () => {
+      const reputationProgram = anchor.workspace.Reputation as Program<Reputation>;
+      const reputations = {
+        sellerReputation: await initReputation(reputationProgram, partialSeller),
+        buyerReputation: await initReputation(reputationProgram, partialBuyer),
+      };
+      await escrowProgram.methods
+        .markPartiallyShipped(new anchor.BN(3), { carrier: "DHL", trackingNumber: "PART-3" })
+        .accounts({ seller: partialSeller.publicKey, escrow: pdas.escrow })
+        .signers([partialSeller])
+        .rpc();
+
+      // A partial confirmation leaves the escrow open, so nothing is credited yet
+      await confirmPart(1, reputations);
+      expect(
+        (await reputationProgram.account.userReputation.fetch(reputations.sellerReputation)).totalSales.toNumber()
+      ).to.equal(0);
+
+      await confirmPart(2, reputations);
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ completed: {} });
+      const seller = await reputationProgram.account.userReputation.fetch(reputations.sellerReputation);
+      const buyer = await reputationProgram.account.userReputation.fetch(reputations.buyerReputation);
+      expect(seller.totalSales.toNumber()).to.equal(1);
+      expect(buyer.totalPurchases.toNumber()).to.equal(1);
+    });
+
+    it('Rejects cancelling an order once part of it has shipped', async () => {
+      await escrowProgram.methods
+        .markPartiallyShipped(new anchor.BN(2), { carrier: "DHL", trackingNumber: "PART-4" })
+        .accounts({ seller: partialSeller.publicKey, escrow: pdas.escrow })
+        .signers([partialSeller])
+        .rpc();
+
+      await expectError(
+        escrowProgram.methods
+          .cancelEscrow()
+          .accounts({
+            buyer: partialBuyer.publicKey,
+            marketplace: marketplacePda,
+            escrow: pdas.escrow,
+            seller: partialSeller.publicKey,
+            escrowVault: pdas.escrowVault,
+            buyerTokenAccount: null,
+            escrowTokenAccount: null,
+            sellerTokenAccount: null,
+            buyerReputation: null,
+            product,
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, partialBuyer.publicKey),
+            ...escrowTrackingAccounts(escrowProgram),
+            reputationProgram: anchor.workspace.Reputation.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([partialBuyer])
+          .rpc(),
+        "OrderPartiallyFulfilled"
+      );
+
+      // Only the unshipped unit goes back to the buyer
+      await refundPart(1);
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ shipped: {} });
+      expect(escrow.refundedQuantity.toNumber()).to.equal(1);
+    });
+
+    it('Settles as refunded once every unit is refunded', async () => {
+      await refundPart(3);
+
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ refunded: {} });
+      expect(escrow.refundedAmount.toString()).to.equal(escrow.amount.toString());
+      await expectError(refundPart(1), "InvalidEscrowState");
+    });
+  });
+
+  describe('batch funding', () => {
+    // Three escrows from one buyer, each for a different product
+    const cartEscrows = async (buyer: Keypair, price: anchor.BN) => {
+      const cartSeller = await fundedKeypair(provider);
+      const escrows = [];
+      for (let i = 0; i < 3; i++) {
+        const product = await createProduct(marketplaceProgram, marketplacePda, cartSeller, { price });
+        escrows.push(await createEscrow(escrowProgram, marketplacePda, product, buyer));
+      }
+      return escrows;
+    };
+
+    const fundBatch = (buyer: Keypair, escrows: { escrow: PublicKey; escrowVault: PublicKey }[]) =>
+      escrowProgram.methods
+        .fundEscrowsBatch(null)
+        .accounts({
+          buyer: buyer.publicKey,
+          marketplace: marketplacePda,
+          buyerTokenAccount: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          escrows.flatMap(({ escrow, escrowVault }) => [
+            { pubkey: escrow, isWritable: true, isSigner: false },
+            { pubkey: escrowVault, isWritable: true, isSigner: false },
+          ])
+        )
+        .signers([buyer])
+        .rpc();
+
+    it('Funds a cart of escrows in one transaction', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const escrows = await cartEscrows(cartBuyer, new anchor.BN(100000));
+
+      await fundBatch(cartBuyer, escrows);
+
+      for (const { escrow, escrowVault } of escrows) {
+        const escrowAccount = await escrowProgram.account.escrow.fetch(escrow);
+        expect(escrowAccount.status).to.deep.equal({ funded: {} });
+        expect(escrowAccount.fundedAmount.toString()).to.equal(escrowAccount.amount.toString());
+        expect(await provider.connection.getBalance(escrowVault)).to.equal(escrowAccount.amount.toNumber());
+      }
+    });
+
+    it('Funds none of the cart when the buyer cannot cover all of it', async () => {
+      const cartBuyer = await fundedKeypair(provider, 2);
+      const escrows = await cartEscrows(cartBuyer, new anchor.BN(0.9 * anchor.web3.LAMPORTS_PER_SOL));
+
+      await expectError(fundBatch(cartBuyer, escrows), "InsufficientFunds");
+
+      for (const { escrow } of escrows) {
+        const escrowAccount = await escrowProgram.account.escrow.fetch(escrow);
+        expect(escrowAccount.status).to.deep.equal({ created: {} });
+        expect(escrowAccount.fundedAmount.toNumber()).to.equal(0);
+      }
+    });
+  });
+
+  it('Closes disputes once the dispute window after shipment has passed', async () => {
+    const windowBuyer = await fundedKeypair(provider);
+    const windowSeller = await fundedKeypair(provider);
+    const setDisputeWindow = (seconds: number) =>
+      marketplaceProgram.methods
+        .setDisputeWindow(new anchor.BN(seconds))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    const shippedEscrow = async () => {
+      const product = await createProduct(marketplaceProgram, marketplacePda, windowSeller);
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, windowBuyer);
+      await escrowProgram.methods
+        .markAsShipped(null)
+        .accounts({ seller: windowSeller.publicKey, escrow: pdas.escrow })
+        .signers([windowSeller])
+        .rpc();
+      return pdas;
+    };
+    const dispute = (escrow: PublicKey) =>
+      escrowProgram.methods
+        .disputeTransaction("Never arrived", { notReceived: {} })
+        .accounts({ user: windowBuyer.publicKey, escrow })
+        .signers([windowBuyer])
+        .rpc();
+
+    await setDisputeWindow(2);
+    try {
+      const timely = await shippedEscrow();
+      const late = await shippedEscrow();
+      const escrowAccount = await escrowProgram.account.escrow.fetch(late.escrow);
+      expect(escrowAccount.disputeDeadline.toNumber()).to.be.greaterThan(0);
+
+      await dispute(timely.escrow);
+      expect((await escrowProgram.account.escrow.fetch(timely.escrow)).status).to.deep.equal({ disputed: {} });
+
+      await sleep(4000);
+      await expectError(dispute(late.escrow), "DisputeWindowClosed");
+    } finally {
+      await setDisputeWindow(0);
+    }
+  });
+
+  it('Records evidence slots and rejects evidence after the cutoff', async () => {
+    const evidenceBuyer = await fundedKeypair(provider);
+    const evidenceSeller = await fundedKeypair(provider);
+    const setEvidenceWindow = (slots: number) =>
+      marketplaceProgram.methods
+        .setEvidenceWindow(new anchor.BN(slots))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    const submitEvidence = (user: Keypair, escrow: PublicKey, evidence: string) =>
+      escrowProgram.methods
+        .submitEvidence([...createHash('sha256').update(evidence).digest()])
+        .accounts({ user: user.publicKey, escrow })
+        .signers([user])
+        .rpc();
+
+    // Escrows keep the evidence window in force at creation
+    const product = await createProduct(marketplaceProgram, marketplacePda, evidenceSeller);
+    await setEvidenceWindow(5);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, evidenceBuyer)
+      .finally(() => setEvidenceWindow(0));
+    await expectError(submitEvidence(evidenceBuyer, pdas.escrow, "photos"), "InvalidEscrowState");
+
+    await escrowProgram.methods
+      .disputeTransaction("Spoiled on arrival", { notAsDescribed: {} })
+      .accounts({ user: evidenceBuyer.publicKey, escrow: pdas.escrow })
+      .signers([evidenceBuyer])
+      .rpc();
+    const cutoff = (await escrowProgram.account.escrow.fetch(pdas.escrow)).evidenceCutoffSlot.toNumber();
+    expect(cutoff).to.be.greaterThan(0);
+
+    const slotBefore = await provider.connection.getSlot();
+    await submitEvidence(evidenceBuyer, pdas.escrow, "photos");
+    const slotAfter = await provider.connection.getSlot();
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.evidenceCount).to.equal(1);
+    expect(escrow.evidence[0].submitter.toBase58()).to.equal(evidenceBuyer.publicKey.toBase58());
+    expect(escrow.evidence[0].slot.toNumber()).to.be.within(slotBefore, slotAfter);
+
+    while ((await provider.connection.getSlot()) <= cutoff) {
+      await sleep(500);
+    }
+    await expectError(submitEvidence(evidenceSeller, pdas.escrow, "invoice"), "EvidenceWindowClosed");
+  });
+
+  it('Rejects token accounts passed for a SOL escrow', async () => {
+    const solBuyer = await fundedKeypair(provider);
+    const solSeller = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, solSeller);
+    const pdas = await createEscrow(escrowProgram, marketplacePda, product, solBuyer);
+    const mint = await createMint(provider);
+    const strayTokenAccount = await mint.createAccount(solBuyer.publicKey);
+
+    await expectError(
+      escrowProgram.methods
+        .fundEscrow(null)
+        .accounts({
+          buyer: solBuyer.publicKey,
+          escrow: pdas.escrow,
+          marketplace: marketplacePda,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: strayTokenAccount,
+          escrowTokenAccount: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([solBuyer])
+        .rpc(),
+      "AccountsCurrencyMismatch"
+    );
+
+    // Nothing moved, so the escrow funds normally with the right accounts
+    await fundEscrow(escrowProgram, pdas, solBuyer);
+    await escrowProgram.methods
+      .markAsShipped(null)
+      .accounts({ seller: solSeller.publicKey, escrow: pdas.escrow })
+      .signers([solSeller])
+      .rpc();
+
+    await expectError(
+      escrowProgram.methods
+        .confirmDelivery()
+        .accounts({
+          buyer: solBuyer.publicKey,
+          seller: solSeller.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          sellerTokenAccount: strayTokenAccount,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, solBuyer.publicKey),
+          sellerStatus: findSellerStatusPda(marketplacePda, solSeller.publicKey),
+          feeDestination: feesDestination,
+          sellerReputation: null,
+          buyerReputation: null,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([solBuyer])
+        .rpc(),
+      "AccountsCurrencyMismatch"
+    );
+  });
+
+  it('Caps the escrows a buyer can hold open', async () => {
+    const busyBuyer = await fundedKeypair(provider);
+    const busySeller = await fundedKeypair(provider);
+    const setMaxOpenEscrows = (max: number) =>
+      marketplaceProgram.methods
+        .setMaxOpenEscrowsPerBuyer(new anchor.BN(max))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    const products = [];
+    for (let i = 0; i < 3; i++) {
+      products.push(await createProduct(marketplaceProgram, marketplacePda, busySeller));
+    }
+
+    await setMaxOpenEscrows(2);
+    try {
+      const first = await createFundedEscrow(escrowProgram, marketplacePda, products[0], busyBuyer);
+      await createEscrow(escrowProgram, marketplacePda, products[1], busyBuyer);
+      await expectError(
+        createEscrow(escrowProgram, marketplacePda, products[2], busyBuyer),
+        "TooManyOpenEscrows"
+      );
+
+      // Settling an escrow frees its slot
+      await shipAndConfirm(escrowProgram, first, busyBuyer, busySeller);
+      await createEscrow(escrowProgram, marketplacePda, products[2], busyBuyer);
+
+      const counter = await escrowProgram.account.buyerEscrowCounter.fetch(
+        findBuyerEscrowCounterPda(marketplacePda, busyBuyer.publicKey)
+      );
+      expect(counter.openEscrows.toNumber()).to.equal(2);
+    } finally {
+      await setMaxOpenEscrows(0);
+    }
+  });
+
+  describe('cart escrows', () => {
+    const findCartPda = (buyer: PublicKey, cartId: anchor.BN) =>
+      PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("cart"),
+          marketplacePda.toBuffer(),
+          buyer.toBuffer(),
+          cartId.toArrayLike(Buffer, "le", 8),
+        ],
+        escrowProgram.programId
+      )[0];
+
+    const settleAccounts = async (authority: PublicKey, cart: PublicKey) => {
+      const { escrowAuthority, marketplaceProgram: marketplaceProgramId } = escrowTrackingAccounts(escrowProgram);
+      const { buyer, feeDestination } = await escrowProgram.account.cartEscrow.fetch(cart);
+      return {
+        authority,
+        marketplace: marketplacePda,
+        cart,
+        feeDestination,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, buyer),
+        arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+        escrowAuthority,
+        marketplaceProgram: marketplaceProgramId,
+      };
+    };
+
+    const writable = (pubkey: PublicKey) => ({ pubkey, isWritable: true, isSigner: false });
+    const readonly = (pubkey: PublicKey) => ({ pubkey, isWritable: false, isSigner: false });
+
+    // Open a cart over (product, seller) line items
+    const createCart = (
+      cartBuyer: Keypair,
+      cartId: anchor.BN,
+      items: [PublicKey, PublicKey][],
+      quantities: number[],
+      buyerReputation: PublicKey | null = null
+    ) =>
+      escrowProgram.methods
+        .createCartEscrow(cartId, quantities.map((quantity) => new anchor.BN(quantity)))
+        .accounts({
+          buyer: cartBuyer.publicKey,
+          marketplace: marketplacePda,
+          cart: findCartPda(cartBuyer.publicKey, cartId),
+          buyerReputation,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, cartBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          items.flatMap(([product, seller]) => [
+            writable(product),
+            readonly(findSellerStatusPda(marketplacePda, seller)),
+            readonly(findUserReputationPda(seller)),
+          ])
+        )
+        .signers([cartBuyer])
+        .rpc();
+
+    it('Pays one seller and refunds a disputed item from another', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const sellerA = await fundedKeypair(provider);
+      const sellerB = await fundedKeypair(provider);
+      const price = new anchor.BN(200000);
+      const productA = await createProduct(marketplaceProgram, marketplacePda, sellerA, { price });
+      const productB = await createProduct(marketplaceProgram, marketplacePda, sellerB, { price });
+      const cartId = new anchor.BN(1);
+      const cart = findCartPda(cartBuyer.publicKey, cartId);
+
+      await createCart(cartBuyer, cartId, [[productA, sellerA.publicKey], [productB, sellerB.publicKey]], [2, 1]);
+
+      await escrowProgram.methods
+        .fundCartEscrow()
+        .accounts({
+          buyer: cartBuyer.publicKey,
+          cart,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([cartBuyer])
+        .rpc();
+
+      let cartAccount = await escrowProgram.account.cartEscrow.fetch(cart);
+      expect(cartAccount.amount.toNumber()).to.equal(600000);
+      expect(cartAccount.feeDestination.toString()).to.equal((await findSolFeeDestination(marketplacePda)).toString());
+      const [itemA, itemB] = cartAccount.items;
+
+      // Seller A ships and the buyer confirms; seller B's item is disputed
+      await escrowProgram.methods
+        .markCartItemShipped(0)
+        .accounts({ seller: sellerA.publicKey, cart })
+        .signers([sellerA])
+        .rpc();
+      await expectError(
+        escrowProgram.methods
+          .markCartItemShipped(1)
+          .accounts({ seller: sellerA.publicKey, cart })
+          .signers([sellerA])
+          .rpc(),
+        "UnauthorizedSeller"
+      );
+      await escrowProgram.methods
+        .disputeCartItem(1, { notReceived: {} })
+        .accounts({ user: cartBuyer.publicKey, cart })
+        .signers([cartBuyer])
+        .rpc();
+
+      const feesBefore = (await marketplaceProgram.account.marketplaceState.fetch(marketplacePda)).solFeesCollected;
+      const sellerABefore = await provider.connection.getBalance(sellerA.publicKey);
+      await escrowProgram.methods
+        .confirmCartItems(Buffer.from([0]))
+        .accounts(await settleAccounts(cartBuyer.publicKey, cart))
+        .remainingAccounts([writable(productA), writable(sellerA.publicKey)])
+        .signers([cartBuyer])
+        .rpc();
+
+      expect(await provider.connection.getBalance(sellerA.publicKey)).to.equal(
+        sellerABefore + itemA.amount.toNumber() - itemA.fee.toNumber()
+      );
+      const marketplace = await marketplaceProgram.account.marketplaceState.fetch(marketplacePda);
+      expect(marketplace.solFeesCollected.sub(feesBefore).toNumber()).to.equal(itemA.fee.toNumber());
+      expect((await marketplaceProgram.account.product.fetch(productA)).quantity.toNumber()).to.equal(8);
+
+      // The disputed item can only be settled through resolution
+      await expectError(
+        escrowProgram.methods
+          .confirmCartItems(Buffer.from([1]))
+          .accounts(await settleAccounts(cartBuyer.publicKey, cart))
+          .remainingAccounts([writable(productB), writable(sellerB.publicKey)])
+          .signers([cartBuyer])
+          .rpc(),
+        "InvalidEscrowState"
+      );
+
+      const buyerBefore = await provider.connection.getBalance(cartBuyer.publicKey);
+      await escrowProgram.methods
+        .resolveCartItemDispute(1, false)
+        .accounts(await settleAccounts(marketplaceAuthority.publicKey, cart))
+        .remainingAccounts([writable(productB), writable(cartBuyer.publicKey)])
+        .rpc();
+
+      expect(await provider.connection.getBalance(cartBuyer.publicKey)).to.equal(
+        buyerBefore + itemB.amount.toNumber()
+      );
+      expect((await marketplaceProgram.account.product.fetch(productB)).quantity.toNumber()).to.equal(10);
+
+      cartAccount = await escrowProgram.account.cartEscrow.fetch(cart);
+      expect(cartAccount.items[0].status).to.deep.equal({ completed: {} });
+      expect(cartAccount.items[1].status).to.deep.equal({ refunded: {} });
+
+      // The settled cart no longer counts against the buyer's open escrows
+      const counter = await escrowProgram.account.buyerEscrowCounter.fetch(
+        findBuyerEscrowCounterPda(marketplacePda, cartBuyer.publicKey)
+      );
+      expect(counter.openEscrows.toNumber()).to.equal(0);
+    });
+
+    it('Only lets the dispute resolvers settle a disputed item', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const cartSeller = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, cartSeller, {
+        price: new anchor.BN(200000),
+      });
+      const cartId = new anchor.BN(3);
+      const cart = findCartPda(cartBuyer.publicKey, cartId);
+      await createCart(cartBuyer, cartId, [[product, cartSeller.publicKey]], [1]);
+      await escrowProgram.methods
+        .fundCartEscrow()
+        .accounts({ buyer: cartBuyer.publicKey, cart, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([cartBuyer])
+        .rpc();
+      await escrowProgram.methods
+        .disputeCartItem(0, { damaged: {} })
+        .accounts({ user: cartBuyer.publicKey, cart })
+        .signers([cartBuyer])
+        .rpc();
+      expect((await escrowProgram.account.cartEscrow.fetch(cart)).items[0].disputeReason)
+        .to.deep.equal({ damaged: {} });
+
+      const stranger = await fundedKeypair(provider);
+      await expectError(
+        escrowProgram.methods
+          .resolveCartItemDispute(0, true)
+          .accounts(await settleAccounts(stranger.publicKey, cart))
+          .remainingAccounts([writable(product), writable(cartSeller.publicKey)])
+          .signers([stranger])
+          .rpc(),
+        "UnauthorizedAuthority"
+      );
+      await escrowProgram.methods
+        .resolveCartItemDispute(0, true)
+        .accounts(await settleAccounts(marketplaceAuthority.publicKey, cart))
+        .remainingAccounts([writable(product), writable(cartSeller.publicKey)])
+        .rpc();
+      expect((await escrowProgram.account.cartEscrow.fetch(cart)).items[0].status)
+        .to.deep.equal({ completed: {} });
+    });
+
+    it('Lets the buyer cancel a cart they have not funded', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const cartSeller = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, cartSeller);
+      const cancel = (cartId: anchor.BN) =>
+        escrowProgram.methods
+          .cancelCartEscrow()
+          .accounts({
+            buyer: cartBuyer.publicKey,
+            marketplace: marketplacePda,
+            cart: findCartPda(cartBuyer.publicKey, cartId),
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, cartBuyer.publicKey),
+            ...escrowTrackingAccounts(escrowProgram),
+          })
+          .remainingAccounts([writable(product)])
+          .signers([cartBuyer])
+          .rpc();
+
+      const unfunded = new anchor.BN(1);
+      await createCart(cartBuyer, unfunded, [[product, cartSeller.publicKey]], [3]);
+      expect((await marketplaceProgram.account.product.fetch(product)).reservedQuantity.toNumber()).to.equal(3);
+      await cancel(unfunded);
+
+      expect(await provider.connection.getAccountInfo(findCartPda(cartBuyer.publicKey, unfunded))).to.equal(null);
+      expect((await marketplaceProgram.account.product.fetch(product)).reservedQuantity.toNumber()).to.equal(0);
+      const counter = await escrowProgram.account.buyerEscrowCounter.fetch(
+        findBuyerEscrowCounterPda(marketplacePda, cartBuyer.publicKey)
+      );
+      expect(counter.openEscrows.toNumber()).to.equal(0);
+
+      // A paid cart settles item by item instead
+      const funded = new anchor.BN(2);
+      await createCart(cartBuyer, funded, [[product, cartSeller.publicKey]], [1]);
+      await escrowProgram.methods
+        .fundCartEscrow()
+        .accounts({
+          buyer: cartBuyer.publicKey,
+          cart: findCartPda(cartBuyer.publicKey, funded),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([cartBuyer])
+        .rpc();
+      await expectError(cancel(funded), "AlreadyFunded");
+    });
+
+    it('Holds carts to the buyer and fee rules of single escrows', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const veteranSeller = await fundedKeypair(provider);
+      const reputationProgram = anchor.workspace.Reputation as Program<Reputation>;
+      const sellerReputation = await initReputation(reputationProgram, veteranSeller);
+      const firstProduct = await createProduct(marketplaceProgram, marketplacePda, veteranSeller);
+      const firstSale = await createFundedEscrow(escrowProgram, marketplacePda, firstProduct, cartBuyer);
+      await shipAndConfirm(escrowProgram, firstSale, cartBuyer, veteranSeller, sellerReputation);
+
+      // Sellers limiting risky buyers need to see the buyer's reputation
+      const limited = await createProduct(marketplaceProgram, marketplacePda, veteranSeller, {
+        maxBuyerLostDisputes: new anchor.BN(0),
+      });
+      await expectError(
+        createCart(cartBuyer, new anchor.BN(1), [[limited, veteranSeller.publicKey]], [1]),
+        "BuyerReputationRequired"
+      );
+
+      const setMaxOpenEscrows = (max: number) =>
+        marketplaceProgram.methods
+          .setMaxOpenEscrowsPerBuyer(new anchor.BN(max))
+          .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+          .rpc();
+      const setTiers = (tiers: { minSales: anchor.BN; feesBasisPoints: number }[]) =>
+        marketplaceProgram.methods
+          .setFeeTiers(tiers)
+          .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+          .rpc();
+      const product = await createProduct(marketplaceProgram, marketplacePda, veteranSeller, {
+        price: new anchor.BN(400000),
+      });
+
+      await setTiers([{ minSales: new anchor.BN(1), feesBasisPoints: 100 }]);
+      await setMaxOpenEscrows(1);
+      try {
+        const cartId = new anchor.BN(2);
+        await createCart(cartBuyer, cartId, [[product, veteranSeller.publicKey]], [1]);
+        const [item] = (await escrowProgram.account.cartEscrow.fetch(findCartPda(cartBuyer.publicKey, cartId))).items;
+        expect(item.fee.toNumber()).to.equal(400000 * 100 / 10000);
+
+        // The open cart takes the buyer's only slot
+        await expectError(
+          createCart(cartBuyer, new anchor.BN(3), [[product, veteranSeller.publicKey]], [1]),
+          "TooManyOpenEscrows"
+        );
+      } finally {
+        await setMaxOpenEscrows(0);
+        await setTiers([]);
+      }
+    });
+
+    it('Waives the fee on exempt products', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const farmer = await fundedKeypair(provider);
+      const price = new anchor.BN(400000);
+      const staple = await createProduct(marketplaceProgram, marketplacePda, farmer, { price });
+      const specialty = await createProduct(marketplaceProgram, marketplacePda, farmer, { price });
+      const setFeeExempt = (authority: Keypair | null, product: PublicKey) =>
+        marketplaceProgram.methods
+          .setFeeExempt(true)
+          .accounts({
+            authority: authority ? authority.publicKey : marketplaceAuthority.publicKey,
+            marketplace: marketplacePda,
+            product,
+          })
+          .signers(authority ? [authority] : [])
+          .rpc();
+
+      await expectError(setFeeExempt(farmer, staple), "UnauthorizedAuthority");
+      await setFeeExempt(null, staple);
+
+      // Single escrows keep the exemption for settlement
+      const single = await createEscrow(escrowProgram, marketplacePda, staple, await fundedKeypair(provider));
+      expect((await escrowProgram.account.escrow.fetch(single.escrow)).feeExempt).to.equal(true);
+
+      const cartId = new anchor.BN(2);
+      const cart = findCartPda(cartBuyer.publicKey, cartId);
+      await createCart(cartBuyer, cartId, [[staple, farmer.publicKey], [specialty, farmer.publicKey]], [1, 1]);
+      await escrowProgram.methods
+        .fundCartEscrow()
+        .accounts({ buyer: cartBuyer.publicKey, cart, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([cartBuyer])
+        .rpc();
+      for (const index of [0, 1]) {
+        await escrowProgram.methods
+          .markCartItemShipped(index)
+          .accounts({ seller: farmer.publicKey, cart })
+          .signers([farmer])
+          .rpc();
+      }
+
+      const [stapleItem, specialtyItem] = (await escrowProgram.account.cartEscrow.fetch(cart)).items;
+      expect(stapleItem.fee.toNumber()).to.equal(0);
+      expect(specialtyItem.fee.toNumber()).to.equal(price.toNumber() * 250 / 10000);
+
+      const farmerBefore = await provider.connection.getBalance(farmer.publicKey);
+      await escrowProgram.methods
+        .confirmCartItems(Buffer.from([0, 1]))
+        .accounts(await settleAccounts(cartBuyer.publicKey, cart))
+        .remainingAccounts([writable(staple), writable(farmer.publicKey), writable(specialty), writable(farmer.publicKey)])
+        .signers([cartBuyer])
+        .rpc();
+
+      // The staple pays out in full, the specialty item less its fee
+      expect(await provider.connection.getBalance(farmer.publicKey)).to.equal(
+        farmerBefore + 2 * price.toNumber() - specialtyItem.fee.toNumber()
+      );
+    });
+
+    it('Only accepts SOL products', async () => {
+      const cartBuyer = await fundedKeypair(provider);
+      const stablecoinSeller = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, stablecoinSeller, {
+        currency: { usdc: {} },
+      });
+      const cartId = new anchor.BN(1);
+
+      await expectError(
+        createCart(cartBuyer, cartId, [[product, stablecoinSeller.publicKey]], [1]),
+        "UnsupportedCartCurrency"
+      );
+    });
+  });
+
+  it('Opens cancellation for a full refund once the handling time is missed', async () => {
+    const slowSeller = await fundedKeypair(provider);
+    const waitingBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, slowSeller, {
+      handlingTimeSeconds: new anchor.BN(2),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, waitingBuyer);
+
+    // The ship-by deadline runs from funding
+    let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.handlingTimeSeconds.toNumber()).to.equal(2);
+    expect(escrow.shipBy.toNumber()).to.equal(escrow.updatedAt.toNumber() + 2);
+
+    const cancel = () =>
+      escrowProgram.methods
+        .cancelEscrow()
+        .accounts({
+          buyer: waitingBuyer.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          seller: slowSeller.publicKey,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          buyerReputation: null,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, waitingBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([waitingBuyer])
+        .rpc();
+    await expectError(cancel(), "HandlingTimeNotElapsed");
+
+    await sleep(4000);
+    const buyerBalanceBefore = await provider.connection.getBalance(waitingBuyer.publicKey);
+    await cancel();
+
+    escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ cancelled: {} });
+    const buyerBalanceAfter = await provider.connection.getBalance(waitingBuyer.publicKey);
+    expect(buyerBalanceAfter - buyerBalanceBefore).to.equal(escrow.amount.toNumber());
+  });
+
+  it('Penalizes buyers who keep cancelling funded escrows', async () => {
+    const reputationProgram = anchor.workspace.Reputation as Program<Reputation>;
+    const patientSeller = await fundedKeypair(provider);
+    const fickleBuyer = await fundedKeypair(provider);
+    const buyerReputation = await initReputation(reputationProgram, fickleBuyer);
+    const setPenalty = (threshold: number, windowSeconds: number, feeBps: number) =>
+      marketplaceProgram.methods
+        .setCancellationPenalty(new anchor.BN(threshold), new anchor.BN(windowSeconds), feeBps)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    const cancel = (product: PublicKey, pdas: { escrow: PublicKey; escrowVault: PublicKey }, withReputation = true) =>
+      escrowProgram.methods
+        .cancelEscrow()
+        .accounts({
+          buyer: fickleBuyer.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          seller: patientSeller.publicKey,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          buyerReputation: withReputation ? buyerReputation : null,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, fickleBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: reputationProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([fickleBuyer])
+        .rpc();
+
+    // One cancellation per hour is free; each one past it forfeits 10% more
+    await setPenalty(1, 3600, 1000);
+    try {
+      const forfeited = [];
+      for (let i = 0; i < 3; i++) {
+        const product = await createProduct(marketplaceProgram, marketplacePda, patientSeller);
+        const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, fickleBuyer);
+        if (i === 0) {
+          await expectError(cancel(product, pdas, false), "BuyerReputationRequired");
+        }
+
+        const sellerBalanceBefore = await provider.connection.getBalance(patientSeller.publicKey);
+        await cancel(product, pdas);
+        const sellerBalanceAfter = await provider.connection.getBalance(patientSeller.publicKey);
+        const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+        expect(escrow.status).to.deep.equal({ cancelled: {} });
+        forfeited.push((sellerBalanceAfter - sellerBalanceBefore) / escrow.amount.toNumber());
+      }
+      expect(forfeited).to.deep.equal([0, 0.1, 0.2]);
+
+      const reputation = await reputationProgram.account.userReputation.fetch(buyerReputation);
+      expect(reputation.fundedCancellations.toNumber()).to.equal(3);
+      expect(reputation.recentCancellations.toNumber()).to.equal(3);
+    } finally {
+      await setPenalty(0, 0, 0);
+    }
+  });
+
+  it('Lets a program-owned seller list and ship through its program', async () => {
+    const proxyProgram = anchor.workspace.SellerProxy as Program<SellerProxy>;
+    const operator = await fundedKeypair(provider);
+    const [proxySeller] = PublicKey.findProgramAddressSync(
+      [Buffer.from("proxy_seller"), operator.publicKey.toBuffer()],
+      proxyProgram.programId
+    );
+    // The proxy seller pays for its listings
+    await airdrop(provider, proxySeller, 1);
+
+    const { productCount } = await marketplaceProgram.account.marketplaceState.fetch(marketplacePda);
+    const [product] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("product"),
+        marketplacePda.toBuffer(),
+        proxySeller.toBuffer(),
+        productCount.toArrayLike(Buffer, "le", 8),
+      ],
+      marketplaceProgram.programId
+    );
+    await proxyProgram.methods
+      .listProduct("Cooperative Maize", new anchor.BN(100000), new anchor.BN(10))
+      .accounts({
+        operator: operator.publicKey,
+        proxySeller,
+        marketplace: marketplacePda,
+        product,
+        productFingerprint: findProductFingerprintPda(marketplacePda, proxySeller, "Cooperative Maize", { other: {} }),
+        fingerprintedProduct: null,
+        marketplaceProgram: marketplaceProgram.programId,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([operator])
+      .rpc();
+    expect((await marketplaceProgram.account.product.fetch(product)).sellerIsPda).to.equal(true);
+
+    const proxyBuyer = await fundedKeypair(provider);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, proxyBuyer);
+    expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).sellerIsPda).to.equal(true);
+
+    // The operator's wallet is not the seller; only the proxy program can sign for it
+    await expectError(
+      escrowProgram.methods
+        .markAsShipped(null)
+        .accounts({ seller: operator.publicKey, escrow: pdas.escrow })
+        .signers([operator])
+        .rpc(),
+      "UnauthorizedSeller"
+    );
+
+    await proxyProgram.methods
+      .ship()
+      .accounts({
+        operator: operator.publicKey,
+        proxySeller,
+        escrow: pdas.escrow,
+        escrowProgram: escrowProgram.programId,
+      })
+      .signers([operator])
+      .rpc();
+    expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).status).to.deep.equal({ shipped: {} });
+  });
+
+  it('Converts a SOL payout into the seller\'s preferred token', async () => {
+    const swapProgram = anchor.workspace.MockSwap as Program<MockSwap>;
+    const swapBuyer = await fundedKeypair(provider);
+    const swapSeller = await fundedKeypair(provider);
+    const usdc = await createMint(provider);
+    const [pool] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), provider.wallet.publicKey.toBuffer()],
+      swapProgram.programId
+    );
+    const reserve = await usdc.createAccount(pool);
+    await usdc.mintTo(reserve, provider.wallet.publicKey, [], 1_000_000_000);
+    const sellerUsdc = await usdc.createAccount(swapSeller.publicKey);
+
+    // SOL at $100 and USDC at $1, with the pool quoting 5% below the feeds
+    const priceFeed = (flag: number) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("price_feed"), marketplacePda.toBuffer(), Buffer.from([flag])],
+        marketplaceProgram.programId
+      )[0];
+    const [solFeed, usdcFeed] = [priceFeed(1), priceFeed(2)];
+    for (const [currency, feed, usdPrice] of [
+      [{ sol: {} }, solFeed, 100_000_000],
+      [{ usdc: {} }, usdcFeed, 1_000_000],
+    ] as [object, PublicKey, number][]) {
+      await marketplaceProgram.methods
+        .setPriceFeed(currency, new anchor.BN(usdPrice))
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          priceFeed: feed,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await swapProgram.methods
+      .initializePool(new anchor.BN(95_000_000))
+      .accounts({
+        authority: provider.wallet.publicKey,
+        pool,
+        reserve,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    await marketplaceProgram.methods
+      .setPayoutPreference({ usdc: {} }, 100)
+      .accounts({
+        seller: swapSeller.publicKey,
+        marketplace: marketplacePda,
+        sellerStatus: findSellerStatusPda(marketplacePda, swapSeller.publicKey),
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([swapSeller])
+      .rpc();
+    const product = await createProduct(marketplaceProgram, marketplacePda, swapSeller, {
+      price: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 10),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, swapBuyer);
+    await escrowProgram.methods
+      .markAsShipped(null)
+      .accounts({ seller: swapSeller.publicKey, escrow: pdas.escrow })
+      .signers([swapSeller])
+      .rpc();
+
+    const account = (pubkey: PublicKey, isWritable: boolean) => ({ pubkey, isWritable, isSigner: false });
+    const confirm = () =>
+      escrowProgram.methods
+        .confirmDelivery()
+        .accounts({
+          buyer: swapBuyer.publicKey,
+          seller: swapSeller.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, swapBuyer.publicKey),
+          sellerStatus: findSellerStatusPda(marketplacePda, swapSeller.publicKey),
+          feeDestination: feesDestination,
+          sellerReputation: null,
+          buyerReputation: null,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts([
+          account(swapProgram.programId, false),
+          account(solFeed, false),
+          account(usdcFeed, false),
+          account(sellerUsdc, true),
+          account(pool, true),
+          account(reserve, true),
+          account(TOKEN_PROGRAM_ID, false),
+          account(anchor.web3.SystemProgram.programId, false),
+        ])
+        .signers([swapBuyer])
+        .rpc();
+
+    await expectError(confirm(), "InvalidSwapProgram");
+    await marketplaceProgram.methods
+      .setSwapProgram(swapProgram.programId)
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+      .rpc();
+    try {
+      // 0.1 SOL less the 2.5% fee is worth $9.75, and the seller accepts no less than 1% below that
+      await expectError(confirm(), "SlippageExceeded");
+
+      await swapProgram.methods
+        .setRate(new anchor.BN(100_000_000))
+        .accounts({ authority: provider.wallet.publicKey, pool })
+        .rpc();
+      await confirm();
+      expect((await usdc.getAccountInfo(sellerUsdc)).amount.toNumber()).to.equal(9_750_000);
+      expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).status).to.deep.equal({ completed: {} });
+    } finally {
+      await marketplaceProgram.methods
+        .setSwapProgram(PublicKey.default)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    }
+  });
+
+  it('Blocks releases while listings continue when releases are paused', async () => {
+    const pausedSeller = await fundedKeypair(provider);
+    const pausedBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, pausedSeller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, pausedBuyer);
+
+    await marketplaceProgram.methods
+      .setReleasesPaused(true)
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+      .rpc();
+
+    try {
+      await expectError(
+        shipAndConfirm(escrowProgram, pdas, pausedBuyer, pausedSeller),
+        "ReleasesPaused"
+      );
+
+      // Listings are unaffected by the release pause
+      const listing = await createProduct(marketplaceProgram, marketplacePda, pausedSeller);
+      const listingAccount = await marketplaceProgram.account.product.fetch(listing);
+      expect(listingAccount.status).to.deep.equal({ active: {} });
+    } finally {
+      await marketplaceProgram.methods
+        .setReleasesPaused(false)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    }
+
+    // The shipped escrow can be confirmed once releases resume
+    await escrowProgram.methods
+      .confirmDelivery()
+      .accounts({
+        buyer: pausedBuyer.publicKey,
+        seller: pausedSeller.publicKey,
+        marketplace: marketplacePda,
+        escrow: pdas.escrow,
+        escrowVault: pdas.escrowVault,
+        escrowTokenAccount: null,
+        sellerTokenAccount: null,
+        product,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, pausedBuyer.publicKey),
+        sellerStatus: findSellerStatusPda(marketplacePda, pausedSeller.publicKey),
+        feeDestination: feesDestination,
+        sellerReputation: null,
+        buyerReputation: null,
+        ...escrowTrackingAccounts(escrowProgram),
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([pausedBuyer])
+      .rpc();
+  });
+
+  it('Only changes a product currency once its escrows have settled', async () => {
+    const currencySeller = await fundedKeypair(provider);
+    const currencyBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, currencySeller);
+    const changeCurrency = (currency: object, price: number) =>
+      marketplaceProgram.methods
+        .changeCurrency(currency, new anchor.BN(price))
+        .accounts({ seller: currencySeller.publicKey, marketplace: marketplacePda, product })
+        .signers([currencySeller])
+        .rpc();
+
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, currencyBuyer);
+    let productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.activeEscrows.toNumber()).to.equal(1);
+    await expectError(changeCurrency({ usdc: {} }, 50000), "ActiveEscrowsExist");
+
+    await shipAndConfirm(escrowProgram, pdas, currencyBuyer, currencySeller);
+    productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.activeEscrows.toNumber()).to.equal(0);
+
+    // The price is restated in the new currency's base units and held to its limits
+    await marketplaceProgram.methods
+      .setMaxEscrowValues(new anchor.BN(0), new anchor.BN(40000), new anchor.BN(0))
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+      .rpc();
+    try {
+      await expectError(changeCurrency({ usdc: {} }, 50000), "PriceOutsideEscrowLimits");
+    } finally {
+      await marketplaceProgram.methods
+        .setMaxEscrowValues(new anchor.BN(0), new anchor.BN(0), new anchor.BN(0))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    }
+
+    // A one-unit order must reach the minimum, however much stock is listed
+    await marketplaceProgram.methods
+      .setMinEscrowAmounts(new anchor.BN(0), new anchor.BN(60000), new anchor.BN(0))
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+      .rpc();
+    try {
+      await expectError(changeCurrency({ usdc: {} }, 50000), "PriceOutsideEscrowLimits");
+    } finally {
+      await marketplaceProgram.methods
+        .setMinEscrowAmounts(new anchor.BN(0), new anchor.BN(0), new anchor.BN(0))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    }
+
+    await changeCurrency({ usdc: {} }, 50000);
+    productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.currency).to.deep.equal({ usdc: {} });
+    expect(productAccount.price.toNumber()).to.equal(50000);
+    expect(productAccount.referencePrice.toNumber()).to.equal(0);
+  });
+
+  it('Releases scheduled portions until a dispute freezes them', async () => {
+    const scheduleSeller = await fundedKeypair(provider);
+    const scheduleBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, scheduleSeller);
+    const pdas = await createEscrow(escrowProgram, marketplacePda, product, scheduleBuyer);
+
+    // Three equal tranches a few seconds apart
+    const now = Math.floor(Date.now() / 1000);
+    const tranche = new anchor.BN(30000);
+    await escrowProgram.methods
+      .setReleaseSchedule([
+        { releaseAt: new anchor.BN(now + 2), amount: tranche },
+        { releaseAt: new anchor.BN(now + 4), amount: tranche },
+        { releaseAt: new anchor.BN(now + 6), amount: tranche },
+      ])
+      .accounts({
+        buyer: scheduleBuyer.publicKey,
+        seller: scheduleSeller.publicKey,
+        escrow: pdas.escrow,
+      })
+      .signers([scheduleBuyer, scheduleSeller])
+      .rpc();
+    await fundEscrow(escrowProgram, pdas, scheduleBuyer);
+    const { feeDestination, feesBasisPoints } = await escrowProgram.account.escrow.fetch(pdas.escrow);
+
+    const releaseNext = () =>
+      escrowProgram.methods
+        .releaseScheduledPortion()
+        .accounts({
+          seller: scheduleSeller.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          feeDestination,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    const sellerBalanceBefore = await provider.connection.getBalance(scheduleSeller.publicKey);
+    await sleep(2500);
+    await releaseNext();
+    await expectError(releaseNext(), "ScheduledReleaseNotDue");
+    await sleep(2000);
+    await releaseNext();
+
+    // Each tranche pays the marketplace fee on it
+    const trancheFee = Math.floor((30000 * feesBasisPoints) / 10000);
+    let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.scheduledReleasesPaid).to.equal(2);
+    expect(escrow.releasedAmount.toNumber()).to.equal(60000);
+    expect(escrow.feesCollected.toNumber()).to.equal(2 * trancheFee);
+    const sellerBalanceAfter = await provider.connection.getBalance(scheduleSeller.publicKey);
+    expect(sellerBalanceAfter - sellerBalanceBefore).to.equal(60000 - 2 * trancheFee);
+
+    // The buyer disputes before the third tranche falls due
+    await escrowProgram.methods
+      .disputeTransaction("Field preparation stopped", { other: {} })
+      .accounts({ user: scheduleBuyer.publicKey, escrow: pdas.escrow })
+      .signers([scheduleBuyer])
+      .rpc();
+    await sleep(2000);
+    await expectError(releaseNext(), "ScheduledReleasesFrozen");
+
+    escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.scheduledReleasesPaid).to.equal(2);
+  });
+
+  it('Applies the escrow minimum for each currency', async () => {
+    const minimumSeller = await fundedKeypair(provider);
+    const minimumBuyer = await fundedKeypair(provider);
+    const setMinimums = (sol: number, usdc: number, usdt: number) =>
+      marketplaceProgram.methods
+        .setMinEscrowAmounts(new anchor.BN(sol), new anchor.BN(usdc), new anchor.BN(usdt))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    // Roughly $1 in each currency's base units
+    const stablecoinMint = (await createMint(provider)).publicKey;
+    await setMinimums(5000000, 1000000, 1000000);
+    try {
+      const cases = [
+        { currency: { sol: {} }, price: 2500000, mint: null },
+        { currency: { usdc: {} }, price: 500000, mint: stablecoinMint },
+        { currency: { usdt: {} }, price: 500000, mint: stablecoinMint },
+      ];
+      for (const { currency, price, mint } of cases) {
+        const product = await createProduct(marketplaceProgram, marketplacePda, minimumSeller, {
+          currency,
+          price: new anchor.BN(price),
+        });
+
+        // One unit falls short of the minimum, two meet it exactly
+        await expectError(
+          createEscrow(escrowProgram, marketplacePda, product, minimumBuyer, new anchor.BN(1), mint),
+          "EscrowBelowMinimum"
+        );
+        const pdas = await createEscrow(escrowProgram, marketplacePda, product, minimumBuyer, new anchor.BN(2), mint);
+        const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+        expect(escrow.currency).to.deep.equal(currency);
+      }
+    } finally {
+      await setMinimums(0, 0, 0);
+    }
+  });
+
+  it('Caps the escrow value for each currency', async () => {
+    const cappedSeller = await fundedKeypair(provider);
+    const cappedBuyer = await fundedKeypair(provider);
+    const setCaps = (sol: number, usdc: number, usdt: number) =>
+      marketplaceProgram.methods
+        .setMaxEscrowValues(new anchor.BN(sol), new anchor.BN(usdc), new anchor.BN(usdt))
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    const stablecoinMint = (await createMint(provider)).publicKey;
+    await setCaps(5000000, 1000000, 1000000);
+    try {
+      const cases = [
+        { currency: { sol: {} }, price: 2500000, mint: null },
+        { currency: { usdc: {} }, price: 500000, mint: stablecoinMint },
+        { currency: { usdt: {} }, price: 500000, mint: stablecoinMint },
+      ];
+      for (const { currency, price, mint } of cases) {
+        const product = await createProduct(marketplaceProgram, marketplacePda, cappedSeller, {
+          currency,
+          price: new anchor.BN(price),
+        });
+
+        // Three units exceed the cap, two meet it exactly
+        await expectError(
+          createEscrow(escrowProgram, marketplacePda, product, cappedBuyer, new anchor.BN(3), mint),
+          "OrderValueTooHigh"
+        );
+        const pdas = await createEscrow(escrowProgram, marketplacePda, product, cappedBuyer, new anchor.BN(2), mint);
+        const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+        expect(escrow.amount.toNumber()).to.equal(2 * price);
+      }
+    } finally {
+      await setCaps(0, 0, 0);
+    }
+  });
+
+  it('Charges high-volume sellers the fee of their tier', async () => {
+    const veteranSeller = await fundedKeypair(provider);
+    const newSeller = await fundedKeypair(provider);
+    const tierBuyer = await fundedKeypair(provider);
+    const setTiers = (tiers: { minSales: anchor.BN; feesBasisPoints: number }[]) =>
+      marketplaceProgram.methods
+        .setFeeTiers(tiers)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    // The veteran completes a sale before the tiers apply
+    const reputationProgram = anchor.workspace.Reputation as Program<Reputation>;
+    const sellerReputation = await initReputation(reputationProgram, veteranSeller);
+    const firstProduct = await createProduct(marketplaceProgram, marketplacePda, veteranSeller);
+    const firstSale = await createFundedEscrow(escrowProgram, marketplacePda, firstProduct, tierBuyer);
+    await shipAndConfirm(escrowProgram, firstSale, tierBuyer, veteranSeller, sellerReputation);
+
+    await expectError(
+      setTiers([
+        { minSales: new anchor.BN(5), feesBasisPoints: 100 },
+        { minSales: new anchor.BN(1), feesBasisPoints: 200 },
+      ]),
+      "InvalidFeeTiers"
+    );
+    await setTiers([{ minSales: new anchor.BN(1), feesBasisPoints: 100 }]);
+    try {
+      const cases: [Keypair, number][] = [[veteranSeller, 100], [newSeller, 250]];
+      for (const [seller, feesBasisPoints] of cases) {
+        const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+        const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, tierBuyer);
+        const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+        expect(escrow.feesBasisPoints).to.equal(feesBasisPoints);
+      }
+    } finally {
+      await setTiers([]);
+    }
+  });
+
+  it('Releases at the fee and to the destination set when the escrow was created', async () => {
+    const seller = await fundedKeypair(provider);
+    const buyer = await fundedKeypair(provider);
+    const treasury = await fundedKeypair(provider, 1);
+    const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, buyer);
+    const { amount } = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    const updateFees = (feesBasisPoints: number, feeDestination: PublicKey) =>
+      marketplaceProgram.methods
+        .updateFees(feesBasisPoints)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda, feeDestination })
+        .rpc();
+
+    await updateFees(1000, treasury.publicKey);
+    try {
+      const sellerBalance = await provider.connection.getBalance(seller.publicKey);
+      const treasuryBalance = await provider.connection.getBalance(treasury.publicKey);
+      await shipAndConfirm(escrowProgram, pdas, buyer, seller);
+
+      // The original 2.5% went to the original destination
+      const fee = amount.muln(250).divn(10000);
+      expect(await provider.connection.getBalance(seller.publicKey)).to.equal(
+        sellerBalance + amount.sub(fee).toNumber()
+      );
+      expect(await provider.connection.getBalance(treasury.publicKey)).to.equal(treasuryBalance);
+    } finally {
+      await updateFees(250, marketplaceAuthority.publicKey);
+    }
+  });
+
+  it('Rejects token funding until the escrow vault is initialized', async () => {
+    const tokenSeller = await fundedKeypair(provider);
+    const tokenBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, tokenSeller, {
+      currency: { usdc: {} },
+    });
+    const usdcMint = (await createMint(provider)).publicKey;
+    const pdas = await createEscrow(escrowProgram, marketplacePda, product, tokenBuyer, new anchor.BN(1), usdcMint);
+    const fundWithVault = (escrowTokenAccount: PublicKey | null) =>
+      escrowProgram.methods
+        .fundEscrow(null)
+        .accounts({
+          buyer: tokenBuyer.publicKey,
+          escrow: pdas.escrow,
+          marketplace: marketplacePda,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          escrowTokenAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([tokenBuyer])
+        .rpc();
+
+    await expectError(fundWithVault(null), "VaultNotInitialized");
+    await expectError(fundWithVault(Keypair.generate().publicKey), "VaultNotInitialized");
+  });
+
+  it('Rejects a token escrow paid with a mint of different decimals', async () => {
+    const tokenSeller = await fundedKeypair(provider);
+    const tokenBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, tokenSeller, {
+      currency: { usdc: {} },
+    });
+
+    // USDC prices are in 6-decimal base units
+    const nineDecimalMint = (await createMint(provider, 9)).publicKey;
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, tokenBuyer, new anchor.BN(1), nineDecimalMint),
+      "DecimalsMismatch"
+    );
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, tokenBuyer, new anchor.BN(1), null),
+      "MissingTokenAccount"
+    );
+
+    const sixDecimalMint = (await createMint(provider, 6)).publicKey;
+    await createEscrow(escrowProgram, marketplacePda, product, tokenBuyer, new anchor.BN(1), sixDecimalMint);
+  });
+
+  it('Rejects token escrows paid or funded in the wrong mint', async () => {
+    const tokenSeller = await fundedKeypair(provider);
+    const tokenBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, tokenSeller, {
+      currency: { usdc: {} },
+    });
+    const usdc = await createMint(provider);
+    const worthless = await createMint(provider);
+    const setUsdcMint = (mint: PublicKey) =>
+      marketplaceProgram.methods
+        .setCurrencyMint({ usdc: {} }, mint)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    await setUsdcMint(usdc.publicKey);
+    let pdas: { escrow: PublicKey; escrowVault: PublicKey };
+    try {
+      await expectError(
+        createEscrow(escrowProgram, marketplacePda, product, tokenBuyer, new anchor.BN(1), worthless.publicKey),
+        "InvalidMint"
+      );
+      pdas = await createEscrow(escrowProgram, marketplacePda, product, tokenBuyer, new anchor.BN(1), usdc.publicKey);
+    } finally {
+      await setUsdcMint(PublicKey.default);
+    }
+
+    // A buyer holding only the worthless token cannot fund the USDC escrow
+    const escrowTokenAccount = await usdc.createAccount(pdas.escrow);
+    const buyerTokenAccount = await worthless.createAccount(tokenBuyer.publicKey);
+    await worthless.mintTo(buyerTokenAccount, provider.wallet.publicKey, [], 1_000_000_000);
+    await expectError(
+      escrowProgram.methods
+        .fundEscrow(null)
+        .accounts({
+          buyer: tokenBuyer.publicKey,
+          escrow: pdas.escrow,
+          marketplace: marketplacePda,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount,
+          escrowTokenAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([tokenBuyer])
+        .rpc(),
+      "InvalidMint"
+    );
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.mint.toString()).to.equal(usdc.publicKey.toString());
+    expect(escrow.status).to.deep.equal({ created: {} });
+  });
+
+  it('Emits a stats snapshot matching the marketplace counters', async () => {
+    const statsSeller = await fundedKeypair(provider);
+    const statsBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, statsSeller);
+    const before = await marketplaceProgram.account.marketplaceState.fetch(marketplacePda);
+
+    // One sale completes and another stays open
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, statsBuyer);
+    await shipAndConfirm(escrowProgram, pdas, statsBuyer, statsSeller);
+    await createEscrow(escrowProgram, marketplacePda, product, await fundedKeypair(provider));
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+
+    let snapshot = null;
+    const listener = marketplaceProgram.addEventListener('StatsSnapshot', (event) => {
+      if (event.marketplace.equals(marketplacePda)) {
+        snapshot = event;
+      }
+    });
+    await marketplaceProgram.methods
+      .snapshotStats()
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+      .rpc();
+    await sleep(1000);
+    await marketplaceProgram.removeEventListener(listener);
+
+    const after = await marketplaceProgram.account.marketplaceState.fetch(marketplacePda);
+    expect(snapshot).to.not.equal(null);
+    expect(after.activeEscrows.toNumber()).to.equal(before.activeEscrows.toNumber() + 1);
+    expect(after.solVolume.sub(before.solVolume).toString()).to.equal(escrow.amount.toString());
+    expect(snapshot.activeEscrows.toString()).to.equal(after.activeEscrows.toString());
+    expect(snapshot.solVolume.toString()).to.equal(after.solVolume.toString());
+    expect(snapshot.productCount.toString()).to.equal(after.productCount.toString());
+    expect(snapshot.solFeesCollected.toString()).to.equal(after.solFeesCollected.toString());
+    expect(after.lastSnapshotSlot.toString()).to.equal(snapshot.slot.toString());
+  });
+
+  describe('coupons', () => {
+    // Issue a coupon for `code` and return its PDA
+    const createCoupon = async (code: string, discountBps: number, maxUses: number, expiresIn: number) => {
+      const codeHash = createHash('sha256').update(code).digest();
+      const [coupon] = PublicKey.findProgramAddressSync(
+        [Buffer.from("coupon"), marketplacePda.toBuffer(), codeHash],
+        escrowProgram.programId
+      );
+      await escrowProgram.methods
+        .createCoupon(
+          [...codeHash],
+          discountBps,
+          maxUses,
+          new anchor.BN(Math.floor(Date.now() / 1000) + expiresIn)
+        )
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          coupon,
+          mint: null,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+      return coupon;
+    };
+
+    const redeem = async (coupon: PublicKey) => {
+      const couponSeller = await fundedKeypair(provider);
+      const couponBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, couponSeller);
+      const pdas = findEscrowPdas(escrowProgram, marketplacePda, couponBuyer.publicKey, product);
+      await escrowProgram.methods
+        .createEscrow(new anchor.BN(1), null, coupon)
+        .accounts({
+          buyer: couponBuyer.publicKey,
+          marketplace: marketplacePda,
+          product,
+          escrow: pdas.escrow,
+          buyerReputation: null,
+          coupon,
+          mint: null,
+          sellerStatus: findSellerStatusPda(marketplacePda, couponSeller.publicKey),
+          sellerReputation: findUserReputationPda(couponSeller.publicKey),
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, couponBuyer.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([couponBuyer])
+        .rpc();
+      return pdas;
+    };
+
+    it('Applies a valid coupon and uses it up', async () => {
+      const coupon = await createCoupon("HARVEST10", 1000, 2, 3600);
+      const pdas = await redeem(coupon);
+
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.amount.toNumber()).to.equal(90000); // 10% off 100000
+      expect(escrow.discountBps).to.equal(1000);
+      const couponAccount = await escrowProgram.account.coupon.fetch(coupon);
+      expect(couponAccount.remainingUses).to.equal(1);
+    });
+
+    it('Rejects an expired coupon', async () => {
+      const coupon = await createCoupon("FLASHSALE", 2000, 5, 2);
+      await sleep(4000);
+      await expectError(redeem(coupon), "CouponExpired");
+    });
+
+    it('Rejects an exhausted coupon', async () => {
+      const coupon = await createCoupon("FIRSTBUYER", 1500, 1, 3600);
+      await redeem(coupon);
+      await expectError(redeem(coupon), "CouponExhausted");
+    });
+  });
+
+  describe('dispute appeals', () => {
+    let arbitrator: Keypair;
+
+    before(async () => {
+      arbitrator = await fundedKeypair(provider, 1);
+      await marketplaceProgram.methods
+        .setAppealPolicy(new anchor.BN(3), arbitrator.publicKey)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    });
+
+    after(async () => {
+      await marketplaceProgram.methods
+        .setAppealPolicy(new anchor.BN(0), PublicKey.default)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    });
+
+    // Fund and dispute an escrow, then have the authority rule for the seller
+    const sellerFavoredDispute = async () => {
+      const appealSeller = await fundedKeypair(provider);
+      const appealBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, appealSeller);
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, appealBuyer);
+      await escrowProgram.methods
+        .disputeTransaction("Wrong variety delivered", { notAsDescribed: {} })
+        .accounts({ user: appealBuyer.publicKey, escrow: pdas.escrow })
+        .signers([appealBuyer])
+        .rpc();
+
+      const accounts = (authority: PublicKey) => ({
+        authority,
+        marketplace: marketplacePda,
+        buyer: appealBuyer.publicKey,
+        seller: appealSeller.publicKey,
+        escrow: pdas.escrow,
+        escrowVault: pdas.escrowVault,
+        buyerTokenAccount: null,
+        sellerTokenAccount: null,
+        escrowTokenAccount: null,
+        sellerBond: null,
+        product,
+        arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+        loserReputation: null,
+        sellerReputation: null,
+        buyerReputation: null,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, appealBuyer.publicKey),
+        feeDestination: feesDestination,
+        ...escrowTrackingAccounts(escrowProgram),
+        reputationProgram: anchor.workspace.Reputation.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      });
+      await escrowProgram.methods
+        .resolveDispute(true, 0)
+        .accounts(accounts(marketplaceAuthority.publicKey))
+        .rpc();
+
+      return { appealSeller, appealBuyer, pdas, accounts };
+    };
+
+    it('Pays out an unappealed resolution once the window passes', async () => {
+      const { appealSeller, pdas, accounts } = await sellerFavoredDispute();
+      let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ resolutionPending: {} });
+
+      const execute = () =>
+        escrowProgram.methods
+          .executeResolution()
+          .accounts(accounts(provider.wallet.publicKey))
+          .rpc();
+      await expectError(execute(), "AppealWindowOpen");
+
+      await sleep(4000);
+      const sellerBalanceBefore = await provider.connection.getBalance(appealSeller.publicKey);
+      await execute();
+
+      escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ completed: {} });
+      expect(escrow.favoredSeller).to.equal(true);
+      const sellerBalanceAfter = await provider.connection.getBalance(appealSeller.publicKey);
+      const fee = escrow.amount.muln(250).divn(10000);
+      expect(sellerBalanceAfter - sellerBalanceBefore).to.equal(escrow.amount.sub(fee).toNumber());
+    });
+
+    it('Escalates an appealed resolution to the arbitrator', async () => {
+      const { appealSeller, appealBuyer, pdas, accounts } = await sellerFavoredDispute();
+
+      // Only the losing buyer may appeal
+      const appeal = (user: Keypair) =>
+        escrowProgram.methods
+          .appealResolution()
+          .accounts({ user: user.publicKey, escrow: pdas.escrow })
+          .signers([user])
+          .rpc();
+      await expectError(appeal(appealSeller), "NotLosingParty");
+      await appeal(appealBuyer);
+
+      let escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ appealed: {} });
+
+      // The appeal holds the funds past the original window
+      await sleep(4000);
+      await expectError(
+        escrowProgram.methods.executeResolution().accounts(accounts(provider.wallet.publicKey)).rpc(),
+        "InvalidEscrowState"
+      );
+      await expectError(
+        escrowProgram.methods.resolveAppeal(false, 0).accounts(accounts(marketplaceAuthority.publicKey)).rpc(),
+        "UnauthorizedArbitrator"
+      );
+
+      const buyerBalanceBefore = await provider.connection.getBalance(appealBuyer.publicKey);
+      await escrowProgram.methods
+        .resolveAppeal(false, 0)
+        .accounts(accounts(arbitrator.publicKey))
+        .signers([arbitrator])
+        .rpc();
+
+      escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ refunded: {} });
+      expect(escrow.favoredSeller).to.equal(false);
+      const buyerBalanceAfter = await provider.connection.getBalance(appealBuyer.publicKey);
+      expect(buyerBalanceAfter - buyerBalanceBefore).to.equal(escrow.amount.toNumber());
+    });
+  });
+
+  it('Blocks purchases while the seller is on hold', async () => {
+    const holdSeller = await fundedKeypair(provider);
+    const holdBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, holdSeller);
+    const sellerStatus = findSellerStatusPda(marketplacePda, holdSeller.publicKey);
+    const setHold = (onHold: boolean) =>
+      marketplaceProgram.methods
+        .setSellerHold(onHold)
+        .accounts({
+          seller: holdSeller.publicKey,
+          marketplace: marketplacePda,
+          sellerStatus,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([holdSeller])
+        .rpc();
+
+    await setHold(true);
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, holdBuyer),
+      "SellerOnHold"
+    );
+
+    // The listing is untouched and sells again once the hold lifts
+    const productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.status).to.deep.equal({ active: {} });
+
+    await setHold(false);
+    const pdas = await createEscrow(escrowProgram, marketplacePda, product, holdBuyer);
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ created: {} });
+  });
+
+  it('Blocks purchases of products flagged for moderation', async () => {
+    const flaggedSeller = await fundedKeypair(provider);
+    const flaggedBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, flaggedSeller);
+    const [productFlag] = PublicKey.findProgramAddressSync(
+      [Buffer.from("product_flag"), product.toBuffer(), flaggedBuyer.publicKey.toBuffer()],
+      marketplaceProgram.programId
+    );
+
+    await marketplaceProgram.methods
+      .flagProduct("Listed as organic but sprayed")
+      .accounts({
+        flagger: flaggedBuyer.publicKey,
+        product,
+        productFlag,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([flaggedBuyer])
+      .rpc();
+    await marketplaceProgram.methods
+      .resolveFlag({ flag: {} })
+      .accounts({
+        authority: marketplaceAuthority.publicKey,
+        marketplace: marketplacePda,
+        product,
+        productFlag,
+      })
+      .rpc();
+
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, flaggedBuyer),
+      "ProductFlagged"
+    );
+  });
+
+  it('Keeps a flagged product flagged when its last units sell and it is restocked', async () => {
+    const flaggedSeller = await fundedKeypair(provider);
+    const flaggedBuyer = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, flaggedSeller, {
+      quantity: new anchor.BN(1),
+    });
+    const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, flaggedBuyer);
+    const [productFlag] = PublicKey.findProgramAddressSync(
+      [Buffer.from("product_flag"), product.toBuffer(), flaggedBuyer.publicKey.toBuffer()],
+      marketplaceProgram.programId
+    );
+    await marketplaceProgram.methods
+      .flagProduct("Listed as organic but sprayed")
+      .accounts({
+        flagger: flaggedBuyer.publicKey,
+        product,
+        productFlag,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([flaggedBuyer])
+      .rpc();
+    await marketplaceProgram.methods
+      .resolveFlag({ flag: {} })
+      .accounts({
+        authority: marketplaceAuthority.publicKey,
+        marketplace: marketplacePda,
+        product,
+        productFlag,
+      })
+      .rpc();
+
+    // The open order still settles, but selling out doesn't lift the flag
+    await shipAndConfirm(escrowProgram, pdas, flaggedBuyer, flaggedSeller);
+    let productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.quantity.toNumber()).to.equal(0);
+    expect(productAccount.status).to.deep.equal({ flagged: {} });
+
+    // Nor does restocking
+    await marketplaceProgram.methods
+      .updateProduct(null, null, new anchor.BN(5), null, null, null, null, null)
+      .accounts({
+        seller: flaggedSeller.publicKey,
+        product,
+        productFingerprint: await productFingerprintOf(marketplaceProgram, product),
+      })
+      .signers([flaggedSeller])
+      .rpc();
+    productAccount = await marketplaceProgram.account.product.fetch(product);
+    expect(productAccount.status).to.deep.equal({ flagged: {} });
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, await fundedKeypair(provider)),
+      "ProductFlagged"
+    );
+  });
+
+  it('Blocks purchases of listings the marketplace took down', async () => {
+    const takenDownSeller = await fundedKeypair(provider);
+    const flagger = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, takenDownSeller);
+    const [productFlag] = PublicKey.findProgramAddressSync(
+      [Buffer.from("product_flag"), product.toBuffer(), flagger.publicKey.toBuffer()],
+      marketplaceProgram.programId
+    );
+
+    await marketplaceProgram.methods
+      .flagProduct("Counterfeit certification")
+      .accounts({
+        flagger: flagger.publicKey,
+        product,
+        productFlag,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([flagger])
+      .rpc();
+    await marketplaceProgram.methods
+      .resolveFlag({ deactivate: {} })
+      .accounts({
+        authority: marketplaceAuthority.publicKey,
+        marketplace: marketplacePda,
+        product,
+        productFlag,
+      })
+      .rpc();
+
+    await expectError(
+      createEscrow(escrowProgram, marketplacePda, product, await fundedKeypair(provider)),
+      "ProductNotActive"
+    );
+  });
+
+  it('Requires a source of funds attestation when the marketplace asks for one', async () => {
+    const attestationSeller = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, attestationSeller);
+    const attestation = [...createHash('sha256').update('kyc-bundle').digest()];
+    const setRequired = (required: boolean) =>
+      marketplaceProgram.methods
+        .setRequireSourceAttestation(required)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    // Without the requirement, attestation is optional
+    const optionalBuyer = await fundedKeypair(provider);
+    const optionalPdas = await createEscrow(escrowProgram, marketplacePda, product, optionalBuyer);
+    await fundEscrow(escrowProgram, optionalPdas, optionalBuyer);
+    let escrow = await escrowProgram.account.escrow.fetch(optionalPdas.escrow);
+    expect(escrow.sourceAttestationHash).to.deep.equal(Array(32).fill(0));
+
+    await setRequired(true);
+    try {
+      const attestedBuyer = await fundedKeypair(provider);
+      const pdas = await createEscrow(escrowProgram, marketplacePda, product, attestedBuyer);
+      await expectError(
+        fundEscrow(escrowProgram, pdas, attestedBuyer),
+        "SourceAttestationRequired"
+      );
+      await fundEscrow(escrowProgram, pdas, attestedBuyer, attestation);
+
+      escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ funded: {} });
+      expect(escrow.sourceAttestationHash).to.deep.equal(attestation);
+    } finally {
+      await setRequired(false);
+    }
+  });
+
+  describe('arbitrator routing', () => {
+    const logisticsArbitrator = Keypair.generate();
+    const arbitratorRouting = findArbitratorRoutingPda(marketplacePda);
+    const setDamagedPool = (arbitrators: PublicKey[]) =>
+      escrowProgram.methods
+        .setArbitratorPool({ damaged: {} }, arbitrators)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          arbitratorRouting,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    before(async () => {
+      await setDamagedPool([logisticsArbitrator.publicKey]);
+    });
+
+    after(async () => {
+      await setDamagedPool([]);
+    });
+
+    const disputedEscrow = async (disputeReason: object) => {
+      const routedSeller = await fundedKeypair(provider);
+      const routedBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, routedSeller);
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, routedBuyer);
+      await escrowProgram.methods
+        .disputeTransaction("Crates crushed in transit", disputeReason)
+        .accounts({ user: routedBuyer.publicKey, escrow: pdas.escrow })
+        .signers([routedBuyer])
+        .rpc();
+
+      return (authority: Keypair | null) => {
+        const method = escrowProgram.methods
+          .resolveDispute(false, 0)
+          .accounts({
+            authority: authority ? authority.publicKey : marketplaceAuthority.publicKey,
+            marketplace: marketplacePda,
+            buyer: routedBuyer.publicKey,
+            seller: routedSeller.publicKey,
+            escrow: pdas.escrow,
+            escrowVault: pdas.escrowVault,
+            buyerTokenAccount: null,
+            sellerTokenAccount: null,
+            escrowTokenAccount: null,
+            sellerBond: null,
+            product,
+            arbitratorRouting,
+            loserReputation: null,
+            sellerReputation: null,
+            buyerReputation: null,
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, routedBuyer.publicKey),
+            feeDestination: feesDestination,
+            ...escrowTrackingAccounts(escrowProgram),
+            reputationProgram: anchor.workspace.Reputation.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          });
+        return authority ? method.signers([authority]).rpc() : method.rpc();
+      };
+    };
+
+    it('Lets only the assigned pool resolve disputes for a routed reason', async () => {
+      const resolve = await disputedEscrow({ damaged: {} });
+      await expectError(resolve(null), "ArbitratorNotInPool");
+      await resolve(logisticsArbitrator);
+    });
+
+    it('Rejects an arbitrator for the wrong dispute reason', async () => {
+      const resolve = await disputedEscrow({ notReceived: {} });
+      await expectError(resolve(logisticsArbitrator), "UnauthorizedAuthority");
+      await resolve(null);
+    });
+
+    it('Lets the marketplace arbiter resolve disputes outside the pools', async () => {
+      const arbiter = Keypair.generate();
+      const setArbiter = (key: PublicKey) =>
+        marketplaceProgram.methods
+          .setArbiter(key)
+          .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+          .rpc();
+
+      await setArbiter(arbiter.publicKey);
+      try {
+        const resolve = await disputedEscrow({ notReceived: {} });
+        await expectError(resolve(Keypair.generate()), "UnauthorizedAuthority");
+        await resolve(arbiter);
+      } finally {
+        await setArbiter(PublicKey.default);
+      }
+    });
+  });
+
+  it('Releases an escrow only once its condition oracle reports true', async () => {
+    const oracleSeller = await fundedKeypair(provider);
+    const oracleBuyer = await fundedKeypair(provider);
+    const oracleAuthority = await fundedKeypair(provider);
+    const product = await createProduct(marketplaceProgram, marketplacePda, oracleSeller);
+    const pdas = await createEscrow(escrowProgram, marketplacePda, product, oracleBuyer, new anchor.BN(2));
+
+    // A mocked delivery-scan oracle, starting out false
+    const conditionId = [...createHash('sha256').update('shipment-0001').digest()];
+    const [oracle] = PublicKey.findProgramAddressSync(
+      [Buffer.from("condition_oracle"), oracleAuthority.publicKey.toBuffer(), Buffer.from(conditionId)],
+      escrowProgram.programId
+    );
+    await escrowProgram.methods
+      .initializeConditionOracle(conditionId)
+      .accounts({
+        authority: oracleAuthority.publicKey,
+        oracle,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([oracleAuthority])
+      .rpc();
+
+    await escrowProgram.methods
+      .setReleaseCondition(oracle)
+      .accounts({ buyer: oracleBuyer.publicKey, seller: oracleSeller.publicKey, escrow: pdas.escrow })
+      .signers([oracleBuyer, oracleSeller])
+      .rpc();
+    await fundEscrow(escrowProgram, pdas, oracleBuyer);
+    const { feeDestination } = await escrowProgram.account.escrow.fetch(pdas.escrow);
+
+    const release = () =>
+      escrowProgram.methods
+        .oracleRelease()
+        .accounts({
+          payer: provider.wallet.publicKey,
+          seller: oracleSeller.publicKey,
+          buyer: oracleBuyer.publicKey,
+          marketplace: marketplacePda,
+          escrow: pdas.escrow,
+          oracle,
+          escrowVault: pdas.escrowVault,
+          escrowTokenAccount: null,
+          sellerTokenAccount: null,
+          feeDestination,
+          product,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, oracleBuyer.publicKey),
+          sellerReputation: null,
+          buyerReputation: null,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: anchor.workspace.Reputation.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    await expectError(release(), "ReleaseConditionNotMet");
+
+    await escrowProgram.methods
+      .reportCondition(true)
+      .accounts({ authority: oracleAuthority.publicKey, oracle })
+      .signers([oracleAuthority])
+      .rpc();
+
+    // One unit the seller will not ship is refunded first
+    await escrowProgram.methods
+      .refundUnshipped(new anchor.BN(1))
+      .accounts({
+        seller: oracleSeller.publicKey,
+        buyer: oracleBuyer.publicKey,
+        marketplace: marketplacePda,
+        escrow: pdas.escrow,
+        escrowVault: pdas.escrowVault,
+        escrowTokenAccount: null,
+        buyerTokenAccount: null,
+        product,
+        buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, oracleBuyer.publicKey),
+        ...escrowTrackingAccounts(escrowProgram),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([oracleSeller])
+      .rpc();
+    const sellerBalanceBefore = await provider.connection.getBalance(oracleSeller.publicKey);
+    const volumeBefore = (await marketplaceProgram.account.marketplaceState.fetch(marketplacePda)).solVolume;
+    await release();
+
+    // The seller receives what was not refunded, less the marketplace fee
+    const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+    expect(escrow.status).to.deep.equal({ partiallyCompleted: {} });
+    expect(escrow.confirmedQuantity.toNumber()).to.equal(1);
+    const released = escrow.amount.sub(escrow.refundedAmount);
+    expect(escrow.refundedAmount.toNumber()).to.be.greaterThan(0);
+    expect(escrow.feesCollected.toNumber()).to.be.greaterThan(0);
+    const sellerBalanceAfter = await provider.connection.getBalance(oracleSeller.publicKey);
+    expect(sellerBalanceAfter - sellerBalanceBefore).to.equal(
+      released.toNumber() - escrow.feesCollected.toNumber()
+    );
+
+    // Only the unrefunded amount counts toward the marketplace's volume
+    const volumeAfter = (await marketplaceProgram.account.marketplaceState.fetch(marketplacePda)).solVolume;
+    expect(volumeAfter.sub(volumeBefore).toString()).to.equal(released.toString());
+  });
+
+  describe('auctions', () => {
+    const findAuctionPda = (product: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("auction"), product.toBuffer()],
+        escrowProgram.programId
+      )[0];
+
+    const createAuction = async (auctionSeller: Keypair, product: PublicKey, endTime: number) => {
+      const auction = findAuctionPda(product);
+      await escrowProgram.methods
+        .createAuction(new anchor.BN(1), new anchor.BN(100000), new anchor.BN(endTime))
+        .accounts({
+          seller: auctionSeller.publicKey,
+          marketplace: marketplacePda,
+          product,
+          auction,
+          sellerStatus: findSellerStatusPda(marketplacePda, auctionSeller.publicKey),
+          ...escrowTrackingAccounts(escrowProgram),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([auctionSeller])
+        .rpc();
+      return auction;
+    };
+
+    const placeBid = (auction: PublicKey, bidder: Keypair, amount: number, previousBidder: PublicKey | null) =>
+      escrowProgram.methods
+        .placeBid(new anchor.BN(amount))
+        .accounts({
+          bidder: bidder.publicKey,
+          marketplace: marketplacePda,
+          auction,
+          previousBidder,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bidder])
+        .rpc();
+
+    const now = () => Math.floor(Date.now() / 1000);
+
+    it('Refunds the outbid bidder and holds only the highest bid', async () => {
+      const auctionSeller = await fundedKeypair(provider);
+      const alice = await fundedKeypair(provider);
+      const bob = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, auctionSeller, {
+        price: new anchor.BN(1000000),
+        quantity: new anchor.BN(1),
+      });
+      const auction = await createAuction(auctionSeller, product, now() + 60);
+      const productAccount = await marketplaceProgram.account.product.fetch(product);
+      expect(productAccount.reservedQuantity.toNumber()).to.equal(1);
+
+      await expectError(placeBid(auction, alice, 900000, null), "BidTooLow");
+      await placeBid(auction, alice, 1000000, null);
+      const auctionRent = (await provider.connection.getBalance(auction)) - 1000000;
+
+      // Bob has to beat Alice by the minimum increment
+      await expectError(placeBid(auction, bob, 1050000, alice.publicKey), "BidTooLow");
+      const aliceBefore = await provider.connection.getBalance(alice.publicKey);
+      await placeBid(auction, bob, 1100000, alice.publicKey);
+
+      const aliceAfter = await provider.connection.getBalance(alice.publicKey);
+      expect(aliceAfter - aliceBefore).to.equal(1000000);
+      expect(await provider.connection.getBalance(auction)).to.equal(auctionRent + 1100000);
+      const auctionAccount = await escrowProgram.account.auction.fetch(auction);
+      expect(auctionAccount.highestBid.toNumber()).to.equal(1100000);
+      expect(auctionAccount.highestBidder.toString()).to.equal(bob.publicKey.toString());
+
+      await expectError(placeBid(auction, auctionSeller, 1200000, bob.publicKey), "SellerCannotBid");
+      await expectError(placeBid(auction, alice, 1200000, null), "InvalidPreviousBidder");
+    });
+
+    it('Settles an ended auction into a funded escrow for the winner', async () => {
+      const auctionSeller = await fundedKeypair(provider);
+      const winner = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, auctionSeller, {
+        price: new anchor.BN(1000000),
+        quantity: new anchor.BN(2),
+      });
+
+      // The winner already holds an escrow for the product
+      const earlier = await createEscrow(escrowProgram, marketplacePda, product, winner);
+      const auction = await createAuction(auctionSeller, product, now() + 2);
+      const pdas = findEscrowPdas(escrowProgram, marketplacePda, winner.publicKey, product, 1);
+      expect(pdas.escrow.toString()).to.not.equal(earlier.escrow.toString());
+      const settle = () =>
+        escrowProgram.methods
+          .settleAuction()
+          .accounts({
+            payer: provider.wallet.publicKey,
+            marketplace: marketplacePda,
+            product,
+            auction,
+            seller: auctionSeller.publicKey,
+            winner: winner.publicKey,
+            escrow: pdas.escrow,
+            escrowVault: pdas.escrowVault,
+            sellerReputation: findUserReputationPda(auctionSeller.publicKey),
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, winner.publicKey),
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+
+      await placeBid(auction, winner, 1500000, null);
+      await expectError(settle(), "AuctionNotEnded");
+
+      await sleep(4000);
+      await expectError(placeBid(auction, auctionSeller, 1600000, winner.publicKey), "AuctionEnded");
+      const sellerBefore = await provider.connection.getBalance(auctionSeller.publicKey);
+      const auctionRent = (await provider.connection.getBalance(auction)) - 1500000;
+      await settle();
+
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ funded: {} });
+      expect(escrow.buyer.toString()).to.equal(winner.publicKey.toString());
+      expect(escrow.seller.toString()).to.equal(auctionSeller.publicKey.toString());
+      expect(escrow.amount.toNumber()).to.equal(1500000);
+      expect(escrow.fundedAmount.toNumber()).to.equal(1500000);
+      expect(await provider.connection.getBalance(pdas.escrowVault)).to.equal(1500000);
+      expect(await provider.connection.getAccountInfo(auction)).to.be.null;
+      const sellerAfter = await provider.connection.getBalance(auctionSeller.publicKey);
+      expect(sellerAfter - sellerBefore).to.equal(auctionRent);
+
+      // The escrow keeps the unit the auction reserved and settles as usual
+      const productAccount = await marketplaceProgram.account.product.fetch(product);
+      expect(productAccount.reservedQuantity.toNumber()).to.equal(2);
+      await shipAndConfirm(escrowProgram, pdas, winner, auctionSeller);
+      expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).status).to.deep.equal({ completed: {} });
+    });
+
+    it('Rejects bids while the marketplace is paused', async () => {
+      const auctionSeller = await fundedKeypair(provider);
+      const bidder = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, auctionSeller, {
+        price: new anchor.BN(1000000),
+        quantity: new anchor.BN(1),
+      });
+      const auction = await createAuction(auctionSeller, product, now() + 60);
+      const setPaused = (paused: boolean) =>
+        (paused ? marketplaceProgram.methods.pauseMarketplace() : marketplaceProgram.methods.unpauseMarketplace())
+          .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+          .rpc();
+
+      await setPaused(true);
+      try {
+        await expectError(placeBid(auction, bidder, 1000000, null), "MarketplacePaused");
+      } finally {
+        await setPaused(false);
+      }
+      await placeBid(auction, bidder, 1000000, null);
+      expect((await escrowProgram.account.auction.fetch(auction)).highestBidder.toString())
+        .to.equal(bidder.publicKey.toString());
+    });
+
+    it('Lets the seller withdraw an auction nobody bid on', async () => {
+      const auctionSeller = await fundedKeypair(provider);
+      const bidder = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, auctionSeller, {
+        price: new anchor.BN(1000000),
+        quantity: new anchor.BN(1),
+      });
+      const auction = await createAuction(auctionSeller, product, now() + 60);
+      const cancel = () =>
+        escrowProgram.methods
+          .cancelAuction()
+          .accounts({
+            seller: auctionSeller.publicKey,
+            marketplace: marketplacePda,
+            product,
+            auction,
+            ...escrowTrackingAccounts(escrowProgram),
+          })
+          .signers([auctionSeller])
+          .rpc();
+
+      await cancel();
+      expect(await provider.connection.getAccountInfo(auction)).to.be.null;
+      const productAccount = await marketplaceProgram.account.product.fetch(product);
+      expect(productAccount.reservedQuantity.toNumber()).to.equal(0);
+
+      const relisted = await createAuction(auctionSeller, product, now() + 60);
+      await placeBid(relisted, bidder, 1000000, null);
+      await expectError(cancel(), "AuctionHasBids");
+    });
+
+    it('Will not auction the listings of a seller on hold', async () => {
+      const auctionSeller = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, auctionSeller, {
+        price: new anchor.BN(1000000),
+        quantity: new anchor.BN(1),
+      });
+
+      await setSellerHold(marketplaceProgram, marketplacePda, auctionSeller, true);
+      await expectError(createAuction(auctionSeller, product, now() + 60), "SellerOnHold");
+
+      await setSellerHold(marketplaceProgram, marketplacePda, auctionSeller, false);
+      const auction = await createAuction(auctionSeller, product, now() + 60);
+      const auctionAccount = await escrowProgram.account.auction.fetch(auction);
+      expect(auctionAccount.seller.toString()).to.equal(auctionSeller.publicKey.toString());
+    });
+  });
+
+  describe('offers', () => {
+    const findOfferPda = (product: PublicKey, buyer: PublicKey, offerId: anchor.BN) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("offer"), product.toBuffer(), buyer.toBuffer(), offerId.toArrayLike(Buffer, "le", 8)],
+        escrowProgram.programId
+      )[0];
+
+    const listingTakingOffers = async (offerSeller: Keypair) => {
+      const product = await createProduct(marketplaceProgram, marketplacePda, offerSeller, {
+        price: new anchor.BN(1000000),
+      });
+      await marketplaceProgram.methods
+        .setOfferWindow(new anchor.BN(60))
+        .accounts({ seller: offerSeller.publicKey, product, productFingerprint: await productFingerprintOf(marketplaceProgram, product) })
+        .signers([offerSeller])
+        .rpc();
+      return product;
+    };
+
+    const makeOffer = async (product: PublicKey, offerBuyer: Keypair, offerId: number, price: number, quantity: number) => {
+      const offer = findOfferPda(product, offerBuyer.publicKey, new anchor.BN(offerId));
+      await escrowProgram.methods
+        .makeOffer(new anchor.BN(offerId), new anchor.BN(price), new anchor.BN(quantity))
+        .accounts({
+          buyer: offerBuyer.publicKey,
+          marketplace: marketplacePda,
+          product,
+          offer,
+          buyerReputation: null,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([offerBuyer])
+        .rpc();
+      return offer;
+    };
+
+    const acceptOffer = (offerSeller: Keypair, product: PublicKey, offer: PublicKey, offerBuyer: PublicKey) =>
+      escrowProgram.methods
+        .acceptOffer()
+        .accounts({
+          seller: offerSeller.publicKey,
+          marketplace: marketplacePda,
+          product,
+          offer,
+          buyer: offerBuyer,
+          escrow: findEscrowPdas(escrowProgram, marketplacePda, offerBuyer, product).escrow,
+          mint: null,
+          sellerStatus: findSellerStatusPda(marketplacePda, offerSeller.publicKey),
+          sellerReputation: findUserReputationPda(offerSeller.publicKey),
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, offerBuyer),
+          ...escrowTrackingAccounts(escrowProgram),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([offerSeller])
+        .rpc();
+
+    it('Creates an escrow at the negotiated price when the seller accepts an offer', async () => {
+      const offerSeller = await fundedKeypair(provider);
+      const offerBuyer = await fundedKeypair(provider);
+      const product = await listingTakingOffers(offerSeller);
+
+      await expectError(makeOffer(product, offerBuyer, 0, 1000000, 2), "InvalidOfferPrice");
+      const offer = await makeOffer(product, offerBuyer, 1, 800000, 2);
+      const offerAccount = await escrowProgram.account.offer.fetch(offer);
+      expect(offerAccount.status).to.deep.equal({ pending: {} });
+      expect(offerAccount.expiresAt.toNumber() - offerAccount.createdAt.toNumber()).to.equal(60);
+
+      // Only the seller may accept
+      await expectError(acceptOffer(offerBuyer, product, offer, offerBuyer.publicKey), "UnauthorizedSeller");
+      await acceptOffer(offerSeller, product, offer, offerBuyer.publicKey);
+
+      const pdas = findEscrowPdas(escrowProgram, marketplacePda, offerBuyer.publicKey, product);
+      const escrow = await escrowProgram.account.escrow.fetch(pdas.escrow);
+      expect(escrow.status).to.deep.equal({ created: {} });
+      expect(escrow.buyer.toString()).to.equal(offerBuyer.publicKey.toString());
+      expect(escrow.quantity.toNumber()).to.equal(2);
+      expect(escrow.amount.toNumber()).to.equal(1600000);
+      expect((await escrowProgram.account.offer.fetch(offer)).status).to.deep.equal({ accepted: {} });
+      const productAccount = await marketplaceProgram.account.product.fetch(product);
+      expect(productAccount.reservedQuantity.toNumber()).to.equal(2);
+
+      // The buyer pays the negotiated amount and the order settles as usual
+      await fundEscrow(escrowProgram, pdas, offerBuyer);
+      expect(await provider.connection.getBalance(pdas.escrowVault)).to.equal(1600000);
+      await shipAndConfirm(escrowProgram, pdas, offerBuyer, offerSeller);
+      expect((await escrowProgram.account.escrow.fetch(pdas.escrow)).status).to.deep.equal({ completed: {} });
+    });
+
+    it('Cannot accept an offer after rejecting it', async () => {
+      const offerSeller = await fundedKeypair(provider);
+      const offerBuyer = await fundedKeypair(provider);
+      const product = await listingTakingOffers(offerSeller);
+      const offer = await makeOffer(product, offerBuyer, 1, 900000, 1);
+
+      await escrowProgram.methods
+        .rejectOffer()
+        .accounts({ seller: offerSeller.publicKey, offer })
+        .signers([offerSeller])
+        .rpc();
+      expect((await escrowProgram.account.offer.fetch(offer)).status).to.deep.equal({ rejected: {} });
+
+      await expectError(acceptOffer(offerSeller, product, offer, offerBuyer.publicKey), "OfferNotPending");
+    });
+
+    it('Cannot accept an offer while the seller is on hold', async () => {
+      const offerSeller = await fundedKeypair(provider);
+      const offerBuyer = await fundedKeypair(provider);
+      const product = await listingTakingOffers(offerSeller);
+      const offer = await makeOffer(product, offerBuyer, 1, 900000, 1);
+
+      await setSellerHold(marketplaceProgram, marketplacePda, offerSeller, true);
+      await expectError(acceptOffer(offerSeller, product, offer, offerBuyer.publicKey), "SellerOnHold");
+      expect((await escrowProgram.account.offer.fetch(offer)).status).to.deep.equal({ pending: {} });
+
+      // The offer stays open and can be taken once the hold lifts
+      await setSellerHold(marketplaceProgram, marketplacePda, offerSeller, false);
+      await acceptOffer(offerSeller, product, offer, offerBuyer.publicKey);
+      expect((await escrowProgram.account.offer.fetch(offer)).status).to.deep.equal({ accepted: {} });
+    });
+
+    it('Expires offers after the seller\'s offer window', async () => {
+      const offerSeller = await fundedKeypair(provider);
+      const offerBuyer = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, offerSeller, {
+        price: new anchor.BN(1000000),
+      });
+      await expectError(makeOffer(product, offerBuyer, 1, 900000, 1), "OffersNotAccepted");
+
+      await marketplaceProgram.methods
+        .setOfferWindow(new anchor.BN(2))
+        .accounts({ seller: offerSeller.publicKey, product, productFingerprint: await productFingerprintOf(marketplaceProgram, product) })
+        .signers([offerSeller])
+        .rpc();
+      const offer = await makeOffer(product, offerBuyer, 1, 900000, 1);
+
+      await sleep(4000);
+      await expectError(acceptOffer(offerSeller, product, offer, offerBuyer.publicKey), "OfferExpired");
+    });
+  });
+});
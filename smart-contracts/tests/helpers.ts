@@ -0,0 +1,613 @@
+import * as anchor from '@project-serum/anchor';
+import { Program } from '@project-serum/anchor';
+import { PublicKey, Keypair } from '@solana/web3.js';
+import { TOKEN_PROGRAM_ID, Token } from '@solana/spl-token';
+import { createHash } from 'crypto';
+import { Marketplace } from '../target/types/marketplace';
+import { Escrow } from '../target/types/escrow';
+import { Reputation } from '../target/types/reputation';
+
+// Shared helpers for tests that need a full product -> escrow lifecycle
+
+export const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
+
+export async function airdrop(
+  provider: anchor.AnchorProvider,
+  recipient: PublicKey,
+  sol = 2
+) {
+  const signature = await provider.connection.requestAirdrop(
+    recipient,
+    sol * anchor.web3.LAMPORTS_PER_SOL
+  );
+  await provider.connection.confirmTransaction(signature);
+}
+
+export async function fundedKeypair(provider: anchor.AnchorProvider, sol = 2) {
+  const keypair = Keypair.generate();
+  await airdrop(provider, keypair.publicKey, sol);
+  return keypair;
+}
+
+export function findMarketplacePda(program: Program<Marketplace>, authority: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("marketplace"), authority.toBuffer()],
+    program.programId
+  )[0];
+}
+
+/// PDAs of a buyer's escrow, numbered `nonce` among the escrows they created
+export function findEscrowPdas(
+  program: Program<Escrow>,
+  marketplace: PublicKey,
+  buyer: PublicKey,
+  product: PublicKey,
+  nonce = 0
+) {
+  const [escrow] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("escrow"),
+      marketplace.toBuffer(),
+      buyer.toBuffer(),
+      product.toBuffer(),
+      new anchor.BN(nonce).toArrayLike(Buffer, "le", 8),
+    ],
+    program.programId
+  );
+  const [escrowVault] = PublicKey.findProgramAddressSync(
+    [Buffer.from("escrow_vault"), escrow.toBuffer()],
+    program.programId
+  );
+  return { escrow, escrowVault };
+}
+
+/// PDAs of the next escrow the buyer creates
+export async function nextEscrowPdas(
+  program: Program<Escrow>,
+  marketplace: PublicKey,
+  buyer: PublicKey,
+  product: PublicKey
+) {
+  const counter = await program.account.buyerEscrowCounter.fetchNullable(
+    findBuyerEscrowCounterPda(marketplace, buyer)
+  );
+  const nonce = counter ? counter.escrowsCreated.toNumber() : 0;
+  return findEscrowPdas(program, marketplace, buyer, product, nonce);
+}
+
+export function findSellerStatusPda(marketplace: PublicKey, seller: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("seller_status"), marketplace.toBuffer(), seller.toBuffer()],
+    (anchor.workspace.Marketplace as Program<Marketplace>).programId
+  )[0];
+}
+
+/// Variants of the program's `Category` enum, in declaration order
+const CATEGORIES = ["grains", "produce", "livestock", "seeds", "equipment", "other"];
+
+/// Mirrors the program's `product_fingerprint`: the title is lowercased with
+/// whitespace collapsed, then hashed with the category and seller
+export function productFingerprint(title: string, category: object, seller: PublicKey) {
+  const normalize = (text: string) => text.trim().split(/\s+/).join(" ").toLowerCase();
+  return createHash('sha256')
+    .update(Buffer.from(normalize(title)))
+    .update(Buffer.from([0]))
+    .update(Buffer.from([CATEGORIES.indexOf(Object.keys(category)[0])]))
+    .update(Buffer.from([0]))
+    .update(seller.toBuffer())
+    .digest();
+}
+
+export function findProductFingerprintPda(
+  marketplace: PublicKey,
+  seller: PublicKey,
+  title: string,
+  category: object
+) {
+  return PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("product_fingerprint"),
+      marketplace.toBuffer(),
+      productFingerprint(title, category, seller),
+    ],
+    (anchor.workspace.Marketplace as Program<Marketplace>).programId
+  )[0];
+}
+
+/// Put a seller on hold on the marketplace, or lift the hold
+export async function setSellerHold(
+  program: Program<Marketplace>,
+  marketplace: PublicKey,
+  seller: Keypair,
+  onHold: boolean
+) {
+  await program.methods
+    .setSellerHold(onHold)
+    .accounts({
+      seller: seller.publicKey,
+      marketplace,
+      sellerStatus: findSellerStatusPda(marketplace, seller.publicKey),
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([seller])
+    .rpc();
+}
+
+export function findUserReputationPda(user: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("user_reputation"), user.toBuffer()],
+    (anchor.workspace.Reputation as Program<Reputation>).programId
+  )[0];
+}
+
+/// PDA naming the marketplace trusted to verify users and record transactions
+export function findReputationConfigPda() {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("reputation_config")],
+    (anchor.workspace.Reputation as Program<Reputation>).programId
+  )[0];
+}
+
+/// Trust a marketplace with reputations. The first suite to run creates the
+/// reputation config with the provider wallet as its admin.
+export async function setReputationMarketplace(program: Program<Reputation>, marketplace: PublicKey) {
+  const reputationConfig = findReputationConfigPda();
+  if (!(await program.account.reputationConfig.fetchNullable(reputationConfig))) {
+    await program.methods
+      .initializeReputationConfig()
+      .accounts({
+        admin: program.provider.publicKey,
+        marketplace,
+        reputationConfig,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+    return;
+  }
+  await program.methods
+    .setReputationMarketplace()
+    .accounts({ admin: program.provider.publicKey, marketplace, reputationConfig })
+    .rpc();
+}
+
+export function findArbitratorRoutingPda(marketplace: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("arbitrator_routing"), marketplace.toBuffer()],
+    (anchor.workspace.Escrow as Program<Escrow>).programId
+  )[0];
+}
+
+export function findBuyerEscrowCounterPda(marketplace: PublicKey, buyer: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("buyer_escrows"), marketplace.toBuffer(), buyer.toBuffer()],
+    (anchor.workspace.Escrow as Program<Escrow>).programId
+  )[0];
+}
+
+/// Accounts the escrow program needs to report open escrows to the marketplace
+export function escrowTrackingAccounts(program: Program<Escrow>) {
+  const [escrowAuthority] = PublicKey.findProgramAddressSync(
+    [Buffer.from("escrow_authority")],
+    program.programId
+  );
+  return {
+    escrowAuthority,
+    marketplaceProgram: (anchor.workspace.Marketplace as Program<Marketplace>).programId,
+  };
+}
+
+export function findFeaturedRegistryPda(marketplace: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("featured_registry"), marketplace.toBuffer()],
+    (anchor.workspace.Marketplace as Program<Marketplace>).programId
+  )[0];
+}
+
+export const SWITCHBOARD_PROGRAM_ID = new PublicKey("SW1TCH7qEPTdLsDHRgPuMQjbQxKdH2aBStViMFnt64f");
+
+/// Account data of a Switchboard VRF account at round `counter`, for
+/// loading into a bankrun bank. Everything past the header is filled with
+/// `resultByte`, so the round's result reads as 32 copies of it.
+export function mockVrfAccount(counter: number, resultByte: number) {
+  const data = Buffer.alloc(32 * 1024, resultByte);
+  createHash("sha256").update("account:VrfAccountData").digest().copy(data, 0, 0, 8);
+  // status: StatusCallbackSuccess
+  data.writeUInt8(3, 8);
+  new anchor.BN(counter).toArrayLike(Buffer, "le", 16).copy(data, 9);
+  // authority, oracle queue and escrow
+  data.fill(0, 25, 121);
+  return {
+    lamports: anchor.web3.LAMPORTS_PER_SOL,
+    data,
+    owner: SWITCHBOARD_PROGRAM_ID,
+    executable: false,
+  };
+}
+
+/// Account the marketplace collects its SOL fees in
+export async function findSolFeeDestination(marketplace: PublicKey) {
+  const state = await (anchor.workspace.Marketplace as Program<Marketplace>).account.marketplaceState.fetch(
+    marketplace
+  );
+  return state.solFeeDestination.equals(PublicKey.default) ? state.feeDestination : state.solFeeDestination;
+}
+
+export interface ProductOptions {
+  title?: string;
+  description?: string;
+  price?: anchor.BN;
+  quantity?: anchor.BN;
+  currency?: object | null;
+  metadataUri?: string;
+  category?: object;
+  returnWindowSeconds?: anchor.BN;
+  availableFrom?: anchor.BN | null;
+  availableUntil?: anchor.BN | null;
+  maxBuyerLostDisputes?: anchor.BN | null;
+  metadataHash?: number[];
+  priceFeed?: PublicKey | null;
+  handlingTimeSeconds?: anchor.BN;
+  tags?: string[];
+  minOrderQuantity?: anchor.BN | null;
+}
+
+/// Create a product listing and return its PDA
+export async function createProduct(
+  program: Program<Marketplace>,
+  marketplace: PublicKey,
+  seller: Keypair,
+  options: ProductOptions = {}
+) {
+  const marketplaceAccount = await program.account.marketplaceState.fetch(marketplace);
+  const [product] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("product"),
+      marketplace.toBuffer(),
+      seller.publicKey.toBuffer(),
+      marketplaceAccount.productCount.toArrayLike(Buffer, "le", 8),
+    ],
+    program.programId
+  );
+  // Distinct default titles keep a seller's listings from sharing a fingerprint
+  const title = options.title ?? `Test Produce ${marketplaceAccount.productCount}`;
+  const category = options.category ?? { produce: {} };
+  const productFingerprint = findProductFingerprintPda(marketplace, seller.publicKey, title, category);
+  const claim = await program.account.productFingerprint.fetchNullable(productFingerprint);
+
+  await program.methods
+    .createProduct(
+      title,
+      options.description ?? "Produce listed by the test suite",
+      options.price ?? new anchor.BN(100000),
+      options.quantity ?? new anchor.BN(10),
+      // null leaves the currency to the marketplace default
+      options.currency === undefined ? { sol: {} } : options.currency,
+      options.metadataUri ?? "https://arweave.net/test",
+      category,
+      options.returnWindowSeconds ?? new anchor.BN(0),
+      options.availableFrom ?? null,
+      options.availableUntil ?? null,
+      options.maxBuyerLostDisputes ?? null,
+      options.metadataHash ?? Array(32).fill(0),
+      options.handlingTimeSeconds ?? new anchor.BN(0),
+      options.tags ?? [],
+      options.minOrderQuantity ?? null
+    )
+    .accounts({
+      seller: seller.publicKey,
+      marketplace,
+      product,
+      priceFeed: options.priceFeed ?? null,
+      productFingerprint,
+      // A released claim no longer names a listing
+      fingerprintedProduct: claim && !claim.product.equals(PublicKey.default) ? claim.product : null,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([seller])
+    .rpc();
+
+  return product;
+}
+
+/// PDA of the fingerprint claim for a product's current title
+export async function productFingerprintOf(program: Program<Marketplace>, product: PublicKey) {
+  const { marketplace, fingerprint } = await program.account.product.fetch(product);
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("product_fingerprint"), marketplace.toBuffer(), Buffer.from(fingerprint)],
+    program.programId
+  )[0];
+}
+
+/// Retitle a product, passing the claim on the new title's fingerprint when
+/// it differs from the listing's current one
+export async function renameProduct(
+  program: Program<Marketplace>,
+  seller: Keypair,
+  product: PublicKey,
+  title: string
+) {
+  const productAccount = await program.account.product.fetch(product);
+  const productFingerprint = await productFingerprintOf(program, product);
+  const newProductFingerprint = findProductFingerprintPda(
+    productAccount.marketplace,
+    seller.publicKey,
+    title,
+    productAccount.category
+  );
+  const renamed = !newProductFingerprint.equals(productFingerprint);
+  const claim = renamed ? await program.account.productFingerprint.fetchNullable(newProductFingerprint) : null;
+
+  await program.methods
+    .renameProduct(title)
+    .accounts({
+      seller: seller.publicKey,
+      product,
+      productFingerprint,
+      newProductFingerprint: renamed ? newProductFingerprint : null,
+      fingerprintedProduct: claim && !claim.product.equals(PublicKey.default) ? claim.product : null,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([seller])
+    .rpc();
+}
+
+/// Create a token mint controlled by the provider wallet
+export async function createMint(provider: anchor.AnchorProvider, decimals = 6) {
+  return Token.createMint(
+    provider.connection,
+    (provider.wallet as anchor.Wallet).payer,
+    provider.wallet.publicKey,
+    null,
+    decimals,
+    TOKEN_PROGRAM_ID
+  );
+}
+
+/// Create an unfunded escrow, returning its PDAs. Token escrows need the
+/// mint they are paid in.
+export async function createEscrow(
+  program: Program<Escrow>,
+  marketplace: PublicKey,
+  product: PublicKey,
+  buyer: Keypair,
+  quantity = new anchor.BN(1),
+  mint: PublicKey | null = null
+) {
+  const pdas = await nextEscrowPdas(program, marketplace, buyer.publicKey, product);
+  const { seller } = await (anchor.workspace.Marketplace as Program<Marketplace>).account.product.fetch(product);
+
+  await program.methods
+    .createEscrow(quantity, null, null)
+    .accounts({
+      buyer: buyer.publicKey,
+      marketplace,
+      product,
+      escrow: pdas.escrow,
+      buyerReputation: null,
+      coupon: null,
+      mint,
+      sellerStatus: findSellerStatusPda(marketplace, seller),
+      sellerReputation: findUserReputationPda(seller),
+      buyerEscrowCounter: findBuyerEscrowCounterPda(marketplace, buyer.publicKey),
+      ...escrowTrackingAccounts(program),
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([buyer])
+    .rpc();
+
+  return pdas;
+}
+
+/// Fund a created SOL escrow
+export async function fundEscrow(
+  program: Program<Escrow>,
+  pdas: { escrow: PublicKey; escrowVault: PublicKey },
+  buyer: Keypair,
+  sourceAttestationHash: number[] | null = null
+) {
+  const { marketplace } = await program.account.escrow.fetch(pdas.escrow);
+
+  await program.methods
+    .fundEscrow(sourceAttestationHash)
+    .accounts({
+      buyer: buyer.publicKey,
+      escrow: pdas.escrow,
+      marketplace,
+      escrowVault: pdas.escrowVault,
+      buyerTokenAccount: null,
+      escrowTokenAccount: null,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([buyer])
+    .rpc();
+}
+
+/// Create and fund a SOL escrow, returning its PDAs
+export async function createFundedEscrow(
+  program: Program<Escrow>,
+  marketplace: PublicKey,
+  product: PublicKey,
+  buyer: Keypair,
+  quantity = new anchor.BN(1)
+) {
+  const pdas = await createEscrow(program, marketplace, product, buyer, quantity);
+  await fundEscrow(program, pdas, buyer);
+  return pdas;
+}
+
+/// Ship and confirm delivery of a funded SOL escrow, crediting the sale to
+/// the seller's reputation when one is passed
+export async function shipAndConfirm(
+  program: Program<Escrow>,
+  pdas: { escrow: PublicKey; escrowVault: PublicKey },
+  buyer: Keypair,
+  seller: Keypair,
+  sellerReputation: PublicKey | null = null
+) {
+  const { marketplace, product, feeDestination } = await program.account.escrow.fetch(pdas.escrow);
+
+  await program.methods
+    .markAsShipped(null)
+    .accounts({ seller: seller.publicKey, escrow: pdas.escrow })
+    .signers([seller])
+    .rpc();
+
+  await program.methods
+    .confirmDelivery()
+    .accounts({
+      buyer: buyer.publicKey,
+      seller: seller.publicKey,
+      marketplace,
+      escrow: pdas.escrow,
+      escrowVault: pdas.escrowVault,
+      escrowTokenAccount: null,
+      sellerTokenAccount: null,
+      product,
+      buyerEscrowCounter: findBuyerEscrowCounterPda(marketplace, buyer.publicKey),
+      sellerStatus: findSellerStatusPda(marketplace, seller.publicKey),
+      feeDestination,
+      sellerReputation,
+      buyerReputation: null,
+      ...escrowTrackingAccounts(program),
+      reputationProgram: (anchor.workspace.Reputation as Program<Reputation>).programId,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([buyer])
+    .rpc();
+}
+
+/// List a product from the seller and complete the buyer's SOL purchase of
+/// it, returning the escrow PDAs. Reviews must reference such an escrow.
+export async function completePurchase(
+  marketplaceProgram: Program<Marketplace>,
+  escrowProgram: Program<Escrow>,
+  marketplace: PublicKey,
+  seller: Keypair,
+  buyer: Keypair
+) {
+  const product = await createProduct(marketplaceProgram, marketplace, seller);
+  const pdas = await createFundedEscrow(escrowProgram, marketplace, product, buyer);
+  await shipAndConfirm(escrowProgram, pdas, buyer, seller);
+  return pdas;
+}
+
+/// Initialize a user's reputation account and return its PDA
+export async function initReputation(program: Program<Reputation>, user: Keypair) {
+  const [userReputation] = PublicKey.findProgramAddressSync(
+    [Buffer.from("user_reputation"), user.publicKey.toBuffer()],
+    program.programId
+  );
+
+  await program.methods
+    .initializeUserReputation()
+    .accounts({
+      user: user.publicKey,
+      userReputation,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([user])
+    .rpc();
+
+  return userReputation;
+}
+
+export function findProductReputationPda(program: Program<Reputation>, product: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("product_reputation"), product.toBuffer()],
+    program.programId
+  )[0];
+}
+
+/// Rating aggregate of the product an escrow was for, initialized on first use
+export async function productReputationFor(program: Program<Reputation>, escrow: PublicKey) {
+  const { product } = await (anchor.workspace.Escrow as Program<Escrow>).account.escrow.fetch(escrow);
+  const productReputation = findProductReputationPda(program, product);
+
+  if ((await program.account.productReputation.fetchNullable(productReputation)) === null) {
+    await program.methods
+      .initializeProductReputation()
+      .accounts({
+        payer: program.provider.publicKey,
+        product,
+        productReputation,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+  }
+
+  return productReputation;
+}
+
+/// Leave a review and return its PDA. The transaction reference is the
+/// completed escrow the review is for; the buyer's review also rates the
+/// escrow's product, in its reputation account unless one is given.
+export async function createReview(
+  program: Program<Reputation>,
+  author: Keypair,
+  recipient: PublicKey,
+  rating: number,
+  comment: string,
+  transactionReference: PublicKey,
+  productReputation: PublicKey | null = null,
+  attachmentUris: string[] = [],
+  authorReputation: PublicKey | null = null,
+  role: object | null = null
+) {
+  const [userReputation] = PublicKey.findProgramAddressSync(
+    [Buffer.from("user_reputation"), recipient.toBuffer()],
+    program.programId
+  );
+
+  const [review] = PublicKey.findProgramAddressSync(
+    [Buffer.from("review"), author.publicKey.toBuffer(), recipient.toBuffer(), transactionReference.toBuffer()],
+    program.programId
+  );
+
+  const { buyer, seller, product } = await (anchor.workspace.Escrow as Program<Escrow>).account.escrow.fetch(
+    transactionReference
+  );
+
+  // Without a role, the recipient is rated in their side of the escrow
+  if (role === null) {
+    role = seller.equals(recipient) ? { seller: {} } : { buyer: {} };
+  }
+
+  // The buyer's review creates the product's aggregate if it has none yet
+  if (productReputation === null && buyer.equals(author.publicKey)) {
+    productReputation = findProductReputationPda(program, product);
+  }
+
+  await program.methods
+    .createReview(rating, comment, transactionReference, attachmentUris, role)
+    .accounts({
+      author: author.publicKey,
+      recipient,
+      userReputation,
+      review,
+      escrow: transactionReference,
+      product,
+      productReputation,
+      authorReputation,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([author])
+    .rpc();
+
+  return review;
+}
+
+/// Assert that a promise rejects with the given Anchor error code
+export async function expectError(promise: Promise<unknown>, code: string) {
+  try {
+    await promise;
+  } catch (e) {
+    const message = e.toString() + (e.logs ? e.logs.join("\n") : "");
+    if (!message.includes(code)) {
+      throw new Error(`Expected error ${code}, got: ${message}`);
+    }
+    return;
+  }
+  throw new Error(`Expected error ${code}, but the call succeeded`);
+}
@@ -2,7 +2,11 @@ import * as anchor from '@project-serum/anchor';
 import { Program } from '@project-serum/anchor';
 import { Marketplace } from '../target/types/marketplace';
 import { expect } from 'chai';
-import { PublicKey } from '@solana/web3.js';
+import { PublicKey, Keypair } from '@solana/web3.js';
+import { createHash } from 'crypto';
+import { startAnchor } from 'solana-bankrun';
+import { BankrunProvider } from 'anchor-bankrun';
+import { createProduct, renameProduct, productFingerprintOf, createMint, fundedKeypair, findMarketplacePda, findProductFingerprintPda, findFeaturedRegistryPda, mockVrfAccount, expectError } from './helpers';
 
 describe('marketplace', () => {
   // Configure the client to use the local cluster
@@ -21,7 +25,7 @@ describe('marketplace', () => {
   const productDescription = "Fresh organic tomatoes from local farm";
   const productPrice = new anchor.BN(500000); // 0.5 SOL in lamports
   const productQuantity = new anchor.BN(100);
-  const productCategory = "Vegetables";
+  const productCategory = { produce: {} };
   const metadataUri = "https://arweave.net/abc123";
 
   beforeEach(async () => {
@@ -93,12 +97,23 @@ describe('marketplace', () => {
         productQuantity,
         { sol: {} }, // CurrencyType enum
         metadataUri,
-        productCategory
+        productCategory,
+        new anchor.BN(0), // No returns accepted
+        null, // Available immediately
+        null, // No end of season
+        null, // Any buyer may purchase
+        Array(32).fill(0), // No metadata hash
+        new anchor.BN(0), // No handling time promised
+        [], // No tags
+        null // Minimum order of one unit
       )
       .accounts({
         seller: seller.publicKey,
         marketplace: marketplacePda,
         product: productPda,
+        priceFeed: null,
+        productFingerprint: findProductFingerprintPda(marketplacePda, seller.publicKey, productTitle, productCategory),
+        fingerprintedProduct: null,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([seller])
@@ -116,7 +131,7 @@ describe('marketplace', () => {
     expect(product.description).to.equal(productDescription);
     expect(product.metadataUri).to.equal(metadataUri);
     expect(product.status).to.deep.equal({ active: {} });
-    expect(product.category).to.equal(productCategory);
+    expect(product.category).to.deep.equal(productCategory);
     
     // Verify marketplace product count was incremented
     const marketplace = await program.account.marketplaceState.fetch(marketplacePda);
@@ -154,12 +169,23 @@ describe('marketplace', () => {
         productQuantity,
         { sol: {} }, // CurrencyType enum
         metadataUri,
-        productCategory
+        productCategory,
+        new anchor.BN(0), // No returns accepted
+        null, // Available immediately
+        null, // No end of season
+        null, // Any buyer may purchase
+        Array(32).fill(0), // No metadata hash
+        new anchor.BN(0), // No handling time promised
+        [], // No tags
+        null // Minimum order of one unit
       )
       .accounts({
         seller: seller.publicKey,
         marketplace: marketplacePda,
         product: productPda,
+        priceFeed: null,
+        productFingerprint: findProductFingerprintPda(marketplacePda, seller.publicKey, productTitle, productCategory),
+        fingerprintedProduct: null,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([seller])
@@ -170,18 +196,22 @@ describe('marketplace', () => {
     const updatedPrice = new anchor.BN(600000); // 0.6 SOL
     
     // Update the product
+    await renameProduct(program, seller, productPda, updatedTitle);
     await program.methods
       .updateProduct(
-        updatedTitle,         // Update title
         null,                 // Don't update description
         updatedPrice,         // Update price
         null,                 // Don't update quantity
         null,                 // Don't update metadata URI
-        null                  // Don't update status
+        null,                 // Don't update status
+        null,                 // Don't update return window
+        null,                 // Don't update tags
+        null                  // Don't update minimum order quantity
       )
       .accounts({
         seller: seller.publicKey,
         product: productPda,
+        productFingerprint: await productFingerprintOf(program, productPda),
       })
       .signers([seller])
       .rpc();
@@ -199,4 +229,982 @@ describe('marketplace', () => {
     expect(updatedProduct.metadataUri).to.equal(metadataUri);
     expect(updatedProduct.status).to.deep.equal({ active: {} });
   });
-}); 
\ No newline at end of file
+
+  it('Defaults the minimum order to one unit and lets the seller change it', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const product = await createProduct(program, marketplacePda, seller);
+    expect((await program.account.product.fetch(product)).minOrderQuantity.toNumber()).to.equal(1);
+
+    const setMinimum = async (minimum: number) =>
+      program.methods
+        .updateProduct(null, null, null, null, null, null, null, new anchor.BN(minimum))
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc();
+
+    await setMinimum(50);
+    expect((await program.account.product.fetch(product)).minOrderQuantity.toNumber()).to.equal(50);
+    await expectError(setMinimum(0), "InvalidMinOrderQuantity");
+  });
+
+  it('Updates products past the seller\'s first listing', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const products = [];
+    for (let i = 0; i < 3; i++) {
+      products.push(await createProduct(program, marketplacePda, seller));
+    }
+
+    for (const [position, product] of products.slice(1).entries()) {
+      const title = `Second harvest ${position}`;
+      await renameProduct(program, seller, product, title);
+      await program.methods
+        .updateProduct(null, new anchor.BN(700000), null, null, null, null, null, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc();
+
+      const productAccount = await program.account.product.fetch(product);
+      expect(productAccount.title).to.equal(title);
+      expect(productAccount.price.toNumber()).to.equal(700000);
+    }
+
+    // Each product's seeds use the marketplace product count at creation
+    const indexes = await Promise.all(
+      products.map(async (product) => (await program.account.product.fetch(product)).index.toNumber())
+    );
+    expect(indexes[1]).to.equal(indexes[0] + 1);
+    expect(indexes[2]).to.equal(indexes[0] + 2);
+  });
+
+  describe('featured rotation', () => {
+    // VRF accounts belong to the Switchboard program, so the rotation runs
+    // in a bankrun bank where a mocked VRF account can be loaded
+    it('Rotates featured placement across the registered products', async () => {
+      const sellers = [Keypair.generate(), Keypair.generate(), Keypair.generate()];
+      const outsider = Keypair.generate();
+      const vrf = Keypair.generate().publicKey;
+      const otherVrf = Keypair.generate().publicKey;
+      const context = await startAnchor(
+        '.',
+        [],
+        [
+          ...[...sellers, outsider].map((keypair) => ({
+            address: keypair.publicKey,
+            info: {
+              lamports: 2 * anchor.web3.LAMPORTS_PER_SOL,
+              data: Buffer.alloc(0),
+              owner: anchor.web3.SystemProgram.programId,
+              executable: false,
+            },
+          })),
+          { address: vrf, info: mockVrfAccount(1, 7) },
+          { address: otherVrf, info: mockVrfAccount(5, 7) },
+        ]
+      );
+      const bankProvider = new BankrunProvider(context) as unknown as anchor.Provider;
+      const bankMarketplace = new Program<Marketplace>(program.idl, program.programId, bankProvider);
+      // Identical transactions need a fresh blockhash to be processed again
+      const nextSlot = async () => context.warpToSlot((await context.banksClient.getSlot()) + BigInt(1));
+
+      const authority = context.payer.publicKey;
+      const marketplace = findMarketplacePda(bankMarketplace, authority);
+      await bankMarketplace.methods
+        .initializeMarketplace(250)
+        .accounts({
+          authority,
+          marketplace,
+          feeDestination: authority,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      const featuredRegistry = findFeaturedRegistryPda(marketplace);
+      const products: PublicKey[] = [];
+      for (const seller of sellers) {
+        const product = await createProduct(bankMarketplace, marketplace, seller);
+        await bankMarketplace.methods
+          .enterFeaturedRotation()
+          .accounts({
+            seller: seller.publicKey,
+            marketplace,
+            product,
+            featuredRegistry,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([seller])
+          .rpc();
+        products.push(product);
+      }
+
+      const setFeaturedVrf = (signer: Keypair | null, vrfAccount: PublicKey) =>
+        bankMarketplace.methods
+          .setFeaturedVrf()
+          .accounts({ authority: signer ? signer.publicKey : authority, marketplace, vrf: vrfAccount })
+          .signers(signer ? [signer] : [])
+          .rpc();
+      const rotate = async (candidates: PublicKey[], vrfAccount = vrf) => {
+        await nextSlot();
+        return bankMarketplace.methods
+          .rotateFeaturedProducts(1, new anchor.BN(86400))
+          .accounts({ authority, marketplace, featuredRegistry, vrf: vrfAccount })
+          .remainingAccounts(candidates.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false })))
+          .rpc();
+      };
+
+      await expectError(setFeaturedVrf(outsider, vrf), "UnauthorizedAuthority");
+      await setFeaturedVrf(null, vrf);
+      await expectError(rotate(products, otherVrf), "InvalidVrfAccount");
+
+      // The round current when the VRF was configured can't drive a rotation
+      await expectError(rotate(products), "VrfResultAlreadyUsed");
+
+      // The authority has to pass the whole registry, in order
+      context.setAccount(vrf, mockVrfAccount(2, 7));
+      await expectError(rotate(products.slice(1)), "InvalidFeaturedCandidate");
+      await expectError(rotate([products[1], products[0], products[2]]), "InvalidFeaturedCandidate");
+
+      // A result of all sevens draws the second of three candidates
+      await rotate(products);
+      const featured = await Promise.all(
+        products.map(async (product) => (await bankMarketplace.account.product.fetch(product)).featured)
+      );
+      expect(featured).to.deep.equal([false, true, false]);
+      const marketplaceAccount = await bankMarketplace.account.marketplaceState.fetch(marketplace);
+      expect(marketplaceAccount.featuredVrfRound.toNumber()).to.equal(2);
+
+      // Configuring the VRF again doesn't reopen a round already used
+      await expectError(rotate(products), "VrfResultAlreadyUsed");
+      await nextSlot();
+      await setFeaturedVrf(null, vrf);
+      await expectError(rotate(products), "VrfResultAlreadyUsed");
+
+      // Leaving the rotation gives up the featured slot
+      await bankMarketplace.methods
+        .leaveFeaturedRotation()
+        .accounts({ seller: sellers[1].publicKey, product: products[1], featuredRegistry })
+        .signers([sellers[1]])
+        .rpc();
+      expect((await bankMarketplace.account.product.fetch(products[1])).featured).to.equal(false);
+      const registry = await bankMarketplace.account.featuredRegistry.fetch(featuredRegistry);
+      expect(registry.count).to.equal(2);
+      expect(registry.products.slice(0, 2).map((product) => product.toBase58())).to.deep.equal(
+        [products[0], products[2]].map((product) => product.toBase58())
+      );
+    });
+  });
+
+  it('Measures title length in bytes for multibyte strings', async () => {
+    const seller = await fundedKeypair(provider, 1);
+
+    // 25 two-byte characters fill the 50 byte limit exactly
+    const product = await createProduct(program, marketplacePda, seller, { title: "é".repeat(25) });
+    expect((await program.account.product.fetch(product)).title).to.equal("é".repeat(25));
+
+    // 26 characters are well under 50 characters but over 50 bytes
+    await expectError(
+      createProduct(program, marketplacePda, seller, { title: "é".repeat(26) }),
+      "TitleTooLong"
+    );
+
+    // Four-byte characters hit the limit even sooner
+    await expectError(
+      createProduct(program, marketplacePda, seller, { title: "🌽".repeat(13) }),
+      "TitleTooLong"
+    );
+  });
+
+  it('Registers and deregisters child marketplaces', async () => {
+    const superAuthority = await fundedKeypair(provider, 1);
+    const [registry] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("marketplace_registry"),
+        superAuthority.publicKey.toBuffer(),
+        new anchor.BN(0).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    );
+    await program.methods
+      .initializeRegistryPage(0)
+      .accounts({
+        superAuthority: superAuthority.publicKey,
+        registry,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([superAuthority])
+      .rpc();
+
+    // A second regional marketplace under its own authority
+    const regionalAuthority = await fundedKeypair(provider, 1);
+    const regionalMarketplace = findMarketplacePda(program, regionalAuthority.publicKey);
+    await program.methods
+      .initializeMarketplace(250)
+      .accounts({
+        authority: regionalAuthority.publicKey,
+        marketplace: regionalMarketplace,
+        feeDestination: regionalAuthority.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([regionalAuthority])
+      .rpc();
+
+    const updateRegistry = (method: 'registerMarketplace' | 'deregisterMarketplace', marketplace: PublicKey) =>
+      program.methods[method]()
+        .accounts({ superAuthority: superAuthority.publicKey, registry, marketplace })
+        .signers([superAuthority])
+        .rpc();
+
+    await updateRegistry('registerMarketplace', marketplacePda);
+    await updateRegistry('registerMarketplace', regionalMarketplace);
+    await expectError(updateRegistry('registerMarketplace', marketplacePda), "MarketplaceAlreadyRegistered");
+
+    let page = await program.account.marketplaceRegistry.fetch(registry);
+    expect(page.count).to.equal(2);
+    expect(page.marketplaces.slice(0, 2).map((key) => key.toString())).to.deep.equal([
+      marketplacePda.toString(),
+      regionalMarketplace.toString(),
+    ]);
+
+    await updateRegistry('deregisterMarketplace', marketplacePda);
+    page = await program.account.marketplaceRegistry.fetch(registry);
+    expect(page.count).to.equal(1);
+    expect(page.marketplaces[0].toString()).to.equal(regionalMarketplace.toString());
+    await expectError(updateRegistry('deregisterMarketplace', marketplacePda), "MarketplaceNotRegistered");
+  });
+
+  it('Stores the metadata hash and requires one when configured', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const metadata = JSON.stringify({ name: "Heirloom Tomatoes", images: ["ar://tomatoes"] });
+    const metadataHash = [...createHash('sha256').update(metadata).digest()];
+
+    const product = await createProduct(program, marketplacePda, seller, { metadataHash });
+    const productAccount = await program.account.product.fetch(product);
+    expect(productAccount.metadataHash).to.deep.equal(metadataHash);
+
+    // Clients verify fetched metadata against the on-chain hash
+    const fetchedHash = [...createHash('sha256').update(metadata).digest()];
+    expect(fetchedHash).to.deep.equal(productAccount.metadataHash);
+
+    const setRequired = (required: boolean) =>
+      program.methods
+        .setRequireMetadataHash(required)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+    await setRequired(true);
+    try {
+      await expectError(createProduct(program, marketplacePda, seller), "MetadataHashRequired");
+      await createProduct(program, marketplacePda, seller, { metadataHash });
+    } finally {
+      await setRequired(false);
+    }
+  });
+
+  it('Computes reference prices from the currency price feed', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const [solPriceFeed] = PublicKey.findProgramAddressSync(
+      [Buffer.from("price_feed"), marketplacePda.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
+    const setSolPrice = (usdPrice: number) =>
+      program.methods
+        .setPriceFeed({ sol: {} }, new anchor.BN(usdPrice))
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          priceFeed: solPriceFeed,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    // 2 SOL at $150.25 is worth $300.50
+    await setSolPrice(150_250_000);
+    const product = await createProduct(program, marketplacePda, seller, {
+      price: new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL),
+      priceFeed: solPriceFeed,
+    });
+    let productAccount = await program.account.product.fetch(product);
+    expect(productAccount.referencePrice.toNumber()).to.equal(300_500_000);
+
+    // Refreshing follows the feed
+    await setSolPrice(100_000_000);
+    await program.methods
+      .refreshReferencePrice()
+      .accounts({ product, priceFeed: solPriceFeed })
+      .rpc();
+    productAccount = await program.account.product.fetch(product);
+    expect(productAccount.referencePrice.toNumber()).to.equal(200_000_000);
+
+    // A feed for another currency cannot price the listing
+    const usdcProduct = await createProduct(program, marketplacePda, seller, { currency: { usdc: {} } });
+    await expectError(
+      program.methods
+        .refreshReferencePrice()
+        .accounts({ product: usdcProduct, priceFeed: solPriceFeed })
+        .rpc(),
+      "ConstraintSeeds"
+    );
+  });
+
+  const setFeeDestination = (currency: object, feeDestination: PublicKey) =>
+    program.methods
+      .setCurrencyFeeDestination(currency)
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda, feeDestination })
+      .rpc();
+
+  const createStablecoinTreasury = async () => {
+    const mint = await createMint(provider);
+    return mint.createAccount(provider.wallet.publicKey);
+  };
+
+  it('Routes fees to per-currency destinations with a default fallback', async () => {
+    const stablecoinTreasury = await createStablecoinTreasury();
+    await setFeeDestination({ usdc: {} }, stablecoinTreasury);
+    try {
+      const marketplace = await program.account.marketplaceState.fetch(marketplacePda);
+      expect(marketplace.usdcFeeDestination.toString()).to.equal(stablecoinTreasury.toString());
+
+      // SOL has no override, so its fees still go to the default destination
+      expect(marketplace.solFeeDestination.toString()).to.equal(PublicKey.default.toString());
+      expect(marketplace.feeDestination.toString()).to.equal(feesDestination.toString());
+    } finally {
+      await setFeeDestination({ usdc: {} }, PublicKey.default);
+    }
+  });
+
+  it('Lets the authority update the fee within the 10% cap', async () => {
+    const treasury = await fundedKeypair(provider, 1);
+    const impostor = await fundedKeypair(provider, 1);
+    const updateFees = (feesBasisPoints: number, feeDestination: PublicKey, authority: Keypair | null = null) =>
+      program.methods
+        .updateFees(feesBasisPoints)
+        .accounts({
+          authority: authority ? authority.publicKey : marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          feeDestination,
+        })
+        .signers(authority ? [authority] : [])
+        .rpc();
+
+    await expectError(updateFees(1001, treasury.publicKey), "FeesTooHigh");
+    await expectError(updateFees(300, treasury.publicKey, impostor), "UnauthorizedAuthority");
+
+    try {
+      await updateFees(1000, treasury.publicKey);
+      const marketplace = await program.account.marketplaceState.fetch(marketplacePda);
+      expect(marketplace.feesBasisPoints).to.equal(1000);
+      expect(marketplace.feeDestination.toString()).to.equal(treasury.publicKey.toString());
+    } finally {
+      await updateFees(250, feesDestination);
+    }
+  });
+
+  it('Rejects fee destinations that cannot receive the currency', async () => {
+    // SOL fees need a system account, not a token account
+    const stablecoinTreasury = await createStablecoinTreasury();
+    await expectError(setFeeDestination({ sol: {} }, stablecoinTreasury), "InvalidFeeDestination");
+
+    // Stablecoin fees need a token account, not a wallet
+    await expectError(
+      setFeeDestination({ usdt: {} }, Keypair.generate().publicKey),
+      "InvalidFeeDestination"
+    );
+
+    const rejectedAuthority = await fundedKeypair(provider);
+    await expectError(
+      program.methods
+        .initializeMarketplace(250)
+        .accounts({
+          authority: rejectedAuthority.publicKey,
+          marketplace: findMarketplacePda(program, rejectedAuthority.publicKey),
+          feeDestination: stablecoinTreasury,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([rejectedAuthority])
+        .rpc(),
+      "InvalidFeeDestination"
+    );
+  });
+
+  it('Rejects purchases made outside the escrow program', async () => {
+    const directSeller = await fundedKeypair(provider);
+    const directBuyer = await fundedKeypair(provider);
+    const product = await createProduct(program, marketplacePda, directSeller);
+    const [sellerStatus] = PublicKey.findProgramAddressSync(
+      [Buffer.from("seller_status"), marketplacePda.toBuffer(), directSeller.publicKey.toBuffer()],
+      program.programId
+    );
+
+    // Without the escrow program's signature, inventory cannot be taken
+    const impostor = Keypair.generate();
+    await expectError(
+      program.methods
+        .purchaseProduct(new anchor.BN(1))
+        .accounts({
+          escrowAuthority: impostor.publicKey,
+          buyer: directBuyer.publicKey,
+          marketplace: marketplacePda,
+          product,
+          sellerStatus,
+        })
+        .signers([impostor, directBuyer])
+        .rpc(),
+      "ConstraintSeeds"
+    );
+
+    const productAccount = await program.account.product.fetch(product);
+    expect(productAccount.quantity.toNumber()).to.equal(10);
+    expect(productAccount.reservedQuantity.toNumber()).to.equal(0);
+  });
+
+  it('Rejects new listings while the marketplace is paused', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const product = await createProduct(program, marketplacePda, seller);
+    const setPaused = (paused: boolean) =>
+      (paused ? program.methods.pauseMarketplace() : program.methods.unpauseMarketplace())
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    await setPaused(true);
+    try {
+      expect((await program.account.marketplaceState.fetch(marketplacePda)).isPaused).to.equal(true);
+      await expectError(createProduct(program, marketplacePda, seller), "MarketplacePaused");
+
+      // Sellers can still take their listings down
+      await program.methods
+        .updateProduct(null, null, null, null, { deactivated: {} }, null, null, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc();
+      expect((await program.account.product.fetch(product)).status).to.deep.equal({ deactivated: {} });
+    } finally {
+      await setPaused(false);
+    }
+  });
+
+  it('Rejects a duplicate listing until the original is closed', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const listing = { title: "Heirloom Carrots", category: { produce: {} } };
+    const original = await createProduct(program, marketplacePda, seller, listing);
+
+    // Case and spacing don't make a listing distinct
+    await expectError(
+      createProduct(program, marketplacePda, seller, { title: "  heirloom   CARROTS ", category: { produce: {} } }),
+      "DuplicateListing"
+    );
+
+    // The fingerprint is per seller
+    await createProduct(program, marketplacePda, await fundedKeypair(provider, 1), listing);
+
+    await program.methods
+      .updateProduct(null, null, null, null, { deactivated: {} }, null, null, null)
+      .accounts({ seller: seller.publicKey, product: original, productFingerprint: await productFingerprintOf(program, original) })
+      .signers([seller])
+      .rpc();
+    const relisted = await createProduct(program, marketplacePda, seller, listing);
+
+    const claim = await program.account.productFingerprint.fetch(
+      findProductFingerprintPda(marketplacePda, seller.publicKey, listing.title, listing.category)
+    );
+    expect(claim.product.toString()).to.equal(relisted.toString());
+    expect((await program.account.product.fetch(relisted)).fingerprint).to.deep.equal(claim.fingerprint);
+  });
+
+  it('Keeps a closed listing from reopening beside its relisting', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const listing = { title: "Shiitake Logs", category: { produce: {} } };
+    const original = await createProduct(program, marketplacePda, seller, listing);
+    const productFingerprint = findProductFingerprintPda(marketplacePda, seller.publicKey, listing.title, listing.category);
+    const update = (product: PublicKey, quantity: number | null, status: object | null) =>
+      program.methods
+        .updateProduct(null, null, quantity === null ? null : new anchor.BN(quantity), null, status, null, null, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint })
+        .signers([seller])
+        .rpc();
+
+    await update(original, null, { deactivated: {} });
+    const relisted = await createProduct(program, marketplacePda, seller, listing);
+
+    // Neither reactivating nor restocking the original reopens it
+    await expectError(update(original, null, { active: {} }), "DuplicateListing");
+    await update(original, 0, { soldOut: {} });
+    await expectError(update(original, 5, null), "DuplicateListing");
+
+    // Once the relisting is deleted, the original can take its claim back
+    await update(relisted, null, { deactivated: {} });
+    await program.methods
+      .deleteProduct()
+      .accounts({ seller: seller.publicKey, marketplace: marketplacePda, product: relisted, productFingerprint })
+      .signers([seller])
+      .rpc();
+    await update(original, 5, null);
+    expect((await program.account.product.fetch(original)).status).to.deep.equal({ active: {} });
+    expect((await program.account.productFingerprint.fetch(productFingerprint)).product.toString()).to.equal(
+      original.toString()
+    );
+  });
+
+  it('Moves a listing\'s fingerprint claim when it is renamed', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const category = { produce: {} };
+    const carrots = await createProduct(program, marketplacePda, seller, { title: "Purple Carrots", category });
+    const beets = await createProduct(program, marketplacePda, seller, { title: "Golden Beets", category });
+    const claimOf = (title: string) =>
+      program.account.productFingerprint.fetch(findProductFingerprintPda(marketplacePda, seller.publicKey, title, category));
+
+    // Renaming can't sidestep the duplicate check
+    await expectError(renameProduct(program, seller, beets, "purple  carrots"), "DuplicateListing");
+
+    // A cosmetic rename keeps the claim
+    await renameProduct(program, seller, carrots, "purple carrots");
+    expect((await claimOf("Purple Carrots")).product.toString()).to.equal(carrots.toString());
+
+    await renameProduct(program, seller, carrots, "Rainbow Carrots");
+    const claim = await claimOf("Rainbow Carrots");
+    expect(claim.product.toString()).to.equal(carrots.toString());
+    expect((await program.account.product.fetch(carrots)).fingerprint).to.deep.equal(claim.fingerprint);
+    expect((await claimOf("Purple Carrots")).product.toString()).to.equal(PublicKey.default.toString());
+
+    // The old title is free to list again
+    await createProduct(program, marketplacePda, seller, { title: "Purple Carrots", category });
+  });
+
+  it('Files listings under a category clients can group by', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const wheat = await createProduct(program, marketplacePda, seller, { title: "Durum Wheat", category: { grains: {} } });
+    const tractor = await createProduct(program, marketplacePda, seller, { title: "Used Tractor", category: { equipment: {} } });
+
+    // Products store the seller right after the 8-byte discriminator and marketplace
+    const listings = await program.account.product.all([
+      { memcmp: { offset: 8 + 32, bytes: seller.publicKey.toBase58() } },
+    ]);
+    const inCategory = (category: string) =>
+      listings.filter((listing) => category in listing.account.category).map((listing) => listing.publicKey.toString());
+    expect(inCategory("grains")).to.deep.equal([wheat.toString()]);
+    expect(inCategory("equipment")).to.deep.equal([tractor.toString()]);
+    expect(inCategory("produce")).to.deep.equal([]);
+  });
+
+  it('Tags listings and rejects too many or too long tags', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const tags = ["organic", "non-gmo", "bulk"];
+    const product = await createProduct(program, marketplacePda, seller, { tags });
+    expect((await program.account.product.fetch(product)).tags).to.deep.equal(tags);
+
+    const retag = async (tags: string[]) =>
+      program.methods
+        .updateProduct(null, null, null, null, null, null, tags, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc();
+    await retag(["organic", "pasture-raised", "local", "bulk", "heirloom"]);
+    expect((await program.account.product.fetch(product)).tags).to.have.length(5);
+
+    await expectError(retag(["a", "b", "c", "d", "e", "f"]), "TooManyTags");
+    await expectError(retag(["sustainably-farmed"]), "TagTooLong");
+    await expectError(
+      createProduct(program, marketplacePda, seller, { title: "Too Many Tags", tags: ["a", "b", "c", "d", "e", "f"] }),
+      "TooManyTags"
+    );
+  });
+
+  it('Deletes a closed listing and returns its rent to the seller', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const listing = { title: "Winter Squash", category: { produce: {} } };
+    const product = await createProduct(program, marketplacePda, seller, listing);
+    const productFingerprint = findProductFingerprintPda(marketplacePda, seller.publicKey, listing.title, listing.category);
+    const deleteProduct = () =>
+      program.methods
+        .deleteProduct()
+        .accounts({ seller: seller.publicKey, marketplace: marketplacePda, product, productFingerprint })
+        .signers([seller])
+        .rpc();
+
+    await expectError(deleteProduct(), "ProductNotClosed");
+
+    await program.methods
+      .updateProduct(null, null, null, null, { deactivated: {} }, null, null, null)
+      .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+      .signers([seller])
+      .rpc();
+    const rent = (await provider.connection.getAccountInfo(product)).lamports;
+    const sellerBalance = await provider.connection.getBalance(seller.publicKey);
+    const { listedProducts, productCount } = await program.account.marketplaceState.fetch(marketplacePda);
+
+    await deleteProduct();
+
+    expect(await provider.connection.getAccountInfo(product)).to.equal(null);
+    expect(await provider.connection.getBalance(seller.publicKey)).to.equal(sellerBalance + rent);
+    const marketplace = await program.account.marketplaceState.fetch(marketplacePda);
+    expect(marketplace.listedProducts.toNumber()).to.equal(listedProducts.toNumber() - 1);
+    expect(marketplace.productCount.toNumber()).to.equal(productCount.toNumber());
+
+    // Deleting the listing frees its fingerprint for a relisting
+    const relisted = await createProduct(program, marketplacePda, seller, listing);
+    expect((await program.account.productFingerprint.fetch(productFingerprint)).product.toString()).to.equal(
+      relisted.toString()
+    );
+  });
+
+  it('Reactivates a sold out listing when it is restocked', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const product = await createProduct(program, marketplacePda, seller);
+    const update = async (quantity: number | null, status: object | null) =>
+      program.methods
+        .updateProduct(null, null, quantity === null ? null : new anchor.BN(quantity), null, status, null, null, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc();
+    const fetchProduct = () => program.account.product.fetch(product);
+
+    await update(0, { soldOut: {} });
+    await update(5, null);
+    let account = await fetchProduct();
+    expect(account.status).to.deep.equal({ active: {} });
+    expect(account.quantity.toNumber()).to.equal(5);
+
+    // A status passed alongside the restock wins
+    await update(0, { soldOut: {} });
+    await update(5, { deactivated: {} });
+    account = await fetchProduct();
+    expect(account.status).to.deep.equal({ deactivated: {} });
+    expect(account.quantity.toNumber()).to.equal(5);
+  });
+
+  it('Lets users flag products and the authority resolve the flags', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const flaggerA = await fundedKeypair(provider, 1);
+    const flaggerB = await fundedKeypair(provider, 1);
+    const product = await createProduct(program, marketplacePda, seller);
+    const findFlag = (flagger: Keypair) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("product_flag"), product.toBuffer(), flagger.publicKey.toBuffer()],
+        program.programId
+      )[0];
+    const flag = (flagger: Keypair, reason: string) =>
+      program.methods
+        .flagProduct(reason)
+        .accounts({
+          flagger: flagger.publicKey,
+          product,
+          productFlag: findFlag(flagger),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([flagger])
+        .rpc();
+    const resolve = (flagger: Keypair, resolution: object, authority: Keypair | null = null) =>
+      program.methods
+        .resolveFlag(resolution)
+        .accounts({
+          authority: authority ? authority.publicKey : marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          product,
+          productFlag: findFlag(flagger),
+        })
+        .signers(authority ? [authority] : [])
+        .rpc();
+    const fetchProduct = () => program.account.product.fetch(product);
+
+    await expectError(flag(flaggerA, "a".repeat(141)), "FlagReasonTooLong");
+    await flag(flaggerA, "Photos show another farm's produce");
+    await expectError(flag(flaggerA, "Still suspicious"), "already in use");
+    let productFlag = await program.account.productFlag.fetch(findFlag(flaggerA));
+    expect(productFlag.flagger.toString()).to.equal(flaggerA.publicKey.toString());
+    expect(productFlag.status).to.deep.equal({ open: {} });
+
+    // Only the authority resolves, and dismissing leaves the listing on sale
+    await expectError(resolve(flaggerA, { dismiss: {} }, flaggerB), "UnauthorizedAuthority");
+    await resolve(flaggerA, { dismiss: {} });
+    productFlag = await program.account.productFlag.fetch(findFlag(flaggerA));
+    expect(productFlag.status).to.deep.equal({ dismissed: {} });
+    expect((await fetchProduct()).status).to.deep.equal({ active: {} });
+    await expectError(resolve(flaggerA, { flag: {} }), "FlagAlreadyResolved");
+
+    await flag(flaggerB, "Mislabelled as organic");
+    await resolve(flaggerB, { flag: {} });
+    expect((await fetchProduct()).status).to.deep.equal({ flagged: {} });
+    expect((await program.account.productFlag.fetch(findFlag(flaggerB))).status).to.deep.equal({ upheld: {} });
+
+    // The seller cannot clear the flag themselves
+    await expectError(
+      program.methods
+        .updateProduct(null, null, null, null, { active: {} }, null, null, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc(),
+      "ProductFlagged"
+    );
+  });
+
+  it('Keeps a listing the authority took down off sale', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const flagger = await fundedKeypair(provider, 1);
+    const product = await createProduct(program, marketplacePda, seller);
+    const [productFlag] = PublicKey.findProgramAddressSync(
+      [Buffer.from("product_flag"), product.toBuffer(), flagger.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .flagProduct("Counterfeit certification")
+      .accounts({
+        flagger: flagger.publicKey,
+        product,
+        productFlag,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([flagger])
+      .rpc();
+    await program.methods
+      .resolveFlag({ deactivate: {} })
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda, product, productFlag })
+      .rpc();
+
+    let account = await program.account.product.fetch(product);
+    expect(account.status).to.deep.equal({ deactivated: {} });
+    expect(account.moderated).to.equal(true);
+
+    const setStatus = async (status: object) =>
+      program.methods
+        .updateProduct(null, null, null, null, status, null, null, null)
+        .accounts({ seller: seller.publicKey, product, productFingerprint: await productFingerprintOf(program, product) })
+        .signers([seller])
+        .rpc();
+    await expectError(setStatus({ active: {} }), "ProductModerated");
+
+    // The seller may still edit the listing while it stays down
+    await setStatus({ deactivated: {} });
+    account = await program.account.product.fetch(product);
+    expect(account.status).to.deep.equal({ deactivated: {} });
+  });
+
+  it('Lets the authority reinstate a flagged listing or take it down', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const product = await createProduct(program, marketplacePda, seller);
+    const flagAndUphold = async () => {
+      const flagger = await fundedKeypair(provider, 1);
+      const [productFlag] = PublicKey.findProgramAddressSync(
+        [Buffer.from("product_flag"), product.toBuffer(), flagger.publicKey.toBuffer()],
+        program.programId
+      );
+      await program.methods
+        .flagProduct("Mislabelled as organic")
+        .accounts({
+          flagger: flagger.publicKey,
+          product,
+          productFlag,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([flagger])
+        .rpc();
+      await program.methods
+        .resolveFlag({ flag: {} })
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda, product, productFlag })
+        .rpc();
+    };
+    const clearFlag = (takeDown: boolean, authority: Keypair | null = null) =>
+      program.methods
+        .clearProductFlag(takeDown)
+        .accounts({
+          authority: authority ? authority.publicKey : marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          product,
+        })
+        .signers(authority ? [authority] : [])
+        .rpc();
+    const fetchProduct = () => program.account.product.fetch(product);
+
+    await expectError(clearFlag(false), "ProductNotFlagged");
+    await flagAndUphold();
+    await expectError(clearFlag(false, seller), "UnauthorizedAuthority");
+    await clearFlag(false);
+    expect((await fetchProduct()).status).to.deep.equal({ active: {} });
+
+    // A listing taken down is closed for good, so the seller can delete it
+    await flagAndUphold();
+    await clearFlag(true);
+    const account = await fetchProduct();
+    expect(account.status).to.deep.equal({ deactivated: {} });
+    expect(account.moderated).to.equal(true);
+    await program.methods
+      .deleteProduct()
+      .accounts({
+        seller: seller.publicKey,
+        marketplace: marketplacePda,
+        product,
+        productFingerprint: await productFingerprintOf(program, product),
+      })
+      .signers([seller])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(product)).to.be.null;
+  });
+
+  it('Pauses and unpauses the marketplace idempotently', async () => {
+    const impostor = await fundedKeypair(provider, 1);
+    const toggle = (paused: boolean, authority: Keypair | null = null) =>
+      (paused ? program.methods.pauseMarketplace() : program.methods.unpauseMarketplace())
+        .accounts({
+          authority: authority ? authority.publicKey : marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+        })
+        .signers(authority ? [authority] : [])
+        .rpc();
+    const isPaused = async () => (await program.account.marketplaceState.fetch(marketplacePda)).isPaused;
+
+    await expectError(toggle(true, impostor), "UnauthorizedAuthority");
+    expect(await isPaused()).to.equal(false);
+
+    try {
+      await toggle(true);
+      await toggle(true);
+      expect(await isPaused()).to.equal(true);
+      await expectError(toggle(false, impostor), "UnauthorizedAuthority");
+    } finally {
+      await toggle(false);
+    }
+    await toggle(false);
+    expect(await isPaused()).to.equal(false);
+  });
+
+  it('Prices listings without a currency in the marketplace default', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const setDefaultCurrency = (currency: object) =>
+      program.methods
+        .setDefaultCurrency(currency)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    await setDefaultCurrency({ usdc: {} });
+    try {
+      const inferred = await createProduct(program, marketplacePda, seller, { currency: null });
+      expect((await program.account.product.fetch(inferred)).currency).to.deep.equal({ usdc: {} });
+
+      const explicit = await createProduct(program, marketplacePda, seller, { currency: { usdt: {} } });
+      expect((await program.account.product.fetch(explicit)).currency).to.deep.equal({ usdt: {} });
+    } finally {
+      await setDefaultCurrency({ sol: {} });
+    }
+  });
+
+  it('Reprices several products in one transaction', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const otherSeller = await fundedKeypair(provider, 1);
+    const products = [];
+    for (let i = 0; i < 3; i++) {
+      products.push(await createProduct(program, marketplacePda, seller));
+    }
+    const foreign = await createProduct(program, marketplacePda, otherSeller);
+    const reprice = (keys: PublicKey[], prices: number[]) =>
+      program.methods
+        .repriceProducts(prices.map((price) => new anchor.BN(price)))
+        .accounts({ seller: seller.publicKey, marketplace: marketplacePda })
+        .remainingAccounts(keys.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false })))
+        .signers([seller])
+        .rpc();
+
+    // A product the seller does not own fails the whole batch
+    await expectError(reprice([products[0], foreign], [150000, 150000]), "NotProductOwner");
+    expect((await program.account.product.fetch(products[0])).price.toNumber()).to.equal(100000);
+    await expectError(reprice(products, [150000, 250000]), "InvalidRepriceBatch");
+    await expectError(reprice(products, [150000, 0, 350000]), "InvalidPrice");
+
+    await reprice(products, [150000, 250000, 350000]);
+    const prices = await Promise.all(
+      products.map(async (product) => (await program.account.product.fetch(product)).price.toNumber())
+    );
+    expect(prices).to.deep.equal([150000, 250000, 350000]);
+  });
+
+  describe('rounding policy', () => {
+    const setRoundingPolicy = (policy: object) =>
+      program.methods
+        .setRoundingPolicy(policy)
+        .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+        .rpc();
+
+    const previewSplit = (total: number, sellerBps: number) =>
+      program.methods
+        .previewSplit(new anchor.BN(total), sellerBps)
+        .accounts({ marketplace: marketplacePda })
+        .view();
+
+    const totals = [0, 1, 2, 3, 7, 99, 101, 333, 9999, 10001, 1234567, 1000000007];
+    const bpsValues = [0, 1, 333, 2500, 3333, 5000, 6667, 9999, 10000];
+
+    after(async () => {
+      await setRoundingPolicy({ dustToSeller: {} });
+    });
+
+    it('Gives the seller the remainder by default', async () => {
+      for (const total of totals) {
+        for (const bps of bpsValues) {
+          const { seller, buyer } = await previewSplit(total, bps);
+          expect(seller.add(buyer).toNumber()).to.equal(total);
+          expect(seller.toNumber()).to.equal(Math.ceil((total * bps) / 10000));
+        }
+      }
+    });
+
+    it('Gives the buyer the remainder when configured', async () => {
+      await setRoundingPolicy({ dustToBuyer: {} });
+      const marketplace = await program.account.marketplaceState.fetch(marketplacePda);
+      expect(marketplace.roundingPolicy).to.deep.equal({ dustToBuyer: {} });
+
+      for (const total of totals) {
+        for (const bps of bpsValues) {
+          const { seller, buyer } = await previewSplit(total, bps);
+          expect(seller.add(buyer).toNumber()).to.equal(total);
+          expect(seller.toNumber()).to.equal(Math.floor((total * bps) / 10000));
+        }
+      }
+    });
+
+    it('Rejects shares above the whole', async () => {
+      await expectError(previewSplit(100, 10001), "InvalidBasisPoints");
+    });
+  });
+
+  describe('closing a marketplace', () => {
+    // A fresh marketplace under its own authority
+    const windingDownMarketplace = async () => {
+      const authority = await fundedKeypair(provider, 1);
+      const marketplace = findMarketplacePda(program, authority.publicKey);
+      await program.methods
+        .initializeMarketplace(250)
+        .accounts({
+          authority: authority.publicKey,
+          marketplace,
+          feeDestination: authority.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([authority])
+        .rpc();
+      return { authority, marketplace };
+    };
+
+    const closeMarketplace = (authority: Keypair, marketplace: PublicKey) =>
+      program.methods
+        .closeMarketplace()
+        .accounts({ authority: authority.publicKey, marketplace })
+        .signers([authority])
+        .rpc();
+
+    it('Closes an empty marketplace and returns its rent', async () => {
+      const { authority, marketplace } = await windingDownMarketplace();
+      const rent = await provider.connection.getBalance(marketplace);
+      const authorityBalanceBefore = await provider.connection.getBalance(authority.publicKey);
+
+      await closeMarketplace(authority, marketplace);
+
+      expect(await provider.connection.getAccountInfo(marketplace)).to.equal(null);
+      const authorityBalanceAfter = await provider.connection.getBalance(authority.publicKey);
+      expect(authorityBalanceAfter).to.be.greaterThan(authorityBalanceBefore + rent - 10000);
+    });
+
+    it('Refuses to close a marketplace with listings', async () => {
+      const { authority, marketplace } = await windingDownMarketplace();
+      const seller = await fundedKeypair(provider);
+      await createProduct(program, marketplace, seller);
+
+      await expectError(closeMarketplace(authority, marketplace), "ListedProductsExist");
+      expect(await provider.connection.getAccountInfo(marketplace)).to.not.equal(null);
+    });
+  });
+});
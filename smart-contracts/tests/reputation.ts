@@ -1,8 +1,32 @@
 import * as anchor from '@project-serum/anchor';
 import { Program } from '@project-serum/anchor';
 import { Reputation } from '../target/types/reputation';
+import { Marketplace } from '../target/types/marketplace';
+import { Escrow } from '../target/types/escrow';
 import { expect } from 'chai';
 import { PublicKey, Keypair } from '@solana/web3.js';
+import { TOKEN_PROGRAM_ID } from '@solana/spl-token';
+import {
+  createProduct,
+  createFundedEscrow,
+  shipAndConfirm,
+  createReview,
+  productReputationFor,
+  findProductReputationPda,
+  completePurchase,
+  initReputation,
+  findMarketplacePda,
+  nextEscrowPdas,
+  escrowTrackingAccounts,
+  findBuyerEscrowCounterPda,
+  findSellerStatusPda,
+  findUserReputationPda,
+  findArbitratorRoutingPda,
+  findReputationConfigPda,
+  setReputationMarketplace,
+  fundedKeypair,
+  expectError,
+} from './helpers';
 
 describe('reputation', () => {
   // Configure the client to use the local cluster
@@ -10,6 +34,8 @@ describe('reputation', () => {
   anchor.setProvider(provider);
 
   const program = anchor.workspace.Reputation as Program<Reputation>;
+  const marketplaceProgram = anchor.workspace.Marketplace as Program<Marketplace>;
+  const escrowProgram = anchor.workspace.Escrow as Program<Escrow>;
   
   // Test accounts
   const marketplaceAuthority = provider.wallet;
@@ -19,6 +45,31 @@ describe('reputation', () => {
   // PDAs
   let userReputationPda: PublicKey;
   let reviewPda: PublicKey;
+  let marketplacePda: PublicKey;
+  const reputationConfig = findReputationConfigPda();
+
+  // Reviews reference completed escrows, so the suite needs a marketplace
+  before(async () => {
+    marketplacePda = findMarketplacePda(marketplaceProgram, marketplaceAuthority.publicKey);
+    try {
+      await marketplaceProgram.methods
+        .initializeMarketplace(250)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          feeDestination: marketplaceAuthority.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    } catch (e) {
+      // Marketplace might already be initialized by another suite
+    }
+    await setReputationMarketplace(program, marketplacePda);
+  });
+
+  // A completed purchase by the buyer from the seller
+  const purchase = (seller: Keypair, buyer: Keypair) =>
+    completePurchase(marketplaceProgram, escrowProgram, marketplacePda, seller, buyer);
   
   beforeEach(async () => {
     // Airdrop SOL to test accounts
@@ -95,33 +146,43 @@ describe('reputation', () => {
     // Review details
     const rating = 4; // 4 stars
     const comment = "Great seller, fast shipping and excellent product quality.";
-    const transactionReference = null; // No transaction reference for this test
+    const { escrow: transactionReference } = await purchase(user, reviewer);
     
-    // Find the review PDA with current timestamp for uniqueness
-    const timestamp = Clock.get().unix_timestamp;
+    // Find the review PDA for this transaction
     const [revPda, _] = await PublicKey.findProgramAddressSync(
       [
         Buffer.from("review"),
         reviewer.publicKey.toBuffer(),
         user.publicKey.toBuffer(),
-        new anchor.BN(timestamp).toArrayLike(Buffer, "le", 8)
+        transactionReference.toBuffer()
       ],
       program.programId
     );
     const currentReviewPda = revPda;
     
+    // The product has no rating aggregate yet; the review creates it
+    const { product } = await escrowProgram.account.escrow.fetch(transactionReference);
+    const productReputation = findProductReputationPda(program, product);
+    expect(await program.account.productReputation.fetchNullable(productReputation)).to.equal(null);
+    
     // Create the review
     await program.methods
       .createReview(
         rating,
         comment,
-        transactionReference
+        transactionReference,
+        [], // No attachments
+        { seller: {} } // Rated as the escrow's seller
       )
       .accounts({
         author: reviewer.publicKey,
         recipient: user.publicKey,
         userReputation: userReputationPda,
         review: currentReviewPda,
+        escrow: transactionReference,
+        product,
+        productReputation,
+        authorReputation: null,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([reviewer])
@@ -142,6 +203,12 @@ describe('reputation', () => {
     // Verify the user reputation was updated
     expect(userReputation.totalRating.toNumber()).to.equal(rating);
     expect(userReputation.reviewCount.toNumber()).to.equal(1);
+    
+    // And the product's rating aggregate was created with it
+    const productRating = await program.account.productReputation.fetch(productReputation);
+    expect(productRating.product.toString()).to.equal(product.toString());
+    expect(productRating.totalRating.toNumber()).to.equal(rating);
+    expect(productRating.reviewCount.toNumber()).to.equal(1);
   });
 
   it('Verifies a user', async () => {
@@ -156,15 +223,13 @@ describe('reputation', () => {
       .signers([user])
       .rpc();
     
-    // Create a mock marketplace account
-    const marketplace = Keypair.generate();
-    
     // Verify the user
     await program.methods
       .verifyUser()
       .accounts({
         authority: marketplaceAuthority.publicKey,
-        marketplace: marketplace.publicKey,
+        marketplace: marketplacePda,
+        reputationConfig,
         userReputation: userReputationPda,
       })
       .rpc();
@@ -176,6 +241,178 @@ describe('reputation', () => {
     expect(userReputation.isVerified).to.equal(true);
   });
 
+  it('Lets only the marketplace authority verify users and record sales', async () => {
+    const member = await fundedKeypair(provider, 1);
+    const impostor = await fundedKeypair(provider, 1);
+    const memberReputation = await initReputation(program, member);
+
+    await expectError(
+      program.methods
+        .verifyUser()
+        .accounts({ authority: impostor.publicKey, marketplace: marketplacePda, userReputation: memberReputation })
+        .signers([impostor])
+        .rpc(),
+      "UnauthorizedAuthority"
+    );
+    await expectError(
+      program.methods
+        .recordSale()
+        .accounts({
+          payer: impostor.publicKey,
+          authority: impostor.publicKey,
+          marketplace: marketplacePda,
+          reputationConfig,
+          user: member.publicKey,
+          userReputation: memberReputation,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([impostor])
+        .rpc(),
+      "UnauthorizedAuthority"
+    );
+    let reputation = await program.account.userReputation.fetch(memberReputation);
+    expect(reputation.isVerified).to.equal(false);
+    expect(reputation.totalSales.toNumber()).to.equal(0);
+
+    await program.methods
+      .verifyUser()
+      .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda, reputationConfig, userReputation: memberReputation })
+      .rpc();
+    reputation = await program.account.userReputation.fetch(memberReputation);
+    expect(reputation.isVerified).to.equal(true);
+  });
+
+  it('Revokes a verification and records when', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const sellerReputation = await initReputation(program, seller);
+    const accounts = { authority: marketplaceAuthority.publicKey, marketplace: marketplacePda, reputationConfig, userReputation: sellerReputation };
+
+    await program.methods.verifyUser().accounts(accounts).rpc();
+    let reputation = await program.account.userReputation.fetch(sellerReputation);
+    expect(reputation.isVerified).to.equal(true);
+    expect(reputation.unverifiedAt).to.equal(null);
+
+    const before = Math.floor(Date.now() / 1000);
+    await program.methods.unverifyUser().accounts(accounts).rpc();
+    reputation = await program.account.userReputation.fetch(sellerReputation);
+    expect(reputation.isVerified).to.equal(false);
+    expect(reputation.unverifiedAt.toNumber()).to.be.closeTo(before, 60);
+
+    const impostor = await fundedKeypair(provider, 1);
+    await expectError(
+      program.methods
+        .unverifyUser()
+        .accounts({ ...accounts, authority: impostor.publicKey })
+        .signers([impostor])
+        .rpc(),
+      "UnauthorizedAuthority"
+    );
+  });
+
+  it('Ignores reputation changes from a marketplace anyone can create', async () => {
+    const attacker = await fundedKeypair(provider, 1);
+    const victim = await fundedKeypair(provider, 1);
+    const victimReputation = await initReputation(program, victim);
+    const attackerMarketplace = findMarketplacePda(marketplaceProgram, attacker.publicKey);
+    await marketplaceProgram.methods
+      .initializeMarketplace(0)
+      .accounts({
+        authority: attacker.publicKey,
+        marketplace: attackerMarketplace,
+        feeDestination: attacker.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([attacker])
+      .rpc();
+
+    const verification = { authority: attacker.publicKey, marketplace: attackerMarketplace, reputationConfig, userReputation: victimReputation };
+    await expectError(
+      program.methods.verifyUser().accounts(verification).signers([attacker]).rpc(),
+      "UntrustedMarketplace"
+    );
+    await expectError(
+      program.methods.unverifyUser().accounts(verification).signers([attacker]).rpc(),
+      "UntrustedMarketplace"
+    );
+
+    const transactionCount = {
+      payer: attacker.publicKey,
+      authority: attacker.publicKey,
+      reputationConfig,
+      marketplace: attackerMarketplace,
+      user: victim.publicKey,
+      userReputation: victimReputation,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+    await expectError(
+      program.methods.recordSale().accounts(transactionCount).signers([attacker]).rpc(),
+      "UnauthorizedAuthority"
+    );
+    await expectError(
+      program.methods.recordPurchase().accounts(transactionCount).signers([attacker]).rpc(),
+      "UnauthorizedAuthority"
+    );
+
+    const [verificationConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("verification_config"), attackerMarketplace.toBuffer()],
+      program.programId
+    );
+    await expectError(
+      program.methods
+        .setVerificationThresholds(new anchor.BN(0), new anchor.BN(0), new anchor.BN("18446744073709551615"))
+        .accounts({
+          authority: attacker.publicKey,
+          marketplace: attackerMarketplace,
+          reputationConfig,
+          verificationConfig,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([attacker])
+        .rpc(),
+      "UntrustedMarketplace"
+    );
+
+    // Only the config's admin chooses the trusted marketplace or a new admin
+    await expectError(
+      program.methods
+        .setReputationMarketplace()
+        .accounts({ admin: attacker.publicKey, marketplace: attackerMarketplace, reputationConfig })
+        .signers([attacker])
+        .rpc(),
+      "UnauthorizedAuthority"
+    );
+    await expectError(
+      program.methods
+        .setReputationAdmin(attacker.publicKey)
+        .accounts({ admin: attacker.publicKey, reputationConfig })
+        .signers([attacker])
+        .rpc(),
+      "UnauthorizedAuthority"
+    );
+    await expectError(
+      program.methods
+        .initializeReputationConfig()
+        .accounts({
+          admin: attacker.publicKey,
+          marketplace: attackerMarketplace,
+          reputationConfig,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([attacker])
+        .rpc(),
+      "already in use"
+    );
+    const config = await program.account.reputationConfig.fetch(reputationConfig);
+    expect(config.admin.toString()).to.equal(provider.wallet.publicKey.toString());
+    expect(config.marketplace.toString()).to.equal(marketplacePda.toString());
+
+    const reputation = await program.account.userReputation.fetch(victimReputation);
+    expect(reputation.isVerified).to.equal(false);
+    expect(reputation.unverifiedAt).to.equal(null);
+    expect(reputation.totalSales.toNumber()).to.equal(0);
+    expect(reputation.totalPurchases.toNumber()).to.equal(0);
+  });
+
   it('Records a sale for a user', async () => {
     // Initialize user reputation first
     await program.methods
@@ -188,16 +425,17 @@ describe('reputation', () => {
       .signers([user])
       .rpc();
     
-    // Create a mock marketplace account
-    const marketplace = Keypair.generate();
-    
     // Record a sale
     await program.methods
       .recordSale()
       .accounts({
+        payer: marketplaceAuthority.publicKey,
         authority: marketplaceAuthority.publicKey,
-        marketplace: marketplace.publicKey,
+        marketplace: marketplacePda,
+        reputationConfig,
+        user: user.publicKey,
         userReputation: userReputationPda,
+        systemProgram: anchor.web3.SystemProgram.programId,
       })
       .rpc();
       
@@ -207,4 +445,1044 @@ describe('reputation', () => {
     // Verify the sale count was incremented
     expect(userReputation.totalSales.toNumber()).to.equal(1);
   });
-}); 
\ No newline at end of file
+
+  it('Creates a reputation on the first recorded sale', async () => {
+    const newSeller = Keypair.generate();
+    const [newSellerReputation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_reputation"), newSeller.publicKey.toBuffer()],
+      program.programId
+    );
+    const recordSale = () =>
+      program.methods
+        .recordSale()
+        .accounts({
+          payer: marketplaceAuthority.publicKey,
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          reputationConfig,
+          user: newSeller.publicKey,
+          userReputation: newSellerReputation,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    await recordSale();
+    let reputation = await program.account.userReputation.fetch(newSellerReputation);
+    expect(reputation.user.toString()).to.equal(newSeller.publicKey.toString());
+    expect(reputation.totalSales.toNumber()).to.equal(1);
+    const createdAt = reputation.createdAt.toNumber();
+
+    // Later sales count against the existing account rather than resetting it
+    await recordSale();
+    reputation = await program.account.userReputation.fetch(newSellerReputation);
+    expect(reputation.totalSales.toNumber()).to.equal(2);
+    expect(reputation.createdAt.toNumber()).to.equal(createdAt);
+  });
+
+  it('Raises the seller tier as sales cross each threshold', async () => {
+    const grower = Keypair.generate();
+    const [growerReputation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_reputation"), grower.publicKey.toBuffer()],
+      program.programId
+    );
+    const recordSale = () =>
+      program.methods
+        .recordSale()
+        .accounts({
+          payer: marketplaceAuthority.publicKey,
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          reputationConfig,
+          user: grower.publicKey,
+          userReputation: growerReputation,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        });
+    let totalSales = 0;
+    // Several sales per transaction keep the run to the Gold threshold short
+    const recordSalesUpTo = async (target: number) => {
+      while (totalSales < target) {
+        const batch = Math.min(10, target - totalSales);
+        const extraSales = await Promise.all(
+          Array.from({ length: batch - 1 }, () => recordSale().instruction())
+        );
+        await recordSale().postInstructions(extraSales).rpc();
+        totalSales += batch;
+      }
+      return (await program.account.userReputation.fetch(growerReputation)).tier;
+    };
+
+    const thresholds: [number, object][] = [
+      [9, { unranked: {} }],
+      [10, { bronze: {} }],
+      [49, { bronze: {} }],
+      [50, { silver: {} }],
+      [199, { silver: {} }],
+      [200, { gold: {} }],
+    ];
+    for (const [sales, tier] of thresholds) {
+      expect(await recordSalesUpTo(sales)).to.deep.equal(tier);
+    }
+  });
+
+  describe('with marketplace escrows', () => {
+    // Fund an escrow, dispute it and resolve the dispute
+    const disputedEscrow = async (favorSeller: boolean) => {
+      const seller = await fundedKeypair(provider);
+      const buyer = await fundedKeypair(provider);
+      const sellerReputation = await initReputation(program, seller);
+      const buyerReputation = await initReputation(program, buyer);
+      const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, buyer);
+
+      await escrowProgram.methods
+        .disputeTransaction("Item never arrived", { notReceived: {} })
+        .accounts({ user: buyer.publicKey, escrow: pdas.escrow })
+        .signers([buyer])
+        .rpc();
+      await escrowProgram.methods
+        .resolveDispute(favorSeller, 0)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          buyer: buyer.publicKey,
+          seller: seller.publicKey,
+          escrow: pdas.escrow,
+          escrowVault: pdas.escrowVault,
+          buyerTokenAccount: null,
+          sellerTokenAccount: null,
+          escrowTokenAccount: null,
+          sellerBond: null,
+          product,
+          arbitratorRouting: findArbitratorRoutingPda(marketplacePda),
+          loserReputation: favorSeller ? buyerReputation : sellerReputation,
+          sellerReputation: null,
+          buyerReputation: null,
+          buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, buyer.publicKey),
+          feeDestination: marketplaceAuthority.publicKey,
+          ...escrowTrackingAccounts(escrowProgram),
+          reputationProgram: program.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      return { seller, buyer, sellerReputation, buyerReputation, escrow: pdas.escrow };
+    };
+
+    const removeReview = async (buyer: Keypair, review: PublicKey, escrow: PublicKey, sellerReputation: PublicKey) =>
+      program.methods
+        .removeReview()
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          author: buyer.publicKey,
+          review,
+          escrow,
+          userReputation: sellerReputation,
+          productReputation: await productReputationFor(program, escrow),
+        })
+        .rpc();
+
+    it('Removes a retaliatory review after a seller-favored dispute', async () => {
+      const { seller, buyer, sellerReputation, escrow } = await disputedEscrow(true);
+      const review = await createReview(program, buyer, seller.publicKey, 1, "Terrible", escrow);
+
+      await removeReview(buyer, review, escrow, sellerReputation);
+
+      expect(await provider.connection.getAccountInfo(review)).to.equal(null);
+      const reputation = await program.account.userReputation.fetch(sellerReputation);
+      expect(reputation.reviewCount.toNumber()).to.equal(0);
+      expect(reputation.totalRating.toNumber()).to.equal(0);
+    });
+
+    it('Keeps reviews of purchases without a seller-favored dispute', async () => {
+      const seller = await fundedKeypair(provider);
+      const buyer = await fundedKeypair(provider);
+      const sellerReputation = await initReputation(program, seller);
+      const { escrow } = await purchase(seller, buyer);
+      const review = await createReview(program, buyer, seller.publicKey, 1, "Terrible", escrow);
+
+      await expectError(removeReview(buyer, review, escrow, sellerReputation), "ReviewNotRemovable");
+    });
+
+    it('Aggregates product ratings separately from the seller rating', async () => {
+      const seller = await fundedKeypair(provider);
+      const sellerReputation = await initReputation(program, seller);
+
+      // Two products from the same seller, each with its own rating aggregate
+      const listProduct = async () => {
+        const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+        const [productReputation] = PublicKey.findProgramAddressSync(
+          [Buffer.from("product_reputation"), product.toBuffer()],
+          program.programId
+        );
+        await program.methods
+          .initializeProductReputation()
+          .accounts({
+            payer: provider.wallet.publicKey,
+            product,
+            productReputation,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        return { product, productReputation };
+      };
+      const apples = await listProduct();
+      const pears = await listProduct();
+
+      const rate = async (listing: { product: PublicKey; productReputation: PublicKey }, rating: number) => {
+        const buyer = await fundedKeypair(provider);
+        const pdas = await createFundedEscrow(escrowProgram, marketplacePda, listing.product, buyer);
+        await shipAndConfirm(escrowProgram, pdas, buyer, seller);
+        await createReview(program, buyer, seller.publicKey, rating, "Rated", pdas.escrow, listing.productReputation);
+      };
+      await rate(apples, 5);
+      await rate(pears, 2);
+      await rate(apples, 3);
+
+      const productRating = (productReputation: PublicKey) =>
+        program.methods.productRating().accounts({ productReputation }).view();
+      expect((await productRating(apples.productReputation)).toNumber()).to.equal(400);
+      expect((await productRating(pears.productReputation)).toNumber()).to.equal(200);
+
+      const reputation = await program.account.userReputation.fetch(sellerReputation);
+      expect(reputation.reviewCount.toNumber()).to.equal(3);
+      expect(reputation.totalRating.toNumber()).to.equal(10);
+    });
+
+    it('Rates the product with the buyer\'s review and only with it', async () => {
+      const seller = await fundedKeypair(provider, 1);
+      const buyer = await fundedKeypair(provider, 1);
+      await initReputation(program, seller);
+      await initReputation(program, buyer);
+      const { escrow } = await purchase(seller, buyer);
+      const productReputation = await productReputationFor(program, escrow);
+
+      // The buyer's review cannot leave the product's aggregate out
+      const [buyerReview] = PublicKey.findProgramAddressSync(
+        [Buffer.from("review"), buyer.publicKey.toBuffer(), seller.publicKey.toBuffer(), escrow.toBuffer()],
+        program.programId
+      );
+      await expectError(
+        program.methods
+          .createReview(5, "Crisp", escrow, [], { seller: {} })
+          .accounts({
+            author: buyer.publicKey,
+            recipient: seller.publicKey,
+            userReputation: findUserReputationPda(seller.publicKey),
+            review: buyerReview,
+            escrow,
+            product: (await escrowProgram.account.escrow.fetch(escrow)).product,
+            productReputation: null,
+            authorReputation: null,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([buyer])
+          .rpc(),
+        "InvalidProductReputation"
+      );
+
+      // And the seller's review of the buyer cannot rate the product
+      await expectError(
+        createReview(program, seller, buyer.publicKey, 1, "Haggled", escrow, productReputation),
+        "InvalidProductReputation"
+      );
+
+      await createReview(program, buyer, seller.publicKey, 5, "Crisp", escrow);
+      const sellerReview = await createReview(program, seller, buyer.publicKey, 4, "Paid promptly", escrow);
+      expect((await program.account.review.fetch(buyerReview)).countedTowardProduct).to.equal(true);
+      expect((await program.account.review.fetch(sellerReview)).countedTowardProduct).to.equal(false);
+
+      // Edits of the seller's review leave the product's aggregate alone
+      await expectError(
+        program.methods
+          .editReview(2, "Haggled after all")
+          .accounts({
+            author: seller.publicKey,
+            review: sellerReview,
+            userReputation: findUserReputationPda(buyer.publicKey),
+            escrow,
+            marketplace: null,
+            productReputation,
+          })
+          .signers([seller])
+          .rpc(),
+        "InvalidProductReputation"
+      );
+      const product = await program.account.productReputation.fetch(productReputation);
+      expect(product.totalRating.toNumber()).to.equal(5);
+      expect(product.reviewCount.toNumber()).to.equal(1);
+    });
+
+    it('Lets users who meet the thresholds verify themselves', async () => {
+      const [verificationConfig] = PublicKey.findProgramAddressSync(
+        [Buffer.from("verification_config"), marketplacePda.toBuffer()],
+        program.programId
+      );
+      await program.methods
+        .setVerificationThresholds(new anchor.BN(2), new anchor.BN(450), new anchor.BN(0))
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          reputationConfig,
+          verificationConfig,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      // A seller with the given sales and ratings
+      const sellerWith = async (sales: number, ratings: number[]) => {
+        const seller = await fundedKeypair(provider, 1);
+        const [userReputation] = PublicKey.findProgramAddressSync(
+          [Buffer.from("user_reputation"), seller.publicKey.toBuffer()],
+          program.programId
+        );
+        for (let i = 0; i < sales; i++) {
+          await program.methods
+            .recordSale()
+            .accounts({
+              payer: marketplaceAuthority.publicKey,
+              authority: marketplaceAuthority.publicKey,
+              marketplace: marketplacePda,
+              reputationConfig,
+              user: seller.publicKey,
+              userReputation,
+              systemProgram: anchor.web3.SystemProgram.programId,
+            })
+            .rpc();
+        }
+        for (const rating of ratings) {
+          const reviewer = await fundedKeypair(provider, 1);
+          const { escrow } = await purchase(seller, reviewer);
+          await createReview(program, reviewer, seller.publicKey, rating, "Rated", escrow);
+        }
+        return userReputation;
+      };
+      const autoVerify = (userReputation: PublicKey) =>
+        program.methods.autoVerify().accounts({ verificationConfig, reputationConfig, userReputation }).rpc();
+
+      const established = await sellerWith(2, [5, 4]);
+      await autoVerify(established);
+      expect((await program.account.userReputation.fetch(established)).isVerified).to.equal(true);
+
+      const newcomer = await sellerWith(1, [5]);
+      await expectError(autoVerify(newcomer), "VerificationThresholdsNotMet");
+      expect((await program.account.userReputation.fetch(newcomer)).isVerified).to.equal(false);
+    });
+
+    it('Marks reviews of completed purchases as verified', async () => {
+      const seller = await fundedKeypair(provider);
+      const buyer = await fundedKeypair(provider);
+      await initReputation(program, seller);
+      const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, buyer);
+      await shipAndConfirm(escrowProgram, pdas, buyer, seller);
+
+      const review = await createReview(program, buyer, seller.publicKey, 5, "Crisp and fresh", pdas.escrow);
+
+      const account = await program.account.review.fetch(review);
+      expect(account.verifiedPurchase).to.equal(true);
+      expect(account.transactionReference.toString()).to.equal(pdas.escrow.toString());
+    });
+
+    it('Counts purchases toward the buyer only once reviewed when required', async () => {
+      const seller = await fundedKeypair(provider);
+      const buyer = await fundedKeypair(provider);
+      await initReputation(program, seller);
+      const buyerReputation = await initReputation(program, buyer);
+      const setRequired = (required: boolean) =>
+        marketplaceProgram.methods
+          .setRequirePurchaseReviews(required)
+          .accounts({ authority: marketplaceAuthority.publicKey, marketplace: marketplacePda })
+          .rpc();
+      const completedPurchase = async () => {
+        const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+        const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, buyer);
+        await shipAndConfirm(escrowProgram, pdas, buyer, seller);
+        await program.methods
+          .recordPurchase()
+          .accounts({
+            payer: marketplaceAuthority.publicKey,
+            authority: marketplaceAuthority.publicKey,
+            marketplace: marketplacePda,
+            reputationConfig,
+            user: buyer.publicKey,
+            userReputation: buyerReputation,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        return pdas;
+      };
+
+      await setRequired(true);
+      try {
+        const reviewed = await completedPurchase();
+        await completedPurchase();
+        let reputation = await program.account.userReputation.fetch(buyerReputation);
+        expect(reputation.totalPurchases.toNumber()).to.equal(0);
+        expect(reputation.pendingReviewPurchases.toNumber()).to.equal(2);
+
+        // Only the reviewed purchase counts
+        await createReview(program, buyer, seller.publicKey, 5, "Sweet and ripe", reviewed.escrow, null, [], buyerReputation);
+        reputation = await program.account.userReputation.fetch(buyerReputation);
+        expect(reputation.totalPurchases.toNumber()).to.equal(1);
+        expect(reputation.pendingReviewPurchases.toNumber()).to.equal(1);
+      } finally {
+        await setRequired(false);
+      }
+    });
+
+    it('Rejects reviews without a completed escrow between the parties', async () => {
+      const seller = await fundedKeypair(provider);
+      const buyer = await fundedKeypair(provider);
+      const stranger = await fundedKeypair(provider);
+      await initReputation(program, seller);
+
+      // An escrow still awaiting delivery cannot be reviewed
+      const product = await createProduct(marketplaceProgram, marketplacePda, seller);
+      const pending = await createFundedEscrow(escrowProgram, marketplacePda, product, buyer);
+      await expectError(
+        createReview(program, buyer, seller.publicKey, 4, "Not here yet", pending.escrow),
+        "EscrowNotCompleted"
+      );
+
+      // Nor can one refunded to the buyer after a dispute
+      const refunded = await disputedEscrow(false);
+      await expectError(
+        createReview(program, refunded.buyer, refunded.seller.publicKey, 1, "Never came", refunded.escrow),
+        "EscrowNotCompleted"
+      );
+
+      // A completed escrow only admits reviews between its buyer and seller
+      const { escrow } = await purchase(seller, buyer);
+      await expectError(
+        createReview(program, stranger, seller.publicKey, 4, "Heard good things", escrow),
+        "InvalidEscrowAccount"
+      );
+      await expectError(
+        createReview(program, buyer, stranger.publicKey, 4, "Wrong farm", escrow),
+        "InvalidEscrowAccount"
+      );
+
+      // Sellers may review their buyers, without a verified purchase badge
+      const review = await createReview(program, seller, buyer.publicKey, 5, "Paid promptly", escrow);
+      expect((await program.account.review.fetch(review)).verifiedPurchase).to.equal(false);
+    });
+
+    it('Lets the buyer revise a review once after an approved return', async () => {
+      const seller = await fundedKeypair(provider);
+      const buyer = await fundedKeypair(provider);
+      const sellerReputation = await initReputation(program, seller);
+      const product = await createProduct(marketplaceProgram, marketplacePda, seller, {
+        returnWindowSeconds: new anchor.BN(3600),
+      });
+      const pdas = await createFundedEscrow(escrowProgram, marketplacePda, product, buyer);
+      await shipAndConfirm(escrowProgram, pdas, buyer, seller);
+      const review = await createReview(program, buyer, seller.publicKey, 2, "Arrived bruised", pdas.escrow);
+
+      await escrowProgram.methods
+        .requestReturn()
+        .accounts({ buyer: buyer.publicKey, escrow: pdas.escrow })
+        .signers([buyer])
+        .rpc();
+      await escrowProgram.methods
+        .approveReturn()
+        .accounts({
+          seller: seller.publicKey,
+          buyer: buyer.publicKey,
+          escrow: pdas.escrow,
+          sellerTokenAccount: null,
+          buyerTokenAccount: null,
+          sellerReputation,
+          buyerReputation: findUserReputationPda(buyer.publicKey),
+          escrowAuthority: escrowTrackingAccounts(escrowProgram).escrowAuthority,
+          reputationProgram: program.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([seller])
+        .rpc();
+
+      const productReputation = await productReputationFor(program, pdas.escrow);
+      const editReview = (rating: number, comment: string) =>
+        program.methods
+          .editReview(rating, comment)
+          .accounts({
+            author: buyer.publicKey,
+            review,
+            userReputation: sellerReputation,
+            escrow: pdas.escrow,
+            marketplace: marketplacePda,
+            productReputation,
+          })
+          .signers([buyer])
+          .rpc();
+
+      // The return re-opens the review regardless of the normal edit window
+      await editReview(5, "Refunded without any fuss");
+      const reviewAccount = await program.account.review.fetch(review);
+      expect(reviewAccount.rating).to.equal(5);
+      expect(reviewAccount.returnEditUsed).to.equal(true);
+      const reputation = await program.account.userReputation.fetch(sellerReputation);
+      expect(reputation.totalRating.toNumber()).to.equal(5);
+      expect(reputation.reviewCount.toNumber()).to.equal(1);
+
+      // But only once
+      await expectError(editReview(4, "Changed my mind"), "ReviewEditWindowClosed");
+    });
+
+    it('Imports historical reviews flagged as imported', async () => {
+      const farmA = await fundedKeypair(provider, 1);
+      const farmB = await fundedKeypair(provider, 1);
+      const farmAReputation = await initReputation(program, farmA);
+      const farmBReputation = await initReputation(program, farmB);
+
+      const yearAgo = Math.floor(Date.now() / 1000) - 365 * 24 * 60 * 60;
+      const reviews = [
+        { recipient: farmA.publicKey, rating: 5, comment: "Best berries", createdAt: yearAgo },
+        { recipient: farmA.publicKey, rating: 3, comment: "Late delivery", createdAt: yearAgo + 60 },
+        { recipient: farmB.publicKey, rating: 4, comment: "Fresh eggs", createdAt: yearAgo + 120 },
+      ].map((review) => ({
+        ...review,
+        author: Keypair.generate().publicKey,
+        createdAt: new anchor.BN(review.createdAt),
+      }));
+      const reviewPdas = reviews.map((review) =>
+        PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("imported_review"),
+            review.author.toBuffer(),
+            review.recipient.toBuffer(),
+            review.createdAt.toArrayLike(Buffer, "le", 8),
+          ],
+          program.programId
+        )[0]
+      );
+      const reputations = [farmAReputation, farmAReputation, farmBReputation];
+
+      await program.methods
+        .importReviewsBatch(reviews)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          reviewPdas.flatMap((review, i) => [
+            { pubkey: review, isWritable: true, isSigner: false },
+            { pubkey: reputations[i], isWritable: true, isSigner: false },
+          ])
+        )
+        .rpc();
+
+      const farmAAccount = await program.account.userReputation.fetch(farmAReputation);
+      expect(farmAAccount.reviewCount.toNumber()).to.equal(2);
+      expect(farmAAccount.totalRating.toNumber()).to.equal(8);
+      const farmBAccount = await program.account.userReputation.fetch(farmBReputation);
+      expect(farmBAccount.reviewCount.toNumber()).to.equal(1);
+      expect(farmBAccount.totalRating.toNumber()).to.equal(4);
+
+      const imported = await program.account.review.fetch(reviewPdas[1]);
+      expect(imported.imported).to.equal(true);
+      expect(imported.comment).to.equal("Late delivery");
+      expect(imported.createdAt.toNumber()).to.equal(yearAgo + 60);
+    });
+
+    it('Detects and corrects a stored average that drifted from the aggregates', async () => {
+      const farm = await fundedKeypair(provider, 1);
+      const farmReputation = await initReputation(program, farm);
+      const review = {
+        author: Keypair.generate().publicKey,
+        recipient: farm.publicKey,
+        rating: 4,
+        comment: "Sweet corn",
+        createdAt: new anchor.BN(Math.floor(Date.now() / 1000) - 3600),
+      };
+      const [reviewPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("imported_review"),
+          review.author.toBuffer(),
+          review.recipient.toBuffer(),
+          review.createdAt.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+
+      // Imports leave the stored average behind the aggregates
+      await program.methods
+        .importReviewsBatch([review])
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts([
+          { pubkey: reviewPda, isWritable: true, isSigner: false },
+          { pubkey: farmReputation, isWritable: true, isSigner: false },
+        ])
+        .rpc();
+      expect(
+        (await program.account.userReputation.fetch(farmReputation)).averageRatingX100
+      ).to.equal(0);
+
+      const verify = () =>
+        program.methods.verifyReputationConsistency().accounts({ userReputation: farmReputation });
+      expect(await verify().view()).to.equal(true);
+      await verify().rpc();
+
+      expect(
+        (await program.account.userReputation.fetch(farmReputation)).averageRatingX100
+      ).to.equal(400);
+      expect(await verify().view()).to.equal(false);
+    });
+
+    it('Weights recent ratings above old ones in the weighted score', async () => {
+      const improved = await fundedKeypair(provider, 1);
+      const declined = await fundedKeypair(provider, 1);
+      const improvedReputation = await initReputation(program, improved);
+      const declinedReputation = await initReputation(program, declined);
+
+      const now = Math.floor(Date.now() / 1000);
+      const twoYearsAgo = now - 2 * 365 * 24 * 60 * 60;
+      const yesterday = now - 24 * 60 * 60;
+      // The declined farm's reviews arrive newest first, so the old rating
+      // is decayed into aggregates already as of the newer one
+      const reviews = [
+        { recipient: improved.publicKey, rating: 1, comment: "Bruised", createdAt: twoYearsAgo },
+        { recipient: improved.publicKey, rating: 5, comment: "Perfect", createdAt: yesterday },
+        { recipient: declined.publicKey, rating: 1, comment: "Bruised", createdAt: yesterday },
+        { recipient: declined.publicKey, rating: 5, comment: "Perfect", createdAt: twoYearsAgo },
+      ].map((review) => ({
+        ...review,
+        author: Keypair.generate().publicKey,
+        createdAt: new anchor.BN(review.createdAt),
+      }));
+      const reviewPdas = reviews.map((review) =>
+        PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("imported_review"),
+            review.author.toBuffer(),
+            review.recipient.toBuffer(),
+            review.createdAt.toArrayLike(Buffer, "le", 8),
+          ],
+          program.programId
+        )[0]
+      );
+      const reputations = [improvedReputation, improvedReputation, declinedReputation, declinedReputation];
+
+      await program.methods
+        .importReviewsBatch(reviews)
+        .accounts({
+          authority: marketplaceAuthority.publicKey,
+          marketplace: marketplacePda,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          reviewPdas.flatMap((review, i) => [
+            { pubkey: review, isWritable: true, isSigner: false },
+            { pubkey: reputations[i], isWritable: true, isSigner: false },
+          ])
+        )
+        .rpc();
+      for (const userReputation of [improvedReputation, declinedReputation]) {
+        await program.methods.verifyReputationConsistency().accounts({ userReputation }).rpc();
+      }
+
+      // Both farms average three stars, but the weighted score follows the latest rating
+      const improvedAccount = await program.account.userReputation.fetch(improvedReputation);
+      expect(improvedAccount.averageRatingX100).to.equal(300);
+      expect(improvedAccount.weightedScoreX100.toNumber()).to.be.greaterThan(490);
+      expect(improvedAccount.weightedAsOf.toNumber()).to.equal(yesterday);
+      const declinedAccount = await program.account.userReputation.fetch(declinedReputation);
+      expect(declinedAccount.averageRatingX100).to.equal(300);
+      expect(declinedAccount.weightedScoreX100.toNumber()).to.be.lessThan(110);
+      expect(declinedAccount.weightedAsOf.toNumber()).to.equal(yesterday);
+    });
+
+    it('Blocks buyers with too many lost disputes from limited products', async () => {
+      const { buyer: riskyBuyer, buyerReputation } = await disputedEscrow(true);
+      expect(
+        (await program.account.userReputation.fetch(buyerReputation)).disputesLost.toNumber()
+      ).to.equal(1);
+
+      const seller = await fundedKeypair(provider);
+      const product = await createProduct(marketplaceProgram, marketplacePda, seller, {
+        maxBuyerLostDisputes: new anchor.BN(0),
+      });
+      const createEscrow = async (buyer: Keypair, reputation: PublicKey | null) =>
+        escrowProgram.methods
+          .createEscrow(new anchor.BN(1), null, null)
+          .accounts({
+            buyer: buyer.publicKey,
+            marketplace: marketplacePda,
+            product,
+            escrow: (await nextEscrowPdas(escrowProgram, marketplacePda, buyer.publicKey, product)).escrow,
+            buyerReputation: reputation,
+            coupon: null,
+            mint: null,
+            sellerStatus: findSellerStatusPda(marketplacePda, seller.publicKey),
+            sellerReputation: findUserReputationPda(seller.publicKey),
+            buyerEscrowCounter: findBuyerEscrowCounterPda(marketplacePda, buyer.publicKey),
+            ...escrowTrackingAccounts(escrowProgram),
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([buyer])
+          .rpc();
+
+      await expectError(createEscrow(riskyBuyer, buyerReputation), "BuyerTooRisky");
+
+      const cleanBuyer = await fundedKeypair(provider);
+      await expectError(createEscrow(cleanBuyer, null), "BuyerReputationRequired");
+      await createEscrow(cleanBuyer, await initReputation(program, cleanBuyer));
+    });
+  });
+
+  it('Measures comment length in bytes for multibyte strings', async () => {
+    const recipient = await fundedKeypair(provider, 1);
+    const author = await fundedKeypair(provider, 1);
+    await initReputation(program, recipient);
+    const { escrow } = await purchase(recipient, author);
+
+    await expectError(
+      createReview(program, author, recipient.publicKey, 5, "🌾".repeat(126), escrow),
+      "CommentTooLong"
+    );
+
+    // 125 four-byte characters fill the 500 byte limit exactly
+    const review = await createReview(program, author, recipient.publicKey, 5, "🌾".repeat(125), escrow);
+    expect((await program.account.review.fetch(review)).comment).to.equal("🌾".repeat(125));
+  });
+
+  it('Allows only one review per author and transaction', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const buyer = await fundedKeypair(provider, 1);
+    const sellerReputation = await initReputation(program, seller);
+    const { escrow } = await purchase(seller, buyer);
+
+    await createReview(program, buyer, seller.publicKey, 5, "Lovely peaches", escrow);
+    await expectError(
+      createReview(program, buyer, seller.publicKey, 5, "Lovely peaches", escrow),
+      "already in use"
+    );
+
+    const reputation = await program.account.userReputation.fetch(sellerReputation);
+    expect(reputation.reviewCount.toNumber()).to.equal(1);
+    expect(reputation.totalRating.toNumber()).to.equal(5);
+  });
+
+  it('Rejects self-reviews but not reviews of other users', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const buyer = await fundedKeypair(provider, 1);
+    const { escrow } = await purchase(seller, buyer);
+
+    await expectError(
+      createReview(program, seller, seller.publicKey, 5, "Best seller around", escrow),
+      "SelfReviewNotAllowed"
+    );
+    const review = await createReview(program, buyer, seller.publicKey, 5, "Fresh and on time", escrow);
+    expect((await program.account.review.fetch(review)).author.toString()).to.equal(buyer.publicKey.toString());
+  });
+
+  it('Stores review attachments and rejects too many or malformed URIs', async () => {
+    const recipient = await fundedKeypair(provider, 1);
+    const author = await fundedKeypair(provider, 1);
+    const attachments = [
+      "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+      "ar://bNbA3TEQVL60xlgCcqdz4ZPHFZ711cZ3hmkpGttDt_U",
+      "https://photos.example.com/crate-as-received.jpg",
+    ];
+    const { escrow } = await purchase(recipient, author);
+
+    await expectError(
+      createReview(program, author, recipient.publicKey, 4, "Too many", escrow, null, [...attachments, attachments[0]]),
+      "TooManyAttachments"
+    );
+    await expectError(
+      createReview(program, author, recipient.publicKey, 4, "Bad scheme", escrow, null, ["ftp://example.com/a.jpg"]),
+      "InvalidAttachmentUri"
+    );
+    await expectError(
+      createReview(program, author, recipient.publicKey, 4, "Too long", escrow, null, ["https://" + "a".repeat(193)]),
+      "InvalidAttachmentUri"
+    );
+
+    const review = await createReview(program, author, recipient.publicKey, 4, "Slightly bruised", escrow, null, attachments);
+    expect((await program.account.review.fetch(review)).attachmentUris).to.deep.equal(attachments);
+  });
+
+  it('Stores the average rating in hundredths of a star', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const sellerReputation = await initReputation(program, seller);
+    expect(
+      (await program.account.userReputation.fetch(sellerReputation)).averageRatingX100
+    ).to.equal(0);
+
+    for (const rating of [5, 4, 3]) {
+      const buyer = await fundedKeypair(provider, 1);
+      const { escrow } = await purchase(seller, buyer);
+      await createReview(program, buyer, seller.publicKey, rating, "Rated", escrow);
+    }
+
+    const reputation = await program.account.userReputation.fetch(sellerReputation);
+    expect(reputation.reviewCount.toNumber()).to.equal(3);
+    expect(reputation.averageRatingX100).to.equal(400);
+  });
+
+  it('Lets authors edit a review within a day of posting it', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const buyer = await fundedKeypair(provider, 1);
+    const sellerReputation = await initReputation(program, seller);
+    const { escrow } = await purchase(seller, buyer);
+    const review = await createReview(program, buyer, seller.publicKey, 2, "Seemed small", escrow);
+
+    const productReputation = await productReputationFor(program, escrow);
+    const editReview = (
+      author: Keypair,
+      review: PublicKey,
+      rating: number,
+      comment: string,
+      counted: { escrow: PublicKey; productReputation: PublicKey } | null = null
+    ) =>
+      program.methods
+        .editReview(rating, comment)
+        .accounts({
+          author: author.publicKey,
+          review,
+          userReputation: sellerReputation,
+          escrow: counted && counted.escrow,
+          marketplace: null,
+          productReputation: counted && counted.productReputation,
+        })
+        .signers([author])
+        .rpc();
+
+    // The buyer's review counted toward the product, so its aggregate moves too
+    await expectError(editReview(buyer, review, 4, "Weighed it, the full 10kg"), "InvalidProductReputation");
+    await editReview(buyer, review, 4, "Weighed it, the full 10kg", { escrow, productReputation });
+    const account = await program.account.review.fetch(review);
+    expect(account.rating).to.equal(4);
+    expect(account.comment).to.equal("Weighed it, the full 10kg");
+    expect(account.editedAt.toNumber()).to.be.greaterThan(0);
+    expect((await program.account.productReputation.fetch(productReputation)).totalRating.toNumber()).to.equal(4);
+    let reputation = await program.account.userReputation.fetch(sellerReputation);
+    expect(reputation.totalRating.toNumber()).to.equal(4);
+    expect(reputation.reviewCount.toNumber()).to.equal(1);
+
+    // A review older than the window can no longer be edited
+    const author = Keypair.generate();
+    const stale = {
+      author: author.publicKey,
+      recipient: seller.publicKey,
+      rating: 3,
+      comment: "Fine",
+      createdAt: new anchor.BN(Math.floor(Date.now() / 1000) - 2 * 24 * 60 * 60),
+    };
+    const [staleReview] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("imported_review"),
+        stale.author.toBuffer(),
+        stale.recipient.toBuffer(),
+        stale.createdAt.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    await program.methods
+      .importReviewsBatch([stale])
+      .accounts({
+        authority: marketplaceAuthority.publicKey,
+        marketplace: marketplacePda,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .remainingAccounts([
+        { pubkey: staleReview, isWritable: true, isSigner: false },
+        { pubkey: sellerReputation, isWritable: true, isSigner: false },
+      ])
+      .rpc();
+    await expectError(editReview(author, staleReview, 5, "Great"), "ReviewEditWindowClosed");
+    reputation = await program.account.userReputation.fetch(sellerReputation);
+    expect(reputation.totalRating.toNumber()).to.equal(7);
+  });
+
+  it('Keeps separate averages for ratings as a seller and as a buyer', async () => {
+    const trader = await fundedKeypair(provider, 1);
+    const customer = await fundedKeypair(provider, 1);
+    const supplier = await fundedKeypair(provider, 1);
+    const traderReputation = await initReputation(program, trader);
+    const sale = await purchase(trader, customer);
+    const restock = await purchase(supplier, trader);
+
+    // The role has to match the recipient's side of the escrow
+    await expectError(
+      createReview(program, customer, trader.publicKey, 5, "Great produce", sale.escrow, null, [], null, { buyer: {} }),
+      "InvalidReviewerRole"
+    );
+    await createReview(program, customer, trader.publicKey, 5, "Great produce", sale.escrow);
+    await createReview(program, supplier, trader.publicKey, 2, "Paid late", restock.escrow);
+
+    const account = await program.account.userReputation.fetch(traderReputation);
+    expect(account.sellerReviewCount.toNumber()).to.equal(1);
+    expect(account.sellerTotalRating.toNumber()).to.equal(5);
+    expect(account.buyerReviewCount.toNumber()).to.equal(1);
+    expect(account.buyerTotalRating.toNumber()).to.equal(2);
+    expect(account.reviewCount.toNumber()).to.equal(2);
+    expect(account.averageRatingX100).to.equal(350);
+  });
+
+  it('Returns a reputation snapshot for other programs', async () => {
+    const subject = await fundedKeypair(provider, 1);
+    const reviewerA = await fundedKeypair(provider, 1);
+    const reviewerB = await fundedKeypair(provider, 1);
+    const subjectReputation = await initReputation(program, subject);
+    const purchaseA = await purchase(subject, reviewerA);
+    const purchaseB = await purchase(subject, reviewerB);
+    await createReview(program, reviewerA, subject.publicKey, 5, "Great", purchaseA.escrow);
+    await createReview(program, reviewerB, subject.publicKey, 4, "Good", purchaseB.escrow);
+
+    // view() simulates the call and decodes the return data a CPI caller reads
+    const snapshot = await program.methods
+      .reputationProof()
+      .accounts({ userReputation: subjectReputation })
+      .view();
+
+    expect(snapshot.user.toString()).to.equal(subject.publicKey.toString());
+    expect(snapshot.averageRatingX100.toNumber()).to.equal(450);
+    expect(snapshot.reviewCount.toNumber()).to.equal(2);
+    expect(snapshot.totalSales.toNumber()).to.equal(0);
+    expect(snapshot.isVerified).to.equal(false);
+    expect(snapshot.slot.toNumber()).to.be.greaterThan(0);
+  });
+
+  it('Counts one helpfulness vote per wallet and none from the author', async () => {
+    const seller = await fundedKeypair(provider, 1);
+    const buyer = await fundedKeypair(provider, 1);
+    const voter = await fundedKeypair(provider, 1);
+    const { escrow } = await purchase(seller, buyer);
+    const review = await createReview(program, buyer, seller.publicKey, 5, "Juicy and sweet", escrow);
+
+    const vote = (wallet: Keypair, helpful: boolean) => {
+      const [reviewVote] = PublicKey.findProgramAddressSync(
+        [Buffer.from("review_vote"), review.toBuffer(), wallet.publicKey.toBuffer()],
+        program.programId
+      );
+      return program.methods
+        .voteOnReview(helpful)
+        .accounts({
+          voter: wallet.publicKey,
+          review,
+          reviewVote,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+    };
+
+    await vote(voter, true);
+    await expectError(vote(voter, false), "already in use");
+    await expectError(vote(buyer, true), "AuthorCannotVote");
+
+    const account = await program.account.review.fetch(review);
+    expect(account.helpfulVotes.toNumber()).to.equal(1);
+    expect(account.unhelpfulVotes.toNumber()).to.equal(0);
+  });
+
+  describe('review responses', () => {
+    const respond = (seller: Keypair, review: PublicKey, response: string) => {
+      const [sellerActivity] = PublicKey.findProgramAddressSync(
+        [Buffer.from("seller_activity"), seller.publicKey.toBuffer()],
+        program.programId
+      );
+      return program.methods
+        .respondToReview(response)
+        .accounts({
+          seller: seller.publicKey,
+          review,
+          sellerActivity,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([seller])
+        .rpc();
+    };
+
+    const editResponse = (seller: Keypair, review: PublicKey, response: string) =>
+      program.methods
+        .editReviewResponse(response)
+        .accounts({ seller: seller.publicKey, review })
+        .signers([seller])
+        .rpc();
+
+    it('Stores the seller\'s response on the review', async () => {
+      const seller = await fundedKeypair(provider, 1);
+      const reviewer = await fundedKeypair(provider, 1);
+      const { escrow } = await purchase(seller, reviewer);
+      const review = await createReview(program, reviewer, seller.publicKey, 2, "Late", escrow);
+
+      expect((await program.account.review.fetch(review)).sellerResponse).to.be.null;
+
+      await respond(seller, review, "Sorry, see https://example.com/delays");
+      const account = await program.account.review.fetch(review);
+      expect(account.sellerResponse).to.equal("Sorry, see https://example.com/delays");
+      expect(account.responseEditedAt.toNumber()).to.equal(0);
+      expect(account.responseEditCount).to.equal(0);
+    });
+
+    it('Only lets the reviewed user respond', async () => {
+      const seller = await fundedKeypair(provider, 1);
+      const reviewer = await fundedKeypair(provider, 1);
+      const outsider = await fundedKeypair(provider, 1);
+      const { escrow } = await purchase(seller, reviewer);
+      const review = await createReview(program, reviewer, seller.publicKey, 1, "Rotten", escrow);
+
+      await expectError(respond(reviewer, review, "Not my review"), "InvalidResponder");
+      await expectError(respond(outsider, review, "Not my review either"), "InvalidResponder");
+      await expectError(editResponse(outsider, review, "Not my review"), "InvalidResponder");
+      expect((await program.account.review.fetch(review)).sellerResponse).to.be.null;
+    });
+
+    it('Makes sellers wait between responses', async () => {
+      const seller = await fundedKeypair(provider, 1);
+      const reviewerA = await fundedKeypair(provider, 1);
+      const reviewerB = await fundedKeypair(provider, 1);
+      const purchaseA = await purchase(seller, reviewerA);
+      const purchaseB = await purchase(seller, reviewerB);
+      const reviewA = await createReview(program, reviewerA, seller.publicKey, 2, "Late", purchaseA.escrow);
+      const reviewB = await createReview(program, reviewerB, seller.publicKey, 3, "Fine", purchaseB.escrow);
+
+      await respond(seller, reviewA, "Sorry for the delay");
+      await expectError(respond(seller, reviewB, "Thanks"), "ResponseCooldown");
+    });
+
+    it('Limits responses to 300 bytes', async () => {
+      const seller = await fundedKeypair(provider, 1);
+      const reviewer = await fundedKeypair(provider, 1);
+      const { escrow } = await purchase(seller, reviewer);
+      const review = await createReview(program, reviewer, seller.publicKey, 2, "Bruised", escrow);
+
+      await expectError(respond(seller, review, "a".repeat(301)), "ResponseTooLong");
+
+      await respond(seller, review, "a".repeat(300));
+      expect((await program.account.review.fetch(review)).sellerResponse).to.have.lengthOf(300);
+    });
+
+    it('Limits response edits and link schemes', async () => {
+      const seller = await fundedKeypair(provider, 1);
+      const reviewer = await fundedKeypair(provider, 1);
+      const { escrow } = await purchase(seller, reviewer);
+      const review = await createReview(program, reviewer, seller.publicKey, 1, "Rotten", escrow);
+
+      await expectError(respond(seller, review, "Refunds at ftp://example.com"), "InvalidResponseLink");
+      await expectError(editResponse(seller, review, "Nothing to edit"), "NoResponseToEdit");
+
+      await respond(seller, review, "We have refunded you");
+      await expectError(respond(seller, review, "Overwritten"), "ResponseAlreadyPosted");
+      await editResponse(seller, review, "We have refunded you in full");
+      await editResponse(seller, review, "Refund sent via ipfs://receipt");
+      await expectError(editResponse(seller, review, "One more"), "ResponseEditLimitReached");
+      await expectError(editResponse(seller, review, "a".repeat(301)), "ResponseTooLong");
+
+      const account = await program.account.review.fetch(review);
+      expect(account.responseEditCount).to.equal(2);
+      expect(account.responseEditedAt.toNumber()).to.be.greaterThan(0);
+      expect(account.sellerResponse).to.equal("Refund sent via ipfs://receipt");
+    });
+  });
+});